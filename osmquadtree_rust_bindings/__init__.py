@@ -1,4 +1,5 @@
-from .rust import call_count, timestamp_string
+from .rust import call_count, timestamp_string, PbfWriter, XmlReader, XmlWriter
+from .rust import OsmQuadtreeError, OsmIoError, MissingFileError, PbfDecodeError, TimestampParseError, StyleError, FilterError, InvalidInputError, GeometryError
 import time, os
 
 default_numchan = os.cpu_count()
@@ -147,7 +148,12 @@ def run_sortblocks(
     target=40000,
     min_target=None,
     max_qt_level=17,
-    numchan=default_numchan):
+    numchan=default_numchan,
+    resume=False,
+    compression_type=("ZlibLevel", 6)):
+    """With resume, stages completed by an earlier run (recorded in each
+    output's .checkpoint.json manifest) are not repeated. compression_type
+    may also be ("ZstdLevel", level) or ("Lz4", 0)."""
 
     lt = LogTimes()
     if not timestamp is None:
@@ -159,30 +165,34 @@ def run_sortblocks(
             raise Exception("unexpected in_filename %s" % in_filename)
         qts_filename = "%s-qts.pbf" % (in_filename[:-4],)
     
-    
-    tree = rust.prepare_quadtree_tree(numchan, max_qt_level)
-    print(tree)
-    lt("prepare quadtree tree")
-    
     if min_target is None:
         min_target = target // 2
     
-    groups = rust.find_tree_groups(tree, target, min_target)
-    lt("find tree groups")
+    groups = rust.prepare_tree_groups(qts_filename, "%s-groups.bin" % (out_filename,), target, min_target, max_qt_level, numchan, resume)
+    lt("prepare quadtree tree groups")
     print(groups)
     
     splitat=1500000//target
-    limit=30000000 // (groups.num_entries()//splitat)
+    limit=30000000 // max(1, groups.num_entries()//splitat)
     
     in_mem = os.stat(in_filename).st_size < 4*1024*1024*1024
-    lt.messages += orb.rust.sort_blocks(in_filename, qts_filename, out_filename, groups, numchan, splitat,in_mem,limit,timestamp, False)
+    lt.messages += rust.sort_blocks(in_filename, qts_filename, out_filename, groups, numchan, splitat, in_mem, limit, timestamp or 0, False, compression_type, resume)
     
     
     print(lt)
     
-def run_calcqts(in_filename, qts_filename=None, max_qt_level=18, qt_buffer=0.05, mode=None, numchan=default_numchan):
+def run_calcqts(in_filename, qts_filename=None, max_qt_level=18, qt_buffer=0.05, mode=None, numchan=default_numchan, resume=False):
     
-    print(LogTimes(rust.run_calcqts(in_filename, qts_filename, max_qt_level, qt_buffer, mode, numchan)))
+    outfn, msgs, max_timestamp = rust.run_calcqts(in_filename, qts_filename, max_qt_level, qt_buffer, mode, numchan=numchan, resume=resume)
+    print(LogTimes(msgs))
+
+
+def extract_many(prfx, extracts, strategy="complete_ways", sort=False, compression_type=("ZlibLevel", 6), timestamp=None, numchan=default_numchan):
+    """extracts is a list of (filter, out_filename) pairs, where filter is a
+    bbox, a Poly or the filename of a .poly file."""
+
+    extracts = [(rust.Poly.from_file(f) if isinstance(f, str) else f, o) for f, o in extracts]
+    return rust.extract_many(prfx, extracts, strategy, sort, compression_type, timestamp, numchan)
     
 
 
@@ -228,6 +238,12 @@ class ReadFileBlocksParallel:
         self.inner.read_all(ab, ids_obj, numchan)
         return ab.bls
 
+    def iter_blocks(self, ids_obj=None, numchan=default_numchan):
+        return self.inner.iter_blocks(ids_obj, numchan)
+    
+    def __iter__(self):
+        return self.inner.iter_blocks(None, default_numchan)
+
     def prep_bbox_filter(self, numchan=default_numchan):
         return self.inner.prep_bbox_filter(numchan)
     
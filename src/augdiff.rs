@@ -0,0 +1,604 @@
+use pyo3::prelude::*;
+use pyo3::exceptions::PyRuntimeError;
+use std::collections::{BTreeMap,BTreeSet};
+use std::io::Write;
+use std::sync::Arc;
+use std::sync::mpsc::{sync_channel,Receiver};
+
+use quick_xml::escape::escape;
+use osmquadtree::elements::{Bbox,ElementType,Member,Tag};
+
+use crate::ErrorWrapped;
+use crate::cancel::CancelToken;
+use crate::columns::element_type_code;
+use crate::xml::{coord_xml,info_attrs,tags_xml,timestamp_xml};
+
+
+#[derive(Clone)]
+enum Element {
+    Node(osmquadtree::elements::Node),
+    Way(osmquadtree::elements::Way),
+    Relation(osmquadtree::elements::Relation)
+}
+
+impl Element {
+    fn key(&self) -> (u8, i64) {
+        match self {
+            Element::Node(n) => (0, n.id),
+            Element::Way(w) => (1, w.id),
+            Element::Relation(r) => (2, r.id)
+        }
+    }
+
+    fn version(&self) -> Option<i64> {
+        let info = match self {
+            Element::Node(n) => &n.info,
+            Element::Way(w) => &w.info,
+            Element::Relation(r) => &r.info
+        };
+        info.as_ref().map(|i| i.version)
+    }
+
+    fn to_object(&self, py: Python) -> PyResult<PyObject> {
+        Ok(match self {
+            Element::Node(n) => crate::elements::Node::as_item(n.clone())?.into_py(py),
+            Element::Way(w) => crate::elements::Way::as_item(w.clone())?.into_py(py),
+            Element::Relation(r) => crate::elements::Relation::as_item(r.clone())?.into_py(py)
+        })
+    }
+}
+
+fn same_tags(a: &[Tag], b: &[Tag]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(x, y)| x.key == y.key && x.val == y.val)
+}
+
+fn same_members(a: &[Member], b: &[Member]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(x, y)|
+        element_type_code(&x.mem_type) == element_type_code(&y.mem_type) && x.mem_ref == y.mem_ref && x.role == y.role)
+}
+
+//the quadtree isn't compared: elements move between blocks as they change
+fn same_element(a: &Element, b: &Element) -> bool {
+    if a.version() != b.version() {
+        return false;
+    }
+    match (a, b) {
+        (Element::Node(x), Element::Node(y)) => x.lon == y.lon && x.lat == y.lat && same_tags(&x.tags, &y.tags),
+        (Element::Way(x), Element::Way(y)) => x.refs == y.refs && same_tags(&x.tags, &y.tags),
+        (Element::Relation(x), Element::Relation(y)) => same_members(&x.members, &y.members) && same_tags(&x.tags, &y.tags),
+        _ => false
+    }
+}
+
+fn block_elements(bl: osmquadtree::elements::PrimitiveBlock) -> BTreeMap<(u8, i64), Element> {
+    let mut res = BTreeMap::new();
+    for n in bl.nodes { res.insert((0, n.id), Element::Node(n)); }
+    for w in bl.ways { res.insert((1, w.id), Element::Way(w)); }
+    for r in bl.relations { res.insert((2, r.id), Element::Relation(r)); }
+    res
+}
+
+fn open_at(prfx: &str, ts: i64) -> PyResult<osmquadtree::pbfformat::ParallelFileLocs> {
+    Ok(osmquadtree::pbfformat::get_file_locs(prfx, Some(Bbox::planet()), Some(ts))
+        .map_err(|e| ErrorWrapped::from(e).with_filename(prfx))?)
+}
+
+fn blocks_by_quadtree(pfilelocs: &osmquadtree::pbfformat::ParallelFileLocs) -> BTreeMap<i64, usize> {
+    pfilelocs.1.iter().enumerate().map(|(i, (q, _))| (q.as_int(), i)).collect()
+}
+
+type BlockReceiver = Receiver<PyResult<Option<osmquadtree::elements::PrimitiveBlock>>>;
+
+//reads blocks `idxs` of `pfilelocs` in order on a thread of its own, sending
+//None where there's no block, so that the two sides of the diff are read and
+//decoded in parallel
+fn spawn_reader<'s>(scope: &'s std::thread::Scope<'s, '_>, mut pfilelocs: osmquadtree::pbfformat::ParallelFileLocs, prfx: &'s str, idxs: Vec<Option<usize>>, token: CancelToken) -> BlockReceiver {
+    let (sender, receiver) = sync_channel(4);
+    scope.spawn(move || {
+        for i in idxs {
+            let res = token.check().and_then(|_| i.map(|i| crate::dataset::read_block_at(&mut pfilelocs, prfx, i)).transpose());
+            let failed = res.is_err();
+            if sender.send(res).is_err() || failed {
+                return;
+            }
+        }
+    });
+    receiver
+}
+
+fn next_block(r: &BlockReceiver) -> PyResult<BTreeMap<(u8, i64), Element>> {
+    match r.recv() {
+        Ok(res) => Ok(res?.map_or_else(BTreeMap::new, block_elements)),
+        Err(_) => Err(PyRuntimeError::new_err("block reader stopped"))
+    }
+}
+
+type Changes = BTreeMap<(u8, i64), (Option<Element>, Option<Element>)>;
+
+//compares the blocks for each quadtree at the two timestamps. elements which
+//are only found on one side may have moved to another quadtree, so are only
+//reported as created or deleted once every block has been read
+fn compare_snapshots(prfx: &str, start: i64, end: i64) -> PyResult<Changes> {
+    let left = open_at(prfx, start)?;
+    let right = open_at(prfx, end)?;
+    let left_qts = blocks_by_quadtree(&left);
+    let right_qts = blocks_by_quadtree(&right);
+    let quadtrees: BTreeSet<i64> = left_qts.keys().chain(right_qts.keys()).cloned().collect();
+    let left_idxs = quadtrees.iter().map(|q| left_qts.get(q).cloned()).collect();
+    let right_idxs = quadtrees.iter().map(|q| right_qts.get(q).cloned()).collect();
+
+    let mut changes = Changes::new();
+    let mut only_left = BTreeMap::new();
+    let mut only_right = BTreeMap::new();
+    let add = |changes: &mut Changes, x: Element, y: Element| {
+        if !same_element(&x, &y) {
+            changes.insert(x.key(), (Some(x), Some(y)));
+        }
+    };
+
+    let token = crate::cancel::current();
+    std::thread::scope(|scope| -> PyResult<()> {
+        let left_blocks = spawn_reader(scope, left, prfx, left_idxs, token.clone());
+        let right_blocks = spawn_reader(scope, right, prfx, right_idxs, token.clone());
+        for _ in 0..quadtrees.len() {
+            let a = next_block(&left_blocks)?;
+            let mut b = next_block(&right_blocks)?;
+            for (k, x) in a {
+                match b.remove(&k).or_else(|| only_right.remove(&k)) {
+                    Some(y) => add(&mut changes, x, y),
+                    None => { only_left.insert(k, x); }
+                }
+            }
+            for (k, y) in b {
+                match only_left.remove(&k) {
+                    Some(x) => add(&mut changes, x, y),
+                    None => { only_right.insert(k, y); }
+                }
+            }
+        }
+        Ok(())
+    })?;
+    for (k, x) in only_left { changes.insert(k, (Some(x), None)); }
+    for (k, y) in only_right { changes.insert(k, (None, Some(y))); }
+    Ok(changes)
+}
+
+fn scan_blocks<F: FnMut(osmquadtree::elements::PrimitiveBlock)>(pfilelocs: &mut osmquadtree::pbfformat::ParallelFileLocs, prfx: &str, idxs: &[usize], token: &CancelToken, mut f: F) -> PyResult<()> {
+    for i in idxs {
+        token.check()?;
+        f(crate::dataset::read_block_at(pfilelocs, prfx, *i)?);
+    }
+    Ok(())
+}
+
+//node locations and way nodes at one timestamp, for resolving geometries
+#[derive(Default)]
+struct Side {
+    coords: BTreeMap<i64, (i32, i32)>,
+    ways: BTreeMap<i64, Vec<i64>>
+}
+
+//the old or new version of each change
+fn side_elements<'a>(changes: &'a Changes, old: bool) -> impl Iterator<Item=&'a Element> + 'a {
+    changes.values().filter_map(move |(a, b)| if old { a.as_ref() } else { b.as_ref() })
+}
+
+//finds the node locations and member ways needed for one side in a single
+//pass, along with the unchanged ways with moved nodes. the nodes of ways
+//found in that pass which weren't already known are then read from just
+//the blocks around those ways
+fn resolve_side(prfx: &str, ts: i64, changes: &Changes, old: bool, moved: &BTreeSet<i64>, token: &CancelToken) -> PyResult<(Side, BTreeMap<i64, osmquadtree::elements::Way>)> {
+    let mut side = Side::default();
+    let mut touched = BTreeMap::new();
+
+    let mut member_ways = BTreeSet::new();
+    let mut nodes = BTreeSet::new();
+    for e in side_elements(changes, old) {
+        match e {
+            Element::Node(_) => {},
+            Element::Way(w) => { nodes.extend(w.refs.iter().cloned()); },
+            Element::Relation(r) => {
+                for m in &r.members {
+                    match m.mem_type {
+                        ElementType::Node => { nodes.insert(m.mem_ref); },
+                        ElementType::Way => { member_ways.insert(m.mem_ref); },
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+    if nodes.is_empty() && moved.is_empty() && member_ways.is_empty() {
+        return Ok((side, touched));
+    }
+
+    let mut pfilelocs = open_at(prfx, ts)?;
+    let all: Vec<usize> = (0..pfilelocs.1.len()).collect();
+    let mut way_quadtrees = BTreeSet::new();
+    scan_blocks(&mut pfilelocs, prfx, &all, token, |bl| {
+        for n in bl.nodes {
+            if nodes.contains(&n.id) || moved.contains(&n.id) {
+                side.coords.insert(n.id, (n.lon, n.lat));
+            }
+        }
+        for w in bl.ways {
+            let is_member = member_ways.contains(&w.id);
+            let is_touched = !changes.contains_key(&(1, w.id)) && w.refs.iter().any(|r| moved.contains(r));
+            if is_member || is_touched {
+                way_quadtrees.insert(w.quadtree.as_int());
+            }
+            if is_member {
+                side.ways.insert(w.id, w.refs.clone());
+            }
+            if is_touched {
+                touched.insert(w.id, w);
+            }
+        }
+    })?;
+
+    let missing: BTreeSet<i64> = side.ways.values().chain(touched.values().map(|w| &w.refs))
+        .flat_map(|rr| rr.iter().cloned()).filter(|r| !side.coords.contains_key(r)).collect();
+    if !missing.is_empty() {
+        let boxes: Vec<Bbox> = way_quadtrees.iter().map(|q| osmquadtree::elements::Quadtree::new(*q).as_bbox(crate::dataset::QUADTREE_BUFFER)).collect();
+        let idxs: Vec<usize> = pfilelocs.1.iter().enumerate()
+            .filter(|(_, (q, _))| { let qb = q.as_bbox(crate::dataset::QUADTREE_BUFFER); boxes.iter().any(|b| crate::dataset::overlaps(b, &qb)) })
+            .map(|(i, _)| i).collect();
+        scan_blocks(&mut pfilelocs, prfx, &idxs, token, |bl| {
+            for n in bl.nodes {
+                if missing.contains(&n.id) {
+                    side.coords.insert(n.id, (n.lon, n.lat));
+                }
+            }
+        })?;
+    }
+    Ok((side, touched))
+}
+
+#[derive(Clone)]
+enum Geometry {
+    Missing,
+    Point(i32, i32),
+    Line(Vec<Option<(i32, i32)>>),
+    Members(Vec<Geometry>)
+}
+
+impl Geometry {
+    fn line(refs: &[i64], side: &Side) -> Geometry {
+        Geometry::Line(refs.iter().map(|r| side.coords.get(r).cloned()).collect())
+    }
+
+    fn of(e: &Element, side: &Side) -> Geometry {
+        match e {
+            Element::Node(n) => Geometry::Point(n.lon, n.lat),
+            Element::Way(w) => Geometry::line(&w.refs, side),
+            Element::Relation(r) => Geometry::Members(r.members.iter().map(|m| match m.mem_type {
+                ElementType::Node => side.coords.get(&m.mem_ref).map_or(Geometry::Missing, |(x, y)| Geometry::Point(*x, *y)),
+                ElementType::Way => side.ways.get(&m.mem_ref).map_or(Geometry::Missing, |rr| Geometry::line(rr, side)),
+                _ => Geometry::Missing
+            }).collect())
+        }
+    }
+
+    fn to_object(&self, py: Python) -> PyObject {
+        match self {
+            Geometry::Missing => py.None(),
+            Geometry::Point(x, y) => (*x, *y).into_py(py),
+            Geometry::Line(pts) => pts.clone().into_py(py),
+            Geometry::Members(mm) => mm.iter().map(|m| m.to_object(py)).collect::<Vec<_>>().into_py(py)
+        }
+    }
+
+    fn add_bounds(&self, bounds: &mut Option<(i32, i32, i32, i32)>) {
+        let mut add = |x: i32, y: i32| {
+            *bounds = Some(match bounds {
+                None => (x, y, x, y),
+                Some(b) => (b.0.min(x), b.1.min(y), b.2.max(x), b.3.max(y))
+            });
+        };
+        match self {
+            Geometry::Missing => {},
+            Geometry::Point(x, y) => add(*x, *y),
+            Geometry::Line(pts) => { for (x, y) in pts.iter().flatten() { add(*x, *y); } },
+            Geometry::Members(mm) => { for m in mm { m.add_bounds(bounds); } }
+        }
+    }
+}
+
+pub(crate) struct Action {
+    old: Option<Element>,
+    new: Option<Element>,
+    old_geometry: Geometry,
+    new_geometry: Geometry
+}
+
+impl Action {
+    fn action(&self) -> &'static str {
+        match (&self.old, &self.new) {
+            (None, _) => "create",
+            (_, None) => "delete",
+            _ => "modify"
+        }
+    }
+
+    fn element(&self) -> &Element {
+        self.new.as_ref().or(self.old.as_ref()).unwrap()
+    }
+}
+
+//the changes with the node locations and member ways at each timestamp.
+//actions, with their geometries, are only built as they are used
+struct DiffData {
+    changes: Vec<(Option<Element>, Option<Element>)>,
+    left: Side,
+    right: Side
+}
+
+impl DiffData {
+    fn action(&self, i: usize) -> Option<Action> {
+        let (old, new) = self.changes.get(i)?;
+        Some(Action{
+            old_geometry: old.as_ref().map_or(Geometry::Missing, |e| Geometry::of(e, &self.left)),
+            new_geometry: new.as_ref().map_or(Geometry::Missing, |e| Geometry::of(e, &self.right)),
+            old: old.clone(), new: new.clone()})
+    }
+}
+
+fn compute_diff(prfx: &str, start: i64, end: i64) -> PyResult<DiffData> {
+    let mut changes = compare_snapshots(prfx, start, end)?;
+
+    //nodes whose location changed, which change the shape of the ways using them
+    let mut moved = BTreeSet::new();
+    for (a, b) in changes.values() {
+        if let (Some(Element::Node(x)), Some(Element::Node(y))) = (a, b) {
+            if x.lon != y.lon || x.lat != y.lat {
+                moved.insert(x.id);
+            }
+        }
+    }
+
+    //both sides at once
+    let token = crate::cancel::current();
+    let (left, right) = std::thread::scope(|scope| {
+        let h = scope.spawn(|| resolve_side(prfx, start, &changes, true, &moved, &token));
+        let right = resolve_side(prfx, end, &changes, false, &moved, &token);
+        let left = h.join().unwrap_or_else(|e| std::panic::resume_unwind(e));
+        (left, right)
+    });
+    let (left, touched_old) = left?;
+    let (right, mut touched_new) = right?;
+    for (id, w) in touched_old {
+        if let Some(w2) = touched_new.remove(&id) {
+            changes.insert((1, id), (Some(Element::Way(w)), Some(Element::Way(w2))));
+        }
+    }
+
+    Ok(DiffData{changes: changes.into_values().collect(), left: left, right: right})
+}
+
+fn bounds_xml(out: &mut String, geom: &Geometry) {
+    let mut bounds = None;
+    geom.add_bounds(&mut bounds);
+    if let Some(b) = bounds {
+        out.push_str(&format!("    <bounds minlat=\"{}\" minlon=\"{}\" maxlat=\"{}\" maxlon=\"{}\"/>\n",
+            coord_xml(b.1), coord_xml(b.0), coord_xml(b.3), coord_xml(b.2)));
+    }
+}
+
+fn point_attrs(p: &Option<(i32, i32)>) -> String {
+    match p {
+        Some((x, y)) => format!(" lat=\"{}\" lon=\"{}\"", coord_xml(*y), coord_xml(*x)),
+        None => String::new()
+    }
+}
+
+fn element_xml(e: &Element, geom: &Geometry) -> String {
+    match e {
+        Element::Node(n) => crate::xml::node_xml(n),
+        Element::Way(w) => {
+            let mut res = format!("  <way id=\"{}\"{}>\n", w.id, info_attrs(&w.info));
+            bounds_xml(&mut res, geom);
+            let pts = match geom { Geometry::Line(pts) => pts.clone(), _ => Vec::new() };
+            for (i, r) in w.refs.iter().enumerate() {
+                res.push_str(&format!("    <nd ref=\"{}\"{}/>\n", r, point_attrs(pts.get(i).unwrap_or(&None))));
+            }
+            tags_xml(&mut res, &w.tags);
+            res.push_str("  </way>\n");
+            res
+        },
+        Element::Relation(r) => {
+            let mut res = format!("  <relation id=\"{}\"{}>\n", r.id, info_attrs(&r.info));
+            bounds_xml(&mut res, geom);
+            let mm = match geom { Geometry::Members(mm) => mm.clone(), _ => Vec::new() };
+            for (i, m) in r.members.iter().enumerate() {
+                let mt = match m.mem_type {
+                    ElementType::Node => "node",
+                    ElementType::Way => "way",
+                    _ => "relation"
+                };
+                let head = format!("    <member type=\"{}\" ref=\"{}\" role=\"{}\"", mt, m.mem_ref, escape(m.role.as_str()));
+                match mm.get(i) {
+                    Some(Geometry::Point(x, y)) => { res.push_str(&format!("{}{}/>\n", head, point_attrs(&Some((*x, *y))))); },
+                    Some(Geometry::Line(pts)) => {
+                        res.push_str(&format!("{}>\n", head));
+                        for p in pts {
+                            res.push_str(&format!("      <nd{}/>\n", point_attrs(p)));
+                        }
+                        res.push_str("    </member>\n");
+                    },
+                    _ => { res.push_str(&format!("{}/>\n", head)); }
+                }
+            }
+            tags_xml(&mut res, &r.tags);
+            res.push_str("  </relation>\n");
+            res
+        }
+    }
+}
+
+fn deleted_xml(e: &Element) -> String {
+    let (t, id) = match e.key() {
+        (0, id) => ("node", id),
+        (1, id) => ("way", id),
+        (_, id) => ("relation", id)
+    };
+    format!("  <{} id=\"{}\" visible=\"false\"/>\n", t, id)
+}
+
+fn action_xml(a: &Action) -> String {
+    let mut res = format!("<action type=\"{}\">\n", a.action());
+    match (&a.old, &a.new) {
+        (None, Some(n)) => { res.push_str(&element_xml(n, &a.new_geometry)); },
+        (Some(o), None) => {
+            res.push_str("<old>\n");
+            res.push_str(&element_xml(o, &a.old_geometry));
+            res.push_str("</old>\n<new>\n");
+            res.push_str(&deleted_xml(o));
+            res.push_str("</new>\n");
+        },
+        (Some(o), Some(n)) => {
+            res.push_str("<old>\n");
+            res.push_str(&element_xml(o, &a.old_geometry));
+            res.push_str("</old>\n<new>\n");
+            res.push_str(&element_xml(n, &a.new_geometry));
+            res.push_str("</new>\n");
+        },
+        (None, None) => {}
+    }
+    res.push_str("</action>\n");
+    res
+}
+
+
+/// One change in an AugmentedDiff: the old and new versions of the element,
+/// with their geometries as of each timestamp.
+#[pyclass]
+pub struct AugmentedAction {
+    inner: Arc<Action>
+}
+
+#[pymethods]
+impl AugmentedAction {
+    /// "create", "modify" or "delete".
+    #[getter]
+    pub fn action(&self) -> PyResult<&'static str> { Ok(self.inner.action()) }
+
+    #[getter]
+    pub fn element_type(&self) -> PyResult<&'static str> {
+        Ok(match self.inner.element().key().0 { 0 => "node", 1 => "way", _ => "relation" })
+    }
+
+    #[getter]
+    pub fn id(&self) -> PyResult<i64> { Ok(self.inner.element().key().1) }
+
+    #[getter]
+    pub fn old(&self, py: Python) -> PyResult<PyObject> {
+        self.inner.old.as_ref().map_or(Ok(py.None()), |e| e.to_object(py))
+    }
+
+    #[getter]
+    pub fn new(&self, py: Python) -> PyResult<PyObject> {
+        self.inner.new.as_ref().map_or(Ok(py.None()), |e| e.to_object(py))
+    }
+
+    /// For a node (lon, lat); for a way a list of (lon, lat) for each node,
+    /// or None where a node is missing; for a relation, a list with the
+    /// geometry of each member (None for member relations).
+    #[getter]
+    pub fn old_geometry(&self, py: Python) -> PyResult<PyObject> { Ok(self.inner.old_geometry.to_object(py)) }
+
+    #[getter]
+    pub fn new_geometry(&self, py: Python) -> PyResult<PyObject> { Ok(self.inner.new_geometry.to_object(py)) }
+
+    fn __repr__(&self) -> PyResult<String> {
+        Ok(format!("AugmentedAction({} {} {})", self.action()?, self.element_type()?, self.id()?))
+    }
+}
+
+/// The changes to the dataset at `prfx` between timestamps `start` and
+/// `end`, as with Overpass augmented diffs: each created, modified or
+/// deleted element with its old and new versions and geometries. Ways whose
+/// nodes have moved are included as modified. Iterate over the diff for
+/// AugmentedActions, or write it as augmented diff XML with write_xml: each
+/// action's geometries are only built as it is returned or written.
+#[pyclass]
+pub struct AugmentedDiff {
+    prfx: String,
+    start: i64,
+    end: i64,
+    data: Arc<DiffData>,
+    pos: usize
+}
+
+#[pymethods]
+impl AugmentedDiff {
+    #[new]
+    pub fn new(py: Python, prfx: &str, start: &str, end: &str) -> PyResult<AugmentedDiff> {
+        let start = crate::elements::parse_timestamp(start)?;
+        let end = crate::elements::parse_timestamp(end)?;
+        if start > end {
+            return Err(crate::errors::invalid_input_error(format!("start {} is after end {}",
+                osmquadtree::utils::timestamp_string(start), osmquadtree::utils::timestamp_string(end))));
+        }
+        let data = crate::cancel::run_interruptible(py, &[], || compute_diff(prfx, start, end))?;
+        Ok(AugmentedDiff{prfx: String::from(prfx), start: start, end: end, data: Arc::new(data), pos: 0})
+    }
+
+    #[getter]
+    pub fn start(&self) -> PyResult<i64> { Ok(self.start) }
+
+    #[getter]
+    pub fn end(&self) -> PyResult<i64> { Ok(self.end) }
+
+    fn __len__(&self) -> usize {
+        self.data.changes.len()
+    }
+
+    fn __iter__(mut slf: PyRefMut<'_, Self>) -> PyRefMut<'_, Self> {
+        slf.pos = 0;
+        slf
+    }
+
+    fn __next__(&mut self) -> Option<AugmentedAction> {
+        let a = self.data.action(self.pos)?;
+        self.pos += 1;
+        Some(AugmentedAction{inner: Arc::new(a)})
+    }
+
+    /// Writes the diff as Overpass augmented diff XML, gzipped if `gzip` is
+    /// set or by default if `outfn` ends with ".gz". Returns the number of
+    /// actions written.
+    #[pyo3(signature = (outfn, gzip=None))]
+    pub fn write_xml(&self, py: Python, outfn: &str, gzip: Option<bool>) -> PyResult<usize> {
+        let data = self.data.clone();
+        let end = self.end;
+        crate::cancel::run_interruptible(py, &[String::from(outfn)], || {
+            let mut out = crate::xml::open_output(outfn, gzip)?;
+            let mut write = |s: &str| out.write_all(s.as_bytes()).map_err(|e| ErrorWrapped::from(e).with_filename(outfn));
+            write("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n")?;
+            write("<osm version=\"0.6\" generator=\"osmquadtree_rust_bindings\">\n")?;
+            write("<note>The data included in this document is from www.openstreetmap.org. The data is made available under ODbL.</note>\n")?;
+            write(&format!("<meta osm_base=\"{}\"/>\n", timestamp_xml(end)))?;
+            for i in 0..data.changes.len() {
+                crate::cancel::check_cancelled()?;
+                if let Some(a) = data.action(i) {
+                    write(&action_xml(&a))?;
+                }
+            }
+            write("</osm>\n")?;
+            out.finish().map_err(|e| ErrorWrapped::from(e).with_filename(outfn))?;
+            Ok(data.changes.len())
+        })
+    }
+
+    fn __repr__(&self) -> PyResult<String> {
+        Ok(format!("AugmentedDiff {} {} to {}: {} actions", self.prfx,
+            osmquadtree::utils::timestamp_string(self.start), osmquadtree::utils::timestamp_string(self.end), self.data.changes.len()))
+    }
+}
+
+
+pub(crate) fn wrap_augdiff(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<AugmentedDiff>()?;
+    m.add_class::<AugmentedAction>()?;
+    Ok(())
+}
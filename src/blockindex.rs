@@ -0,0 +1,254 @@
+use pyo3::prelude::*;
+use pyo3::wrap_pyfunction;
+use std::fs::File;
+use std::io::{BufReader,BufWriter,Read,Write};
+
+use osmquadtree::elements::{Bbox,Quadtree};
+
+use crate::ErrorWrapped;
+
+
+const MAGIC: &[u8] = b"OQTBIX\x01\n";
+const ENTRY_LEN: usize = 17;
+
+const TYPE_NAMES: [&str; 3] = ["node", "way", "relation"];
+
+/// One OSMData block: its position and length in the file, the number of
+/// each element type with their id range, the block quadtree (negative if
+/// the file doesn't have quadtrees) and the bounds of its nodes.
+#[derive(Clone)]
+pub(crate) struct BlockIndexEntry {
+    pub pos: u64,
+    pub len: u64,
+    pub quadtree: i64,
+    pub ids: [(i64, i64, i64); 3],
+    pub bbox: Option<(i32, i32, i32, i32)>
+}
+
+impl BlockIndexEntry {
+    fn from_block(pos: u64, len: u64, bl: &osmquadtree::elements::MinimalBlock) -> BlockIndexEntry {
+        let range = |ids: &mut dyn Iterator<Item=i64>| {
+            match ids.fold((0, i64::MAX, i64::MIN), |(c, a, b), i| (c + 1, a.min(i), b.max(i))) {
+                (0, _, _) => (0, 0, 0),
+                r => r
+            }
+        };
+        let mut bbox: Option<(i32, i32, i32, i32)> = None;
+        for n in &bl.nodes {
+            bbox = Some(match bbox {
+                None => (n.lon, n.lat, n.lon, n.lat),
+                Some(b) => (b.0.min(n.lon), b.1.min(n.lat), b.2.max(n.lon), b.3.max(n.lat))
+            });
+        }
+        BlockIndexEntry{
+            pos: pos,
+            len: len,
+            quadtree: bl.quadtree.as_int(),
+            ids: [
+                range(&mut bl.nodes.iter().map(|n| n.id)),
+                range(&mut bl.ways.iter().map(|w| w.id)),
+                range(&mut bl.relations.iter().map(|r| r.id))
+            ],
+            bbox: bbox
+        }
+    }
+
+    /// "node", "way" or "relation" if the block only holds one type of
+    /// element, otherwise "mixed" or "empty".
+    pub fn element_type(&self) -> &'static str {
+        let present: Vec<usize> = (0..3).filter(|i| self.ids[*i].0 > 0).collect();
+        match present.len() {
+            0 => "empty",
+            1 => TYPE_NAMES[present[0]],
+            _ => "mixed"
+        }
+    }
+
+    //blocks of ways and relations in a file without quadtrees have no
+    //location, so may overlap any box
+    fn overlaps(&self, bbox: &Bbox) -> bool {
+        let b = if self.quadtree >= 0 {
+            Quadtree::new(self.quadtree).as_bbox(crate::dataset::QUADTREE_BUFFER)
+        } else {
+            match (self.bbox, self.ids[1].0 + self.ids[2].0) {
+                (Some(b), 0) => Bbox::new(b.0, b.1, b.2, b.3),
+                _ => { return true; }
+            }
+        };
+        crate::dataset::overlaps(&b, bbox)
+    }
+
+    fn to_vals(&self) -> [i64; ENTRY_LEN] {
+        let b = self.bbox.unwrap_or((0, 0, 0, 0));
+        [self.pos as i64, self.len as i64, self.quadtree,
+            self.ids[0].0, self.ids[0].1, self.ids[0].2,
+            self.ids[1].0, self.ids[1].1, self.ids[1].2,
+            self.ids[2].0, self.ids[2].1, self.ids[2].2,
+            self.bbox.is_some() as i64, b.0 as i64, b.1 as i64, b.2 as i64, b.3 as i64]
+    }
+
+    fn from_vals(v: &[i64]) -> BlockIndexEntry {
+        BlockIndexEntry{
+            pos: v[0] as u64,
+            len: v[1] as u64,
+            quadtree: v[2],
+            ids: [(v[3], v[4], v[5]), (v[6], v[7], v[8]), (v[9], v[10], v[11])],
+            bbox: if v[12] != 0 { Some((v[13] as i32, v[14] as i32, v[15] as i32, v[16] as i32)) } else { None }
+        }
+    }
+}
+
+//the length and modification time of the indexed file, so that a stale
+//index isn't used
+fn file_stamp(fname: &str) -> std::io::Result<(u64, u64)> {
+    let md = std::fs::metadata(fname)?;
+    let mtime = md.modified()?.duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    Ok((md.len(), mtime))
+}
+
+//the id ranges of one element type sorted by their first id, with the
+//largest last id up to each position, so block_for_id can binary search
+//rather than checking every block. the ranges of a sorted file don't
+//overlap, so only one candidate is usually checked
+struct IdLookup {
+    ranges: Vec<(i64, i64, usize)>,
+    max_to: Vec<i64>
+}
+
+impl IdLookup {
+    fn new(entries: &[BlockIndexEntry], t: usize) -> IdLookup {
+        let mut ranges: Vec<(i64, i64, usize)> = entries.iter().enumerate()
+            .filter(|(_, e)| e.ids[t].0 > 0)
+            .map(|(i, e)| (e.ids[t].1, e.ids[t].2, i))
+            .collect();
+        ranges.sort();
+        let mut max_to = Vec::with_capacity(ranges.len());
+        let mut m = i64::MIN;
+        for r in &ranges {
+            m = m.max(r.1);
+            max_to.push(m);
+        }
+        IdLookup{ranges: ranges, max_to: max_to}
+    }
+
+    fn find(&self, id: i64) -> Option<usize> {
+        let mut j = self.ranges.partition_point(|r| r.0 <= id);
+        let mut found: Option<usize> = None;
+        while j > 0 && self.max_to[j - 1] >= id {
+            j -= 1;
+            let (_, b, i) = self.ranges[j];
+            if id <= b {
+                found = Some(found.map_or(i, |f| f.min(i)));
+            }
+        }
+        found
+    }
+}
+
+/// The position and contents of every OSMData block in a pbf file.
+pub(crate) struct BlockIndex {
+    stamp: (u64, u64),
+    pub entries: Vec<BlockIndexEntry>,
+    lookup: [IdLookup; 3]
+}
+
+impl BlockIndex {
+    fn new(stamp: (u64, u64), entries: Vec<BlockIndexEntry>) -> BlockIndex {
+        let lookup = [IdLookup::new(&entries, 0), IdLookup::new(&entries, 1), IdLookup::new(&entries, 2)];
+        BlockIndex{stamp: stamp, entries: entries, lookup: lookup}
+    }
+
+    /// Path of the sidecar index for `fname`.
+    pub fn sidecar_path(fname: &str) -> String {
+        format!("{}.blockindex", fname)
+    }
+
+    /// Reads every block of `fname` once.
+    pub fn build(fname: &str) -> PyResult<BlockIndex> {
+        let stamp = file_stamp(fname).map_err(|e| ErrorWrapped::from(e).with_filename(fname))?;
+        let mut fbuf = BufReader::new(File::open(fname).map_err(|e| ErrorWrapped::from(e).with_filename(fname))?);
+        let mut entries = Vec::new();
+        while osmquadtree::pbfformat::file_position(&mut fbuf).map_err(ErrorWrapped::from)? < stamp.0 {
+            crate::cancel::check_cancelled()?;
+            let fb = crate::compression::read_file_block(&mut fbuf)
+                .map_err(|e| ErrorWrapped::from(e).with_filename(fname))?;
+            if fb.block_type != "OSMData" {
+                continue;
+            }
+            let bl = osmquadtree::elements::MinimalBlock::read(entries.len() as i64, fb.pos, &fb.data(), false)
+                .map_err(|e| ErrorWrapped::from(e).with_filename(fname).with_position(fb.pos))?;
+            entries.push(BlockIndexEntry::from_block(fb.pos, fb.len, &bl));
+        }
+        Ok(BlockIndex::new(stamp, entries))
+    }
+
+    pub fn write(&self, outfn: &str) -> std::io::Result<()> {
+        let mut w = BufWriter::new(File::create(outfn)?);
+        w.write_all(MAGIC)?;
+        w.write_all(&self.stamp.0.to_le_bytes())?;
+        w.write_all(&self.stamp.1.to_le_bytes())?;
+        w.write_all(&(self.entries.len() as u64).to_le_bytes())?;
+        for e in &self.entries {
+            for v in e.to_vals().iter() {
+                w.write_all(&v.to_le_bytes())?;
+            }
+        }
+        w.flush()
+    }
+
+    pub fn read(infn: &str) -> PyResult<BlockIndex> {
+        crate::errors::check_exists(infn)?;
+        let mut data = Vec::new();
+        File::open(infn).map_err(|e| ErrorWrapped::from(e).with_filename(infn))?.read_to_end(&mut data).map_err(ErrorWrapped::from)?;
+        let head = MAGIC.len() + 24;
+        if !data.starts_with(MAGIC) || data.len() < head {
+            return Err(crate::errors::invalid_input_error(format!("{} is not a block index", infn)));
+        }
+        let vals: Vec<u64> = data[MAGIC.len()..].chunks_exact(8).map(|c| { let mut b = [0u8; 8]; b.copy_from_slice(c); u64::from_le_bytes(b) }).collect();
+        let n = vals[2] as usize;
+        if data.len() != head + n * ENTRY_LEN * 8 {
+            return Err(crate::errors::invalid_input_error(format!("{}: truncated block index", infn)));
+        }
+        let entries = vals[3..].chunks_exact(ENTRY_LEN)
+            .map(|c| BlockIndexEntry::from_vals(&c.iter().map(|v| *v as i64).collect::<Vec<_>>()))
+            .collect();
+        Ok(BlockIndex::new((vals[0], vals[1]), entries))
+    }
+
+    /// True if `fname` hasn't changed since the index was built.
+    pub fn matches(&self, fname: &str) -> bool {
+        file_stamp(fname).map_or(false, |s| s == self.stamp)
+    }
+
+    /// Index of the block holding element `id` of type `t` (0 for nodes, 1
+    /// for ways and 2 for relations), if any block's id range includes it.
+    pub fn block_for_id(&self, t: usize, id: i64) -> Option<usize> {
+        self.lookup[t].find(id)
+    }
+
+    pub fn blocks_in_bbox(&self, bbox: &Bbox) -> Vec<usize> {
+        (0..self.entries.len()).filter(|i| self.entries[*i].overlaps(bbox)).collect()
+    }
+}
+
+/// Reads every block of the pbf file `fname` once, and writes the position,
+/// length, element types, id ranges, quadtree and node bounds of each to the
+/// sidecar file `<fname>.blockindex` (or `outfn`). ReadFileBlocks uses this
+/// for block_for_id and blocks_in_bbox. Returns the number of blocks.
+#[pyfunction]
+#[pyo3(signature = (fname, outfn=None))]
+pub fn build_block_index(py: Python, fname: &str, outfn: Option<&str>) -> PyResult<usize> {
+    crate::errors::check_exists(fname)?;
+    let outfn = outfn.map_or_else(|| BlockIndex::sidecar_path(fname), String::from);
+    crate::cancel::run_interruptible(py, &[outfn.clone()], || {
+        let idx = BlockIndex::build(fname)?;
+        idx.write(&outfn).map_err(|e| ErrorWrapped::from(e).with_filename(&outfn))?;
+        Ok(idx.entries.len())
+    })
+}
+
+
+pub(crate) fn wrap_blockindex(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_wrapped(wrap_pyfunction!(build_block_index))?;
+    Ok(())
+}
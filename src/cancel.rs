@@ -0,0 +1,269 @@
+use pyo3::prelude::*;
+use pyo3::wrap_pyfunction;
+use pyo3::exceptions::PyKeyboardInterrupt;
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::path::{Path,PathBuf};
+use std::sync::{Arc,Mutex};
+use std::sync::atomic::{AtomicBool,Ordering};
+use std::panic::resume_unwind;
+use std::time::Duration;
+
+use channelled_callbacks::{CallFinish, Result as ccResult};
+use osmquadtree::utils::Error;
+
+struct CallState {
+    cancelled: AtomicBool,
+    callback_error: Mutex<Option<PyErr>>
+}
+
+/// Set when the run_interruptible call which owns it is interrupted, or by
+/// request_cancel. Each call has its own token, so one operation being
+/// cancelled doesn't affect any other. The token also keeps the first
+/// exception raised by the messenger during the call.
+#[derive(Clone)]
+pub struct CancelToken(Arc<CallState>);
+
+impl CancelToken {
+    pub(crate) fn new() -> CancelToken {
+        CancelToken(Arc::new(CallState{cancelled: AtomicBool::new(false), callback_error: Mutex::new(None)}))
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::Relaxed)
+    }
+
+    pub fn cancel(&self) {
+        self.0.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    fn set_callback_error(&self, e: PyErr) {
+        let mut slot = self.0.callback_error.lock().unwrap_or_else(|e| e.into_inner());
+        if slot.is_none() {
+            *slot = Some(e);
+        }
+    }
+
+    fn take_callback_error(&self) -> Option<PyErr> {
+        self.0.callback_error.lock().unwrap_or_else(|e| e.into_inner()).take()
+    }
+
+    pub fn check(&self) -> PyResult<()> {
+        if self.is_cancelled() {
+            Err(PyKeyboardInterrupt::new_err("cancelled"))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<CancelToken>> = const { RefCell::new(None) };
+}
+
+//the tokens of every run_interruptible call in progress, for request_cancel
+static ACTIVE: Mutex<Vec<CancelToken>> = Mutex::new(Vec::new());
+
+fn active() -> std::sync::MutexGuard<'static, Vec<CancelToken>> {
+    ACTIVE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn current_opt() -> Option<CancelToken> {
+    CURRENT.with(|c| c.borrow().clone())
+}
+
+/// The token of the run_interruptible call running on this thread. Block
+/// loops on other threads (such as CallFinish implementations run by the
+/// osmquadtree pipelines) should take a copy of this when they are created,
+/// as with stop_when_cancelled.
+pub fn current() -> CancelToken {
+    current_opt().unwrap_or_else(CancelToken::new)
+}
+
+pub fn is_cancelled() -> bool {
+    current().is_cancelled()
+}
+
+pub fn check_cancelled() -> PyResult<()> {
+    current().check()
+}
+
+/// Runs `f` with `token` as the current token of this thread, for threads
+/// which aren't started by run_interruptible, such as iter_geometry's.
+pub(crate) fn with_token<T, F: FnOnce() -> T>(token: CancelToken, f: F) -> T {
+    CURRENT.with(|c| *c.borrow_mut() = Some(token));
+    let res = f();
+    CURRENT.with(|c| *c.borrow_mut() = None);
+    res
+}
+
+/// Keeps an exception raised by the messenger, which the osmquadtree
+/// logging traits can't return, for run_interruptible to raise once the
+/// operation has finished. The messenger is called from osmquadtree's own
+/// threads, which don't know which call they are working for: the
+/// exception then goes to every call in progress. With none, it is printed.
+pub(crate) fn set_callback_error(py: Python, e: PyErr) {
+    if let Some(t) = current_opt() {
+        t.set_callback_error(e);
+        return;
+    }
+    let calls = active();
+    if calls.is_empty() {
+        e.print(py);
+    }
+    for t in calls.iter() {
+        t.set_callback_error(e.clone_ref(py));
+    }
+}
+
+/// Passes blocks on to `out` until `token` is cancelled, then drops them,
+/// so that the later stages of a pipeline stop working on an interrupted
+/// call.
+pub(crate) struct StopWhenCancelled<T, R> {
+    out: Box<dyn CallFinish<CallType = T, ReturnType = R, ErrorType = Error>>,
+    token: CancelToken
+}
+
+impl<T: Send + 'static, R: Send + 'static> CallFinish for StopWhenCancelled<T, R> {
+    type CallType = T;
+    type ReturnType = R;
+    type ErrorType = Error;
+
+    fn call(&mut self, t: T) {
+        if !self.token.is_cancelled() {
+            self.out.call(t);
+        }
+    }
+
+    fn finish(&mut self) -> ccResult<R, Error> {
+        self.out.finish()
+    }
+}
+
+/// Wraps `out` with the token of the call running on this thread. The
+/// pipeline stages run on threads started by channelled_callbacks, which
+/// `current` doesn't know about, so this must be called from the
+/// operation's own thread, i.e. inside run_interruptible.
+pub(crate) fn stop_when_cancelled<T: Send + 'static, R: Send + 'static>(
+        out: Box<dyn CallFinish<CallType = T, ReturnType = R, ErrorType = Error>>)
+        -> Box<dyn CallFinish<CallType = T, ReturnType = R, ErrorType = Error>> {
+    Box::new(StopWhenCancelled{out: out, token: current()})
+}
+
+//the files in the directories of `outputs` which start with one of their
+//names, so that only files created by the operation are removed
+fn matching_files(outputs: &[String]) -> BTreeSet<PathBuf> {
+    let mut res = BTreeSet::new();
+    for o in outputs {
+        let path = Path::new(o);
+        let name = match path.file_name().and_then(|n| n.to_str()) {
+            Some(n) => n,
+            None => { continue; }
+        };
+        let dir = match path.parent() {
+            Some(d) if !d.as_os_str().is_empty() => d.to_path_buf(),
+            _ => PathBuf::from(".")
+        };
+        if let Ok(entries) = std::fs::read_dir(&dir) {
+            for e in entries.flatten() {
+                if e.file_name().to_string_lossy().starts_with(name) {
+                    res.insert(e.path());
+                }
+            }
+        }
+    }
+    res
+}
+
+/// Runs `op` on a new thread with the GIL released, checking for signals
+/// (i.e. Ctrl-C) every 100ms. When a signal is raised the call's cancel
+/// token is set and we wait for `op` to stop. The block loops in this crate
+/// return as soon as they see the token, and the pipeline stages wrapped
+/// with stop_when_cancelled drop the remaining blocks. Steps run entirely
+/// inside osmquadtree (calcqts, write_temp_blocks, call_count) can't be
+/// stopped part way: callers split these into one call per stage. If `op`
+/// was interrupted, or the token was cancelled by request_cancel, the files
+/// it created whose names start with one of `outputs` (the outputs
+/// themselves, and temporary files such as `<output>-partial`) are removed,
+/// and a KeyboardInterrupt returned even if `op` finished.
+/// An exception raised by the messenger during `op` is returned once `op`
+/// has finished.
+pub fn run_interruptible<T, F>(py: Python, outputs: &[String], op: F) -> PyResult<T>
+    where T: Send, F: FnOnce() -> PyResult<T> + Send {
+
+    let token = CancelToken::new();
+    let existing = matching_files(outputs);
+    active().push(token.clone());
+
+    let (res, mut interrupted) = py.allow_threads(|| std::thread::scope(|s| {
+        let op_token = token.clone();
+        let h = s.spawn(move || {
+            CURRENT.with(|c| *c.borrow_mut() = Some(op_token));
+            op()
+        });
+
+        let mut interrupted: Option<PyErr> = None;
+        while !h.is_finished() {
+            std::thread::sleep(Duration::from_millis(100));
+            if interrupted.is_none() {
+                if let Err(e) = Python::with_gil(|py| py.check_signals()) {
+                    token.cancel();
+                    interrupted = Some(e);
+                }
+            }
+        }
+        (h.join(), interrupted)
+    }));
+
+    active().retain(|t| !Arc::ptr_eq(&t.0, &token.0));
+    if interrupted.is_none() && token.is_cancelled() {
+        //by request_cancel
+        interrupted = Some(PyKeyboardInterrupt::new_err("cancelled"));
+    }
+
+    let res = match res {
+        Ok(r) => r,
+        Err(payload) => resume_unwind(payload)
+    };
+    //an exception raised by the messenger is passed on if the operation
+    //itself succeeded
+    let res = match (res, token.take_callback_error()) {
+        (Ok(_), Some(e)) => Err(e),
+        (res, _) => res
+    };
+
+    match (res, interrupted) {
+        (Ok(r), None) => Ok(r),
+        (_, Some(e)) => {
+            //the stages which stopped early may still have written
+            //incomplete outputs
+            for p in matching_files(outputs).difference(&existing) {
+                let _ = std::fs::remove_file(p);
+            }
+            Err(e)
+        },
+        (Err(e), None) => Err(e)
+    }
+}
+
+/// Ask every operation currently running to stop at the next block.
+#[pyfunction]
+pub fn request_cancel() -> PyResult<()> {
+    for t in active().iter() {
+        t.cancel();
+    }
+    Ok(())
+}
+
+/// True if any operation currently running has been asked to stop.
+#[pyfunction]
+pub fn cancel_requested() -> PyResult<bool> {
+    Ok(active().iter().any(|t| t.is_cancelled()))
+}
+
+
+pub(crate) fn wrap_cancel(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_wrapped(wrap_pyfunction!(request_cancel))?;
+    m.add_wrapped(wrap_pyfunction!(cancel_requested))?;
+    Ok(())
+}
@@ -0,0 +1,122 @@
+use pyo3::prelude::*;
+use pyo3::wrap_pyfunction;
+use serde_json::{json,Value};
+
+use crate::ErrorWrapped;
+
+
+//size and modification time, to tell if a file has changed since it was
+//recorded
+fn fingerprint(path: &str) -> Option<Value> {
+    let md = std::fs::metadata(path).ok()?;
+    let mtime = md.modified().ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs());
+    Some(json!({"path": path, "size": md.len(), "mtime": mtime}))
+}
+
+fn unchanged(recorded: &Value) -> bool {
+    match recorded["path"].as_str() {
+        Some(p) => fingerprint(p).as_ref() == Some(recorded),
+        None => false
+    }
+}
+
+/// A manifest of the completed stages of a long running operation, kept
+/// next to its output as `<outfn>.checkpoint.json`. Each stage records its
+/// parameters, the artefacts it wrote and a result. A stage is only treated
+/// as done if the inputs and its artefacts are unchanged since it finished.
+pub(crate) struct Checkpoint {
+    path: String,
+    inputs: Vec<Value>,
+    stages: Vec<Value>
+}
+
+impl Checkpoint {
+    pub fn path_for(outfn: &str) -> String {
+        format!("{}.checkpoint.json", outfn)
+    }
+
+    /// Opens the manifest for `outfn`. Unless `resume` is set, or if any of
+    /// `inputs` have changed, any stages recorded by an earlier run are
+    /// discarded.
+    pub fn open(outfn: &str, inputs: &[&str], resume: bool) -> PyResult<Checkpoint> {
+        let path = Checkpoint::path_for(outfn);
+        let mut inputs_fp = Vec::new();
+        for i in inputs {
+            crate::errors::check_exists(i)?;
+            inputs_fp.push(fingerprint(i).ok_or_else(|| crate::errors::invalid_input_error(format!("can't read {}", i)))?);
+        }
+
+        let mut stages = Vec::new();
+        if resume {
+            if let Ok(s) = std::fs::read_to_string(&path) {
+                if let Ok(v) = serde_json::from_str::<Value>(&s) {
+                    if v["inputs"].as_array() == Some(&inputs_fp) {
+                        stages = v["stages"].as_array().cloned().unwrap_or_default();
+                    }
+                }
+            }
+        } else if std::path::Path::new(&path).exists() {
+            std::fs::remove_file(&path).map_err(|e| ErrorWrapped::from(e).with_filename(&path))?;
+        }
+        Ok(Checkpoint{path: path, inputs: inputs_fp, stages: stages})
+    }
+
+    /// The result recorded for `stage`, if it finished with the same
+    /// `params` and all its artefacts are unchanged.
+    pub fn completed(&self, stage: &str, params: &Value) -> Option<Value> {
+        let s = self.stages.iter().find(|s| s["stage"] == stage)?;
+        if &s["params"] != params {
+            return None;
+        }
+        if !s["artefacts"].as_array()?.iter().all(unchanged) {
+            return None;
+        }
+        Some(s["result"].clone())
+    }
+
+    /// Records that `stage` has finished, writing `artefacts`. Any later
+    /// stages recorded by an earlier run are dropped, as they depend on
+    /// this one.
+    pub fn finish_stage(&mut self, stage: &str, params: Value, artefacts: &[&str], result: Value) -> PyResult<()> {
+        if let Some(i) = self.stages.iter().position(|s| s["stage"] == stage) {
+            self.stages.truncate(i);
+        }
+        let mut arts = Vec::new();
+        for a in artefacts {
+            arts.push(fingerprint(a).ok_or_else(|| crate::errors::invalid_input_error(format!("stage {}: missing artefact {}", stage, a)))?);
+        }
+        self.stages.push(json!({"stage": stage, "params": params, "artefacts": arts, "result": result}));
+        self.write()
+    }
+
+    //written to a temporary file first, so an interrupted write can't leave
+    //a truncated manifest
+    fn write(&self) -> PyResult<()> {
+        let v = json!({"inputs": self.inputs, "stages": self.stages});
+        let tmp = format!("{}.tmp", self.path);
+        std::fs::write(&tmp, v.to_string()).map_err(|e| ErrorWrapped::from(e).with_filename(&tmp))?;
+        std::fs::rename(&tmp, &self.path).map_err(|e| ErrorWrapped::from(e).with_filename(&self.path))?;
+        Ok(())
+    }
+}
+
+/// Names of the stages recorded as completed in the checkpoint manifest for
+/// `outfn`, in order.
+#[pyfunction]
+pub fn checkpoint_stages(outfn: &str) -> PyResult<Vec<String>> {
+    let path = Checkpoint::path_for(outfn);
+    if !std::path::Path::new(&path).exists() {
+        return Ok(Vec::new());
+    }
+    let s = std::fs::read_to_string(&path).map_err(|e| ErrorWrapped::from(e).with_filename(&path))?;
+    let v: Value = serde_json::from_str(&s).map_err(|e| crate::errors::invalid_input_error(format!("{}: {}", path, e)))?;
+    Ok(v["stages"].as_array().map_or_else(Vec::new, |ss| ss.iter().filter_map(|s| s["stage"].as_str().map(String::from)).collect()))
+}
+
+
+pub(crate) fn wrap_checkpoint(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_wrapped(wrap_pyfunction!(checkpoint_stages))?;
+    Ok(())
+}
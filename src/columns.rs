@@ -0,0 +1,195 @@
+use pyo3::prelude::*;
+use pyo3::ffi;
+use pyo3::exceptions::PyBufferError;
+use pyo3::types::PyDict;
+use std::os::raw::{c_char,c_int,c_void};
+use std::ptr;
+
+
+pub enum ColumnData {
+    Int64(Vec<i64>),
+    Int32(Vec<i32>),
+    UInt8(Vec<u8>),
+    Float64(Vec<f64>)
+}
+
+impl ColumnData {
+    fn len(&self) -> usize {
+        match self {
+            ColumnData::Int64(v) => v.len(),
+            ColumnData::Int32(v) => v.len(),
+            ColumnData::UInt8(v) => v.len(),
+            ColumnData::Float64(v) => v.len()
+        }
+    }
+
+    //pointer, itemsize and struct module format character
+    fn raw(&self) -> (*const c_void, usize, &'static [u8]) {
+        match self {
+            ColumnData::Int64(v) => (v.as_ptr() as *const c_void, 8, b"q\0"),
+            ColumnData::Int32(v) => (v.as_ptr() as *const c_void, 4, b"i\0"),
+            ColumnData::UInt8(v) => (v.as_ptr() as *const c_void, 1, b"B\0"),
+            ColumnData::Float64(v) => (v.as_ptr() as *const c_void, 8, b"d\0")
+        }
+    }
+}
+
+/// A single typed column, exposed through the buffer protocol so that
+/// `numpy.asarray(col)` or `memoryview(col)` use the data without copying.
+#[pyclass(frozen)]
+pub struct Column {
+    data: ColumnData,
+    shape: [isize; 1],
+    strides: [isize; 1]
+}
+
+impl Column {
+    pub fn new(data: ColumnData) -> Column {
+        let (_, itemsize, _) = data.raw();
+        let len = data.len() as isize;
+        Column{data: data, shape: [len], strides: [itemsize as isize]}
+    }
+}
+
+#[pymethods]
+impl Column {
+    #[getter]
+    pub fn dtype(&self) -> PyResult<&'static str> {
+        Ok(match self.data {
+            ColumnData::Int64(_) => "int64",
+            ColumnData::Int32(_) => "int32",
+            ColumnData::UInt8(_) => "uint8",
+            ColumnData::Float64(_) => "float64"
+        })
+    }
+
+    pub fn to_list(&self, py: Python) -> PyResult<PyObject> {
+        Ok(match &self.data {
+            ColumnData::Int64(v) => v.clone().into_py(py),
+            ColumnData::Int32(v) => v.clone().into_py(py),
+            ColumnData::UInt8(v) => v.clone().into_py(py),
+            ColumnData::Float64(v) => v.clone().into_py(py)
+        })
+    }
+
+    fn __len__(&self) -> usize {
+        self.data.len()
+    }
+
+    fn __repr__(&self) -> PyResult<String> {
+        Ok(format!("Column {} [{}]", self.dtype()?, self.data.len()))
+    }
+
+    unsafe fn __getbuffer__(slf: Bound<'_, Self>, view: *mut ffi::Py_buffer, flags: c_int) -> PyResult<()> {
+        if view.is_null() {
+            return Err(PyBufferError::new_err("view is null"));
+        }
+        if (flags & ffi::PyBUF_WRITABLE) == ffi::PyBUF_WRITABLE {
+            return Err(PyBufferError::new_err("Column is read-only"));
+        }
+
+        let col = slf.get();
+        let (buf, itemsize, format) = col.data.raw();
+
+        (*view).buf = buf as *mut c_void;
+        (*view).len = (col.data.len() * itemsize) as isize;
+        (*view).readonly = 1;
+        (*view).itemsize = itemsize as isize;
+        (*view).format = if (flags & ffi::PyBUF_FORMAT) == ffi::PyBUF_FORMAT { format.as_ptr() as *mut c_char } else { ptr::null_mut() };
+        (*view).ndim = 1;
+        //shape and strides are stored on the (frozen) Column, which view.obj keeps alive
+        (*view).shape = if (flags & ffi::PyBUF_ND) == ffi::PyBUF_ND { col.shape.as_ptr() as *mut isize } else { ptr::null_mut() };
+        (*view).strides = if (flags & ffi::PyBUF_STRIDES) == ffi::PyBUF_STRIDES { col.strides.as_ptr() as *mut isize } else { ptr::null_mut() };
+        (*view).suboffsets = ptr::null_mut();
+        (*view).internal = ptr::null_mut();
+        (*view).obj = slf.into_any().into_ptr();
+        Ok(())
+    }
+
+    unsafe fn __releasebuffer__(&self, _view: *mut ffi::Py_buffer) {}
+}
+
+/// Returns a dict of name to Column.
+pub fn make_columns(py: Python, cols: Vec<(&str, ColumnData)>) -> PyResult<PyObject> {
+    let res = PyDict::new(py);
+    for (k, v) in cols {
+        res.set_item(k, Py::new(py, Column::new(v))?)?;
+    }
+    Ok(res.into())
+}
+
+/// Changetypes are given as the index into
+/// `["normal", "delete", "remove", "modify", "unchanged", "create"]`.
+pub fn changetype_code(ct: &osmquadtree::elements::Changetype) -> u8 {
+    match ct {
+        osmquadtree::elements::Changetype::Normal => 0,
+        osmquadtree::elements::Changetype::Delete => 1,
+        osmquadtree::elements::Changetype::Remove => 2,
+        osmquadtree::elements::Changetype::Modify => 3,
+        osmquadtree::elements::Changetype::Unchanged => 4,
+        osmquadtree::elements::Changetype::Create => 5
+    }
+}
+
+pub fn element_type_code(et: &osmquadtree::elements::ElementType) -> u8 {
+    match et {
+        osmquadtree::elements::ElementType::Node => 0,
+        osmquadtree::elements::ElementType::Way => 1,
+        osmquadtree::elements::ElementType::Relation => 2,
+        _ => 255
+    }
+}
+
+//columns shared by all element types: missing info is given as zeros
+pub struct CommonColumns {
+    full: bool,
+    id: Vec<i64>,
+    changetype: Vec<u8>,
+    version: Vec<i64>,
+    timestamp: Vec<i64>,
+    changeset: Vec<i64>,
+    user_id: Vec<i64>,
+    quadtree: Vec<i64>
+}
+
+impl CommonColumns {
+    //full: include changeset and user_id, which MinimalBlocks do not have
+    pub fn new(full: bool) -> CommonColumns {
+        CommonColumns{full: full, id: Vec::new(), changetype: Vec::new(), version: Vec::new(), timestamp: Vec::new(), changeset: Vec::new(), user_id: Vec::new(), quadtree: Vec::new()}
+    }
+
+    pub fn add(&mut self, id: i64, changetype: &osmquadtree::elements::Changetype, info: &Option<osmquadtree::elements::Info>, quadtree: &osmquadtree::elements::Quadtree) {
+        self.add_minimal(id, changetype, info.as_ref().map(|i| i.version).unwrap_or(0), info.as_ref().map(|i| i.timestamp).unwrap_or(0), quadtree);
+        self.changeset.push(info.as_ref().map(|i| i.changeset).unwrap_or(0));
+        self.user_id.push(info.as_ref().map(|i| i.user_id).unwrap_or(0));
+    }
+
+    pub fn add_minimal(&mut self, id: i64, changetype: &osmquadtree::elements::Changetype, version: i64, timestamp: i64, quadtree: &osmquadtree::elements::Quadtree) {
+        self.id.push(id);
+        self.changetype.push(changetype_code(changetype));
+        self.version.push(version);
+        self.timestamp.push(timestamp);
+        self.quadtree.push(quadtree.as_int());
+    }
+
+    pub fn into_columns(self) -> Vec<(&'static str, ColumnData)> {
+        let mut res = vec![
+            ("id", ColumnData::Int64(self.id)),
+            ("changetype", ColumnData::UInt8(self.changetype)),
+            ("version", ColumnData::Int64(self.version)),
+            ("timestamp", ColumnData::Int64(self.timestamp)),
+        ];
+        if self.full {
+            res.push(("changeset", ColumnData::Int64(self.changeset)));
+            res.push(("user_id", ColumnData::Int64(self.user_id)));
+        }
+        res.push(("quadtree", ColumnData::Int64(self.quadtree)));
+        res
+    }
+}
+
+
+pub(crate) fn wrap_columns(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<Column>()?;
+    Ok(())
+}
@@ -0,0 +1,300 @@
+use pyo3::prelude::*;
+use pyo3::types::{PyDict,PyList};
+use pyo3::wrap_pyfunction;
+use std::collections::{BTreeMap,BTreeSet};
+use std::io::{Seek,SeekFrom};
+use std::sync::{Arc,Mutex};
+
+use channelled_callbacks::{CallFinish,Callback,CallbackMerge,MergeTimings,Timings,Result as ccResult};
+use osmquadtree::elements::{Element,Quadtree,Tag,Member};
+use osmquadtree::pbfformat::FileBlock;
+use osmquadtree::utils::Error;
+
+use crate::ErrorWrapped;
+use crate::columns::element_type_code;
+use crate::elements::prep_element_tuple;
+
+
+//the quadtree of the block, with the file blocks for it from each side
+type CompareItem = (i64, Vec<FileBlock>, Vec<FileBlock>);
+
+enum Difference {
+    OnlyLeftBlock(i64, usize),
+    OnlyRightBlock(i64, usize),
+    Element(&'static str, Element, Option<Element>),
+    MovedBlock(i64, i64, Element, Element)
+}
+
+fn ele_quadtree(e: &Element) -> i64 {
+    match e {
+        Element::Node(n) => n.quadtree.as_int(),
+        Element::Way(w) => w.quadtree.as_int(),
+        Element::Relation(r) => r.quadtree.as_int()
+    }
+}
+
+fn same_tags(a: &[Tag], b: &[Tag]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(x, y)| x.key == y.key && x.val == y.val)
+}
+
+fn same_members(a: &[Member], b: &[Member]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(x, y)|
+        element_type_code(&x.mem_type) == element_type_code(&y.mem_type) && x.mem_ref == y.mem_ref && x.role == y.role)
+}
+
+fn same_info(a: &Option<osmquadtree::elements::Info>, b: &Option<osmquadtree::elements::Info>) -> bool {
+    match (a, b) {
+        (None, None) => true,
+        (Some(x), Some(y)) => x.version == y.version && x.timestamp == y.timestamp && x.changeset == y.changeset && x.user_id == y.user_id && x.user == y.user,
+        _ => false
+    }
+}
+
+//as for compare_pbf_files: DifferentData, DifferentTags, DifferentInfo or
+//DifferentQuadtree, or None if the elements are the same
+fn compare_elements(a: &Element, b: &Element) -> Option<&'static str> {
+    let (data, tags, info) = match (a, b) {
+        (Element::Node(x), Element::Node(y)) => (x.lon == y.lon && x.lat == y.lat, same_tags(&x.tags, &y.tags), same_info(&x.info, &y.info)),
+        (Element::Way(x), Element::Way(y)) => (x.refs == y.refs, same_tags(&x.tags, &y.tags), same_info(&x.info, &y.info)),
+        (Element::Relation(x), Element::Relation(y)) => (same_members(&x.members, &y.members), same_tags(&x.tags, &y.tags), same_info(&x.info, &y.info)),
+        _ => (false, true, true)
+    };
+    if !data {
+        Some("DifferentData")
+    } else if !tags {
+        Some("DifferentTags")
+    } else if !info {
+        Some("DifferentInfo")
+    } else if ele_quadtree(a) != ele_quadtree(b) {
+        Some("DifferentQuadtree")
+    } else {
+        None
+    }
+}
+
+fn block_elements(bl: osmquadtree::elements::PrimitiveBlock) -> BTreeMap<(u8, i64), Element> {
+    let mut res = BTreeMap::new();
+    for n in bl.nodes { res.insert((0, n.id), Element::Node(n)); }
+    for w in bl.ways { res.insert((1, w.id), Element::Way(w)); }
+    for r in bl.relations { res.insert((2, r.id), Element::Relation(r)); }
+    res
+}
+
+#[derive(Default)]
+struct CompareState {
+    differences: Vec<((i64, u8, i64), Difference)>,
+    counts: BTreeMap<&'static str, usize>,
+    //elements only found in one block, which may have moved to another
+    only_left: BTreeMap<(u8, i64), (i64, Element)>,
+    only_right: BTreeMap<(u8, i64), (i64, Element)>,
+    error: Option<Error>
+}
+
+impl CompareState {
+    fn add(&mut self, key: (i64, u8, i64), name: &'static str, d: Difference, max_result_len: usize) {
+        *self.counts.entry(name).or_insert(0) += 1;
+        if self.differences.len() < max_result_len {
+            self.differences.push((key, d));
+        }
+    }
+
+    fn merge(&mut self, other: CompareState) {
+        self.differences.extend(other.differences);
+        for (k, v) in other.counts {
+            *self.counts.entry(k).or_insert(0) += v;
+        }
+        self.only_left.extend(other.only_left);
+        self.only_right.extend(other.only_right);
+        if self.error.is_none() {
+            self.error = other.error;
+        }
+    }
+
+    fn compare_block(&mut self, q: i64, left: Vec<FileBlock>, right: Vec<FileBlock>, max_result_len: usize) -> std::result::Result<(), Error> {
+        let read = |fbs: Vec<FileBlock>| -> std::result::Result<BTreeMap<(u8, i64), Element>, Error> {
+            if fbs.is_empty() {
+                return Ok(BTreeMap::new());
+            }
+            Ok(block_elements(osmquadtree::pbfformat::read_primitive_blocks_combine(0, fbs, None).map_err(|e| e.into())?))
+        };
+        let has_left = !left.is_empty();
+        let has_right = !right.is_empty();
+        let a = read(left)?;
+        let mut b = read(right)?;
+
+        if !has_right {
+            self.add((q, 0, 0), "OnlyLeftBlock", Difference::OnlyLeftBlock(q, a.len()), max_result_len);
+        } else if !has_left {
+            self.add((q, 0, 0), "OnlyRightBlock", Difference::OnlyRightBlock(q, b.len()), max_result_len);
+        }
+
+        for (k, x) in a {
+            match b.remove(&k) {
+                Some(y) => {
+                    if let Some(name) = compare_elements(&x, &y) {
+                        self.add((q, k.0, k.1), name, Difference::Element(name, x, Some(y)), max_result_len);
+                    }
+                },
+                None => { self.only_left.insert(k, (q, x)); }
+            }
+        }
+        for (k, y) in b {
+            self.only_right.insert(k, (q, y));
+        }
+        Ok(())
+    }
+}
+
+struct CompareBlocks {
+    state: CompareState,
+    max_result_len: usize,
+    result: Arc<Mutex<CompareState>>
+}
+
+impl CallFinish for CompareBlocks {
+    type CallType = CompareItem;
+    type ReturnType = Timings<usize>;
+    type ErrorType = Error;
+
+    fn call(&mut self, (q, left, right): CompareItem) {
+        if self.state.error.is_some() {
+            return;
+        }
+        if let Err(e) = self.state.compare_block(q, left, right, self.max_result_len) {
+            self.state.error = Some(e);
+        }
+    }
+
+    fn finish(&mut self) -> ccResult<Self::ReturnType, Error> {
+        let state = std::mem::take(&mut self.state);
+        let mut tm = Timings::new();
+        tm.add_other("CompareBlocks", state.differences.len());
+        self.result.lock().unwrap().merge(state);
+        Ok(tm)
+    }
+}
+
+fn read_locs(pfilelocs: &mut osmquadtree::pbfformat::ParallelFileLocs, idx: Option<&usize>) -> PyResult<Vec<FileBlock>> {
+    let mut fbs = Vec::new();
+    if let Some(i) = idx {
+        for (a, b) in &pfilelocs.1[*i].1 {
+            pfilelocs.0[*a].seek(SeekFrom::Start(*b)).map_err(ErrorWrapped::from)?;
+            let (_, fb) = crate::compression::read_file_block_with_pos(&mut pfilelocs.0[*a], *b).map_err(ErrorWrapped::from)?;
+            fbs.push(fb);
+        }
+    }
+    Ok(fbs)
+}
+
+fn compare_datasets(left: &str, left_timestamp: Option<i64>, right: &str, right_timestamp: Option<i64>, numchan: usize, max_result_len: usize) -> PyResult<CompareState> {
+    let mut left_locs = osmquadtree::pbfformat::get_file_locs(left, None, left_timestamp).map_err(|e| ErrorWrapped::from(e).with_filename(left))?;
+    let mut right_locs = osmquadtree::pbfformat::get_file_locs(right, None, right_timestamp).map_err(|e| ErrorWrapped::from(e).with_filename(right))?;
+    let left_qts: BTreeMap<i64, usize> = left_locs.1.iter().enumerate().map(|(i, (q, _))| (q.as_int(), i)).collect();
+    let right_qts: BTreeMap<i64, usize> = right_locs.1.iter().enumerate().map(|(i, (q, _))| (q.as_int(), i)).collect();
+    let quadtrees: BTreeSet<i64> = left_qts.keys().chain(right_qts.keys()).cloned().collect();
+
+    //blocks are read here, and decoded and compared on numchan threads
+    let result = Arc::new(Mutex::new(CompareState::default()));
+    let make = || Box::new(CompareBlocks{state: CompareState::default(), max_result_len: max_result_len, result: result.clone()});
+    let mut conv: Box<dyn CallFinish<CallType = CompareItem, ReturnType = Timings<usize>, ErrorType = Error>> =
+        if numchan == 0 {
+            make()
+        } else {
+            let mut convs: Vec<Box<dyn CallFinish<CallType = CompareItem, ReturnType = Timings<usize>, ErrorType = Error>>> = Vec::new();
+            for _ in 0..numchan {
+                convs.push(Box::new(Callback::new(make())));
+            }
+            Box::new(CallbackMerge::new(convs, Box::new(MergeTimings::new())))
+        };
+
+    for q in quadtrees {
+        if crate::cancel::is_cancelled() {
+            break;
+        }
+        let l = read_locs(&mut left_locs, left_qts.get(&q))?;
+        let r = read_locs(&mut right_locs, right_qts.get(&q))?;
+        conv.call((q, l, r));
+    }
+    conv.finish().map_err(|e| ErrorWrapped::new(e.into()))?;
+    crate::cancel::check_cancelled()?;
+
+    let mut state = std::mem::take(&mut *result.lock().unwrap());
+    if let Some(e) = state.error.take() {
+        return Err(ErrorWrapped::new(e.into()).into());
+    }
+
+    //elements found in different blocks on each side. these can come before
+    //the differences already found, so all are kept until the sort below
+    let only_left = std::mem::take(&mut state.only_left);
+    let mut only_right = std::mem::take(&mut state.only_right);
+    for (k, (lq, x)) in only_left {
+        match only_right.remove(&k) {
+            Some((rq, y)) => {
+                //a moved element may also have changed
+                if let Some(name) = compare_elements(&x, &y).filter(|n| *n != "DifferentQuadtree") {
+                    state.add((lq, k.0, k.1), name, Difference::Element(name, x.clone(), Some(y.clone())), usize::MAX);
+                }
+                state.add((lq, k.0, k.1), "MovedBlock", Difference::MovedBlock(lq, rq, x, y), usize::MAX);
+            },
+            None => state.add((lq, k.0, k.1), "OnlyLeft", Difference::Element("OnlyLeft", x, None), usize::MAX)
+        }
+    }
+    for (k, (rq, y)) in only_right {
+        state.add((rq, k.0, k.1), "OnlyRight", Difference::Element("OnlyRight", y, None), usize::MAX);
+    }
+    state.differences.sort_by_key(|(k, _)| *k);
+    state.differences.truncate(max_result_len);
+    Ok(state)
+}
+
+/// Compares two quadtree sorted datasets (prefix directories or single
+/// sorted .pbf files), such as before and after re-running sort_blocks,
+/// block by block using `numchan` threads. `left_timestamp` and
+/// `right_timestamp` select the state of each dataset as for
+/// ReadFileBlocksParallel, so this can also compare one prefix at two
+/// timestamps.
+///
+/// Returns a list of up to `max_result_len` differences, ordered by block
+/// quadtree, and a dict with the number of each kind of difference. As well
+/// as the element differences of compare_pbf_files ("OnlyLeft",
+/// "OnlyRight", "DifferentData", "DifferentTags", "DifferentInfo" and
+/// "DifferentQuadtree"), this reports blocks only in one dataset as
+/// ("OnlyLeftBlock", quadtree, num_elements) or ("OnlyRightBlock", ...), and
+/// elements found in a different block as ("MovedBlock", left_quadtree,
+/// right_quadtree, left, right). A moved element whose data, tags or info
+/// have also changed is reported as that difference as well.
+#[pyfunction]
+#[pyo3(signature = (left, right, left_timestamp=None, right_timestamp=None, numchan=crate::readpbf::default_numchan(), max_result_len=1000))]
+pub fn compare_quadtree_datasets(py: Python, left: &str, right: &str, left_timestamp: Option<&str>, right_timestamp: Option<&str>, numchan: usize, max_result_len: usize) -> PyResult<PyObject> {
+    crate::errors::check_exists(left)?;
+    crate::errors::check_exists(right)?;
+    let left_ts = left_timestamp.map(crate::elements::parse_timestamp).transpose()?;
+    let right_ts = right_timestamp.map(crate::elements::parse_timestamp).transpose()?;
+
+    let state = crate::cancel::run_interruptible(py, &[], || compare_datasets(left, left_ts, right, right_ts, numchan, max_result_len))?;
+
+    let list = PyList::empty(py);
+    for (_, d) in &state.differences {
+        let item: PyObject = match d {
+            Difference::OnlyLeftBlock(q, n) => ("OnlyLeftBlock", crate::elements::Quadtree::new(Quadtree::new(*q)), *n).into_py(py),
+            Difference::OnlyRightBlock(q, n) => ("OnlyRightBlock", crate::elements::Quadtree::new(Quadtree::new(*q)), *n).into_py(py),
+            Difference::Element(name, x, None) => (*name, prep_element_tuple(py, x)?).into_py(py),
+            Difference::Element(name, x, Some(y)) => (*name, prep_element_tuple(py, x)?, prep_element_tuple(py, y)?).into_py(py),
+            Difference::MovedBlock(lq, rq, x, y) => ("MovedBlock",
+                crate::elements::Quadtree::new(Quadtree::new(*lq)), crate::elements::Quadtree::new(Quadtree::new(*rq)),
+                prep_element_tuple(py, x)?, prep_element_tuple(py, y)?).into_py(py)
+        };
+        list.append(item)?;
+    }
+    let counts = PyDict::new(py);
+    for (k, v) in &state.counts {
+        counts.set_item(*k, *v)?;
+    }
+    Ok((list, counts).into_py(py))
+}
+
+
+pub(crate) fn wrap_compare(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_wrapped(wrap_pyfunction!(compare_quadtree_datasets))?;
+    Ok(())
+}
@@ -0,0 +1,497 @@
+use pyo3::prelude::*;
+use pyo3::wrap_pyfunction;
+use std::collections::{BTreeMap,BTreeSet};
+use std::fs::File;
+use std::io::{BufReader,BufWriter,Cursor,Read,Seek,SeekFrom,Write};
+use std::sync::{Arc,Mutex};
+
+use channelled_callbacks::{CallFinish,CallbackMerge,CallbackSync,Callback,ReplaceNoneWithTimings,Timings,MergeTimings, Result as ccResult};
+use osmquadtree::pbfformat::{CompressionType,FileBlock,ParallelFileLocs};
+use osmquadtree::utils::Error;
+use simple_protocolbuffers::{IterTags,PbfTag,pack_data,pack_value};
+
+use crate::ErrorWrapped;
+use crate::readpbf::{ErrorSlot,has_error,set_error,take_error};
+
+
+/// Block compression: any type osmquadtree supports, or zstd and lz4 which
+/// osmquadtree can't read or write, so are handled here.
+#[derive(Clone)]
+pub(crate) enum Compression {
+    Upstream(CompressionType),
+    Zstd(i32),
+    Lz4
+}
+
+impl Compression {
+    /// ("ZstdLevel", level) or ("Lz4", _), or any input accepted by
+    /// compression_type_from_string.
+    pub fn from_tuple(input: (&str, u32)) -> PyResult<Compression> {
+        match input {
+            ("ZstdLevel", l) => Ok(Compression::Zstd(l as i32)),
+            ("Lz4", _) => Ok(Compression::Lz4),
+            a => Ok(Compression::Upstream(crate::readpbf::compression_type_from_string(a)?))
+        }
+    }
+}
+
+//limits from the pbf format spec
+pub(crate) const MAX_HEADER_LEN: usize = 64 * 1024;
+pub(crate) const MAX_BLOB_LEN: usize = 32 * 1024 * 1024;
+
+fn invalid_data(msg: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+//a BlobHeader and Blob, as written by osmquadtree::pbfformat::pack_file_block
+fn pack_blob(block_type: &str, blob: &[u8]) -> Vec<u8> {
+    let mut header = Vec::new();
+    pack_data(&mut header, 1, block_type.as_bytes());
+    pack_value(&mut header, 3, blob.len() as u64);
+
+    let mut res = Vec::with_capacity(4 + header.len() + blob.len());
+    res.extend_from_slice(&(header.len() as u32).to_be_bytes());
+    res.extend_from_slice(&header);
+    res.extend_from_slice(blob);
+    res
+}
+
+/// Packs `data` as a file block of `block_type`.
+pub(crate) fn pack_file_block(block_type: &str, data: &[u8], compression: &Compression) -> PyResult<Vec<u8>> {
+    let (field, comp) = match compression {
+        Compression::Upstream(ct) => {
+            return Ok(osmquadtree::pbfformat::pack_file_block(block_type, data, ct).map_err(ErrorWrapped::from)?);
+        },
+        Compression::Zstd(l) => (7, zstd::bulk::compress(data, *l)?),
+        Compression::Lz4 => (6, lz4_flex::block::compress(data))
+    };
+    let mut blob = Vec::with_capacity(comp.len() + 16);
+    pack_value(&mut blob, 2, data.len() as u64);
+    pack_data(&mut blob, field, &comp);
+    Ok(pack_blob(block_type, &blob))
+}
+
+//reads into buf until it is full or the end of the file, returning the
+//number of bytes read
+pub(crate) fn read_fully<R: Read>(r: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match r.read(&mut buf[n..]) {
+            Ok(0) => { break; },
+            Ok(m) => { n += m; },
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {},
+            Err(e) => { return Err(e); }
+        }
+    }
+    Ok(n)
+}
+
+//the uncompressed contents of a zstd or lz4 blob, and the compression name
+fn unpack_extra(blob: &[u8]) -> std::io::Result<Option<(Vec<u8>, &'static str)>> {
+    let mut raw_size = None;
+    let mut comp = None;
+    for t in IterTags::new(blob) {
+        match t {
+            PbfTag::Value(2, v) => { raw_size = Some(v as usize); },
+            PbfTag::Data(6, d) => { comp = Some((d, "Lz4")); },
+            PbfTag::Data(7, d) => { comp = Some((d, "Zstd")); },
+            _ => {}
+        }
+    }
+    let (d, name) = match comp {
+        Some(c) => c,
+        None => { return Ok(None); }
+    };
+    let raw_size = raw_size.ok_or_else(|| invalid_data(format!("{} blob without raw_size", name)))?;
+    if raw_size > MAX_BLOB_LEN {
+        return Err(invalid_data(format!("{} blob: raw_size {} is too large", name, raw_size)));
+    }
+    let data = if name == "Zstd" {
+        zstd::bulk::decompress(d, raw_size)?
+    } else {
+        lz4_flex::block::decompress(d, raw_size).map_err(|e| invalid_data(format!("lz4: {}", e)))?
+    };
+    if data.len() != raw_size {
+        return Err(invalid_data(format!("{} blob: expected {} bytes, got {}", name, raw_size, data.len())));
+    }
+    Ok(Some((data, name)))
+}
+
+/// Reads the block at `pos`, returning the position of the next block, the
+/// block and, if it was compressed with zstd or lz4, the name of the
+/// compression. These blocks are returned uncompressed, as osmquadtree
+/// can't decode them. Returns None at the end of the file.
+pub(crate) fn read_block<R: Read>(r: &mut R, pos: u64) -> std::io::Result<Option<(u64, FileBlock, Option<&'static str>)>> {
+    let mut lenb = [0u8; 4];
+    match read_fully(r, &mut lenb)? {
+        0 => { return Ok(None); },
+        4 => {},
+        _ => { return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, format!("truncated block at {}", pos))); }
+    }
+    let hlen = u32::from_be_bytes(lenb) as usize;
+    //the lengths are checked before allocating, so that other files fail
+    //quickly rather than asking for huge buffers
+    if hlen == 0 || hlen > MAX_HEADER_LEN {
+        return Err(invalid_data(format!("bad blob header length {} at {}", hlen, pos)));
+    }
+    let mut bytes = lenb.to_vec();
+    bytes.resize(4 + hlen, 0);
+    r.read_exact(&mut bytes[4..])?;
+
+    let mut block_type = String::new();
+    let mut datasize = 0;
+    for t in IterTags::new(&bytes[4..]) {
+        match t {
+            PbfTag::Data(1, d) => { block_type = String::from_utf8_lossy(d).to_string(); },
+            PbfTag::Value(3, v) => { datasize = v as usize; },
+            _ => {}
+        }
+    }
+    if datasize > MAX_BLOB_LEN {
+        return Err(invalid_data(format!("bad blob length {} at {}", datasize, pos)));
+    }
+    bytes.resize(4 + hlen + datasize, 0);
+    r.read_exact(&mut bytes[4 + hlen..])?;
+    let next = pos + bytes.len() as u64;
+
+    match unpack_extra(&bytes[4 + hlen..])? {
+        None => {
+            let (_, fb) = osmquadtree::pbfformat::read_file_block_with_pos(&mut Cursor::new(&bytes), pos)?;
+            Ok(Some((next, fb, None)))
+        },
+        Some((data, name)) => {
+            let mut blob = Vec::with_capacity(data.len() + 16);
+            pack_data(&mut blob, 1, &data);
+            pack_value(&mut blob, 2, data.len() as u64);
+            let unpacked = pack_blob(&block_type, &blob);
+            let (_, mut fb) = osmquadtree::pbfformat::read_file_block_with_pos(&mut Cursor::new(&unpacked), pos)?;
+            //report the length of the block in the file
+            fb.len = fb.len + bytes.len() as u64 - unpacked.len() as u64;
+            Ok(Some((next, fb, Some(name))))
+        }
+    }
+}
+
+/// As osmquadtree::pbfformat::read_file_block_with_pos, but also reads zstd
+/// and lz4 blocks.
+pub(crate) fn read_file_block_with_pos<R: Read>(r: &mut R, pos: u64) -> std::io::Result<(u64, FileBlock)> {
+    match read_block(r, pos)? {
+        Some((next, fb, _)) => Ok((next, fb)),
+        None => Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, format!("no block at {}", pos)))
+    }
+}
+
+/// As osmquadtree::pbfformat::read_file_block, but also reads zstd and lz4
+/// blocks.
+pub(crate) fn read_file_block<R: Read + Seek>(r: &mut R) -> std::io::Result<FileBlock> {
+    let pos = r.stream_position()?;
+    Ok(read_file_block_with_pos(r, pos)?.1)
+}
+
+/// Path of the intermediate file written before recompressing, when
+/// `write_with_compression` is called with zstd or lz4.
+pub(crate) fn intermediate_path(outfn: &str) -> String {
+    format!("{}-intermediate", outfn)
+}
+
+/// Calls `write(fname, compression_type)` to write `outfn`. osmquadtree
+/// can't write zstd or lz4 blocks, so for these `write` writes an
+/// intermediate file, with the fastest zlib level to keep it small, which
+/// is then recompressed.
+pub(crate) fn write_with_compression<F>(outfn: &str, compression: &Compression, numchan: usize, write: F) -> PyResult<()>
+    where F: FnOnce(&str, CompressionType) -> PyResult<()> {
+
+    match compression {
+        Compression::Upstream(ct) => write(outfn, *ct),
+        _ => {
+            let tempfn = intermediate_path(outfn);
+            let res = write(&tempfn, CompressionType::ZlibLevel(1))
+                .and_then(|_| crate::cancel::check_cancelled())
+                .and_then(|_| recompress_file(&tempfn, outfn, compression, numchan));
+            let _ = std::fs::remove_file(&tempfn);
+            res.map(|_| ())
+        }
+    }
+}
+
+
+type PackedBlock = (usize, Vec<u8>);
+
+struct RecompressBlocks {
+    out: Box<dyn CallFinish<CallType = PackedBlock, ReturnType = Timings<usize>, ErrorType = Error>>,
+    compression: Compression,
+    error: ErrorSlot
+}
+
+impl CallFinish for RecompressBlocks {
+    type CallType = (usize, FileBlock);
+    type ReturnType = Timings<usize>;
+    type ErrorType = Error;
+
+    fn call(&mut self, (idx, fb): (usize, FileBlock)) {
+        if has_error(&self.error) {
+            return;
+        }
+        match pack_file_block(&fb.block_type, &fb.data(), &self.compression) {
+            Ok(p) => { self.out.call((idx, p)); },
+            Err(e) => { set_error(&self.error, e); }
+        }
+    }
+
+    fn finish(&mut self) -> ccResult<Self::ReturnType, Error> {
+        self.out.finish()
+    }
+}
+
+fn compression_error(name: &str, fname: &str, what: &str) -> PyErr {
+    crate::errors::invalid_input_error(format!(
+        "{} uses osmquadtree, which can't read the {} compressed blocks of {}: convert it with recompress first",
+        what, name, fname))
+}
+
+/// Raises InvalidInputError if the first data block of `fname` is zstd or
+/// lz4 compressed, for the operations which read through osmquadtree.
+pub(crate) fn check_upstream_readable(fname: &str, what: &str) -> PyResult<()> {
+    let mut fbuf = BufReader::new(File::open(fname).map_err(|e| ErrorWrapped::from(e).with_filename(fname))?);
+    let mut pos = 0;
+    while let Some((next, fb, name)) = read_block(&mut fbuf, pos).map_err(|e| ErrorWrapped::from(e).with_filename(fname).with_position(pos))? {
+        if let Some(name) = name {
+            return Err(compression_error(name, fname, what));
+        }
+        if fb.block_type != "OSMHeader" {
+            break;
+        }
+        pos = next;
+    }
+    Ok(())
+}
+
+/// As check_upstream_readable, for the first block of each file of
+/// `pfilelocs`.
+pub(crate) fn check_upstream_readable_locs(pfilelocs: &mut ParallelFileLocs, prfx: &str, what: &str) -> PyResult<()> {
+    let mut seen = BTreeSet::new();
+    for (_, ll) in &pfilelocs.1 {
+        for (f, p) in ll {
+            if !seen.insert(*f) {
+                continue;
+            }
+            let r = &mut pfilelocs.0[*f];
+            r.seek(SeekFrom::Start(*p)).map_err(|e| ErrorWrapped::from(e).with_filename(prfx))?;
+            let res = read_block(r, *p).map_err(|e| ErrorWrapped::from(e).with_filename(prfx).with_position(*p))?;
+            r.seek(SeekFrom::Start(0)).map_err(|e| ErrorWrapped::from(e).with_filename(prfx))?;
+            if let Some((_, _, Some(name))) = res {
+                return Err(compression_error(name, prfx, what));
+            }
+        }
+    }
+    Ok(())
+}
+
+//blocks arrive from the worker channels in any order: write them by index
+struct WriteBlocksInOrder {
+    outf: BufWriter<File>,
+    outfn: String,
+    pending: BTreeMap<usize, Vec<u8>>,
+    locs: Arc<Mutex<Vec<(u64, u64)>>>,
+    pos: u64,
+    error: ErrorSlot
+}
+
+impl WriteBlocksInOrder {
+    fn write_pending(&mut self) -> std::io::Result<()> {
+        let mut locs = self.locs.lock().map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "lock poisoned"))?;
+        while let Some(p) = self.pending.remove(&locs.len()) {
+            self.outf.write_all(&p)?;
+            locs.push((self.pos, p.len() as u64));
+            self.pos += p.len() as u64;
+        }
+        Ok(())
+    }
+}
+
+impl CallFinish for WriteBlocksInOrder {
+    type CallType = PackedBlock;
+    type ReturnType = Timings<usize>;
+    type ErrorType = Error;
+
+    fn call(&mut self, (idx, p): PackedBlock) {
+        if has_error(&self.error) {
+            return;
+        }
+        self.pending.insert(idx, p);
+        if let Err(e) = self.write_pending() {
+            set_error(&self.error, ErrorWrapped::from(e).with_filename(&self.outfn).into());
+        }
+    }
+
+    fn finish(&mut self) -> ccResult<Self::ReturnType, Error> {
+        if let Err(e) = self.outf.flush() {
+            set_error(&self.error, ErrorWrapped::from(e).with_filename(&self.outfn).into());
+        }
+        let mut tm = Timings::new();
+        tm.add_other("WriteBlocksInOrder", self.locs.lock().map_or(0, |l| l.len()));
+        Ok(tm)
+    }
+}
+
+//the header index holds the location of each block, relative to the start
+//of the file: update these to the rewritten blocks, or return None if the
+//block isn't in locs
+fn rewrite_index_entry(data: &[u8], locs: &BTreeMap<u64, (u64, u64)>, offset: u64) -> Option<Vec<u8>> {
+    let mut res = Vec::new();
+    let mut length = None;
+    for t in IterTags::new(data) {
+        match t {
+            PbfTag::Value(3, v) => {
+                let (p, l) = locs.get(&v)?;
+                pack_value(&mut res, 3, p + offset);
+                length = Some(*l);
+            },
+            PbfTag::Value(4, v) => { pack_value(&mut res, 4, length.unwrap_or(v)); },
+            PbfTag::Value(k, v) => { pack_value(&mut res, k, v); },
+            PbfTag::Data(k, d) => { pack_data(&mut res, k, d); }
+        }
+    }
+    Some(res)
+}
+
+const MAX_HEADER_PASSES: usize = 8;
+
+/// Packs an OSMHeader block from `pack(offset)`, where `offset` is the
+/// length of the packed block, which the index locations are stored
+/// relative to. The header is always zlib compressed, even in zstd and lz4
+/// files, so that osmquadtree can read the index.
+pub(crate) fn pack_header_block<F: Fn(u64) -> Vec<u8>>(pack: F, compression: &Compression) -> PyResult<Vec<u8>> {
+    let compression = match compression {
+        Compression::Upstream(ct) => *ct,
+        _ => CompressionType::ZlibLevel(6)
+    };
+    //the block locations depend on the length of the header, which
+    //depends on the locations: repeat until the length is stable. the
+    //compressed length needn't settle, but the uncompressed length only
+    //grows with the offset, so always does
+    let mut offset = 0;
+    let mut pass = 0;
+    loop {
+        let ct = if pass < MAX_HEADER_PASSES { compression } else { CompressionType::Uncompressed };
+        let packed = osmquadtree::pbfformat::pack_file_block("OSMHeader", &pack(offset), &ct).map_err(ErrorWrapped::from)?;
+        if packed.len() as u64 == offset {
+            return Ok(packed);
+        }
+        offset = packed.len() as u64;
+        pass += 1;
+    }
+}
+
+/// Repacks the OSMHeader `data`, moving each block in the quadtree index
+/// from its old location to the new location, relative to the end of the
+/// header, and length in `locs`. Blocks not in `locs` are dropped from the
+/// index.
+pub(crate) fn rewrite_header(data: &[u8], locs: &BTreeMap<u64, (u64, u64)>, compression: &Compression) -> PyResult<Vec<u8>> {
+    pack_header_block(|offset| {
+        let mut header = Vec::with_capacity(data.len());
+        for t in IterTags::new(data) {
+            match t {
+                PbfTag::Data(22, d) => {
+                    if let Some(e) = rewrite_index_entry(d, locs, offset) {
+                        pack_data(&mut header, 22, &e);
+                    }
+                },
+                PbfTag::Data(k, d) => { pack_data(&mut header, k, d); },
+                PbfTag::Value(k, v) => { pack_value(&mut header, k, v); }
+            }
+        }
+        header
+    }, compression)
+}
+
+fn recompress_file(infile: &str, outfile: &str, compression: &Compression, numchan: usize) -> PyResult<usize> {
+    let flen = std::fs::metadata(infile).map_err(|e| ErrorWrapped::from(e).with_filename(infile))?.len();
+    let mut fbuf = BufReader::new(File::open(infile).map_err(|e| ErrorWrapped::from(e).with_filename(infile))?);
+
+    let tempfn = format!("{}-partial", outfile);
+    let error: ErrorSlot = Arc::new(Mutex::new(None));
+    let locs = Arc::new(Mutex::new(Vec::new()));
+    let writer = Box::new(WriteBlocksInOrder{
+        outf: BufWriter::new(File::create(&tempfn).map_err(|e| ErrorWrapped::from(e).with_filename(&tempfn))?),
+        outfn: tempfn.clone(), pending: BTreeMap::new(), locs: locs.clone(), pos: 0, error: error.clone()});
+
+    let mut conv: Box<dyn CallFinish<CallType = (usize, FileBlock), ReturnType = Timings<usize>, ErrorType = Error>> =
+        if numchan == 0 {
+            Box::new(RecompressBlocks{out: writer, compression: compression.clone(), error: error.clone()})
+        } else {
+            let cosp = CallbackSync::new(writer, numchan);
+            let mut convs: Vec<
+                Box<dyn CallFinish<CallType = (usize, FileBlock), ReturnType = Timings<usize>, ErrorType = Error>>,
+            > = Vec::new();
+            for cos in cosp {
+                let cos2 = Box::new(ReplaceNoneWithTimings::new(cos));
+                convs.push(Box::new(Callback::new(
+                    Box::new(RecompressBlocks{out: cos2, compression: compression.clone(), error: error.clone()})
+                )));
+            }
+            Box::new(CallbackMerge::new(convs, Box::new(MergeTimings::new())))
+        };
+
+    let mut header = None;
+    let mut old_pos = Vec::new();
+    let mut pos = 0;
+    while pos < flen && !has_error(&error) && !crate::cancel::is_cancelled() {
+        let (next, fb) = read_file_block_with_pos(&mut fbuf, pos)
+            .map_err(|e| ErrorWrapped::from(e).with_filename(infile).with_position(pos))?;
+        if pos == 0 && fb.block_type == "OSMHeader" {
+            header = Some(fb.data());
+        } else {
+            old_pos.push(pos);
+            conv.call((old_pos.len() - 1, fb));
+        }
+        pos = next;
+    }
+
+    let res = conv.finish().map_err(|e| PyErr::from(ErrorWrapped::new(e.into())));
+    drop(conv);
+    let res = res.and_then(|_| take_error(&error)).and_then(|_| crate::cancel::check_cancelled());
+    if let Err(e) = res {
+        let _ = std::fs::remove_file(&tempfn);
+        return Err(e);
+    }
+
+    let locs = std::mem::take(&mut *locs.lock().map_err(|_| pyo3::exceptions::PyRuntimeError::new_err("lock poisoned"))?);
+    let locs_map: BTreeMap<u64, (u64, u64)> = old_pos.iter().cloned().zip(locs.iter().cloned()).collect();
+
+    let assemble = || -> PyResult<()> {
+        let mut outf = BufWriter::new(File::create(outfile).map_err(|e| ErrorWrapped::from(e).with_filename(outfile))?);
+        if let Some(h) = &header {
+            outf.write_all(&rewrite_header(h, &locs_map, compression)?).map_err(|e| ErrorWrapped::from(e).with_filename(outfile))?;
+        }
+        let mut inf = File::open(&tempfn).map_err(|e| ErrorWrapped::from(e).with_filename(&tempfn))?;
+        std::io::copy(&mut inf, &mut outf).map_err(|e| ErrorWrapped::from(e).with_filename(outfile))?;
+        outf.flush().map_err(|e| ErrorWrapped::from(e).with_filename(outfile))?;
+        Ok(())
+    };
+    let res = assemble();
+    let _ = std::fs::remove_file(&tempfn);
+    res?;
+    Ok(locs.len())
+}
+
+/// Rewrites the blocks of `infile` to `outfile` compressed with
+/// `compression` (eg ("ZstdLevel", 3), ("Lz4", 0) or ("ZlibLevel", 6)),
+/// without decoding the elements. The quadtree index in the header is
+/// updated to the new block locations. Returns the number of data blocks.
+#[pyfunction]
+#[pyo3(signature = (infile, outfile, compression, numchan=crate::readpbf::default_numchan()))]
+pub fn recompress(py: Python, infile: &str, outfile: &str, compression: (String, u32), numchan: usize) -> PyResult<usize> {
+    crate::errors::check_exists(infile)?;
+    if std::path::Path::new(infile) == std::path::Path::new(outfile) {
+        return Err(crate::errors::invalid_input_error(format!("can't recompress {} in place", infile)));
+    }
+    let compression = Compression::from_tuple((&compression.0, compression.1))?;
+    crate::cancel::run_interruptible(py, &[String::from(outfile)], || recompress_file(infile, outfile, &compression, numchan))
+}
+
+
+pub(crate) fn wrap_compression(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_wrapped(wrap_pyfunction!(recompress))?;
+    Ok(())
+}
@@ -492,7 +492,7 @@ impl CountChange {
 
 /// Parses the File from the specified Path into a document
 #[pyfunction]
-#[pyo3(signature = (fname, use_primitive=false, numchan=4, filter_in=None, tstamp=None))]
+#[pyo3(signature = (fname, use_primitive=false, numchan=crate::readpbf::default_numchan(), filter_in=None, tstamp=None))]
 fn call_count(py: Python,
     fname: &str,
     use_primitive: bool,
@@ -500,6 +500,10 @@ fn call_count(py: Python,
     filter_in: Option<&str>,
     tstamp: Option<&str>) -> PyResult<PyObject> {
     
+    crate::errors::check_exists(fname)?;
+    if let Some(t) = tstamp {
+        crate::elements::parse_timestamp(t)?;
+    }
     
     let op = || osmquadtree::count::call_count(fname, use_primitive, numchan, filter_in, tstamp);
     
@@ -508,12 +512,12 @@ fn call_count(py: Python,
             op()
         
         } else {
-            py.allow_threads(op)
+            crate::cancel::run_interruptible(py, &[], || Ok(op()))?
         };
     
     
     match res {
-        Err(e) => Err(PyErr::from(ErrorWrapped{e:e})),
+        Err(e) => Err(PyErr::from(ErrorWrapped::new(e).with_filename(fname))),
         Ok(osmquadtree::count::CountAny::Count(cc)) => Ok(Count{inner: Arc::new(RwLock::new(cc)) }.into_py(py)),
         Ok(osmquadtree::count::CountAny::CountChange(cc)) => Ok(CountChange{inner: Arc::new(RwLock::new(cc)) }.into_py(py)),
     }
@@ -527,6 +531,10 @@ use crate::elements::prep_element_tuple;
 
 #[pyfunction]
 fn compare_pbf_files(py: Python, left: &str, right: &str, numchan: usize, max_result_len: usize) -> PyResult<PyObject> {
+    crate::errors::check_exists(left)?;
+    crate::errors::check_exists(right)?;
+    crate::compression::check_upstream_readable(left, "compare_pbf_files")?;
+    crate::compression::check_upstream_readable(right, "compare_pbf_files")?;
     
     let (eles,users,count) = match py.allow_threads( || {
     
@@ -535,7 +543,7 @@ fn compare_pbf_files(py: Python, left: &str, right: &str, numchan: usize, max_re
         osmquadtree::elements::compare_element_iters(left_iter,right_iter,max_result_len)
     }) {
         Ok(x) => Ok(x), 
-        Err(e) => Err(ErrorWrapped{e:e})
+        Err(e) => Err(ErrorWrapped::new(e))
     }?;
     
     let list = PyList::empty(py);
@@ -557,6 +565,10 @@ fn compare_pbf_files(py: Python, left: &str, right: &str, numchan: usize, max_re
 
 #[pyfunction]
 fn compare_pbf_files_json(py: Python, left: &str, right: &str, numchan: usize, outfn: &str) -> PyResult<PyObject> {
+    crate::errors::check_exists(left)?;
+    crate::errors::check_exists(right)?;
+    crate::compression::check_upstream_readable(left, "compare_pbf_files")?;
+    crate::compression::check_upstream_readable(right, "compare_pbf_files")?;
     
     let (users,count) = match py.allow_threads( || {
     
@@ -565,7 +577,7 @@ fn compare_pbf_files_json(py: Python, left: &str, right: &str, numchan: usize, o
         osmquadtree::elements::compare_element_iters_json(left_iter,right_iter,outfn)
     }) {
         Ok(x) => Ok(x), 
-        Err(e) => Err(ErrorWrapped{e:e})
+        Err(e) => Err(ErrorWrapped::new(e))
     }?;
     
    
@@ -0,0 +1,578 @@
+use pyo3::prelude::*;
+use std::sync::Arc;
+use std::collections::{BTreeMap,BTreeSet};
+use std::io::{Seek,SeekFrom};
+
+use osmquadtree::elements::{Bbox,ElementType};
+use osmquadtree::mergechanges::Poly;
+use osmquadtree_geometry::WithBounds;
+
+use crate::ErrorWrapped;
+
+
+//as used by osmquadtree when assigning elements to quadtrees
+pub(crate) const QUADTREE_BUFFER: f64 = 0.05;
+
+pub(crate) fn overlaps(a: &Bbox, b: &Bbox) -> bool {
+    a.minlon <= b.maxlon && b.minlon <= a.maxlon && a.minlat <= b.maxlat && b.minlat <= a.maxlat
+}
+
+/// A bbox, optionally with a Poly within it.
+pub(crate) struct QueryArea {
+    pub bbox: Bbox,
+    pub poly: Option<Poly>
+}
+
+impl QueryArea {
+    pub fn contains_point(&self, lon: i32, lat: i32) -> bool {
+        self.bbox.contains_point(lon, lat) && self.poly.as_ref().map_or(true, |p| p.contains_point(lon, lat))
+    }
+
+    pub fn overlaps_box(&self, b: &Bbox) -> bool {
+        overlaps(&self.bbox, b) && self.poly.as_ref().map_or(true, |p| p.check_box(b))
+    }
+}
+
+//decoded blocks, evicting the least recently used
+struct BlockCache<T> {
+    capacity: usize,
+    blocks: BTreeMap<usize, (Arc<T>, u64)>,
+    tick: u64,
+    hits: u64,
+    misses: u64
+}
+
+impl<T> BlockCache<T> {
+    fn new(capacity: usize) -> BlockCache<T> {
+        BlockCache{capacity: capacity, blocks: BTreeMap::new(), tick: 0, hits: 0, misses: 0}
+    }
+
+    fn get(&mut self, idx: usize) -> Option<Arc<T>> {
+        self.tick += 1;
+        match self.blocks.get_mut(&idx) {
+            Some((bl, t)) => {
+                *t = self.tick;
+                self.hits += 1;
+                Some(bl.clone())
+            },
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    fn insert(&mut self, idx: usize, bl: Arc<T>) {
+        if self.capacity == 0 {
+            return;
+        }
+        while self.blocks.len() >= self.capacity {
+            let oldest = *self.blocks.iter().min_by_key(|(_, (_, t))| *t).unwrap().0;
+            self.blocks.remove(&oldest);
+        }
+        self.tick += 1;
+        self.blocks.insert(idx, (bl, self.tick));
+    }
+}
+
+/// Reads block `idx` of `pfilelocs`, with any changes applied.
+pub(crate) fn read_block_at(pfilelocs: &mut osmquadtree::pbfformat::ParallelFileLocs, prfx: &str, idx: usize) -> PyResult<osmquadtree::elements::PrimitiveBlock> {
+    let mut fbs = Vec::new();
+    for (a, b) in &pfilelocs.1[idx].1 {
+        pfilelocs.0[*a].seek(SeekFrom::Start(*b)).map_err(ErrorWrapped::from)?;
+        let (_, fb) = crate::compression::read_file_block_with_pos(&mut pfilelocs.0[*a], *b).map_err(ErrorWrapped::from)?;
+        fbs.push(fb);
+    }
+    let pos = fbs.first().map(|fb| fb.pos);
+    let bl = osmquadtree::pbfformat::read_primitive_blocks_combine(idx as i64, fbs, None)
+        .map_err(|e| { let e = ErrorWrapped::from(e).with_filename(prfx); match pos { Some(p) => e.with_position(p), None => e } })?;
+    Ok(bl)
+}
+
+/// An osmquadtree prefix, opened once for repeated bbox and poly queries.
+/// The quadtree index of the blocks is kept, along with up to `cache_size`
+/// decoded blocks, and as many blocks of geometry for the last style used,
+/// so that queries over the same area don't read and decode the same blocks
+/// again.
+#[pyclass]
+pub struct Dataset {
+    prfx: String,
+    pfilelocs: osmquadtree::pbfformat::ParallelFileLocs,
+    cache: BlockCache<osmquadtree::elements::PrimitiveBlock>,
+    geometry_cache: BlockCache<osmquadtree_geometry::GeometryBlock>,
+    geometry_key: String,
+    blocks_by_quadtree: BTreeMap<i64, usize>,
+    id_index: Option<crate::idindex::IdIndex>
+}
+
+impl Dataset {
+    fn block_indices_for(&self, area: &QueryArea) -> Vec<usize> {
+        let mut res = Vec::new();
+        for (i, (q, _)) in self.pfilelocs.1.iter().enumerate() {
+            if area.overlaps_box(&q.as_bbox(QUADTREE_BUFFER)) {
+                res.push(i);
+            }
+        }
+        res
+    }
+
+    fn read_block(&mut self, idx: usize) -> PyResult<osmquadtree::elements::PrimitiveBlock> {
+        read_block_at(&mut self.pfilelocs, &self.prfx, idx)
+    }
+
+    fn get_blocks(&mut self, idxs: &[usize]) -> PyResult<Vec<Arc<osmquadtree::elements::PrimitiveBlock>>> {
+        let mut res = Vec::with_capacity(idxs.len());
+        for i in idxs {
+            crate::cancel::check_cancelled()?;
+            let bl = match self.cache.get(*i) {
+                Some(bl) => bl,
+                None => {
+                    let bl = Arc::new(self.read_block(*i)?);
+                    self.cache.insert(*i, bl.clone());
+                    bl
+                }
+            };
+            res.push(bl);
+        }
+        Ok(res)
+    }
+
+    //elements are stored in the block with the closest enclosing quadtree
+    fn block_for_quadtree(&self, q: &osmquadtree::elements::Quadtree) -> Option<usize> {
+        for d in (0..=q.depth()).rev() {
+            if let Some(i) = self.blocks_by_quadtree.get(&q.round(d).as_int()) {
+                return Some(*i);
+            }
+        }
+        None
+    }
+
+    fn find_block(&mut self, et: &ElementType, id: i64) -> PyResult<Option<Arc<osmquadtree::elements::PrimitiveBlock>>> {
+        let q = {
+            let idx = self.id_index.as_ref().ok_or_else(|| crate::errors::invalid_input_error(
+                format!("{}: no id index, see build_id_index", self.prfx)))?;
+            match et {
+                ElementType::Node => idx.node(id),
+                ElementType::Way => idx.way(id),
+                ElementType::Relation => idx.relation(id),
+                _ => None
+            }
+        };
+        match q.and_then(|q| self.block_for_quadtree(&q)) {
+            None => Ok(None),
+            Some(i) => Ok(Some(self.get_blocks(&[i])?.remove(0)))
+        }
+    }
+
+    fn get_node_ele(&mut self, id: i64) -> PyResult<Option<osmquadtree::elements::Node>> {
+        Ok(self.find_block(&ElementType::Node, id)?.and_then(|bl| bl.nodes.iter().find(|n| n.id == id).cloned()))
+    }
+
+    fn get_way_ele(&mut self, id: i64) -> PyResult<Option<osmquadtree::elements::Way>> {
+        Ok(self.find_block(&ElementType::Way, id)?.and_then(|bl| bl.ways.iter().find(|w| w.id == id).cloned()))
+    }
+
+    fn get_relation_ele(&mut self, id: i64) -> PyResult<Option<osmquadtree::elements::Relation>> {
+        Ok(self.find_block(&ElementType::Relation, id)?.and_then(|bl| bl.relations.iter().find(|r| r.id == id).cloned()))
+    }
+
+    //the element with everything it refers to, skipping any referenced
+    //elements which aren't in the dataset
+    fn get_full(&mut self, et: ElementType, id: i64) -> PyResult<Option<osmquadtree::elements::PrimitiveBlock>> {
+        let mut nodes = BTreeMap::new();
+        let mut ways = BTreeMap::new();
+        let mut relations = BTreeMap::new();
+        let mut node_ids = BTreeSet::new();
+        let mut way_ids = BTreeSet::new();
+
+        let quadtree = match et {
+            ElementType::Node => match self.get_node_ele(id)? {
+                None => { return Ok(None); },
+                Some(n) => { let q = n.quadtree.clone(); nodes.insert(n.id, n); q }
+            },
+            ElementType::Way => match self.get_way_ele(id)? {
+                None => { return Ok(None); },
+                Some(w) => { let q = w.quadtree.clone(); way_ids.insert(w.id); ways.insert(w.id, w); q }
+            },
+            _ => {
+                let rel = match self.get_relation_ele(id)? {
+                    None => { return Ok(None); },
+                    Some(r) => r
+                };
+                let q = rel.quadtree.clone();
+                let mut pending = vec![rel];
+                while let Some(r) = pending.pop() {
+                    for m in &r.members {
+                        match m.mem_type {
+                            ElementType::Node => { node_ids.insert(m.mem_ref); },
+                            ElementType::Way => { way_ids.insert(m.mem_ref); },
+                            ElementType::Relation => {
+                                if m.mem_ref != r.id && !relations.contains_key(&m.mem_ref) && !pending.iter().any(|p| p.id == m.mem_ref) {
+                                    if let Some(r2) = self.get_relation_ele(m.mem_ref)? {
+                                        pending.push(r2);
+                                    }
+                                }
+                            },
+                            _ => {}
+                        }
+                    }
+                    relations.insert(r.id, r);
+                }
+                q
+            }
+        };
+
+        for w in way_ids {
+            crate::cancel::check_cancelled()?;
+            let w = match ways.remove(&w) {
+                Some(w) => Some(w),
+                None => self.get_way_ele(w)?
+            };
+            if let Some(w) = w {
+                node_ids.extend(w.refs.iter().cloned());
+                ways.insert(w.id, w);
+            }
+        }
+        for n in node_ids {
+            crate::cancel::check_cancelled()?;
+            if let Some(n) = self.get_node_ele(n)? {
+                nodes.insert(n.id, n);
+            }
+        }
+
+        let mut pb = osmquadtree::elements::PrimitiveBlock::new(0, 0);
+        pb.quadtree = quadtree;
+        pb.nodes.extend(nodes.into_iter().map(|(_, n)| n));
+        pb.ways.extend(ways.into_iter().map(|(_, w)| w));
+        pb.relations.extend(relations.into_iter().map(|(_, r)| r));
+        Ok(Some(pb))
+    }
+
+    fn get_element(&mut self, py: Python, et: ElementType, id: i64, full: bool) -> PyResult<PyObject> {
+        if full {
+            let bl = crate::cancel::run_interruptible(py, &[], || self.get_full(et, id))?;
+            return Ok(bl.map(crate::elements::PrimitiveBlock::new).into_py(py));
+        }
+        Ok(match et {
+            ElementType::Node => self.get_node_ele(id)?.map(|n| crate::elements::Node::as_item(n)).transpose()?.into_py(py),
+            ElementType::Way => self.get_way_ele(id)?.map(|w| crate::elements::Way::as_item(w)).transpose()?.into_py(py),
+            _ => self.get_relation_ele(id)?.map(|r| crate::elements::Relation::as_item(r)).transpose()?.into_py(py)
+        })
+    }
+
+    //the blocks holding nodes `ids`, which are used by ways with quadtrees
+    //`way_quadtrees` and aren't in blocks `skip`. without an id index, these
+    //are the blocks overlapping the ways' quadtrees, which must hold all
+    //their nodes
+    fn blocks_for_nodes(&mut self, ids: &BTreeSet<i64>, way_quadtrees: &BTreeSet<i64>, skip: &[usize]) -> PyResult<Vec<Arc<osmquadtree::elements::PrimitiveBlock>>> {
+        let skip: BTreeSet<usize> = skip.iter().cloned().collect();
+        if self.id_index.is_some() {
+            let mut res: BTreeMap<i64, Arc<osmquadtree::elements::PrimitiveBlock>> = BTreeMap::new();
+            for n in ids {
+                if let Some(bl) = self.find_block(&ElementType::Node, *n)? {
+                    if !skip.contains(&(bl.index as usize)) {
+                        res.insert(bl.index, bl);
+                    }
+                }
+            }
+            return Ok(res.into_values().collect());
+        }
+
+        let boxes: Vec<Bbox> = way_quadtrees.iter().map(|q| osmquadtree::elements::Quadtree::new(*q).as_bbox(QUADTREE_BUFFER)).collect();
+        let mut idxs = Vec::new();
+        for (i, (q, _)) in self.pfilelocs.1.iter().enumerate() {
+            if !skip.contains(&i) {
+                let qb = q.as_bbox(QUADTREE_BUFFER);
+                if boxes.iter().any(|b| overlaps(b, &qb)) {
+                    idxs.push(i);
+                }
+            }
+        }
+        self.get_blocks(&idxs)
+    }
+
+    fn query_elements(&mut self, area: &QueryArea) -> PyResult<Vec<osmquadtree::elements::PrimitiveBlock>> {
+        let idxs = self.block_indices_for(area);
+        let mut blocks = self.get_blocks(&idxs)?;
+
+        //nodes in the area, then the ways which use them (with all their
+        //nodes), then the relations with any selected member
+        let mut nodes = BTreeSet::new();
+        for bl in &blocks {
+            for n in &bl.nodes {
+                if area.contains_point(n.lon, n.lat) {
+                    nodes.insert(n.id);
+                }
+            }
+        }
+        let mut ways = BTreeSet::new();
+        let mut way_nodes = BTreeSet::new();
+        let mut way_quadtrees = BTreeSet::new();
+        for bl in &blocks {
+            for w in &bl.ways {
+                if w.refs.iter().any(|r| nodes.contains(r)) {
+                    ways.insert(w.id);
+                    way_nodes.extend(w.refs.iter().cloned());
+                    way_quadtrees.insert(w.quadtree.as_int());
+                }
+            }
+        }
+
+        //ways crossing the edge of the area have nodes outside it, which
+        //may be in blocks we haven't read
+        let mut missing = way_nodes.clone();
+        for bl in &blocks {
+            for n in &bl.nodes {
+                missing.remove(&n.id);
+            }
+        }
+        if !missing.is_empty() {
+            blocks.extend(self.blocks_for_nodes(&missing, &way_quadtrees, &idxs)?);
+            blocks.sort_by_key(|bl| bl.index);
+        }
+        nodes.extend(way_nodes);
+
+        let mut relations = BTreeSet::new();
+        for bl in &blocks {
+            for r in &bl.relations {
+                if r.members.iter().any(|m| match m.mem_type {
+                    ElementType::Node => nodes.contains(&m.mem_ref),
+                    ElementType::Way => ways.contains(&m.mem_ref),
+                    _ => false
+                }) {
+                    relations.insert(r.id);
+                }
+            }
+        }
+        //and the parents of those relations
+        let mut parents = BTreeSet::new();
+        for bl in &blocks {
+            for r in &bl.relations {
+                if r.members.iter().any(|m| matches!(m.mem_type, ElementType::Relation) && relations.contains(&m.mem_ref)) {
+                    parents.insert(r.id);
+                }
+            }
+        }
+        relations.extend(parents);
+
+        let mut res = Vec::new();
+        for bl in &blocks {
+            let mut pb = osmquadtree::elements::PrimitiveBlock::new(bl.index, bl.location);
+            pb.quadtree = bl.quadtree.clone();
+            pb.start_date = bl.start_date;
+            pb.end_date = bl.end_date;
+            pb.nodes.extend(bl.nodes.iter().filter(|n| nodes.contains(&n.id)).cloned());
+            pb.ways.extend(bl.ways.iter().filter(|w| ways.contains(&w.id)).cloned());
+            pb.relations.extend(bl.relations.iter().filter(|r| relations.contains(&r.id)).cloned());
+            if !(pb.nodes.is_empty() && pb.ways.is_empty() && pb.relations.is_empty()) {
+                res.push(pb);
+            }
+        }
+        Ok(res)
+    }
+
+    fn query_geometry(&mut self, py: Python, area: &QueryArea, style_in: Option<PyObject>, minzoom_in: Option<PyObject>, numchan: usize) -> PyResult<Vec<osmquadtree_geometry::GeometryBlock>> {
+        //cached geometry is only used with the same style and minzoom
+        let arg_key = |a: &Option<PyObject>| a.as_ref().map_or(Ok(String::from("None")), |o| o.bind(py).repr().map(|r| r.to_string()));
+        let key = format!("{} {}", arg_key(&style_in)?, arg_key(&minzoom_in)?);
+        let style = crate::geometry::prep_style(py, style_in)?;
+        let minzoom = crate::geometry::prep_minzoom(py, minzoom_in)?;
+        if key != self.geometry_key {
+            self.geometry_cache.blocks.clear();
+            self.geometry_key = key;
+        }
+
+        let idxs = self.block_indices_for(area);
+        let mut found = BTreeMap::new();
+        let mut missing = Vec::new();
+        for i in &idxs {
+            match self.geometry_cache.get(*i) {
+                Some(g) => { found.insert(*i, g); },
+                None => { missing.push(*i); }
+            }
+        }
+
+        if !missing.is_empty() {
+            crate::compression::check_upstream_readable_locs(&mut self.pfilelocs, &self.prfx, "query_geometry")?;
+            //run the pipeline over just the blocks which aren't cached, using
+            //the files we already have open
+            let locs: Vec<_> = missing.iter().map(|i| self.pfilelocs.1[*i].clone()).collect();
+            let qq = locs.iter().map(|(q, _)| q.clone()).collect();
+            let all_locs = std::mem::replace(&mut self.pfilelocs.1, locs);
+
+            let pfilelocs = &mut self.pfilelocs;
+            let res = crate::cancel::run_interruptible(py, &[], || Ok(osmquadtree_geometry::process_geometry_call(
+                pfilelocs,
+                Some(crate::cancel::stop_when_cancelled(Box::new(osmquadtree_geometry::StoreBlocks::new(qq)))),
+                style,
+                minzoom,
+                numchan)));
+            self.pfilelocs.1 = all_locs;
+
+            for x in res?.others {
+                if let (_, osmquadtree_geometry::OtherData::GeometryBlocks(gg)) = x {
+                    for (_, g) in gg {
+                        if let Some(i) = self.blocks_by_quadtree.get(&g.quadtree.as_int()).cloned() {
+                            found.insert(i, Arc::new(g));
+                        }
+                    }
+                }
+            }
+            //blocks without any geometry are cached as empty
+            for i in missing {
+                let g = found.entry(i).or_insert_with(|| Arc::new(osmquadtree_geometry::GeometryBlock::new(i as i64, self.pfilelocs.1[i].0, 0))).clone();
+                self.geometry_cache.insert(i, g);
+            }
+        }
+
+        let mut blocks = Vec::new();
+        for (_, g) in found {
+            let mut bl = osmquadtree_geometry::GeometryBlock::new(g.index, g.quadtree, g.end_date);
+            bl.points.extend(g.points.iter().filter(|p| area.overlaps_box(&p.bounds())).cloned());
+            bl.linestrings.extend(g.linestrings.iter().filter(|p| area.overlaps_box(&p.bounds())).cloned());
+            bl.simple_polygons.extend(g.simple_polygons.iter().filter(|p| area.overlaps_box(&p.bounds())).cloned());
+            bl.complicated_polygons.extend(g.complicated_polygons.iter().filter(|p| area.overlaps_box(&p.bounds())).cloned());
+            blocks.push(bl);
+        }
+        Ok(blocks)
+    }
+
+    fn query(&mut self, py: Python, area: QueryArea, geometry: bool, style_in: Option<PyObject>, minzoom_in: Option<PyObject>, numchan: usize) -> PyResult<PyObject> {
+        if geometry {
+            let bls = self.query_geometry(py, &area, style_in, minzoom_in, numchan)?;
+            Ok(bls.into_iter().map(crate::geometry::GeometryBlock::new).collect::<Vec<_>>().into_py(py))
+        } else {
+            let bls = crate::cancel::run_interruptible(py, &[], || self.query_elements(&area))?;
+            Ok(bls.into_iter().map(crate::elements::PrimitiveBlock::new).collect::<Vec<_>>().into_py(py))
+        }
+    }
+}
+
+#[pymethods]
+impl Dataset {
+    #[new]
+    #[pyo3(signature = (prfx, timestamp=None, cache_size=256, id_index=None))]
+    pub fn new(prfx: &str, timestamp: Option<&str>, cache_size: usize, id_index: Option<&str>) -> PyResult<Dataset> {
+        let ts = match timestamp {
+            Some(t) => Some(osmquadtree::utils::parse_timestamp(t).map_err(|e| crate::errors::timestamp_error(format!("{}: {}", t, e)))?),
+            None => None
+        };
+
+        let pfilelocs = osmquadtree::pbfformat::get_file_locs(prfx, Some(Bbox::planet()), ts)
+            .map_err(|e| ErrorWrapped::from(e).with_filename(prfx))?;
+
+        let mut blocks_by_quadtree = BTreeMap::new();
+        for (i, (q, _)) in pfilelocs.1.iter().enumerate() {
+            blocks_by_quadtree.insert(q.as_int(), i);
+        }
+
+        //use the index written by build_id_index, if there is one
+        let id_index = match id_index {
+            Some(fname) => Some(crate::idindex::IdIndex::read(fname)?),
+            None => {
+                let fname = crate::idindex::default_index_path(prfx);
+                if std::path::Path::new(&fname).exists() { Some(crate::idindex::IdIndex::read(&fname)?) } else { None }
+            }
+        };
+
+        Ok(Dataset{prfx: String::from(prfx), pfilelocs: pfilelocs, cache: BlockCache::new(cache_size),
+            geometry_cache: BlockCache::new(cache_size), geometry_key: String::new(),
+            blocks_by_quadtree: blocks_by_quadtree, id_index: id_index})
+    }
+
+    pub fn num_blocks(&self) -> PyResult<usize> {
+        Ok(self.pfilelocs.1.len())
+    }
+
+    /// Indices of the blocks which may hold elements in `filter` (a bbox or
+    /// Poly).
+    pub fn block_indices(&self, py: Python, filter: PyObject) -> PyResult<Vec<usize>> {
+        let (_, bbox, poly) = crate::readpbf::read_filter(py, Some(filter))?;
+        Ok(self.block_indices_for(&QueryArea{bbox: bbox, poly: poly}))
+    }
+
+    /// Returns the elements in `bbox`, (minlon, minlat, maxlon, maxlat) in
+    /// units of 1e-7 degrees, as a list of PrimitiveBlocks. As for extracts,
+    /// this is the nodes in the box, the ways using any of those nodes
+    /// (with all their nodes, which are found with the id index if loaded,
+    /// or else by reading the blocks around the way), and the relations with
+    /// any selected member.
+    /// If `geometry` is set, returns GeometryBlocks instead, with the objects
+    /// whose bounds overlap the box.
+    #[pyo3(signature = (bbox, geometry=false, style_in=None, minzoom_in=None, numchan=crate::readpbf::default_numchan()))]
+    pub fn query_bbox(&mut self, py: Python, bbox: (i32,i32,i32,i32), geometry: bool, style_in: Option<PyObject>, minzoom_in: Option<PyObject>, numchan: usize) -> PyResult<PyObject> {
+        if bbox.0 > bbox.2 || bbox.1 > bbox.3 {
+            return Err(crate::errors::filter_error(format!("invalid bbox {:?}", bbox)));
+        }
+        let area = QueryArea{bbox: Bbox::new(bbox.0, bbox.1, bbox.2, bbox.3), poly: None};
+        self.query(py, area, geometry, style_in, minzoom_in, numchan)
+    }
+
+    /// As query_bbox, for the area inside `poly`.
+    #[pyo3(signature = (poly, geometry=false, style_in=None, minzoom_in=None, numchan=crate::readpbf::default_numchan()))]
+    pub fn query_poly(&mut self, py: Python, poly: &crate::readpbf::Poly, geometry: bool, style_in: Option<PyObject>, minzoom_in: Option<PyObject>, numchan: usize) -> PyResult<PyObject> {
+        let area = QueryArea{bbox: poly.inner.bounds(), poly: Some(poly.inner.clone())};
+        self.query(py, area, geometry, style_in, minzoom_in, numchan)
+    }
+
+    /// Loads the id index written by build_id_index, from the dataset
+    /// directory if `fname` isn't given.
+    #[pyo3(signature = (fname=None))]
+    pub fn load_id_index(&mut self, fname: Option<&str>) -> PyResult<()> {
+        let fname = fname.map_or_else(|| crate::idindex::default_index_path(&self.prfx), String::from);
+        self.id_index = Some(crate::idindex::IdIndex::read(&fname)?);
+        Ok(())
+    }
+
+    #[getter]
+    pub fn has_id_index(&self) -> PyResult<bool> {
+        Ok(self.id_index.is_some())
+    }
+
+    /// Returns node `id`, or None if it isn't in the dataset. If `full` is
+    /// set, returns a PrimitiveBlock holding the node instead. Needs an id
+    /// index, see build_id_index.
+    #[pyo3(signature = (id, full=true))]
+    pub fn get_node(&mut self, py: Python, id: i64, full: bool) -> PyResult<PyObject> {
+        self.get_element(py, ElementType::Node, id, full)
+    }
+
+    /// Returns way `id`, or None. If `full` is set, returns a PrimitiveBlock
+    /// holding the way and its nodes.
+    #[pyo3(signature = (id, full=true))]
+    pub fn get_way(&mut self, py: Python, id: i64, full: bool) -> PyResult<PyObject> {
+        self.get_element(py, ElementType::Way, id, full)
+    }
+
+    /// Returns relation `id`, or None. If `full` is set, returns a
+    /// PrimitiveBlock holding the relation and its members, with member
+    /// relations and the nodes of member ways resolved recursively.
+    /// Members missing from the dataset are left out.
+    #[pyo3(signature = (id, full=true))]
+    pub fn get_relation(&mut self, py: Python, id: i64, full: bool) -> PyResult<PyObject> {
+        self.get_element(py, ElementType::Relation, id, full)
+    }
+
+    /// (hits, misses, cached blocks, cache size), for the decoded blocks
+    /// used by element queries
+    #[getter]
+    pub fn cache_stats(&self) -> PyResult<(u64, u64, usize, usize)> {
+        Ok((self.cache.hits, self.cache.misses, self.cache.blocks.len(), self.cache.capacity))
+    }
+
+    pub fn clear_cache(&mut self) -> PyResult<()> {
+        self.cache.blocks.clear();
+        self.geometry_cache.blocks.clear();
+        Ok(())
+    }
+
+    fn __repr__(&self) -> PyResult<String> {
+        Ok(format!("Dataset {} [{} blocks, {} cached]", self.prfx, self.pfilelocs.1.len(), self.cache.blocks.len()))
+    }
+}
+
+
+pub(crate) fn wrap_dataset(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<Dataset>()?;
+    Ok(())
+}
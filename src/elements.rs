@@ -7,6 +7,7 @@ use pyo3::basic::CompareOp;
 use std::sync::Arc;
 //use std::ops::Drop;
 use crate::ErrorWrapped;
+use crate::columns::{ColumnData,CommonColumns,make_columns,element_type_code};
 
 
 #[pyclass]
@@ -151,6 +152,63 @@ impl PrimitiveBlock {
     pub fn num_ways(&self) -> PyResult<i64> { Ok(self.inner.ways.len() as i64) }
     pub fn num_relations(&self) -> PyResult<i64> { Ok(self.inner.relations.len() as i64) }
     
+    /// Node columns as a dict of Column: id, changetype, version, timestamp,
+    /// changeset, user_id, quadtree, lon and lat.
+    pub fn node_columns(&self, py: Python) -> PyResult<PyObject> {
+        let mut common = CommonColumns::new(true);
+        let mut lon = Vec::with_capacity(self.inner.nodes.len());
+        let mut lat = Vec::with_capacity(self.inner.nodes.len());
+        for n in &self.inner.nodes {
+            common.add(n.id, &n.changetype, &n.info, &n.quadtree);
+            lon.push(n.lon);
+            lat.push(n.lat);
+        }
+        let mut cols = common.into_columns();
+        cols.push(("lon", ColumnData::Int32(lon)));
+        cols.push(("lat", ColumnData::Int32(lat)));
+        make_columns(py, cols)
+    }
+    
+    /// Way columns: as node_columns, with the refs of way i given by
+    /// `refs[ref_offsets[i]:ref_offsets[i+1]]`.
+    pub fn way_columns(&self, py: Python) -> PyResult<PyObject> {
+        let mut common = CommonColumns::new(true);
+        let mut ref_offsets = vec![0];
+        let mut refs = Vec::new();
+        for w in &self.inner.ways {
+            common.add(w.id, &w.changetype, &w.info, &w.quadtree);
+            refs.extend(w.refs.iter());
+            ref_offsets.push(refs.len() as i64);
+        }
+        let mut cols = common.into_columns();
+        cols.push(("ref_offsets", ColumnData::Int64(ref_offsets)));
+        cols.push(("refs", ColumnData::Int64(refs)));
+        make_columns(py, cols)
+    }
+    
+    /// Relation columns: as node_columns, with members given by
+    /// member_offsets, member_types (0=node, 1=way, 2=relation) and
+    /// member_refs. Roles are not included.
+    pub fn relation_columns(&self, py: Python) -> PyResult<PyObject> {
+        let mut common = CommonColumns::new(true);
+        let mut member_offsets = vec![0];
+        let mut member_types = Vec::new();
+        let mut member_refs = Vec::new();
+        for r in &self.inner.relations {
+            common.add(r.id, &r.changetype, &r.info, &r.quadtree);
+            for m in &r.members {
+                member_types.push(element_type_code(&m.mem_type));
+                member_refs.push(m.mem_ref);
+            }
+            member_offsets.push(member_refs.len() as i64);
+        }
+        let mut cols = common.into_columns();
+        cols.push(("member_offsets", ColumnData::Int64(member_offsets)));
+        cols.push(("member_types", ColumnData::UInt8(member_types)));
+        cols.push(("member_refs", ColumnData::Int64(member_refs)));
+        make_columns(py, cols)
+    }
+    
     pub fn node_at(&self, which: i64) -> PyResult<Node> {
         
         Node::as_view(self.inner.clone(), prep_which(&self.inner.nodes, which)?)
@@ -573,7 +631,7 @@ fn elementtype_from_str(et: &str) -> PyResult<osmquadtree::elements::ElementType
     }
 }
 
-fn changetype_str(e: &osmquadtree::elements::Changetype) -> String {
+pub(crate) fn changetype_str(e: &osmquadtree::elements::Changetype) -> String {
     match e {
         osmquadtree::elements::Changetype::Normal => String::from("normal"),
         osmquadtree::elements::Changetype::Delete => String::from("delete"),
@@ -697,7 +755,7 @@ pub fn read_primitive_block<'py>(index: i64, location: u64, data: &Bound<'py, Py
     
     match osmquadtree::elements::PrimitiveBlock::read(index, location, data.as_bytes(), ischange,minimal) {
         Ok(bl) => Ok(PrimitiveBlock{inner: Arc::new(bl)}),
-        Err(e) => Err(PyErr::from(ErrorWrapped{e:e}))
+        Err(e) => Err(PyErr::from(ErrorWrapped::new(e).with_position(location)))
     }
 }
 #[pyfunction]
@@ -705,7 +763,7 @@ pub fn read_minimal_block<'py>(index: i64, location: u64, data: &Bound<'py, PyBy
     
     match osmquadtree::elements::MinimalBlock::read(index, location, data.as_bytes(), ischange) {
         Ok(bl) => Ok(MinimalBlock{inner: Box::new(bl)}),
-        Err(e) => Err(PyErr::from(ErrorWrapped{e:e}))
+        Err(e) => Err(PyErr::from(ErrorWrapped::new(e).with_position(location)))
     }
     
 }
@@ -743,6 +801,58 @@ impl MinimalBlock {
     pub fn num_ways(&self) -> PyResult<i64> { Ok(self.inner.ways.len() as i64) }
     pub fn num_relations(&self) -> PyResult<i64> { Ok(self.inner.relations.len() as i64) }
     
+    /// As PrimitiveBlock.node_columns, without changeset and user_id.
+    pub fn node_columns(&self, py: Python) -> PyResult<PyObject> {
+        let mut common = CommonColumns::new(false);
+        let mut lon = Vec::with_capacity(self.inner.nodes.len());
+        let mut lat = Vec::with_capacity(self.inner.nodes.len());
+        for n in &self.inner.nodes {
+            common.add_minimal(n.id, &n.changetype, n.version, n.timestamp, &n.quadtree);
+            lon.push(n.lon);
+            lat.push(n.lat);
+        }
+        let mut cols = common.into_columns();
+        cols.push(("lon", ColumnData::Int32(lon)));
+        cols.push(("lat", ColumnData::Int32(lat)));
+        make_columns(py, cols)
+    }
+    
+    pub fn way_columns(&self, py: Python) -> PyResult<PyObject> {
+        let mut common = CommonColumns::new(false);
+        let mut ref_offsets = vec![0];
+        let mut refs = Vec::new();
+        for w in &self.inner.ways {
+            common.add_minimal(w.id, &w.changetype, w.version, w.timestamp, &w.quadtree);
+            refs.extend(simple_protocolbuffers::DeltaPackedInt::new(&w.refs_data));
+            ref_offsets.push(refs.len() as i64);
+        }
+        let mut cols = common.into_columns();
+        cols.push(("ref_offsets", ColumnData::Int64(ref_offsets)));
+        cols.push(("refs", ColumnData::Int64(refs)));
+        make_columns(py, cols)
+    }
+    
+    pub fn relation_columns(&self, py: Python) -> PyResult<PyObject> {
+        let mut common = CommonColumns::new(false);
+        let mut member_offsets = vec![0];
+        let mut member_types = Vec::new();
+        let mut member_refs = Vec::new();
+        for r in &self.inner.relations {
+            common.add_minimal(r.id, &r.changetype, r.version, r.timestamp, &r.quadtree);
+            for (a,b) in simple_protocolbuffers::DeltaPackedInt::new(&r.refs_data).zip(
+                simple_protocolbuffers::PackedInt::new(&r.types_data)) {
+                member_refs.push(a);
+                member_types.push(b as u8);
+            }
+            member_offsets.push(member_refs.len() as i64);
+        }
+        let mut cols = common.into_columns();
+        cols.push(("member_offsets", ColumnData::Int64(member_offsets)));
+        cols.push(("member_types", ColumnData::UInt8(member_types)));
+        cols.push(("member_refs", ColumnData::Int64(member_refs)));
+        make_columns(py, cols)
+    }
+    
     pub fn node_at(&self, py: Python, which: i64) -> PyResult<PyObject> {
         
         prep_minimal_node_tuple(py, &self.inner.nodes[prep_which(&self.inner.nodes, which)?])
@@ -1030,7 +1140,7 @@ pub fn apply_change_primitive(left: &PrimitiveBlock, right: &PrimitiveBlock) ->
 pub fn parse_timestamp(ts: &str) -> PyResult<i64> {
     match osmquadtree::utils::parse_timestamp(ts) {
         Ok(x) => Ok(x),
-        Err(e) => Err(PyErr::from(ErrorWrapped{e:e}))
+        Err(e) => Err(crate::errors::timestamp_error(format!("{}: {}", ts, e)))
     }
 }
 
@@ -0,0 +1,153 @@
+use pyo3::prelude::*;
+use pyo3::sync::GILOnceCell;
+use pyo3::types::{PyDict,PyTuple,PyType};
+use pyo3::exceptions::{PyException,PyOSError,PyValueError};
+
+//OsmQuadtreeError is a mixin, so that errors can also derive from the
+//builtin python class for their kind: I/O and decode errors are OSErrors,
+//as everything was before this hierarchy was added, and bad arguments or
+//input are ValueErrors. create_exception only allows one base, so the
+//classes are made by calling type
+struct ErrorTypes {
+    base: Py<PyType>,
+    io: Py<PyType>,
+    missing_file: Py<PyType>,
+    pbf_decode: Py<PyType>,
+    timestamp_parse: Py<PyType>,
+    style: Py<PyType>,
+    filter: Py<PyType>,
+    invalid_input: Py<PyType>,
+    geometry: Py<PyType>
+}
+
+static ERROR_TYPES: GILOnceCell<ErrorTypes> = GILOnceCell::new();
+
+fn new_type(py: Python, name: &str, doc: &str, bases: &[&Py<PyType>]) -> PyResult<Py<PyType>> {
+    let dict = PyDict::new(py);
+    dict.set_item("__doc__", doc)?;
+    dict.set_item("__module__", "rust")?;
+    let bases = PyTuple::new(py, bases.iter().map(|b| b.bind(py)))?;
+    Ok(py.get_type::<PyType>().call1((name, bases, dict))?.downcast_into::<PyType>()?.unbind())
+}
+
+impl ErrorTypes {
+    fn new(py: Python) -> PyResult<ErrorTypes> {
+        let os_error = py.get_type::<PyOSError>().unbind();
+        let value_error = py.get_type::<PyValueError>().unbind();
+        let base = new_type(py, "OsmQuadtreeError", "Base class for all errors raised by osmquadtree", &[&py.get_type::<PyException>().unbind()])?;
+        let io = new_type(py, "OsmIoError", "Error reading or writing a file", &[&base, &os_error])?;
+        let missing_file = new_type(py, "MissingFileError", "Input file or prefix does not exist", &[&io])?;
+        let pbf_decode = new_type(py, "PbfDecodeError", "Corrupt or unexpected protobuf data", &[&base, &os_error])?;
+        let timestamp_parse = new_type(py, "TimestampParseError", "Timestamp string not understood", &[&base, &value_error])?;
+        let style = new_type(py, "StyleError", "Invalid GeometryStyle or minzoom spec", &[&base, &value_error])?;
+        let filter = new_type(py, "FilterError", "Invalid bbox, poly or id filter", &[&base, &value_error])?;
+        let invalid_input = new_type(py, "InvalidInputError", "Invalid argument or selection", &[&base, &value_error])?;
+        let geometry = new_type(py, "GeometryError", "Geometry can't be assembled from the given elements", &[&base, &value_error])?;
+        Ok(ErrorTypes{base, io, missing_file, pbf_decode, timestamp_parse, style, filter, invalid_input, geometry})
+    }
+}
+
+fn error_types(py: Python) -> PyResult<&ErrorTypes> {
+    ERROR_TYPES.get_or_try_init(py, || ErrorTypes::new(py))
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum ErrorKind {
+    Other,
+    Io,
+    MissingFile,
+    PbfDecode,
+    TimestampParse,
+    Style,
+    Filter,
+    InvalidInput,
+    Geometry
+}
+
+pub fn error_kind(e: &osmquadtree::Error) -> ErrorKind {
+    use osmquadtree::Error;
+    match e {
+        Error::Io(ioe) if ioe.kind() == std::io::ErrorKind::NotFound => ErrorKind::MissingFile,
+        Error::Io(_) => ErrorKind::Io,
+        Error::PbfDataError(_) | Error::ProtobufError(_) | Error::XmlDataError(_) => ErrorKind::PbfDecode,
+        Error::TimestampFormatError(_) => ErrorKind::TimestampParse,
+        Error::UserSelectionError(_) | Error::InvalidInputError(_) => ErrorKind::InvalidInput,
+        _ => ErrorKind::Other
+    }
+}
+
+/// Creates the python exception for `kind`, with `filename`, `position`
+/// and `element_id` attributes (set to None where not known).
+pub fn make_error(kind: ErrorKind, msg: String, filename: Option<String>, position: Option<u64>, element_id: Option<i64>) -> PyErr {
+    Python::with_gil(|py| {
+        let tt = match error_types(py) {
+            Ok(tt) => tt,
+            Err(e) => { return e; }
+        };
+        let ty = match kind {
+            ErrorKind::Other => &tt.base,
+            ErrorKind::Io => &tt.io,
+            ErrorKind::MissingFile => &tt.missing_file,
+            ErrorKind::PbfDecode => &tt.pbf_decode,
+            ErrorKind::TimestampParse => &tt.timestamp_parse,
+            ErrorKind::Style => &tt.style,
+            ErrorKind::Filter => &tt.filter,
+            ErrorKind::InvalidInput => &tt.invalid_input,
+            ErrorKind::Geometry => &tt.geometry,
+        };
+        let err = PyErr::from_type(ty.bind(py).clone(), msg);
+
+        let v = err.value(py);
+        let _ = v.setattr("filename", filename);
+        let _ = v.setattr("position", position);
+        let _ = v.setattr("element_id", element_id);
+        err
+    })
+}
+
+pub fn filter_error(msg: String) -> PyErr {
+    make_error(ErrorKind::Filter, msg, None, None, None)
+}
+
+pub fn style_error(msg: String) -> PyErr {
+    make_error(ErrorKind::Style, msg, None, None, None)
+}
+
+pub fn timestamp_error(msg: String) -> PyErr {
+    make_error(ErrorKind::TimestampParse, msg, None, None, None)
+}
+
+pub fn invalid_input_error(msg: String) -> PyErr {
+    make_error(ErrorKind::InvalidInput, msg, None, None, None)
+}
+
+pub fn geometry_error(msg: String, element_id: i64) -> PyErr {
+    make_error(ErrorKind::Geometry, msg, None, None, Some(element_id))
+}
+
+pub fn missing_file_error(msg: String, fname: &str) -> PyErr {
+    make_error(ErrorKind::MissingFile, msg, Some(String::from(fname)), None, None)
+}
+
+pub fn check_exists(fname: &str) -> PyResult<()> {
+    if std::path::Path::new(fname).exists() {
+        Ok(())
+    } else {
+        Err(missing_file_error(format!("{} does not exist", fname), fname))
+    }
+}
+
+
+pub(crate) fn wrap_errors(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    let tt = error_types(m.py())?;
+    m.add("OsmQuadtreeError", &tt.base)?;
+    m.add("OsmIoError", &tt.io)?;
+    m.add("MissingFileError", &tt.missing_file)?;
+    m.add("PbfDecodeError", &tt.pbf_decode)?;
+    m.add("TimestampParseError", &tt.timestamp_parse)?;
+    m.add("StyleError", &tt.style)?;
+    m.add("FilterError", &tt.filter)?;
+    m.add("InvalidInputError", &tt.invalid_input)?;
+    m.add("GeometryError", &tt.geometry)?;
+    Ok(())
+}
@@ -0,0 +1,512 @@
+use pyo3::prelude::*;
+use pyo3::wrap_pyfunction;
+use std::collections::{BTreeMap,BTreeSet};
+use std::sync::{Arc,Mutex};
+
+use channelled_callbacks::{CallFinish,Timings, Result as ccResult};
+use osmquadtree::elements::{Bbox,ElementType,IdSetSet,PrimitiveBlock,Quadtree};
+use osmquadtree::pbfformat::ParallelFileLocs;
+use osmquadtree::utils::Error;
+
+use crate::ErrorWrapped;
+use crate::dataset::{QueryArea,QUADTREE_BUFFER,overlaps};
+use crate::readpbf::{ErrorSlot,has_error,set_error,take_error};
+
+
+#[derive(Clone,Copy,PartialEq)]
+enum Strategy {
+    Simple,
+    CompleteWays,
+    Smart
+}
+
+impl Strategy {
+    fn from_str(s: &str) -> PyResult<Strategy> {
+        match s {
+            "simple" => Ok(Strategy::Simple),
+            "complete_ways" => Ok(Strategy::CompleteWays),
+            "smart" => Ok(Strategy::Smart),
+            _ => Err(crate::errors::invalid_input_error(format!("unknown strategy {}: expected simple, complete_ways or smart", s)))
+        }
+    }
+}
+
+//elements in files without quadtrees may be anywhere
+fn quadtree_box(q: &Quadtree) -> Bbox {
+    if q.as_int() < 0 {
+        Bbox::planet()
+    } else {
+        q.as_bbox(QUADTREE_BUFFER)
+    }
+}
+
+//the blocks are decoded on several threads, so may arrive out of order
+struct InOrder {
+    pending: BTreeMap<i64, PrimitiveBlock>,
+    next: i64
+}
+
+impl InOrder {
+    fn new() -> InOrder {
+        InOrder{pending: BTreeMap::new(), next: 0}
+    }
+
+    fn push(&mut self, bl: PrimitiveBlock) -> Vec<PrimitiveBlock> {
+        self.pending.insert(bl.index, bl);
+        let mut res = Vec::new();
+        while let Some(bl) = self.pending.remove(&self.next) {
+            res.push(bl);
+            self.next += 1;
+        }
+        res
+    }
+
+    fn rest(&mut self) -> Vec<PrimitiveBlock> {
+        std::mem::take(&mut self.pending).into_iter().map(|(_, bl)| bl).collect()
+    }
+}
+
+struct StoredWay {
+    areas: Vec<usize>,
+    quadtree: i64,
+    refs: Vec<i64>
+}
+
+struct StoredRelation {
+    id: i64,
+    areas: Vec<usize>,
+    quadtree: i64,
+    multipolygon: bool,
+    members: Vec<(ElementType, i64)>
+}
+
+//the elements of each extract, the member ways of the multipolygons each
+//selects (for smart), and the quadtrees of the ways and multipolygons
+//whose nodes may be in blocks not overlapping any extract
+struct Selected {
+    ids: Vec<IdSetSet>,
+    multipolygon_ways: Vec<BTreeSet<i64>>,
+    quadtrees: BTreeSet<i64>
+}
+
+struct Collected {
+    inside: Vec<BTreeSet<i64>>,
+    ways: BTreeMap<i64, StoredWay>,
+    relations: Vec<StoredRelation>
+}
+
+impl Collected {
+    fn new(n: usize) -> Collected {
+        Collected{inside: vec![BTreeSet::new(); n], ways: BTreeMap::new(), relations: Vec::new()}
+    }
+
+    //ways and relations can't be selected until every block has been read
+    fn select(&self, strategy: Strategy) -> Selected {
+        let mut sel = Selected{ids: Vec::new(), multipolygon_ways: Vec::new(), quadtrees: BTreeSet::new()};
+        for (i, inside) in self.inside.iter().enumerate() {
+            let mut ids = IdSetSet::new();
+            for (id, w) in &self.ways {
+                if !w.areas.contains(&i) || !w.refs.iter().any(|n| inside.contains(n)) {
+                    continue;
+                }
+                ids.ways.insert(*id);
+                if strategy != Strategy::Simple {
+                    ids.nodes.extend(w.refs.iter().cloned());
+                    //ways crossing the edge of the area have nodes outside it
+                    if !w.refs.iter().all(|n| inside.contains(n)) {
+                        sel.quadtrees.insert(w.quadtree);
+                    }
+                }
+            }
+
+            let relations: Vec<&StoredRelation> = self.relations.iter().filter(|r| r.areas.contains(&i)).collect();
+            //repeat to pick up relations whose members are selected relations
+            loop {
+                let mut changed = false;
+                for r in &relations {
+                    if ids.relations.contains(&r.id) {
+                        continue;
+                    }
+                    if r.members.iter().any(|(t, m)| match t {
+                        ElementType::Node => inside.contains(m),
+                        ElementType::Way => ids.ways.contains(m),
+                        ElementType::Relation => ids.relations.contains(m),
+                        _ => false
+                    }) {
+                        ids.relations.insert(r.id);
+                        changed = true;
+                    }
+                }
+                if !changed {
+                    break;
+                }
+            }
+
+            let mut multipolygon_ways = BTreeSet::new();
+            if strategy == Strategy::Smart {
+                for r in &relations {
+                    if !r.multipolygon || !ids.relations.contains(&r.id) {
+                        continue;
+                    }
+                    sel.quadtrees.insert(r.quadtree);
+                    for (t, m) in &r.members {
+                        if let ElementType::Way = t {
+                            multipolygon_ways.insert(*m);
+                        }
+                    }
+                }
+            }
+            ids.nodes.extend(inside.iter().cloned());
+            sel.ids.push(ids);
+            sel.multipolygon_ways.push(multipolygon_ways);
+        }
+        sel
+    }
+}
+
+//the first pass: the nodes in each area, and the ways and relations which
+//may use them. in quadtree sorted data a way's nodes are in the way's block
+//or in later blocks, with quadtrees inside the way's, and likewise for the
+//members of relations, so these are kept and only selected once every
+//block has been read
+struct CollectExtracts {
+    areas: Arc<Vec<QueryArea>>,
+    strategy: Strategy,
+    order: InOrder,
+    collected: Collected,
+    result: Arc<Mutex<Option<Collected>>>
+}
+
+impl CollectExtracts {
+    fn collect(&mut self, bl: &PrimitiveBlock) {
+        let tile = quadtree_box(&bl.quadtree);
+        let areas: Vec<usize> = (0..self.areas.len()).filter(|i| self.areas[*i].overlaps_box(&tile)).collect();
+        if areas.is_empty() {
+            return;
+        }
+        let all_areas = &self.areas;
+        let overlapping = |q: &Quadtree| -> Vec<usize> {
+            let b = quadtree_box(q);
+            areas.iter().cloned().filter(|i| all_areas[*i].overlaps_box(&b)).collect()
+        };
+
+        for r in &bl.relations {
+            let rareas = overlapping(&r.quadtree);
+            if rareas.is_empty() {
+                continue;
+            }
+            let multipolygon = self.strategy == Strategy::Smart && r.tags.iter().any(|t| t.key == "type" && (t.val == "multipolygon" || t.val == "boundary"));
+            let members = r.members.iter().filter_map(|m| match m.mem_type {
+                ElementType::Node => Some((ElementType::Node, m.mem_ref)),
+                ElementType::Way => Some((ElementType::Way, m.mem_ref)),
+                ElementType::Relation => Some((ElementType::Relation, m.mem_ref)),
+                _ => None
+            }).collect();
+            self.collected.relations.push(StoredRelation{id: r.id, areas: rareas, quadtree: r.quadtree.as_int(), multipolygon: multipolygon, members: members});
+        }
+
+        for w in &bl.ways {
+            let wareas = overlapping(&w.quadtree);
+            if !wareas.is_empty() {
+                self.collected.ways.insert(w.id, StoredWay{areas: wareas, quadtree: w.quadtree.as_int(), refs: w.refs.clone()});
+            }
+        }
+
+        for n in &bl.nodes {
+            for i in &areas {
+                if self.areas[*i].contains_point(n.lon, n.lat) {
+                    self.collected.inside[*i].insert(n.id);
+                }
+            }
+        }
+    }
+}
+
+impl CallFinish for CollectExtracts {
+    type CallType = PrimitiveBlock;
+    type ReturnType = Timings<usize>;
+    type ErrorType = Error;
+
+    fn call(&mut self, bl: PrimitiveBlock) {
+        for b in self.order.push(bl) {
+            self.collect(&b);
+        }
+    }
+
+    fn finish(&mut self) -> ccResult<Self::ReturnType, Error> {
+        for b in self.order.rest() {
+            self.collect(&b);
+        }
+        let collected = std::mem::replace(&mut self.collected, Collected::new(0));
+        let mut tm = Timings::new();
+        tm.add_other("CollectExtracts", collected.inside.iter().map(|s| s.len()).sum());
+        *self.result.lock().unwrap() = Some(collected);
+        Ok(tm)
+    }
+}
+
+//for smart, the nodes of multipolygon member ways which weren't in the
+//blocks overlapping the extracts
+struct CollectWayRefs {
+    wanted: BTreeSet<i64>,
+    found: BTreeMap<i64, Vec<i64>>,
+    result: Arc<Mutex<BTreeMap<i64, Vec<i64>>>>
+}
+
+impl CallFinish for CollectWayRefs {
+    type CallType = PrimitiveBlock;
+    type ReturnType = Timings<usize>;
+    type ErrorType = Error;
+
+    fn call(&mut self, bl: PrimitiveBlock) {
+        for w in bl.ways {
+            if self.wanted.contains(&w.id) {
+                self.found.insert(w.id, w.refs);
+            }
+        }
+    }
+
+    fn finish(&mut self) -> ccResult<Self::ReturnType, Error> {
+        let found = std::mem::take(&mut self.found);
+        let mut tm = Timings::new();
+        tm.add_other("CollectWayRefs", found.len());
+        *self.result.lock().unwrap() = found;
+        Ok(tm)
+    }
+}
+
+const SORTED_BLOCK_SIZE: usize = 8000;
+
+//sorted output has no quadtrees: the blocks are given the root quadtree
+fn write_sorted_chunks<T, F: Fn(&mut PrimitiveBlock, Vec<T>)>(items: Vec<T>, w: &mut crate::writepbf::PbfWriter, index: &mut i64, set: F) -> PyResult<()> {
+    let mut iter = items.into_iter().peekable();
+    while iter.peek().is_some() {
+        let mut bl = PrimitiveBlock::new(*index, 0);
+        bl.quadtree = Quadtree::new(0);
+        set(&mut bl, iter.by_ref().take(SORTED_BLOCK_SIZE).collect());
+        w.write_primitive_block(&bl)?;
+        *index += 1;
+    }
+    Ok(())
+}
+
+fn write_sorted(mut all: PrimitiveBlock, w: &mut crate::writepbf::PbfWriter) -> PyResult<()> {
+    all.nodes.sort_by_key(|n| n.id);
+    all.ways.sort_by_key(|x| x.id);
+    all.relations.sort_by_key(|r| r.id);
+    w.set_sorted();
+    let mut index = 0;
+    write_sorted_chunks(all.nodes, w, &mut index, |bl, v| bl.nodes = v)?;
+    write_sorted_chunks(all.ways, w, &mut index, |bl, v| bl.ways = v)?;
+    write_sorted_chunks(all.relations, w, &mut index, |bl, v| bl.relations = v)
+}
+
+//second pass: split each block between the extracts. if the extracts are
+//sorted, their elements are kept in `sorted` and written at the end
+struct WriteExtracts {
+    ids: Vec<IdSetSet>,
+    writers: Vec<crate::writepbf::PbfWriter>,
+    sorted: Option<Vec<PrimitiveBlock>>,
+    order: InOrder,
+    error: ErrorSlot
+}
+
+impl WriteExtracts {
+    fn write(&mut self, bl: &PrimitiveBlock) {
+        for (i, (ids, w)) in self.ids.iter().zip(self.writers.iter_mut()).enumerate() {
+            let mut out = PrimitiveBlock::new(bl.index, bl.location);
+            out.quadtree = bl.quadtree.clone();
+            out.nodes.extend(bl.nodes.iter().filter(|n| ids.nodes.contains(&n.id)).cloned());
+            out.ways.extend(bl.ways.iter().filter(|x| ids.ways.contains(&x.id)).cloned());
+            out.relations.extend(bl.relations.iter().filter(|r| ids.relations.contains(&r.id)).cloned());
+            if out.nodes.is_empty() && out.ways.is_empty() && out.relations.is_empty() {
+                continue;
+            }
+            if let Some(sorted) = self.sorted.as_mut() {
+                sorted[i].nodes.extend(out.nodes);
+                sorted[i].ways.extend(out.ways);
+                sorted[i].relations.extend(out.relations);
+                continue;
+            }
+            if let Err(e) = w.write_primitive_block(&out) {
+                set_error(&self.error, e);
+                return;
+            }
+        }
+    }
+
+    fn finish_files(&mut self) -> PyResult<()> {
+        if let Some(sorted) = self.sorted.take() {
+            for (all, w) in sorted.into_iter().zip(self.writers.iter_mut()) {
+                write_sorted(all, w)?;
+            }
+        }
+        for w in self.writers.iter_mut() {
+            w.finish_file()?;
+        }
+        Ok(())
+    }
+}
+
+impl CallFinish for WriteExtracts {
+    type CallType = PrimitiveBlock;
+    type ReturnType = Timings<usize>;
+    type ErrorType = Error;
+
+    fn call(&mut self, bl: PrimitiveBlock) {
+        if has_error(&self.error) {
+            return;
+        }
+        for b in self.order.push(bl) {
+            self.write(&b);
+        }
+    }
+
+    fn finish(&mut self) -> ccResult<Self::ReturnType, Error> {
+        for b in self.order.rest() {
+            if has_error(&self.error) {
+                break;
+            }
+            self.write(&b);
+        }
+        let mut tm = Timings::new();
+        if !has_error(&self.error) {
+            if let Err(e) = self.finish_files() {
+                set_error(&self.error, e);
+            }
+            tm.add_other("WriteExtracts", self.writers.len());
+        }
+        Ok(tm)
+    }
+}
+
+//the blocks of `pfilelocs` whose quadtrees overlap any of `boxes`
+fn blocks_overlapping(pfilelocs: &ParallelFileLocs, boxes: &[Bbox]) -> BTreeSet<usize> {
+    pfilelocs.1.iter().enumerate()
+        .filter(|(_, (q, _))| { let qb = quadtree_box(q); boxes.iter().any(|b| overlaps(b, &qb)) })
+        .map(|(i, _)| i)
+        .collect()
+}
+
+//reads just blocks `idxs` of `pfilelocs` into `co`
+fn read_blocks(pfilelocs: &mut ParallelFileLocs, idxs: &BTreeSet<usize>,
+    co: Box<dyn CallFinish<CallType = PrimitiveBlock, ReturnType = Timings<usize>, ErrorType = Error>>, numchan: usize) -> PyResult<Timings<usize>> {
+
+    let locs = idxs.iter().map(|i| pfilelocs.1[*i].clone()).collect();
+    let all_locs = std::mem::replace(&mut pfilelocs.1, locs);
+    let res = crate::readpbf::read_primitive_blocks(pfilelocs, co, Arc::new(osmquadtree::elements::IdSetAll()), numchan);
+    pfilelocs.1 = all_locs;
+    res
+}
+
+/// Writes an extract of `prfx` for each (bbox or Poly, output filename)
+/// pair in `extracts`. The elements of all extracts are selected in one
+/// read of the blocks overlapping them, and written in a second, which also
+/// reads the blocks holding the outside nodes of the ways crossing their
+/// edges. `strategy` is one of "simple" (the nodes in each area, the ways
+/// with any of these nodes and the relations with any of these members),
+/// "complete_ways" (as simple, with all the nodes of each way) or "smart"
+/// (as complete_ways, with all the member ways of multipolygon and boundary
+/// relations, which may need one more read of the blocks these relations
+/// cover). If `sort` is true each extract is sorted by element type and id,
+/// in memory. Returns the number of nodes, ways and relations in each
+/// extract.
+#[pyfunction]
+#[pyo3(signature = (prfx, extracts, strategy="complete_ways", sort=false, compression_type=(String::from("ZlibLevel"),6), timestamp=None, numchan=crate::readpbf::default_numchan()))]
+pub fn extract_many(py: Python, prfx: &str, extracts: Vec<(PyObject, String)>, strategy: &str, sort: bool, compression_type: (String, u32), timestamp: Option<&str>, numchan: usize) -> PyResult<Vec<(String, usize, usize, usize)>> {
+    let strategy = Strategy::from_str(strategy)?;
+    crate::compression::Compression::from_tuple((&compression_type.0, compression_type.1))?;
+    if extracts.is_empty() {
+        return Err(crate::errors::invalid_input_error(String::from("no extracts given")));
+    }
+    let outfns: Vec<String> = extracts.iter().map(|(_, o)| o.clone()).collect();
+    if outfns.iter().collect::<BTreeSet<_>>().len() != outfns.len() {
+        return Err(crate::errors::invalid_input_error(String::from("extracts must have different output filenames")));
+    }
+
+    let mut areas = Vec::new();
+    for (filter, _) in extracts {
+        let (_, bbox, poly) = crate::readpbf::read_filter(py, Some(filter))?;
+        areas.push(QueryArea{bbox: bbox, poly: poly});
+    }
+    let areas = Arc::new(areas);
+
+    let ts = match timestamp {
+        Some(t) => Some(osmquadtree::utils::parse_timestamp(t).map_err(|e| crate::errors::timestamp_error(format!("{}: {}", t, e)))?),
+        None => None
+    };
+
+    //only the index is read here: which blocks are needed depends on the
+    //ways and relations selected
+    let mut pfilelocs = osmquadtree::pbfformat::get_file_locs(prfx, Some(Bbox::planet()), ts)
+        .map_err(|e| ErrorWrapped::from(e).with_filename(prfx))?;
+    let area_idxs: BTreeSet<usize> = pfilelocs.1.iter().enumerate()
+        .filter(|(_, (q, _))| { let qb = quadtree_box(q); areas.iter().any(|a| a.overlaps_box(&qb)) })
+        .map(|(i, _)| i)
+        .collect();
+
+    crate::cancel::run_interruptible(py, &outfns, || {
+        let result = Arc::new(Mutex::new(None));
+        let co = Box::new(CollectExtracts{areas: areas.clone(), strategy: strategy, order: InOrder::new(),
+            collected: Collected::new(areas.len()), result: result.clone()});
+        read_blocks(&mut pfilelocs, &area_idxs, co, numchan)?;
+        crate::cancel::check_cancelled()?;
+
+        let collected = result.lock().unwrap().take()
+            .ok_or_else(|| pyo3::exceptions::PyRuntimeError::new_err("selecting extracts failed"))?;
+        let Selected{mut ids, multipolygon_ways, quadtrees} = collected.select(strategy);
+        let boxes: Vec<Bbox> = quadtrees.iter().map(|q| quadtree_box(&Quadtree::new(*q))).collect();
+        let needed_idxs = blocks_overlapping(&pfilelocs, &boxes);
+
+        //member ways of multipolygons which don't overlap the extracts are
+        //within the relations' quadtrees
+        let missing: BTreeSet<i64> = multipolygon_ways.iter().flatten().filter(|w| !collected.ways.contains_key(w)).cloned().collect();
+        let member_refs = Arc::new(Mutex::new(BTreeMap::new()));
+        if !missing.is_empty() {
+            let co = Box::new(CollectWayRefs{wanted: missing, found: BTreeMap::new(), result: member_refs.clone()});
+            read_blocks(&mut pfilelocs, &needed_idxs, co, numchan)?;
+            crate::cancel::check_cancelled()?;
+        }
+        let member_refs = std::mem::take(&mut *member_refs.lock().unwrap());
+        for (ids, ways) in ids.iter_mut().zip(multipolygon_ways.iter()) {
+            for w in ways {
+                if let Some(refs) = collected.ways.get(w).map(|s| &s.refs).or_else(|| member_refs.get(w)) {
+                    ids.ways.insert(*w);
+                    ids.nodes.extend(refs.iter().cloned());
+                }
+            }
+        }
+        drop(collected);
+
+        let counts: Vec<(String, usize, usize, usize)> = outfns.iter().zip(ids.iter())
+            .map(|(o, i)| (o.clone(), i.nodes.len(), i.ways.len(), i.relations.len()))
+            .collect();
+
+        let mut writers = Vec::new();
+        for (o, a) in outfns.iter().zip(areas.iter()) {
+            let b = &a.bbox;
+            let bbox = Some((b.minlon as i64, b.minlat as i64, b.maxlon as i64, b.maxlat as i64));
+            writers.push(crate::writepbf::PbfWriter::new(o, bbox, None, None, None, None, None, compression_type.clone(), false)?);
+        }
+        let sorted = if sort {
+            Some((0..writers.len()).map(|_| PrimitiveBlock::new(0, 0)).collect())
+        } else {
+            None
+        };
+
+        let error: ErrorSlot = Arc::new(Mutex::new(None));
+        let co = Box::new(WriteExtracts{ids: ids, writers: writers, sorted: sorted, order: InOrder::new(), error: error.clone()});
+        let write_idxs = area_idxs.union(&needed_idxs).cloned().collect();
+        let tm = read_blocks(&mut pfilelocs, &write_idxs, co, numchan);
+        take_error(&error)?;
+        tm?;
+        crate::cancel::check_cancelled()?;
+        Ok(counts)
+    })
+}
+
+
+pub(crate) fn wrap_extract(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_wrapped(wrap_pyfunction!(extract_many))?;
+    Ok(())
+}
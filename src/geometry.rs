@@ -3,14 +3,20 @@
 use osmquadtree_geometry::{GeoJsonable,WithBounds};
 
 use crate::elements::{Quadtree,prep_which,prep_tags};//,prep_info};
+use crate::columns::{ColumnData,CommonColumns,make_columns};
 //use crate::readpbf::ReadFileBlocksParallel;
 
 use pyo3::prelude::*;
 use pyo3::wrap_pyfunction;
 use pyo3::exceptions::*;
 use pyo3::types::PyBytes;
-use std::sync::Arc;
+use std::sync::{Arc,Mutex};
+use std::sync::atomic::{AtomicBool,Ordering};
+use std::sync::mpsc::{sync_channel,SyncSender,Receiver,RecvTimeoutError};
 use std::collections::BTreeMap;
+use channelled_callbacks::{CallFinish,Timings,Result as ccResult};
+use crate::readpbf::{CollectBlocksCall,ErrorSlot,take_error};
+use osmquadtree::utils::Error;
 
 #[pyclass]
 pub struct GeometryBlock {
@@ -92,6 +98,88 @@ impl GeometryBlock {
     pub fn as_geojson(&self, py: Python, transform: bool) -> PyResult<PyObject> {
         Ok(wrap_json(py, &self.inner.to_geojson(transform)?))
     }
+    
+    /// Point columns as a dict of Column: id, changetype, version,
+    /// timestamp, changeset, user_id, quadtree, minzoom and layer (-1 if
+    /// not set), lon and lat.
+    pub fn point_columns(&self, py: Python) -> PyResult<PyObject> {
+        let mut common = CommonColumns::new(true);
+        let mut extra = ExtraColumns::new();
+        let mut coords = CoordColumns::new();
+        for p in &self.inner.points {
+            common.add(p.id, &osmquadtree::elements::Changetype::Normal, &p.info, &p.quadtree);
+            extra.add(p.minzoom, p.layer, None, None);
+            coords.add(&p.lonlat);
+        }
+        let mut cols = common.into_columns();
+        cols.extend(extra.into_columns(false, None));
+        cols.push(("lon", ColumnData::Int32(coords.lon)));
+        cols.push(("lat", ColumnData::Int32(coords.lat)));
+        make_columns(py, cols)
+    }
+    
+    /// As point_columns, with z_order and length, and the coordinates of
+    /// linestring i given by `lon[coord_offsets[i]:coord_offsets[i+1]]`.
+    pub fn linestring_columns(&self, py: Python) -> PyResult<PyObject> {
+        let mut common = CommonColumns::new(true);
+        let mut extra = ExtraColumns::new();
+        let mut coords = CoordColumns::new();
+        for l in &self.inner.linestrings {
+            common.add(l.id, &osmquadtree::elements::Changetype::Normal, &l.info, &l.quadtree);
+            extra.add(l.minzoom, l.layer, l.z_order, Some(l.length));
+            coords.add_ring(l.lonlats.iter());
+        }
+        let mut cols = common.into_columns();
+        cols.extend(extra.into_columns(true, Some("length")));
+        cols.extend(coords.into_columns());
+        make_columns(py, cols)
+    }
+    
+    /// As linestring_columns, with area in place of length.
+    pub fn simple_polygon_columns(&self, py: Python) -> PyResult<PyObject> {
+        let mut common = CommonColumns::new(true);
+        let mut extra = ExtraColumns::new();
+        let mut coords = CoordColumns::new();
+        for p in &self.inner.simple_polygons {
+            common.add(p.id, &osmquadtree::elements::Changetype::Normal, &p.info, &p.quadtree);
+            extra.add(p.minzoom, p.layer, p.z_order, Some(p.area));
+            coords.add_ring(p.lonlats.iter());
+        }
+        let mut cols = common.into_columns();
+        cols.extend(extra.into_columns(true, Some("area")));
+        cols.extend(coords.into_columns());
+        make_columns(py, cols)
+    }
+    
+    /// As simple_polygon_columns, where polygon i has parts
+    /// `part_offsets[i]:part_offsets[i+1]`, part j has rings
+    /// `ring_offsets[j]:ring_offsets[j+1]` (the exterior ring first) and ring
+    /// k has coordinates `coord_offsets[k]:coord_offsets[k+1]`.
+    pub fn complicated_polygon_columns(&self, py: Python) -> PyResult<PyObject> {
+        let mut common = CommonColumns::new(true);
+        let mut extra = ExtraColumns::new();
+        let mut coords = CoordColumns::new();
+        let mut part_offsets = vec![0];
+        let mut ring_offsets = vec![0];
+        for p in &self.inner.complicated_polygons {
+            common.add(p.id, &osmquadtree::elements::Changetype::Normal, &p.info, &p.quadtree);
+            extra.add(p.minzoom, p.layer, p.z_order, Some(p.area));
+            for pt in &p.parts {
+                coords.add_ring(pt.exterior.lonlats_iter());
+                for ii in &pt.interiors {
+                    coords.add_ring(ii.lonlats_iter());
+                }
+                ring_offsets.push((coords.coord_offsets.len() - 1) as i64);
+            }
+            part_offsets.push((ring_offsets.len() - 1) as i64);
+        }
+        let mut cols = common.into_columns();
+        cols.extend(extra.into_columns(true, Some("area")));
+        cols.push(("part_offsets", ColumnData::Int64(part_offsets)));
+        cols.push(("ring_offsets", ColumnData::Int64(ring_offsets)));
+        cols.extend(coords.into_columns());
+        make_columns(py, cols)
+    }
 
     fn __str__(&self) -> PyResult<String> {
         Ok(format!("{:?}", self.inner))
@@ -101,6 +189,69 @@ impl GeometryBlock {
     }
 }
 
+struct ExtraColumns {
+    minzoom: Vec<i64>,
+    layer: Vec<i64>,
+    z_order: Vec<i64>,
+    measure: Vec<f64>
+}
+
+impl ExtraColumns {
+    fn new() -> ExtraColumns {
+        ExtraColumns{minzoom: Vec::new(), layer: Vec::new(), z_order: Vec::new(), measure: Vec::new()}
+    }
+    
+    fn add(&mut self, minzoom: Option<i64>, layer: Option<i64>, z_order: Option<i64>, measure: Option<f64>) {
+        self.minzoom.push(minzoom.unwrap_or(-1));
+        self.layer.push(layer.unwrap_or(-1));
+        self.z_order.push(z_order.unwrap_or(-1));
+        self.measure.push(measure.unwrap_or(0.0));
+    }
+    
+    fn into_columns(self, with_z_order: bool, measure: Option<&'static str>) -> Vec<(&'static str, ColumnData)> {
+        let mut res = vec![("minzoom", ColumnData::Int64(self.minzoom)), ("layer", ColumnData::Int64(self.layer))];
+        if with_z_order {
+            res.push(("z_order", ColumnData::Int64(self.z_order)));
+        }
+        if let Some(m) = measure {
+            res.push((m, ColumnData::Float64(self.measure)));
+        }
+        res
+    }
+}
+
+struct CoordColumns {
+    coord_offsets: Vec<i64>,
+    lon: Vec<i32>,
+    lat: Vec<i32>
+}
+
+impl CoordColumns {
+    fn new() -> CoordColumns {
+        CoordColumns{coord_offsets: vec![0], lon: Vec::new(), lat: Vec::new()}
+    }
+    
+    fn add(&mut self, ll: &osmquadtree_geometry::LonLat) {
+        self.lon.push(ll.lon);
+        self.lat.push(ll.lat);
+    }
+    
+    fn add_ring<'a, T: Iterator<Item=&'a osmquadtree_geometry::LonLat>>(&mut self, ll: T) {
+        for l in ll {
+            self.add(l);
+        }
+        self.coord_offsets.push(self.lon.len() as i64);
+    }
+    
+    fn into_columns(self) -> Vec<(&'static str, ColumnData)> {
+        vec![
+            ("coord_offsets", ColumnData::Int64(self.coord_offsets)),
+            ("lon", ColumnData::Int32(self.lon)),
+            ("lat", ColumnData::Int32(self.lat))
+        ]
+    }
+}
+
 fn wrap_json(py: Python, v: &serde_json::Value) -> PyObject {
     
     match v {
@@ -706,7 +857,7 @@ impl ComplicatedPolygonGeometry {
 }
 
 
-fn prep_style(py: Python, style: Option<PyObject>) -> PyResult<Arc<osmquadtree_geometry::GeometryStyle>> {
+pub(crate) fn prep_style(py: Python, style: Option<PyObject>) -> PyResult<Arc<osmquadtree_geometry::GeometryStyle>> {
     match style {
         None => { return Ok(Arc::new(osmquadtree_geometry::GeometryStyle::default())); },
         Some(style_in) => {
@@ -720,7 +871,10 @@ fn prep_style(py: Python, style: Option<PyObject>) -> PyResult<Arc<osmquadtree_g
                 
                 if std::path::Path::new(&style_str).is_file() {
                 
-                    return Ok(Arc::new(osmquadtree_geometry::GeometryStyle::from_file(&style_str)?));
+                    return match osmquadtree_geometry::GeometryStyle::from_file(&style_str) {
+                        Ok(g) => Ok(Arc::new(g)),
+                        Err(e) => Err(crate::errors::style_error(format!("{}: {}", style_str, e)))
+                    };
                 } else {
                     
                     match osmquadtree_geometry::GeometryStyle::from_json(&style_str) {
@@ -733,10 +887,10 @@ fn prep_style(py: Python, style: Option<PyObject>) -> PyResult<Arc<osmquadtree_g
         }
     }
     
-    Err(PyRuntimeError::new_err("can't handle given style argument"))
+    Err(crate::errors::style_error(String::from("can't handle given style argument")))
 }
 
-fn prep_minzoom(py: Python, minzoom: Option<PyObject>) -> PyResult<Option<osmquadtree_geometry::MinZoomSpec>> {
+pub(crate) fn prep_minzoom(py: Python, minzoom: Option<PyObject>) -> PyResult<Option<osmquadtree_geometry::MinZoomSpec>> {
     
     match minzoom {
         None => Ok(None),
@@ -748,51 +902,146 @@ fn prep_minzoom(py: Python, minzoom: Option<PyObject>) -> PyResult<Option<osmqua
                 if &ss == "default" {
                     Ok(Some(osmquadtree_geometry::MinZoomSpec::default(5.0, mz)))
                 } else {
-                    Ok(Some(osmquadtree_geometry::MinZoomSpec::from_reader(5.0, mz, ss.as_bytes())?))
+                    match osmquadtree_geometry::MinZoomSpec::from_reader(5.0, mz, ss.as_bytes()) {
+                        Ok(m) => Ok(Some(m)),
+                        Err(e) => Err(crate::errors::style_error(format!("minzoom: {}", e)))
+                    }
                 }
             } else {
-                Err(PyRuntimeError::new_err("can't handle given minzoom argument"))
+                Err(crate::errors::style_error(String::from("can't handle given minzoom argument")))
             }
         }
     }
 }
 
-#[pyfunction]
-#[pyo3(signature = (prfx, filter=None, timestamp=None, minzoom_in=None, style_in=None, numchan=4))]
-fn process_geometry(py: Python,
+pub(crate) type GeometryCallback = Box<dyn CallFinish<CallType = osmquadtree_geometry::GeometryBlock, ReturnType = Timings<osmquadtree_geometry::OtherData>, ErrorType = Error>>;
+
+/// Opens the input files and prepares the style and minzoom spec, as used
+/// by process_geometry and the exports which run the same pipeline.
+pub(crate) fn prep_process_geometry(py: Python,
     prfx: &str,
     filter: Option<PyObject>,
     timestamp: Option<&str>,
     minzoom_in: Option<PyObject>,
-    style_in: Option<PyObject>,
-    numchan: usize,
-) -> PyResult<Option<Vec<GeometryBlock>>> {
+    style_in: Option<PyObject>
+) -> PyResult<(osmquadtree::pbfformat::ParallelFileLocs, Arc<osmquadtree_geometry::GeometryStyle>, Option<osmquadtree_geometry::MinZoomSpec>)> {
+    
+    //bad arguments are reported before the input is opened
+    let style = prep_style(py, style_in)?;
+    
+    let minzoom = prep_minzoom(py, minzoom_in)?;
     
     let (_isp, bbox, _poly) = crate::readpbf::read_filter(py, filter)?;
     let ts = match timestamp {
-            Some(t) => Some(osmquadtree::utils::parse_timestamp(t)?),
+            Some(t) => Some(osmquadtree::utils::parse_timestamp(t).map_err(|e| crate::errors::timestamp_error(format!("{}: {}", t, e)))?),
             None => None
         };
         
-    let mut pfilelocs = osmquadtree::pbfformat::get_file_locs(prfx, Some(bbox.clone()), ts)?;
+    let mut pfilelocs = osmquadtree::pbfformat::get_file_locs(prfx, Some(bbox.clone()), ts)
+        .map_err(|e| crate::ErrorWrapped::from(e).with_filename(prfx))?;
+    crate::compression::check_upstream_readable_locs(&mut pfilelocs, prfx, "process_geometry")?;
+    
+    Ok((pfilelocs, style, minzoom))
+}
+
+/// process_geometry_call doesn't return the callback's error, so the
+/// writers it runs (write_geoparquet, write_postgis and write_tiles) pass
+/// their outcome back through this.
+pub(crate) struct ResultSlot<T>(Arc<Mutex<Option<std::result::Result<T, String>>>>);
+
+impl<T> Clone for ResultSlot<T> {
+    fn clone(&self) -> ResultSlot<T> {
+        ResultSlot(self.0.clone())
+    }
+}
+
+impl<T> ResultSlot<T> {
+    pub(crate) fn new() -> ResultSlot<T> {
+        ResultSlot(Arc::new(Mutex::new(None)))
+    }
+
+    /// Stores the writer's outcome, returning `tm` to the pipeline or the
+    /// error.
+    pub(crate) fn finish(&self, res: std::result::Result<T, String>, tm: Timings<osmquadtree_geometry::OtherData>) -> ccResult<Timings<osmquadtree_geometry::OtherData>, Error> {
+        let err = res.as_ref().err().cloned();
+        *self.0.lock().unwrap_or_else(|e| e.into_inner()) = Some(res);
+        match err {
+            Some(e) => Err(Error::Io(std::io::Error::new(std::io::ErrorKind::Other, e))),
+            None => Ok(tm)
+        }
+    }
+
+    fn take(&self) -> std::result::Result<T, String> {
+        match self.0.lock().unwrap_or_else(|e| e.into_inner()).take() {
+            Some(r) => r,
+            None => Err(String::from("geometry pipeline did not finish"))
+        }
+    }
+}
+
+/// Runs the process_geometry pipeline with the writer `cb`, returning the
+/// outcome it stored in `result`. If the writer fails, or the call is
+/// interrupted, `outputs` are removed.
+pub(crate) fn run_geometry_writer<T: Send>(py: Python,
+    mut pfilelocs: osmquadtree::pbfformat::ParallelFileLocs,
+    cb: GeometryCallback,
+    result: &ResultSlot<T>,
+    style: Arc<osmquadtree_geometry::GeometryStyle>,
+    minzoom: Option<osmquadtree_geometry::MinZoomSpec>,
+    numchan: usize,
+    outputs: &[String]
+) -> PyResult<T> {
+
+    crate::cancel::run_interruptible(py, outputs, || Ok(osmquadtree_geometry::process_geometry_call(
+        &mut pfilelocs,
+        Some(crate::cancel::stop_when_cancelled(cb)),
+        style,
+        minzoom,
+        numchan)))?;
+
+    result.take().map_err(|e| {
+        for f in outputs {
+            let _ = std::fs::remove_file(f);
+        }
+        crate::ErrorWrapped::from(std::io::Error::new(std::io::ErrorKind::Other, e)).into()
+    })
+}
+
+/// Builds the geometries for `prfx`. If `tag_filter` (a TagFilter or filter
+/// expression) is given, only objects built from elements matching it are
+/// returned: points from selected nodes, linestrings and simple polygons
+/// from selected ways, and multipolygons from selected relations. The
+/// elements are tested on all their tags, with a first pass over the data.
+#[pyfunction]
+#[pyo3(signature = (prfx, filter=None, timestamp=None, minzoom_in=None, style_in=None, numchan=crate::readpbf::default_numchan(), tag_filter=None))]
+fn process_geometry(py: Python,
+    prfx: &str,
+    filter: Option<PyObject>,
+    timestamp: Option<&str>,
+    minzoom_in: Option<PyObject>,
+    style_in: Option<PyObject>,
+    numchan: usize,
+    tag_filter: Option<PyObject>,
+) -> PyResult<Option<Vec<GeometryBlock>>> {
+    
+    let tag_filter = crate::tagfilter::prep_tag_filter_arg(py, tag_filter)?;
+    let (mut pfilelocs, style, minzoom) = prep_process_geometry(py, prfx, filter, timestamp, minzoom_in, style_in)?;
     
     let mut qq = Vec::new();
     for (p,_) in &pfilelocs.1 {
         qq.push(p.clone());
     }
-    let cb = Box::new(osmquadtree_geometry::StoreBlocks::new(qq));
+    let cb: GeometryCallback = Box::new(osmquadtree_geometry::StoreBlocks::new(qq));
     
-    let style = prep_style(py, style_in)?;
-    
-    let minzoom = prep_minzoom(py, minzoom_in)?;
-    
-    
-    let res = py.allow_threads(|| osmquadtree_geometry::process_geometry_call(
-        &mut pfilelocs,
-        Some(cb),
-        style,
-        minzoom,
-        numchan));
+    let res = crate::cancel::run_interruptible(py, &[], || {
+        let cb = crate::tagfilter::filter_geometry_callback(&mut pfilelocs, cb, tag_filter, numchan)?;
+        Ok(osmquadtree_geometry::process_geometry_call(
+            &mut pfilelocs,
+            Some(crate::cancel::stop_when_cancelled(cb)),
+            style,
+            minzoom,
+            numchan))
+    })?;
     
     
     
@@ -813,6 +1062,230 @@ fn process_geometry(py: Python,
     
 }
 
+impl crate::readpbf::PyBlock for osmquadtree_geometry::GeometryBlock {
+    fn into_py_block(self, py: Python) -> PyObject {
+        GeometryBlock::new(self).into_py(py)
+    }
+}
+
+impl crate::readpbf::CollectTimings for Timings<osmquadtree_geometry::OtherData> {
+    fn collected(_name: &'static str, _count: usize) -> Self {
+        Timings::new()
+    }
+}
+
+/// As process_geometry, but calls `callback` with lists of up to
+/// `callback_num_blocks` GeometryBlocks as they are finished, rather than
+/// keeping every block in memory. Blocks are passed in the order they come
+/// out of the pipeline, which need not be quadtree order. Returns the number
+/// of blocks passed to `callback`.
+#[pyfunction]
+#[pyo3(signature = (prfx, callback, filter=None, timestamp=None, minzoom_in=None, style_in=None, callback_num_blocks=4, numchan=crate::readpbf::default_numchan(), tag_filter=None))]
+fn process_geometry_callback(py: Python,
+    prfx: &str,
+    callback: PyObject,
+    filter: Option<PyObject>,
+    timestamp: Option<&str>,
+    minzoom_in: Option<PyObject>,
+    style_in: Option<PyObject>,
+    callback_num_blocks: usize,
+    numchan: usize,
+    tag_filter: Option<PyObject>,
+) -> PyResult<usize> {
+    
+    if callback_num_blocks == 0 {
+        return Err(crate::errors::invalid_input_error(String::from("callback_num_blocks must be greater than zero")));
+    }
+    let tag_filter = crate::tagfilter::prep_tag_filter_arg(py, tag_filter)?;
+    let (mut pfilelocs, style, minzoom) = prep_process_geometry(py, prfx, filter, timestamp, minzoom_in, style_in)?;
+    
+    let error: ErrorSlot = Arc::new(Mutex::new(None));
+    let collect = CollectBlocksCall::<osmquadtree_geometry::GeometryBlock, Timings<osmquadtree_geometry::OtherData>>::new("CollectGeometryBlocksCall", callback, callback_num_blocks, error.clone());
+    let count = collect.counter();
+    let cb: GeometryCallback = Box::new(collect);
+    
+    crate::cancel::run_interruptible(py, &[], || {
+        let cb = crate::tagfilter::filter_geometry_callback(&mut pfilelocs, cb, tag_filter, numchan)?;
+        Ok(osmquadtree_geometry::process_geometry_call(
+            &mut pfilelocs,
+            Some(crate::cancel::stop_when_cancelled(cb)),
+            style,
+            minzoom,
+            numchan))
+    })?;
+    take_error(&error)?;
+    
+    Ok(count.load(Ordering::Relaxed))
+}
+
+
+struct SendGeometryBlocksCall {
+    sender: SyncSender<osmquadtree_geometry::GeometryBlock>,
+    stopped: Arc<AtomicBool>
+}
+
+impl CallFinish for SendGeometryBlocksCall {
+    type CallType = osmquadtree_geometry::GeometryBlock;
+    type ReturnType = Timings<osmquadtree_geometry::OtherData>;
+    type ErrorType = Error;
+    
+    fn call(&mut self, bl: osmquadtree_geometry::GeometryBlock) {
+        if self.stopped.load(Ordering::Relaxed) {
+            return;
+        }
+        if self.sender.send(bl).is_err() {
+            //the iterator has been closed: drop the rest of the blocks
+            self.stopped.store(true, Ordering::Relaxed);
+        }
+    }
+    
+    fn finish(&mut self) -> ccResult<Self::ReturnType, Error> {
+        Ok(Timings::new())
+    }
+}
+
+/// Iterator over the GeometryBlocks produced by process_geometry, returned
+/// by iter_geometry. The pipeline runs on a background thread. When the
+/// iterator is closed or dropped the thread's cancel token is set, so the
+/// remaining blocks are dropped rather than passed on, and the thread is
+/// joined. process_geometry_call itself can't be stopped part way, so this
+/// waits for it to get to the end of the input.
+#[pyclass]
+pub struct GeometryBlocksIter {
+    receiver: Option<Mutex<Receiver<osmquadtree_geometry::GeometryBlock>>>,
+    count: usize,
+    stopped: Arc<AtomicBool>,
+    token: crate::cancel::CancelToken,
+    handle: Option<std::thread::JoinHandle<PyResult<()>>>
+}
+
+impl GeometryBlocksIter {
+    
+    fn recv_block(&mut self, py: Python) -> PyResult<Option<osmquadtree_geometry::GeometryBlock>> {
+        loop {
+            let res = match &self.receiver {
+                None => { return Ok(None); },
+                Some(rx) => py.allow_threads(|| {
+                    match rx.lock() {
+                        Ok(r) => r.recv_timeout(std::time::Duration::from_millis(100)),
+                        Err(_) => Err(RecvTimeoutError::Disconnected)
+                    }
+                })
+            };
+            match res {
+                Ok(bl) => { return Ok(Some(bl)); },
+                Err(RecvTimeoutError::Disconnected) => { return Ok(None); },
+                Err(RecvTimeoutError::Timeout) => {
+                    if let Err(e) = py.check_signals() {
+                        self.stop(py);
+                        return Err(e);
+                    }
+                }
+            }
+        }
+    }
+    
+    fn finish_thread(&mut self, py: Python) -> PyResult<()> {
+        self.receiver = None;
+        match self.handle.take() {
+            None => Ok(()),
+            Some(h) => match py.allow_threads(|| h.join()) {
+                Ok(r) => r,
+                Err(_) => Err(PyRuntimeError::new_err("geometry thread panicked"))
+            }
+        }
+    }
+    
+    fn stop(&mut self, py: Python) {
+        self.stopped.store(true, Ordering::Relaxed);
+        self.token.cancel();
+        self.receiver = None;
+        if let Some(h) = self.handle.take() {
+            //the thread's outcome doesn't matter once the iterator is closed
+            let _ = py.allow_threads(|| h.join());
+        }
+    }
+}
+
+#[pymethods]
+impl GeometryBlocksIter {
+    
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+    
+    fn __next__(&mut self, py: Python) -> PyResult<Option<GeometryBlock>> {
+        match self.recv_block(py)? {
+            Some(bl) => {
+                self.count += 1;
+                Ok(Some(GeometryBlock::new(bl)))
+            },
+            None => {
+                self.finish_thread(py)?;
+                Ok(None)
+            }
+        }
+    }
+    
+    pub fn close(&mut self, py: Python) -> PyResult<()> {
+        self.stop(py);
+        Ok(())
+    }
+    
+    fn __repr__(&self) -> PyResult<String> {
+        Ok(format!("GeometryBlocksIter [{} blocks returned]", self.count))
+    }
+}
+
+impl Drop for GeometryBlocksIter {
+    fn drop(&mut self) {
+        Python::with_gil(|py| self.stop(py));
+    }
+}
+
+/// As process_geometry, but returns an iterator over the GeometryBlocks as
+/// they are finished, rather than keeping every block in memory.
+#[pyfunction]
+#[pyo3(signature = (prfx, filter=None, timestamp=None, minzoom_in=None, style_in=None, numchan=crate::readpbf::default_numchan(), tag_filter=None))]
+fn iter_geometry(py: Python,
+    prfx: &str,
+    filter: Option<PyObject>,
+    timestamp: Option<&str>,
+    minzoom_in: Option<PyObject>,
+    style_in: Option<PyObject>,
+    numchan: usize,
+    tag_filter: Option<PyObject>,
+) -> PyResult<GeometryBlocksIter> {
+    
+    let tag_filter = crate::tagfilter::prep_tag_filter_arg(py, tag_filter)?;
+    let (mut pfilelocs, style, minzoom) = prep_process_geometry(py, prfx, filter, timestamp, minzoom_in, style_in)?;
+    
+    let (sender, receiver) = sync_channel(usize::max(numchan,1) * 2);
+    let stopped = Arc::new(AtomicBool::new(false));
+    let cb: GeometryCallback = Box::new(SendGeometryBlocksCall{sender: sender, stopped: stopped.clone()});
+    
+    let token = crate::cancel::CancelToken::new();
+    let thread_token = token.clone();
+    let handle = std::thread::spawn(move || crate::cancel::with_token(thread_token, || {
+        let cb = crate::tagfilter::filter_geometry_callback(&mut pfilelocs, cb, tag_filter, numchan)?;
+        crate::cancel::check_cancelled()?;
+        osmquadtree_geometry::process_geometry_call(
+            &mut pfilelocs,
+            Some(crate::cancel::stop_when_cancelled(cb)),
+            style,
+            minzoom,
+            numchan);
+        Ok(())
+    }));
+    
+    Ok(GeometryBlocksIter{
+        receiver: Some(Mutex::new(receiver)),
+        count: 0,
+        stopped: stopped,
+        token: token,
+        handle: Some(handle)})
+}
+
 #[pyfunction]
 pub fn default_style(py: Python) -> PyResult<PyObject> {
     let s = osmquadtree_geometry::GeometryStyle::default();
@@ -837,7 +1310,10 @@ pub(crate) fn wrap_geometry(m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<PolygonPart>()?;
     m.add_class::<Ring>()?;
     m.add_class::<GeometryBlock>()?;
+    m.add_class::<GeometryBlocksIter>()?;
     m.add_wrapped(wrap_pyfunction!(process_geometry))?;
+    m.add_wrapped(wrap_pyfunction!(process_geometry_callback))?;
+    m.add_wrapped(wrap_pyfunction!(iter_geometry))?;
     m.add_wrapped(wrap_pyfunction!(default_style))?;
     m.add_wrapped(wrap_pyfunction!(default_minzoom_values))?;
     Ok(())
@@ -0,0 +1,457 @@
+use pyo3::prelude::*;
+use pyo3::wrap_pyfunction;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::sync::Arc;
+
+use arrow::array::{ArrayRef,BinaryArray,Float64Array,Int64Array,StringArray};
+use arrow::datatypes::{DataType,Field,Schema};
+use arrow::record_batch::RecordBatch;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::{Compression,ZstdLevel};
+use parquet::file::metadata::KeyValue;
+use parquet::file::properties::WriterProperties;
+
+use channelled_callbacks::{CallFinish,Timings,Result as ccResult};
+use osmquadtree::utils::Error;
+use osmquadtree_geometry::GeoJsonable;
+use crate::geometry::ResultSlot;
+
+
+#[derive(Clone,Copy,PartialEq)]
+pub(crate) enum TableKind {
+    Point,
+    Line,
+    Polygon
+}
+
+impl TableKind {
+    pub(crate) const ALL: [TableKind; 3] = [TableKind::Point, TableKind::Line, TableKind::Polygon];
+
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            TableKind::Point => "point",
+            TableKind::Line => "line",
+            TableKind::Polygon => "polygon"
+        }
+    }
+
+    fn geometry_types(&self) -> &'static str {
+        match self {
+            TableKind::Point => "[\"Point\"]",
+            TableKind::Line => "[\"LineString\"]",
+            TableKind::Polygon => "[\"Polygon\", \"MultiPolygon\"]"
+        }
+    }
+
+    pub(crate) fn measure(&self) -> Option<&'static str> {
+        match self {
+            TableKind::Point => None,
+            TableKind::Line => Some("length"),
+            TableKind::Polygon => Some("area")
+        }
+    }
+}
+
+pub(crate) const RESERVED_COLUMNS: [&str; 9] = ["osm_id", "quadtree", "minzoom", "layer", "z_order", "length", "area", "other_tags", "geometry"];
+
+/// The type of a tag column: parent tags which take the min or max of the
+/// parents' values (such as min_admin_level) are integers, all others text.
+#[derive(Clone,Copy,PartialEq)]
+pub(crate) enum TagType {
+    Text,
+    Int
+}
+
+/// A tag column value, as given by the column's TagType. Integer values
+/// which don't parse are left null.
+pub(crate) enum TagValue {
+    Text(Option<String>),
+    Int(Option<i64>)
+}
+
+/// Tag columns taken from the GeometryStyle: the feature keys, other keys
+/// and parent tags. If the style keeps all other keys, tags without a
+/// column are written as a json object in an "other_tags" column.
+pub(crate) struct TagColumns {
+    keys: Vec<String>,
+    types: Vec<TagType>,
+    index: BTreeMap<String, usize>,
+    other_tags: bool
+}
+
+impl TagColumns {
+    pub(crate) fn from_style(style: &osmquadtree_geometry::GeometryStyle) -> TagColumns {
+        let mut keys = BTreeMap::new();
+        for k in &style.feature_keys {
+            keys.insert(k.clone(), TagType::Text);
+        }
+        if let Some(ok) = &style.other_keys {
+            for k in ok {
+                keys.insert(k.clone(), TagType::Text);
+            }
+        }
+        for (k, spec) in &style.parent_tags {
+            let tt = match spec.op.as_str() {
+                "min" | "max" => TagType::Int,
+                _ => TagType::Text
+            };
+            keys.insert(k.clone(), tt);
+        }
+        //tags which would clash with the other columns are prefixed
+        let (keys, types): (Vec<String>, Vec<TagType>) = keys.into_iter().map(|(k,t)| (if RESERVED_COLUMNS.contains(&k.as_str()) { format!("tag_{}", k) } else { k }, t)).unzip();
+        let index = keys.iter().enumerate().map(|(i,k)| (k.clone(), i)).collect();
+        TagColumns{keys: keys, types: types, index: index, other_tags: style.other_keys.is_none()}
+    }
+
+    pub(crate) fn keys(&self) -> &[String] {
+        &self.keys
+    }
+
+    pub(crate) fn types(&self) -> &[TagType] {
+        &self.types
+    }
+
+    pub(crate) fn has_other_tags(&self) -> bool {
+        self.other_tags
+    }
+
+    /// Returns the value for each key column, and the remaining tags as json.
+    pub(crate) fn split(&self, tags: &[osmquadtree::elements::Tag]) -> (Vec<TagValue>, Option<String>) {
+        let mut vals = vec![None; self.keys.len()];
+        let mut others = serde_json::Map::new();
+        for t in tags {
+            let col = if RESERVED_COLUMNS.contains(&t.key.as_str()) { self.index.get(&format!("tag_{}", t.key)) } else { self.index.get(&t.key) };
+            match col {
+                Some(i) => { vals[*i] = Some(t.val.clone()); },
+                None => {
+                    if self.other_tags {
+                        others.insert(t.key.clone(), serde_json::Value::String(t.val.clone()));
+                    }
+                }
+            }
+        }
+        let vals = vals.into_iter().zip(&self.types).map(|(v, t)| match t {
+            TagType::Text => TagValue::Text(v),
+            TagType::Int => TagValue::Int(v.and_then(|v| v.trim().parse().ok()))
+        }).collect();
+        let others = if others.is_empty() { None } else { Some(serde_json::Value::Object(others).to_string()) };
+        (vals, others)
+    }
+}
+
+/// One row of a point, line or polygon table.
+pub(crate) struct GeometryRow<'a> {
+    pub osm_id: i64,
+    pub quadtree: i64,
+    pub minzoom: Option<i64>,
+    pub layer: Option<i64>,
+    pub z_order: Option<i64>,
+    pub measure: Option<f64>,
+    pub tags: &'a [osmquadtree::elements::Tag],
+    pub wkb: Vec<u8>
+}
+
+/// Calls `f` with the table and row for each object in `bl`, with the
+/// geometry as wkb (or ewkb if `srid` is set).
+pub(crate) fn block_rows<F>(bl: &osmquadtree_geometry::GeometryBlock, transform: bool, srid: bool, mut f: F) -> std::result::Result<(), String>
+    where F: FnMut(TableKind, GeometryRow) -> std::result::Result<(), String> {
+
+    for p in &bl.points {
+        let wkb = p.to_wkb(transform, srid).map_err(|e| format!("point {}: {}", p.id, e))?;
+        f(TableKind::Point, GeometryRow{osm_id: p.id, quadtree: p.quadtree.as_int(), minzoom: p.minzoom, layer: p.layer, z_order: None, measure: None, tags: &p.tags, wkb: wkb})?;
+    }
+    for l in &bl.linestrings {
+        let wkb = l.to_wkb(transform, srid).map_err(|e| format!("linestring {}: {}", l.id, e))?;
+        f(TableKind::Line, GeometryRow{osm_id: l.id, quadtree: l.quadtree.as_int(), minzoom: l.minzoom, layer: l.layer, z_order: l.z_order, measure: Some(l.length), tags: &l.tags, wkb: wkb})?;
+    }
+    for p in &bl.simple_polygons {
+        let wkb = p.to_wkb(transform, srid).map_err(|e| format!("polygon {}: {}", p.id, e))?;
+        f(TableKind::Polygon, GeometryRow{osm_id: p.id, quadtree: p.quadtree.as_int(), minzoom: p.minzoom, layer: p.layer, z_order: p.z_order, measure: Some(p.area), tags: &p.tags, wkb: wkb})?;
+    }
+    for p in &bl.complicated_polygons {
+        //relations are given negative ids, as in osm2pgsql
+        let wkb = p.to_wkb(transform, srid).map_err(|e| format!("polygon {}: {}", p.id, e))?;
+        f(TableKind::Polygon, GeometryRow{osm_id: -p.id, quadtree: p.quadtree.as_int(), minzoom: p.minzoom, layer: p.layer, z_order: p.z_order, measure: Some(p.area), tags: &p.tags, wkb: wkb})?;
+    }
+    Ok(())
+}
+
+enum TagArray {
+    Text(Vec<Option<String>>),
+    Int(Vec<Option<i64>>)
+}
+
+impl TagArray {
+    fn push(&mut self, v: TagValue) {
+        match (self, v) {
+            (TagArray::Text(c), TagValue::Text(v)) => c.push(v),
+            (TagArray::Int(c), TagValue::Int(v)) => c.push(v),
+            (TagArray::Text(c), _) => c.push(None),
+            (TagArray::Int(c), _) => c.push(None)
+        }
+    }
+
+    fn take(&mut self) -> ArrayRef {
+        match self {
+            TagArray::Text(c) => Arc::new(StringArray::from(std::mem::take(c))),
+            TagArray::Int(c) => Arc::new(Int64Array::from(std::mem::take(c)))
+        }
+    }
+}
+
+struct TableWriter {
+    kind: TableKind,
+    outfn: String,
+    writer: Option<ArrowWriter<File>>,
+    schema: Arc<Schema>,
+    row_group_size: usize,
+    count: usize,
+
+    osm_id: Vec<i64>,
+    quadtree: Vec<i64>,
+    minzoom: Vec<Option<i64>>,
+    layer: Vec<Option<i64>>,
+    z_order: Vec<Option<i64>>,
+    measure: Vec<Option<f64>>,
+    tags: Vec<TagArray>,
+    other_tags: Vec<Option<String>>,
+    geometry: Vec<Vec<u8>>
+}
+
+fn geo_metadata(kind: TableKind, transform: bool) -> String {
+    //without a crs GeoParquet readers assume OGC:CRS84, ie lon/lat
+    let crs = if transform {
+        ", \"crs\": {\"type\": \"ProjectedCRS\", \"name\": \"WGS 84 / Pseudo-Mercator\", \"id\": {\"authority\": \"EPSG\", \"code\": 3857}}"
+    } else {
+        ""
+    };
+    format!("{{\"version\": \"1.0.0\", \"primary_column\": \"geometry\", \"columns\": {{\"geometry\": {{\"encoding\": \"WKB\", \"geometry_types\": {}{}}}}}}}",
+        kind.geometry_types(), crs)
+}
+
+impl TableWriter {
+    fn new(kind: TableKind, outfn: String, tag_columns: &TagColumns, transform: bool, row_group_size: usize) -> std::result::Result<TableWriter, String> {
+        let mut fields = vec![
+            Field::new("osm_id", DataType::Int64, false),
+            Field::new("quadtree", DataType::Int64, false),
+            Field::new("minzoom", DataType::Int64, true),
+            Field::new("layer", DataType::Int64, true),
+        ];
+        if kind != TableKind::Point {
+            fields.push(Field::new("z_order", DataType::Int64, true));
+        }
+        if let Some(m) = kind.measure() {
+            fields.push(Field::new(m, DataType::Float64, true));
+        }
+        for (k, t) in tag_columns.keys().iter().zip(tag_columns.types()) {
+            let dt = match t {
+                TagType::Text => DataType::Utf8,
+                TagType::Int => DataType::Int64
+            };
+            fields.push(Field::new(k, dt, true));
+        }
+        if tag_columns.has_other_tags() {
+            fields.push(Field::new("other_tags", DataType::Utf8, true));
+        }
+        fields.push(Field::new("geometry", DataType::Binary, false));
+        let schema = Arc::new(Schema::new(fields));
+
+        let props = WriterProperties::builder()
+            .set_compression(Compression::ZSTD(ZstdLevel::default()))
+            .set_max_row_group_size(row_group_size)
+            .build();
+
+        let file = File::create(&outfn).map_err(|e| format!("{}: {}", outfn, e))?;
+        let mut writer = ArrowWriter::try_new(file, schema.clone(), Some(props)).map_err(|e| format!("{}: {}", outfn, e))?;
+        writer.append_key_value_metadata(KeyValue::new(String::from("geo"), geo_metadata(kind, transform)));
+
+        let tags = tag_columns.types().iter().map(|t| match t {
+            TagType::Text => TagArray::Text(Vec::new()),
+            TagType::Int => TagArray::Int(Vec::new())
+        }).collect();
+        Ok(TableWriter{
+            kind: kind, outfn: outfn, writer: Some(writer), schema: schema, row_group_size: row_group_size, count: 0,
+            osm_id: Vec::new(), quadtree: Vec::new(), minzoom: Vec::new(), layer: Vec::new(), z_order: Vec::new(),
+            measure: Vec::new(), tags: tags, other_tags: Vec::new(), geometry: Vec::new()
+        })
+    }
+
+    fn add_row(&mut self, tag_columns: &TagColumns, row: GeometryRow) -> std::result::Result<(), String> {
+        self.osm_id.push(row.osm_id);
+        self.quadtree.push(row.quadtree);
+        self.minzoom.push(row.minzoom);
+        self.layer.push(row.layer);
+        self.z_order.push(row.z_order);
+        self.measure.push(row.measure);
+        let (vals, others) = tag_columns.split(row.tags);
+        for (c, v) in self.tags.iter_mut().zip(vals) {
+            c.push(v);
+        }
+        self.other_tags.push(others);
+        self.geometry.push(row.wkb);
+
+        if self.osm_id.len() >= self.row_group_size {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> std::result::Result<(), String> {
+        if self.osm_id.is_empty() {
+            return Ok(());
+        }
+        let mut cols: Vec<ArrayRef> = vec![
+            Arc::new(Int64Array::from(std::mem::take(&mut self.osm_id))),
+            Arc::new(Int64Array::from(std::mem::take(&mut self.quadtree))),
+            Arc::new(Int64Array::from(std::mem::take(&mut self.minzoom))),
+            Arc::new(Int64Array::from(std::mem::take(&mut self.layer))),
+        ];
+        let z_order = std::mem::take(&mut self.z_order);
+        if self.kind != TableKind::Point {
+            cols.push(Arc::new(Int64Array::from(z_order)));
+        }
+        let measure = std::mem::take(&mut self.measure);
+        if self.kind.measure().is_some() {
+            cols.push(Arc::new(Float64Array::from(measure)));
+        }
+        for c in self.tags.iter_mut() {
+            cols.push(c.take());
+        }
+        let other_tags = std::mem::take(&mut self.other_tags);
+        if self.schema.column_with_name("other_tags").is_some() {
+            cols.push(Arc::new(StringArray::from(other_tags)));
+        }
+        let geometry = std::mem::take(&mut self.geometry);
+        cols.push(Arc::new(BinaryArray::from_iter_values(geometry.iter())));
+
+        let batch = RecordBatch::try_new(self.schema.clone(), cols).map_err(|e| format!("{}: {}", self.outfn, e))?;
+        self.count += batch.num_rows();
+        match self.writer.as_mut() {
+            Some(w) => w.write(&batch).map_err(|e| format!("{}: {}", self.outfn, e)),
+            None => Err(format!("{} already closed", self.outfn))
+        }
+    }
+
+    fn close(&mut self) -> std::result::Result<(), String> {
+        self.flush()?;
+        if let Some(w) = self.writer.take() {
+            w.close().map_err(|e| format!("{}: {}", self.outfn, e))?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes each GeometryBlock from the process_geometry pipeline to point,
+/// line and polygon GeoParquet files as it arrives.
+pub struct WriteGeoParquet {
+    tag_columns: TagColumns,
+    points: TableWriter,
+    lines: TableWriter,
+    polygons: TableWriter,
+    transform: bool,
+    error: Option<String>,
+    time: f64,
+    result: ResultSlot<Vec<(String, usize)>>
+}
+
+impl WriteGeoParquet {
+    fn new(outprfx: &str, style: &osmquadtree_geometry::GeometryStyle, transform: bool, row_group_size: usize, result: ResultSlot<Vec<(String, usize)>>) -> std::result::Result<WriteGeoParquet, String> {
+        let tag_columns = TagColumns::from_style(style);
+        let points = TableWriter::new(TableKind::Point, format!("{}{}.parquet", outprfx, TableKind::Point.name()), &tag_columns, transform, row_group_size)?;
+        let lines = TableWriter::new(TableKind::Line, format!("{}{}.parquet", outprfx, TableKind::Line.name()), &tag_columns, transform, row_group_size)?;
+        let polygons = TableWriter::new(TableKind::Polygon, format!("{}{}.parquet", outprfx, TableKind::Polygon.name()), &tag_columns, transform, row_group_size)?;
+        Ok(WriteGeoParquet{tag_columns: tag_columns, points: points, lines: lines, polygons: polygons, transform: transform, error: None, time: 0.0, result: result})
+    }
+
+    fn add_block(&mut self, bl: &osmquadtree_geometry::GeometryBlock) -> std::result::Result<(), String> {
+        let tag_columns = &self.tag_columns;
+        let (points, lines, polygons) = (&mut self.points, &mut self.lines, &mut self.polygons);
+        block_rows(bl, self.transform, false, |kind, row| match kind {
+            TableKind::Point => points.add_row(tag_columns, row),
+            TableKind::Line => lines.add_row(tag_columns, row),
+            TableKind::Polygon => polygons.add_row(tag_columns, row)
+        })
+    }
+
+    fn outputs(&self) -> Vec<(String, usize)> {
+        vec![
+            (self.points.outfn.clone(), self.points.count),
+            (self.lines.outfn.clone(), self.lines.count),
+            (self.polygons.outfn.clone(), self.polygons.count)
+        ]
+    }
+}
+
+impl CallFinish for WriteGeoParquet {
+    type CallType = osmquadtree_geometry::GeometryBlock;
+    type ReturnType = Timings<osmquadtree_geometry::OtherData>;
+    type ErrorType = Error;
+
+    fn call(&mut self, bl: osmquadtree_geometry::GeometryBlock) {
+        if self.error.is_some() {
+            return;
+        }
+        let st = std::time::Instant::now();
+        if let Err(e) = self.add_block(&bl) {
+            self.error = Some(e);
+        }
+        self.time += st.elapsed().as_secs_f64();
+    }
+
+    fn finish(&mut self) -> ccResult<Self::ReturnType, Error> {
+        if self.error.is_none() {
+            for t in [&mut self.points, &mut self.lines, &mut self.polygons] {
+                if let Err(e) = t.close() {
+                    self.error = Some(e);
+                    break;
+                }
+            }
+        }
+        let res = match self.error.take() {
+            Some(e) => Err(e),
+            None => Ok(self.outputs())
+        };
+        let mut tm = Timings::new();
+        tm.add("WriteGeoParquet", self.time);
+        self.result.finish(res, tm)
+    }
+}
+
+/// Runs process_geometry, writing the output to GeoParquet files
+/// `{outprfx}point.parquet`, `{outprfx}line.parquet` and
+/// `{outprfx}polygon.parquet`, with WKB geometry columns. Coordinates are
+/// lon/lat, or EPSG:3857 if `transform` is set. Returns the filename and
+/// number of rows of each file.
+#[pyfunction]
+#[pyo3(signature = (prfx, outprfx, filter=None, timestamp=None, minzoom_in=None, style_in=None, transform=false, row_group_size=65536, numchan=crate::readpbf::default_numchan()))]
+pub fn write_geoparquet(py: Python,
+    prfx: &str,
+    outprfx: &str,
+    filter: Option<PyObject>,
+    timestamp: Option<&str>,
+    minzoom_in: Option<PyObject>,
+    style_in: Option<PyObject>,
+    transform: bool,
+    row_group_size: usize,
+    numchan: usize
+) -> PyResult<Vec<(String, usize)>> {
+
+    if row_group_size == 0 {
+        return Err(crate::errors::invalid_input_error(String::from("row_group_size must be greater than zero")));
+    }
+    let (pfilelocs, style, minzoom) = crate::geometry::prep_process_geometry(py, prfx, filter, timestamp, minzoom_in, style_in)?;
+
+    let result = ResultSlot::new();
+    let writer = WriteGeoParquet::new(outprfx, &style, transform, row_group_size, result.clone())
+        .map_err(|e| crate::ErrorWrapped::from(std::io::Error::new(std::io::ErrorKind::Other, e)))?;
+    let outfns: Vec<String> = writer.outputs().into_iter().map(|(f,_)| f).collect();
+    let cb: crate::geometry::GeometryCallback = Box::new(writer);
+
+    crate::geometry::run_geometry_writer(py, pfilelocs, cb, &result, style, minzoom, numchan, &outfns)
+}
+
+pub(crate) fn wrap_geoparquet(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_wrapped(wrap_pyfunction!(write_geoparquet))?;
+    Ok(())
+}
@@ -0,0 +1,677 @@
+use pyo3::prelude::*;
+use pyo3::exceptions::*;
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{BufReader,Seek,SeekFrom};
+
+use simple_protocolbuffers::{IterTags,PbfTag,DeltaPackedInt,PackedInt,un_zig_zag};
+use osmquadtree::elements::{Changetype,ElementType,Info,Tag};
+
+use crate::ErrorWrapped;
+
+
+//upstream PrimitiveBlock::read doesn't keep the visible flag, so history
+//blocks are decoded here. versions which aren't visible are given
+//changetype Delete
+struct StringTable {
+    strings: Vec<String>
+}
+
+impl StringTable {
+    fn read(data: &[u8]) -> StringTable {
+        let mut strings = Vec::new();
+        for t in IterTags::new(data) {
+            if let PbfTag::Data(1, s) = t {
+                strings.push(String::from_utf8_lossy(s).into_owned());
+            }
+        }
+        StringTable{strings: strings}
+    }
+
+    fn get(&self, i: u64) -> Result<String, String> {
+        self.strings.get(i as usize).cloned().ok_or_else(|| format!("string {} out of range", i))
+    }
+}
+
+struct BlockContext {
+    strings: StringTable,
+    granularity: i64,
+    lat_offset: i64,
+    lon_offset: i64,
+    date_granularity: i64
+}
+
+impl BlockContext {
+    //coordinates are stored in units of 1e-7 degrees
+    fn coord(&self, offset: i64, v: i64) -> i32 {
+        ((offset + self.granularity * v) / 100) as i32
+    }
+
+    fn timestamp(&self, v: i64) -> i64 {
+        v * self.date_granularity / 1000
+    }
+
+    fn tags(&self, keys: &[u64], vals: &[u64]) -> Result<Vec<Tag>, String> {
+        let mut res = Vec::with_capacity(keys.len());
+        for (k, v) in keys.iter().zip(vals.iter()) {
+            res.push(Tag::new(self.strings.get(*k)?, self.strings.get(*v)?));
+        }
+        Ok(res)
+    }
+
+    fn info(&self, data: &[u8]) -> Result<(Info, bool), String> {
+        let mut info = Info::new();
+        let mut visible = true;
+        for t in IterTags::new(data) {
+            match t {
+                PbfTag::Value(1, v) => { info.version = v as i64; },
+                PbfTag::Value(2, v) => { info.timestamp = self.timestamp(v as i64); },
+                PbfTag::Value(3, v) => { info.changeset = v as i64; },
+                PbfTag::Value(4, v) => { info.user_id = v as i32 as i64; },
+                PbfTag::Value(5, v) => { info.user = self.strings.get(v)?; },
+                PbfTag::Value(6, v) => { visible = v != 0; },
+                _ => {}
+            }
+        }
+        Ok((info, visible))
+    }
+}
+
+fn changetype(visible: bool) -> Changetype {
+    if visible { Changetype::Normal } else { Changetype::Delete }
+}
+
+fn read_node(ctx: &BlockContext, data: &[u8]) -> Result<osmquadtree::elements::Node, String> {
+    let (mut id, mut lat, mut lon) = (0, 0, 0);
+    let (mut keys, mut vals) = (Vec::new(), Vec::new());
+    let mut info = None;
+    for t in IterTags::new(data) {
+        match t {
+            PbfTag::Value(1, v) => { id = un_zig_zag(v); },
+            PbfTag::Data(2, d) => { keys.extend(PackedInt::new(d)); },
+            PbfTag::Data(3, d) => { vals.extend(PackedInt::new(d)); },
+            PbfTag::Data(4, d) => { info = Some(ctx.info(d)?); },
+            PbfTag::Value(8, v) => { lat = un_zig_zag(v); },
+            PbfTag::Value(9, v) => { lon = un_zig_zag(v); },
+            _ => {}
+        }
+    }
+    let (info, visible) = info.ok_or_else(|| format!("node {} has no info", id))?;
+    let mut n = osmquadtree::elements::Node::new(id, changetype(visible));
+    n.info = Some(info);
+    n.tags = ctx.tags(&keys, &vals)?;
+    n.lat = ctx.coord(ctx.lat_offset, lat);
+    n.lon = ctx.coord(ctx.lon_offset, lon);
+    Ok(n)
+}
+
+fn read_dense(ctx: &BlockContext, data: &[u8], res: &mut Vec<osmquadtree::elements::Node>) -> Result<(), String> {
+    let (mut ids, mut lats, mut lons, mut keys_vals) = (Vec::new(), Vec::new(), Vec::new(), Vec::new());
+    let (mut versions, mut timestamps, mut changesets, mut uids, mut users, mut visibles) =
+        (Vec::new(), Vec::new(), Vec::new(), Vec::new(), Vec::new(), Vec::new());
+    for t in IterTags::new(data) {
+        match t {
+            PbfTag::Data(1, d) => { ids.extend(DeltaPackedInt::new(d)); },
+            PbfTag::Data(5, d) => {
+                for t2 in IterTags::new(d) {
+                    match t2 {
+                        PbfTag::Data(1, d2) => { versions.extend(PackedInt::new(d2)); },
+                        PbfTag::Data(2, d2) => { timestamps.extend(DeltaPackedInt::new(d2)); },
+                        PbfTag::Data(3, d2) => { changesets.extend(DeltaPackedInt::new(d2)); },
+                        PbfTag::Data(4, d2) => { uids.extend(DeltaPackedInt::new(d2)); },
+                        PbfTag::Data(5, d2) => { users.extend(DeltaPackedInt::new(d2)); },
+                        PbfTag::Data(6, d2) => { visibles.extend(PackedInt::new(d2)); },
+                        _ => {}
+                    }
+                }
+            },
+            PbfTag::Data(8, d) => { lats.extend(DeltaPackedInt::new(d)); },
+            PbfTag::Data(9, d) => { lons.extend(DeltaPackedInt::new(d)); },
+            PbfTag::Data(10, d) => { keys_vals.extend(PackedInt::new(d)); },
+            _ => {}
+        }
+    }
+    if lats.len() != ids.len() || lons.len() != ids.len() || versions.len() != ids.len() || timestamps.len() != ids.len() {
+        return Err(format!("dense nodes have mismatched lengths"));
+    }
+
+    let mut kv = keys_vals.into_iter();
+    for i in 0..ids.len() {
+        let visible = visibles.get(i).map_or(true, |v| *v != 0);
+        let mut n = osmquadtree::elements::Node::new(ids[i], changetype(visible));
+        let mut info = Info::new();
+        info.version = versions[i] as i64;
+        info.timestamp = ctx.timestamp(timestamps[i]);
+        info.changeset = changesets.get(i).cloned().unwrap_or(0);
+        info.user_id = uids.get(i).cloned().unwrap_or(0);
+        info.user = match users.get(i) { Some(u) => ctx.strings.get(*u as u64)?, None => String::new() };
+        n.info = Some(info);
+        n.lat = ctx.coord(ctx.lat_offset, lats[i]);
+        n.lon = ctx.coord(ctx.lon_offset, lons[i]);
+        loop {
+            match kv.next() {
+                None | Some(0) => { break; },
+                Some(k) => {
+                    let v = kv.next().ok_or_else(|| format!("node {}: missing tag value", ids[i]))?;
+                    n.tags.push(Tag::new(ctx.strings.get(k)?, ctx.strings.get(v)?));
+                }
+            }
+        }
+        res.push(n);
+    }
+    Ok(())
+}
+
+fn read_way(ctx: &BlockContext, data: &[u8]) -> Result<osmquadtree::elements::Way, String> {
+    let mut id = 0;
+    let (mut keys, mut vals, mut refs) = (Vec::new(), Vec::new(), Vec::new());
+    let mut info = None;
+    for t in IterTags::new(data) {
+        match t {
+            PbfTag::Value(1, v) => { id = v as i64; },
+            PbfTag::Data(2, d) => { keys.extend(PackedInt::new(d)); },
+            PbfTag::Data(3, d) => { vals.extend(PackedInt::new(d)); },
+            PbfTag::Data(4, d) => { info = Some(ctx.info(d)?); },
+            PbfTag::Data(8, d) => { refs.extend(DeltaPackedInt::new(d)); },
+            _ => {}
+        }
+    }
+    let (info, visible) = info.ok_or_else(|| format!("way {} has no info", id))?;
+    let mut w = osmquadtree::elements::Way::new(id, changetype(visible));
+    w.info = Some(info);
+    w.tags = ctx.tags(&keys, &vals)?;
+    w.refs = refs;
+    Ok(w)
+}
+
+fn read_relation(ctx: &BlockContext, data: &[u8]) -> Result<osmquadtree::elements::Relation, String> {
+    let mut id = 0;
+    let (mut keys, mut vals, mut roles, mut memids, mut types) = (Vec::new(), Vec::new(), Vec::new(), Vec::new(), Vec::new());
+    let mut info = None;
+    for t in IterTags::new(data) {
+        match t {
+            PbfTag::Value(1, v) => { id = v as i64; },
+            PbfTag::Data(2, d) => { keys.extend(PackedInt::new(d)); },
+            PbfTag::Data(3, d) => { vals.extend(PackedInt::new(d)); },
+            PbfTag::Data(4, d) => { info = Some(ctx.info(d)?); },
+            PbfTag::Data(8, d) => { roles.extend(PackedInt::new(d)); },
+            PbfTag::Data(9, d) => { memids.extend(DeltaPackedInt::new(d)); },
+            PbfTag::Data(10, d) => { types.extend(PackedInt::new(d)); },
+            _ => {}
+        }
+    }
+    if roles.len() != memids.len() || types.len() != memids.len() {
+        return Err(format!("relation {}: mismatched member lengths", id));
+    }
+    let (info, visible) = info.ok_or_else(|| format!("relation {} has no info", id))?;
+    let mut r = osmquadtree::elements::Relation::new(id, changetype(visible));
+    r.info = Some(info);
+    r.tags = ctx.tags(&keys, &vals)?;
+    for ((role, mem_ref), t) in roles.into_iter().zip(memids).zip(types) {
+        let mem_type = match t {
+            0 => ElementType::Node,
+            1 => ElementType::Way,
+            2 => ElementType::Relation,
+            _ => { return Err(format!("relation {}: unexpected member type {}", id, t)); }
+        };
+        r.members.push(osmquadtree::elements::Member{mem_type: mem_type, mem_ref: mem_ref, role: ctx.strings.get(role)?});
+    }
+    Ok(r)
+}
+
+/// Decodes an OSMData block, keeping every version of each element.
+pub(crate) fn read_history_block(index: i64, location: u64, data: &[u8]) -> Result<osmquadtree::elements::PrimitiveBlock, String> {
+    let mut ctx = BlockContext{strings: StringTable{strings: Vec::new()}, granularity: 100, lat_offset: 0, lon_offset: 0, date_granularity: 1000};
+    let mut groups = Vec::new();
+    for t in IterTags::new(data) {
+        match t {
+            PbfTag::Data(1, d) => { ctx.strings = StringTable::read(d); },
+            PbfTag::Data(2, d) => { groups.push(d); },
+            PbfTag::Value(17, v) => { ctx.granularity = v as i64; },
+            PbfTag::Value(18, v) => { ctx.date_granularity = v as i64; },
+            PbfTag::Value(19, v) => { ctx.lat_offset = v as i64; },
+            PbfTag::Value(20, v) => { ctx.lon_offset = v as i64; },
+            _ => {}
+        }
+    }
+
+    let mut bl = osmquadtree::elements::PrimitiveBlock::new(index, location);
+    for g in groups {
+        for t in IterTags::new(g) {
+            match t {
+                PbfTag::Data(1, d) => { bl.nodes.push(read_node(&ctx, d)?); },
+                PbfTag::Data(2, d) => { read_dense(&ctx, d, &mut bl.nodes)?; },
+                PbfTag::Data(3, d) => { bl.ways.push(read_way(&ctx, d)?); },
+                PbfTag::Data(4, d) => { bl.relations.push(read_relation(&ctx, d)?); },
+                _ => {}
+            }
+        }
+    }
+    Ok(bl)
+}
+
+
+enum HistElement {
+    Node(osmquadtree::elements::Node),
+    Way(osmquadtree::elements::Way),
+    Relation(osmquadtree::elements::Relation)
+}
+
+impl HistElement {
+    fn parts(&self) -> (u8, i64, &Option<Info>, &Changetype) {
+        match self {
+            HistElement::Node(n) => (0, n.id, &n.info, &n.changetype),
+            HistElement::Way(w) => (1, w.id, &w.info, &w.changetype),
+            HistElement::Relation(r) => (2, r.id, &r.info, &r.changetype)
+        }
+    }
+
+    fn key(&self) -> (u8, i64) {
+        let (t, id, _, _) = self.parts();
+        (t, id)
+    }
+
+    fn version(&self) -> i64 {
+        self.parts().2.as_ref().map_or(0, |i| i.version)
+    }
+
+    fn timestamp(&self) -> i64 {
+        self.parts().2.as_ref().map_or(0, |i| i.timestamp)
+    }
+
+    fn visible(&self) -> bool {
+        !matches!(self.parts().3, Changetype::Delete)
+    }
+
+    fn into_object(self, py: Python) -> PyResult<PyObject> {
+        Ok(match self {
+            HistElement::Node(n) => crate::elements::Node::as_item(n)?.into_py(py),
+            HistElement::Way(w) => crate::elements::Way::as_item(w)?.into_py(py),
+            HistElement::Relation(r) => crate::elements::Relation::as_item(r)?.into_py(py)
+        })
+    }
+}
+
+pub(crate) fn type_rank(element_type: &str) -> PyResult<u8> {
+    match element_type.to_lowercase().as_str() {
+        "n" | "node" => Ok(0),
+        "w" | "way" => Ok(1),
+        "r" | "relation" => Ok(2),
+        _ => Err(PyValueError::new_err(format!("unexpected type {}", element_type)))
+    }
+}
+
+fn prep_timestamp(py: Python, ts: PyObject) -> PyResult<i64> {
+    if let Ok(t) = ts.extract::<i64>(py) {
+        return Ok(t);
+    }
+    let t = ts.extract::<String>(py)?;
+    osmquadtree::utils::parse_timestamp(&t).map_err(|e| crate::errors::timestamp_error(format!("{}: {}", t, e)))
+}
+
+//reads the OSMData blocks of a history file in order
+struct HistoryReader {
+    fname: String,
+    fbuf: BufReader<File>,
+    flen: u64,
+    index: i64
+}
+
+impl HistoryReader {
+    fn open(fname: &str) -> PyResult<HistoryReader> {
+        let flen = std::fs::metadata(fname).map_err(|e| ErrorWrapped::from(e).with_filename(fname))?.len();
+        let fbuf = BufReader::new(File::open(fname).map_err(|e| ErrorWrapped::from(e).with_filename(fname))?);
+        Ok(HistoryReader{fname: String::from(fname), fbuf: fbuf, flen: flen, index: 0})
+    }
+
+    fn read_at(&mut self, pos: u64) -> PyResult<osmquadtree::elements::PrimitiveBlock> {
+        self.fbuf.seek(SeekFrom::Start(pos)).map_err(|e| ErrorWrapped::from(e).with_filename(&self.fname))?;
+        match self.next_block()? {
+            Some(bl) => Ok(bl),
+            None => Err(crate::errors::invalid_input_error(format!("{}: no block at {}", self.fname, pos)))
+        }
+    }
+
+    fn next_block(&mut self) -> PyResult<Option<osmquadtree::elements::PrimitiveBlock>> {
+        while osmquadtree::pbfformat::file_position(&mut self.fbuf).map_err(ErrorWrapped::from)? < self.flen {
+            crate::cancel::check_cancelled()?;
+            let fb = crate::compression::read_file_block(&mut self.fbuf)
+                .map_err(|e| ErrorWrapped::from(e).with_filename(&self.fname))?;
+            if fb.block_type != "OSMData" {
+                continue;
+            }
+            let bl = read_history_block(self.index, fb.pos, &fb.data())
+                .map_err(|e| crate::errors::make_error(crate::errors::ErrorKind::PbfDecode, format!("{}: {}", self.fname, e), Some(self.fname.clone()), Some(fb.pos), None))?;
+            self.index += 1;
+            return Ok(Some(bl));
+        }
+        Ok(None)
+    }
+}
+
+fn block_elements(bl: osmquadtree::elements::PrimitiveBlock) -> impl Iterator<Item=HistElement> {
+    bl.nodes.into_iter().map(HistElement::Node)
+        .chain(bl.ways.into_iter().map(HistElement::Way))
+        .chain(bl.relations.into_iter().map(HistElement::Relation))
+}
+
+//history files must be sorted by type, id and version
+fn check_order(fname: &str, last: &mut Option<(u8, i64, i64)>, e: &HistElement) -> PyResult<()> {
+    let (t, id) = e.key();
+    let k = (t, id, e.version());
+    if let Some(l) = last {
+        if k < *l {
+            return Err(crate::errors::invalid_input_error(format!("{}: not sorted by type, id and version at {:?}", fname, k)));
+        }
+    }
+    *last = Some(k);
+    Ok(())
+}
+
+//every version in the file, in order
+struct HistoryScanner {
+    reader: HistoryReader,
+    pending: std::vec::IntoIter<HistElement>,
+    last: Option<(u8, i64, i64)>
+}
+
+impl HistoryScanner {
+    fn open(fname: &str) -> PyResult<HistoryScanner> {
+        Ok(HistoryScanner{reader: HistoryReader::open(fname)?, pending: Vec::new().into_iter(), last: None})
+    }
+
+    fn next_element(&mut self) -> PyResult<Option<HistElement>> {
+        loop {
+            if let Some(e) = self.pending.next() {
+                check_order(&self.reader.fname, &mut self.last, &e)?;
+                return Ok(Some(e));
+            }
+            match self.reader.next_block()? {
+                None => { return Ok(None); },
+                Some(bl) => { self.pending = block_elements(bl).collect::<Vec<_>>().into_iter(); }
+            }
+        }
+    }
+}
+
+//the state of each element as of `ts`, skipping elements which didn't
+//exist or had been deleted
+struct AsOfScanner {
+    scanner: HistoryScanner,
+    ts: i64,
+    current: Option<(u8, i64)>,
+    best: Option<HistElement>
+}
+
+impl AsOfScanner {
+    fn open(fname: &str, ts: i64) -> PyResult<AsOfScanner> {
+        Ok(AsOfScanner{scanner: HistoryScanner::open(fname)?, ts: ts, current: None, best: None})
+    }
+
+    fn next_element(&mut self) -> PyResult<Option<HistElement>> {
+        loop {
+            let e = match self.scanner.next_element()? {
+                Some(e) => e,
+                None => { return Ok(self.best.take().filter(|b| b.visible())); }
+            };
+            let mut prev = None;
+            if self.current != Some(e.key()) {
+                prev = self.best.take();
+                self.current = Some(e.key());
+            }
+            if e.timestamp() <= self.ts {
+                self.best = Some(e);
+            }
+            if let Some(p) = prev.filter(|p| p.visible()) {
+                return Ok(Some(p));
+            }
+        }
+    }
+}
+
+//the position and the first and last (type, id) of each block, so that the
+//versions of an element can be found without reading the whole file
+type BlockIndex = Vec<(u64, (u8, i64), (u8, i64))>;
+
+fn build_block_index(fname: &str) -> PyResult<BlockIndex> {
+    let mut reader = HistoryReader::open(fname)?;
+    let mut res = Vec::new();
+    let mut last = None;
+    while let Some(bl) = reader.next_block()? {
+        let pos = bl.location;
+        let mut keys = None;
+        for e in block_elements(bl) {
+            check_order(fname, &mut last, &e)?;
+            let k = e.key();
+            keys = Some(keys.map_or((k, k), |(f, _)| (f, k)));
+        }
+        if let Some((first, last)) = keys {
+            res.push((pos, first, last));
+        }
+    }
+    Ok(res)
+}
+
+const SNAPSHOT_BLOCK_SIZE: usize = 8000;
+
+//the elements selected by a bbox, from a first pass over the data: the
+//nodes inside it, the ways using those nodes and the relations with
+//selected members, along with the nodes of selected ways outside the box
+struct Selection {
+    nodes: BTreeSet<i64>,
+    ways: BTreeSet<i64>,
+    relations: BTreeSet<i64>
+}
+
+impl Selection {
+    fn find(fname: &str, ts: i64, bbox: &osmquadtree::elements::Bbox) -> PyResult<Selection> {
+        let mut res = Selection{nodes: BTreeSet::new(), ways: BTreeSet::new(), relations: BTreeSet::new()};
+        let mut extra_nodes = BTreeSet::new();
+        let mut scanner = AsOfScanner::open(fname, ts)?;
+        while let Some(e) = scanner.next_element()? {
+            match e {
+                HistElement::Node(n) => {
+                    if bbox.contains_point(n.lon, n.lat) {
+                        res.nodes.insert(n.id);
+                    }
+                },
+                HistElement::Way(w) => {
+                    if w.refs.iter().any(|r| res.nodes.contains(r)) {
+                        extra_nodes.extend(w.refs.iter().cloned());
+                        res.ways.insert(w.id);
+                    }
+                },
+                HistElement::Relation(r) => {
+                    if r.members.iter().any(|m| match m.mem_type {
+                        ElementType::Node => res.nodes.contains(&m.mem_ref),
+                        ElementType::Way => res.ways.contains(&m.mem_ref),
+                        ElementType::Relation => res.relations.contains(&m.mem_ref),
+                        _ => false
+                    }) {
+                        res.relations.insert(r.id);
+                    }
+                }
+            }
+        }
+        res.nodes.extend(extra_nodes);
+        Ok(res)
+    }
+
+    fn contains(&self, e: &HistElement) -> bool {
+        match e {
+            HistElement::Node(n) => self.nodes.contains(&n.id),
+            HistElement::Way(w) => self.ways.contains(&w.id),
+            HistElement::Relation(r) => self.relations.contains(&r.id)
+        }
+    }
+}
+
+/// The data as of a timestamp, returned by HistoryFile.snapshot. Each
+/// block holds up to 8000 elements of one type.
+#[pyclass]
+pub struct HistorySnapshot {
+    scanner: AsOfScanner,
+    selection: Option<Selection>,
+    pending: Option<HistElement>,
+    index: i64
+}
+
+impl HistorySnapshot {
+    fn next_selected(&mut self) -> PyResult<Option<HistElement>> {
+        if let Some(e) = self.pending.take() {
+            return Ok(Some(e));
+        }
+        while let Some(e) = self.scanner.next_element()? {
+            if self.selection.as_ref().map_or(true, |s| s.contains(&e)) {
+                return Ok(Some(e));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[pymethods]
+impl HistorySnapshot {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&mut self) -> PyResult<Option<crate::elements::PrimitiveBlock>> {
+        let mut bl = osmquadtree::elements::PrimitiveBlock::new(self.index, 0);
+        let mut block_type = None;
+        let mut count = 0;
+        while let Some(e) = self.next_selected()? {
+            let t = e.key().0;
+            if count == SNAPSHOT_BLOCK_SIZE || block_type.map_or(false, |bt| bt != t) {
+                self.pending = Some(e);
+                break;
+            }
+            block_type = Some(t);
+            count += 1;
+            match e {
+                HistElement::Node(n) => { bl.nodes.push(n); },
+                HistElement::Way(w) => { bl.ways.push(w); },
+                HistElement::Relation(r) => { bl.relations.push(r); }
+            }
+        }
+        if count == 0 {
+            return Ok(None);
+        }
+        self.index += 1;
+        Ok(Some(crate::elements::PrimitiveBlock::new(bl)))
+    }
+}
+
+
+/// A full-history pbf file, such as a history planet dump, holding every
+/// version of each element. Versions which aren't visible (deletions) have
+/// changetype "delete". The file must be sorted by type, id and version.
+#[pyclass]
+pub struct HistoryFile {
+    fname: String,
+    reader: Option<HistoryReader>,
+    block_index: Option<BlockIndex>
+}
+
+impl HistoryFile {
+    //the versions of element `key`, read from just the blocks which hold it.
+    //the block index is built on first use
+    fn find_versions(&mut self, key: (u8, i64)) -> PyResult<Vec<HistElement>> {
+        if self.block_index.is_none() {
+            self.block_index = Some(build_block_index(&self.fname)?);
+        }
+        let index = self.block_index.as_ref().unwrap();
+        let start = index.partition_point(|(_, _, last)| *last < key);
+
+        let mut reader = HistoryReader::open(&self.fname)?;
+        let mut res = Vec::new();
+        for (pos, first, _) in &index[start..] {
+            if *first > key {
+                break;
+            }
+            let bl = reader.read_at(*pos)?;
+            res.extend(block_elements(bl).filter(|e| e.key() == key));
+        }
+        Ok(res)
+    }
+}
+
+#[pymethods]
+impl HistoryFile {
+    #[new]
+    pub fn new(fname: &str) -> PyResult<HistoryFile> {
+        crate::errors::check_exists(fname)?;
+        Ok(HistoryFile{fname: String::from(fname), reader: None, block_index: None})
+    }
+
+    #[getter]
+    pub fn fname(&self) -> PyResult<String> { Ok(self.fname.clone()) }
+
+    fn __iter__(mut slf: PyRefMut<'_, Self>) -> PyResult<PyRefMut<'_, Self>> {
+        slf.reader = Some(HistoryReader::open(&slf.fname)?);
+        Ok(slf)
+    }
+
+    /// Returns each block, with every version of its elements.
+    fn __next__(&mut self) -> PyResult<Option<crate::elements::PrimitiveBlock>> {
+        let bl = match self.reader.as_mut() {
+            Some(r) => r.next_block()?,
+            None => { return Ok(None); }
+        };
+        if bl.is_none() {
+            self.reader = None;
+        }
+        Ok(bl.map(crate::elements::PrimitiveBlock::new))
+    }
+
+    /// Every version of element `id` of `element_type` ("node", "way" or
+    /// "relation"), oldest first. The first call reads the whole file to
+    /// index its blocks: later calls only read the blocks they need.
+    pub fn versions(&mut self, py: Python, element_type: &str, id: i64) -> PyResult<Vec<PyObject>> {
+        let key = (type_rank(element_type)?, id);
+        let vv = crate::cancel::run_interruptible(py, &[], || self.find_versions(key))?;
+        vv.into_iter().map(|e| e.into_object(py)).collect()
+    }
+
+    /// The version of element `id` current at `timestamp` (seconds since
+    /// 1970, or a timestamp string), or None if it didn't exist or had been
+    /// deleted. Uses the same block index as versions.
+    pub fn element_at(&mut self, py: Python, element_type: &str, id: i64, timestamp: PyObject) -> PyResult<Option<PyObject>> {
+        let key = (type_rank(element_type)?, id);
+        let ts = prep_timestamp(py, timestamp)?;
+        let vv = crate::cancel::run_interruptible(py, &[], || self.find_versions(key))?;
+        let best = vv.into_iter().filter(|e| e.timestamp() <= ts).last();
+        best.filter(|b| b.visible()).map(|e| e.into_object(py)).transpose()
+    }
+
+    /// Reconstructs the data as of `timestamp`, returning an iterator of
+    /// PrimitiveBlocks which reads the file as it goes. If `bbox` (minlon,
+    /// minlat, maxlon, maxlat in units of 1e-7 degrees) is given, returns the
+    /// nodes inside it, the ways using those nodes (with all their nodes) and
+    /// the relations with selected members: this needs a first pass over the
+    /// file, keeping only the selected ids.
+    #[pyo3(signature = (timestamp, bbox=None))]
+    pub fn snapshot(&self, py: Python, timestamp: PyObject, bbox: Option<(i32,i32,i32,i32)>) -> PyResult<HistorySnapshot> {
+        let ts = prep_timestamp(py, timestamp)?;
+        let selection = match bbox {
+            Some(b) => {
+                let bbox = osmquadtree::elements::Bbox::new(b.0, b.1, b.2, b.3);
+                let fname = self.fname.clone();
+                Some(crate::cancel::run_interruptible(py, &[], || Selection::find(&fname, ts, &bbox))?)
+            },
+            None => None
+        };
+        Ok(HistorySnapshot{scanner: AsOfScanner::open(&self.fname, ts)?, selection: selection, pending: None, index: 0})
+    }
+
+    fn __repr__(&self) -> PyResult<String> {
+        Ok(format!("HistoryFile {}", self.fname))
+    }
+}
+
+
+pub(crate) fn wrap_history(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<HistoryFile>()?;
+    m.add_class::<HistorySnapshot>()?;
+    Ok(())
+}
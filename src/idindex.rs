@@ -0,0 +1,268 @@
+use pyo3::prelude::*;
+use pyo3::wrap_pyfunction;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::{BufReader,BufWriter,Read,Seek,SeekFrom,Write};
+
+use crate::ErrorWrapped;
+
+
+const MAGIC: &[u8] = b"OQTIDX\x01\n";
+
+/// Sorted (id, quadtree) pairs for each element type, as calculated by
+/// run_calcqts. The index file is mapped into memory and searched in place,
+/// rather than read.
+pub(crate) struct IdIndex {
+    data: memmap2::Mmap,
+    //the offset and length of the nodes, ways and relations sections
+    sections: [(usize, usize); 3]
+}
+
+type IdQuadtrees = Vec<(i64, i64)>;
+
+fn write_section<W: Write>(w: &mut W, vals: &[(i64, i64)]) -> std::io::Result<()> {
+    w.write_all(&(vals.len() as u64).to_le_bytes())?;
+    for (a, b) in vals {
+        w.write_all(&a.to_le_bytes())?;
+        w.write_all(&b.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_i64(data: &[u8], pos: usize) -> i64 {
+    let mut b = [0u8; 8];
+    b.copy_from_slice(&data[pos..pos + 8]);
+    i64::from_le_bytes(b)
+}
+
+fn section_at(data: &[u8], pos: &mut usize) -> std::io::Result<(usize, usize)> {
+    let truncated = || std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "truncated id index");
+    if *pos + 8 > data.len() {
+        return Err(truncated());
+    }
+    let n = read_i64(data, *pos) as u64 as usize;
+    *pos += 8;
+    if n > (data.len() - *pos) / 16 {
+        return Err(truncated());
+    }
+    let res = (*pos, n);
+    *pos += n * 16;
+    Ok(res)
+}
+
+//the pairs of each element type held in memory before a sorted run is
+//written out
+const RUN_LEN: usize = 1 << 22;
+
+fn sort_dedup(vals: &mut IdQuadtrees) {
+    vals.sort();
+    vals.dedup_by_key(|(a, _)| *a);
+}
+
+//the (id, quadtree) pairs of one element type, in sorted runs written to
+//`<outfn>-run-<section>-<n>` once RUN_LEN are held, as the whole planet
+//doesn't fit in memory. the runs are removed when this is dropped
+struct SortedRuns {
+    prfx: String,
+    vals: IdQuadtrees,
+    files: Vec<String>
+}
+
+impl SortedRuns {
+    fn new(outfn: &str, section: usize) -> SortedRuns {
+        SortedRuns{prfx: format!("{}-run-{}", outfn, section), vals: Vec::new(), files: Vec::new()}
+    }
+
+    fn add(&mut self, id: i64, quadtree: i64) -> std::io::Result<()> {
+        self.vals.push((id, quadtree));
+        if self.vals.len() >= RUN_LEN {
+            self.write_run()?;
+        }
+        Ok(())
+    }
+
+    fn write_run(&mut self) -> std::io::Result<()> {
+        sort_dedup(&mut self.vals);
+        let fname = format!("{}-{}", self.prfx, self.files.len());
+        self.files.push(fname.clone());
+        let mut w = BufWriter::new(File::create(&fname)?);
+        for (a, b) in &self.vals {
+            w.write_all(&a.to_le_bytes())?;
+            w.write_all(&b.to_le_bytes())?;
+        }
+        w.flush()?;
+        self.vals.clear();
+        Ok(())
+    }
+
+    /// Writes the merged runs to `w` as a section, keeping the first
+    /// quadtree for each id, and returns the number of pairs.
+    fn write_section<W: Write + Seek>(&mut self, w: &mut W) -> std::io::Result<usize> {
+        if self.files.is_empty() {
+            sort_dedup(&mut self.vals);
+            write_section(w, &self.vals)?;
+            return Ok(self.vals.len());
+        }
+        if !self.vals.is_empty() {
+            self.write_run()?;
+        }
+
+        let mut runs = Vec::new();
+        for f in &self.files {
+            runs.push(BufReader::new(File::open(f)?));
+        }
+        let mut heap = BinaryHeap::new();
+        for (i, r) in runs.iter_mut().enumerate() {
+            if let Some(p) = read_pair(r)? {
+                heap.push(Reverse((p, i)));
+            }
+        }
+
+        //the length isn't known until the runs are merged
+        let start = w.stream_position()?;
+        w.write_all(&0u64.to_le_bytes())?;
+        let mut count = 0;
+        let mut last = None;
+        while let Some(Reverse(((id, qt), i))) = heap.pop() {
+            if last != Some(id) {
+                w.write_all(&id.to_le_bytes())?;
+                w.write_all(&qt.to_le_bytes())?;
+                count += 1;
+                last = Some(id);
+            }
+            if let Some(p) = read_pair(&mut runs[i])? {
+                heap.push(Reverse((p, i)));
+            }
+        }
+        let end = w.stream_position()?;
+        w.seek(SeekFrom::Start(start))?;
+        w.write_all(&(count as u64).to_le_bytes())?;
+        w.seek(SeekFrom::Start(end))?;
+        Ok(count)
+    }
+}
+
+impl Drop for SortedRuns {
+    fn drop(&mut self) {
+        for f in &self.files {
+            let _ = std::fs::remove_file(f);
+        }
+    }
+}
+
+fn read_pair<R: Read>(r: &mut R) -> std::io::Result<Option<(i64, i64)>> {
+    let mut b = [0u8; 16];
+    match r.read_exact(&mut b) {
+        Ok(()) => Ok(Some((read_i64(&b, 0), read_i64(&b, 8)))),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e)
+    }
+}
+
+/// Reads every block of `qtsfn`, the output of run_calcqts, and writes the
+/// sorted (id, quadtree) pairs for nodes, ways and relations to `outfn`.
+/// Returns the number of pairs.
+fn build(qtsfn: &str, outfn: &str) -> PyResult<usize> {
+    let flen = std::fs::metadata(qtsfn).map_err(|e| ErrorWrapped::from(e).with_filename(qtsfn))?.len();
+    let mut fbuf = BufReader::new(File::open(qtsfn).map_err(|e| ErrorWrapped::from(e).with_filename(qtsfn))?);
+    let out_error = |e: std::io::Error| PyErr::from(ErrorWrapped::from(e).with_filename(outfn));
+
+    let mut runs = [SortedRuns::new(outfn, 0), SortedRuns::new(outfn, 1), SortedRuns::new(outfn, 2)];
+    let mut index = 0;
+    while osmquadtree::pbfformat::file_position(&mut fbuf).map_err(ErrorWrapped::from)? < flen {
+        crate::cancel::check_cancelled()?;
+        let fb = crate::compression::read_file_block(&mut fbuf).map_err(ErrorWrapped::from)?;
+        if fb.block_type != "OSMData" {
+            continue;
+        }
+        let bl = osmquadtree::elements::MinimalBlock::read(index, fb.pos, &fb.data(), false)
+            .map_err(|e| ErrorWrapped::from(e).with_filename(qtsfn).with_position(fb.pos))?;
+        for n in &bl.nodes {
+            runs[0].add(n.id, n.quadtree.as_int()).map_err(out_error)?;
+        }
+        for w in &bl.ways {
+            runs[1].add(w.id, w.quadtree.as_int()).map_err(out_error)?;
+        }
+        for r in &bl.relations {
+            runs[2].add(r.id, r.quadtree.as_int()).map_err(out_error)?;
+        }
+        index += 1;
+    }
+
+    let mut w = BufWriter::new(File::create(outfn).map_err(out_error)?);
+    w.write_all(MAGIC).map_err(out_error)?;
+    let mut count = 0;
+    for r in runs.iter_mut() {
+        crate::cancel::check_cancelled()?;
+        count += r.write_section(&mut w).map_err(out_error)?;
+    }
+    w.flush().map_err(out_error)?;
+    Ok(count)
+}
+
+impl IdIndex {
+    pub fn read(infn: &str) -> PyResult<IdIndex> {
+        crate::errors::check_exists(infn)?;
+        let file = File::open(infn).map_err(|e| ErrorWrapped::from(e).with_filename(infn))?;
+        //the index is only written by build_id_index, and isn't changed while
+        //it is mapped
+        let data = unsafe { memmap2::Mmap::map(&file) }.map_err(|e| ErrorWrapped::from(e).with_filename(infn))?;
+        if !data.starts_with(MAGIC) {
+            return Err(crate::errors::invalid_input_error(format!("{} is not an id index", infn)));
+        }
+        let mut pos = MAGIC.len();
+        let mut sections = [(0, 0); 3];
+        for s in sections.iter_mut() {
+            *s = section_at(&data, &mut pos).map_err(|e| crate::errors::invalid_input_error(format!("{}: {}", infn, e)))?;
+        }
+        Ok(IdIndex{data: data, sections: sections})
+    }
+
+    fn lookup(&self, section: usize, id: i64) -> Option<osmquadtree::elements::Quadtree> {
+        let (start, len) = self.sections[section];
+        let (mut lo, mut hi) = (0, len);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            let p = start + mid * 16;
+            match read_i64(&self.data, p).cmp(&id) {
+                std::cmp::Ordering::Less => { lo = mid + 1; },
+                std::cmp::Ordering::Greater => { hi = mid; },
+                std::cmp::Ordering::Equal => { return Some(osmquadtree::elements::Quadtree::new(read_i64(&self.data, p + 8))); }
+            }
+        }
+        None
+    }
+
+    pub fn node(&self, id: i64) -> Option<osmquadtree::elements::Quadtree> { self.lookup(0, id) }
+    pub fn way(&self, id: i64) -> Option<osmquadtree::elements::Quadtree> { self.lookup(1, id) }
+    pub fn relation(&self, id: i64) -> Option<osmquadtree::elements::Quadtree> { self.lookup(2, id) }
+}
+
+/// Path of the id index kept with the dataset at `prfx`.
+pub(crate) fn default_index_path(prfx: &str) -> String {
+    String::from(std::path::Path::new(prfx).join("idindex.bin").to_string_lossy())
+}
+
+/// Builds an index from element id to quadtree from `qtsfn`, the output of
+/// run_calcqts, and writes it to `outfn` (by default idindex.bin in the
+/// dataset directory `prfx`), for Dataset.get_node, get_way and
+/// get_relation. Returns the number of elements indexed.
+#[pyfunction]
+#[pyo3(signature = (qtsfn, prfx=None, outfn=None))]
+pub fn build_id_index(py: Python, qtsfn: &str, prfx: Option<&str>, outfn: Option<&str>) -> PyResult<usize> {
+    crate::errors::check_exists(qtsfn)?;
+    let outfn = match (outfn, prfx) {
+        (Some(o), _) => String::from(o),
+        (None, Some(p)) => default_index_path(p),
+        (None, None) => { return Err(crate::errors::invalid_input_error(String::from("one of prfx or outfn must be given"))); }
+    };
+
+    crate::cancel::run_interruptible(py, &[outfn.clone()], || build(qtsfn, &outfn))
+}
+
+
+pub(crate) fn wrap_idindex(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_wrapped(wrap_pyfunction!(build_id_index))?;
+    Ok(())
+}
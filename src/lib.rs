@@ -3,16 +3,54 @@ mod elements;
 mod readpbf;
 mod messaging;
 mod sortblocks;
+mod cancel;
+mod errors;
+mod writepbf;
+mod update;
+mod xml;
+mod columns;
+mod geoparquet;
+mod postgis;
+mod tiles;
+mod tagfilter;
+mod multipolygon;
+mod dataset;
+mod idindex;
+mod history;
+mod augdiff;
+mod compare;
+mod checkpoint;
+mod blockindex;
+mod compression;
+mod verify;
+mod extract;
 use pyo3::prelude::*;
 
 mod geometry;
 
-//use osmquadtree::Error;
-use pyo3::exceptions::PyOSError;
+use crate::errors::{error_kind,make_error};
 
 #[derive(Debug)]
-struct ErrorWrapped {
-    e: osmquadtree::Error
+pub(crate) struct ErrorWrapped {
+    e: osmquadtree::Error,
+    filename: Option<String>,
+    position: Option<u64>
+}
+
+impl ErrorWrapped {
+    pub fn new(e: osmquadtree::Error) -> ErrorWrapped {
+        ErrorWrapped{e: e, filename: None, position: None}
+    }
+    
+    pub fn with_filename(mut self, filename: &str) -> ErrorWrapped {
+        self.filename = Some(String::from(filename));
+        self
+    }
+    
+    pub fn with_position(mut self, position: u64) -> ErrorWrapped {
+        self.position = Some(position);
+        self
+    }
 }
 
 impl std::fmt::Display for ErrorWrapped {
@@ -25,14 +63,24 @@ impl std::error::Error for ErrorWrapped {}
 
 impl std::convert::From<osmquadtree::Error> for ErrorWrapped {
     fn from(err: osmquadtree::Error) -> ErrorWrapped {
-        ErrorWrapped{e: err}
+        ErrorWrapped::new(err)
+    }
+}
+
+impl std::convert::From<std::io::Error> for ErrorWrapped {
+    fn from(err: std::io::Error) -> ErrorWrapped {
+        ErrorWrapped::new(osmquadtree::Error::Io(err))
     }
 }
 
 
 impl std::convert::From<ErrorWrapped> for pyo3::PyErr {
     fn from(err: ErrorWrapped) -> pyo3::PyErr {
-        PyOSError::new_err(err.e.to_string())
+        let msg = match &err.filename {
+            Some(f) => format!("{}: {}", f, err.e),
+            None => err.e.to_string()
+        };
+        make_error(error_kind(&err.e), msg, err.filename, err.position, None)
     }
 }
 
@@ -46,5 +94,26 @@ fn rust(_py: Python<'_>, m: &Bound<'_, PyModule>) -> PyResult<()> {
     messaging::wrap_messaging(m)?;
     sortblocks::wrap_sortblocks(m)?;
     geometry::wrap_geometry(m)?;
+    cancel::wrap_cancel(m)?;
+    errors::wrap_errors(m)?;
+    writepbf::wrap_writepbf(m)?;
+    update::wrap_update(m)?;
+    xml::wrap_xml(m)?;
+    columns::wrap_columns(m)?;
+    geoparquet::wrap_geoparquet(m)?;
+    postgis::wrap_postgis(m)?;
+    tiles::wrap_tiles(m)?;
+    tagfilter::wrap_tagfilter(m)?;
+    multipolygon::wrap_multipolygon(m)?;
+    dataset::wrap_dataset(m)?;
+    idindex::wrap_idindex(m)?;
+    history::wrap_history(m)?;
+    augdiff::wrap_augdiff(m)?;
+    compare::wrap_compare(m)?;
+    checkpoint::wrap_checkpoint(m)?;
+    blockindex::wrap_blockindex(m)?;
+    compression::wrap_compression(m)?;
+    verify::wrap_verify(m)?;
+    extract::wrap_extract(m)?;
     Ok(())
 }
@@ -5,6 +5,23 @@ use pyo3::wrap_pyfunction;
 use osmquadtree::logging::{ProgressBytes,ProgressPercent,Messenger,TaskSequence};
 use std::cell::RefCell;
 
+/// Calls a method on the python messenger or progress object. Does nothing
+/// (and returns None) if the object is None, or if it raises an exception,
+/// which is kept for run_interruptible to raise (see
+/// cancel::set_callback_error).
+fn call_progress<'py, A: IntoPyObject<'py, Target = pyo3::types::PyTuple>>(py: Python<'py>, obj: &PyObject, name: &str, args: A) -> PyObject {
+    if obj.is_none(py) {
+        return py.None();
+    }
+    match obj.call_method1(py, name, args) {
+        Ok(r) => r,
+        Err(e) => {
+            crate::cancel::set_callback_error(py, e);
+            py.None()
+        }
+    }
+}
+
 
 pub struct ProgressBytesPython {
     obj: PyObject,
@@ -24,7 +41,7 @@ impl ProgressBytes for ProgressBytesPython {
     
     fn change_message(&self, new_message: &str) {
         
-        Python::with_gil(|py| { self.obj.call_method1(py, "set_message", (new_message,)).expect("!!"); });
+        Python::with_gil(|py| { call_progress(py, &self.obj, "set_message", (new_message,)); });
         
         /*let gil_guard = Python::acquire_gil();
         let py = gil_guard.python();
@@ -38,7 +55,7 @@ impl ProgressBytes for ProgressBytesPython {
             //let gil_guard = Python::acquire_gil();
             //let py = gil_guard.python();
             Python::with_gil(|py| { 
-                self.obj.call_method1(py, "progress_bytes", (bytes,)).expect("!!");
+                call_progress(py, &self.obj, "progress_bytes", (bytes,));
             });
             self.timer.borrow_mut().reset();
         }
@@ -50,8 +67,8 @@ impl ProgressBytes for ProgressBytesPython {
         //let py = gil_guard.python();
         
         Python::with_gil(|py| { 
-            self.obj.call_method1(py, "progress_bytes", (*self.bytes.borrow(),)).expect("!!");
-            self.obj.call_method0(py, "finish").expect("!!");
+            call_progress(py, &self.obj, "progress_bytes", (*self.bytes.borrow(),));
+            call_progress(py, &self.obj, "finish", ());
         });
        
         
@@ -79,7 +96,7 @@ impl ProgressPercent for ProgressPercentPython {
         //let py = gil_guard.python();
         
         Python::with_gil(|py| { 
-            self.obj.call_method1(py, "set_message", (new_message,)).expect("!!");
+            call_progress(py, &self.obj, "set_message", (new_message,));
         });
     }
     
@@ -89,7 +106,7 @@ impl ProgressPercent for ProgressPercentPython {
         //let py = gil_guard.python();
         
         Python::with_gil(|py| { 
-            self.obj.call_method1(py, "progress_percent", (percent,)).expect("!!");
+            call_progress(py, &self.obj, "progress_percent", (percent,));
         });
         
         
@@ -99,7 +116,7 @@ impl ProgressPercent for ProgressPercentPython {
         //let py = gil_guard.python();
         
         Python::with_gil(|py| { 
-            self.obj.call_method0(py, "finish").expect("!!");
+            call_progress(py, &self.obj, "finish", ());
         });
        
         
@@ -130,12 +147,11 @@ impl Messenger for MessengerPython {
     
     
     fn message(&self, message: &str) {
-        
         //let gil_guard = Python::acquire_gil();
         //let py = gil_guard.python();
         
         Python::with_gil(|py| { 
-            self.obj.call_method1(py, "message", (message,)).expect("!!");
+            call_progress(py, &self.obj, "message", (message,));
         });
     }
     
@@ -145,7 +161,7 @@ impl Messenger for MessengerPython {
         //let py = gil_guard.python();
         
         let nobj = Python::with_gil(|py| { 
-            self.obj.call_method1(py, "start_progress_percent", (message,)).expect("!!")
+            call_progress(py, &self.obj, "start_progress_percent", (message,))
         });
         
         ProgressPercentPython::new(nobj)
@@ -156,7 +172,7 @@ impl Messenger for MessengerPython {
         //let py = gil_guard.python();
         
         let nobj = Python::with_gil(|py| { 
-            self.obj.call_method1(py, "start_progress_bytes", (message,total_bytes)).expect("!!")
+            call_progress(py, &self.obj, "start_progress_bytes", (message,total_bytes))
         });
         
         ProgressBytesPython::new(nobj)
@@ -0,0 +1,354 @@
+use pyo3::prelude::*;
+use pyo3::wrap_pyfunction;
+use std::collections::{BTreeMap,BTreeSet};
+
+use osmquadtree::elements::ElementType;
+use osmquadtree_geometry::LonLat;
+
+use crate::elements::{Node,Way,Relation};
+use crate::geometry::ComplicatedPolygonGeometry;
+
+
+//a member way, with the locations of its nodes
+struct WayPart {
+    id: i64,
+    refs: Vec<i64>,
+    lonlats: Vec<LonLat>
+}
+
+//a closed ring, as a sequence of (way index, is_reversed)
+struct RingChain {
+    parts: Vec<(usize, bool)>,
+    refs: Vec<i64>,
+    lonlats: Vec<LonLat>,
+    area: f64
+}
+
+impl RingChain {
+    fn new(ways: &[WayPart], parts: Vec<(usize, bool)>) -> RingChain {
+        let mut refs = Vec::new();
+        let mut lonlats = Vec::new();
+        for (i, (w, rev)) in parts.iter().enumerate() {
+            let mut rr = ways[*w].refs.clone();
+            let mut ll = ways[*w].lonlats.clone();
+            if *rev {
+                rr.reverse();
+                ll.reverse();
+            }
+            //each part starts at the end of the previous one
+            let skip = if i == 0 { 0 } else { 1 };
+            refs.extend(rr.into_iter().skip(skip));
+            lonlats.extend(ll.into_iter().skip(skip));
+        }
+        let area = signed_area(&lonlats);
+        RingChain{parts: parts, refs: refs, lonlats: lonlats, area: area}
+    }
+
+    fn reverse(&mut self) {
+        self.parts.reverse();
+        for p in self.parts.iter_mut() {
+            p.1 = !p.1;
+        }
+        self.refs.reverse();
+        self.lonlats.reverse();
+        self.area = -self.area;
+    }
+
+    fn way_ids(&self, ways: &[WayPart]) -> Vec<i64> {
+        self.parts.iter().map(|(w, _)| ways[*w].id).collect()
+    }
+}
+
+//positive for anti-clockwise rings
+fn signed_area(lonlats: &[LonLat]) -> f64 {
+    let mut a = 0.0;
+    for i in 1..lonlats.len() {
+        let (p, q) = (&lonlats[i - 1], &lonlats[i]);
+        a += (p.lon as f64) * (q.lat as f64) - (q.lon as f64) * (p.lat as f64);
+    }
+    a / 2.0
+}
+
+fn orient(p: &LonLat, q: &LonLat, r: &LonLat) -> i128 {
+    let v = ((q.lon as i128) - (p.lon as i128)) * ((r.lat as i128) - (p.lat as i128))
+        - ((q.lat as i128) - (p.lat as i128)) * ((r.lon as i128) - (p.lon as i128));
+    v.signum()
+}
+
+fn on_segment(p: &LonLat, q: &LonLat, r: &LonLat) -> bool {
+    //r is collinear with p-q: check it lies within the segment
+    r.lon >= p.lon.min(q.lon) && r.lon <= p.lon.max(q.lon) && r.lat >= p.lat.min(q.lat) && r.lat <= p.lat.max(q.lat)
+}
+
+fn segments_intersect(a: &LonLat, b: &LonLat, c: &LonLat, d: &LonLat) -> bool {
+    let (o1, o2, o3, o4) = (orient(a, b, c), orient(a, b, d), orient(c, d, a), orient(c, d, b));
+    if o1 != o2 && o3 != o4 && o1 != 0 && o2 != 0 && o3 != 0 && o4 != 0 {
+        return true;
+    }
+    (o1 == 0 && on_segment(a, b, c)) || (o2 == 0 && on_segment(a, b, d))
+        || (o3 == 0 && on_segment(c, d, a)) || (o4 == 0 && on_segment(c, d, b))
+}
+
+//returns the location of a self intersection, if any. a ring which passes
+//through the same node twice also counts as self-intersecting
+fn find_self_intersection(ring: &RingChain) -> Option<LonLat> {
+    let n = ring.lonlats.len() - 1;
+
+    let mut seen = BTreeSet::new();
+    for i in 0..n {
+        if !seen.insert(ring.refs[i]) {
+            return Some(ring.lonlats[i].clone());
+        }
+    }
+
+    //sweep over the segments in order of minimum lon
+    let mut segs: Vec<(i32, i32, usize)> = (0..n).map(|i| {
+        let (p, q) = (&ring.lonlats[i], &ring.lonlats[i + 1]);
+        (p.lon.min(q.lon), p.lon.max(q.lon), i)
+    }).collect();
+    segs.sort();
+
+    for (x, (_, maxlon, i)) in segs.iter().enumerate() {
+        for (minlon2, _, j) in &segs[x + 1..] {
+            if minlon2 > maxlon {
+                break;
+            }
+            let (i, j) = (usize::min(*i, *j), usize::max(*i, *j));
+            //adjacent segments share an end point
+            if j == i + 1 || (i == 0 && j == n - 1) {
+                continue;
+            }
+            let (a, b, c, d) = (&ring.lonlats[i], &ring.lonlats[i + 1], &ring.lonlats[j], &ring.lonlats[j + 1]);
+            if segments_intersect(a, b, c, d) {
+                return Some(a.clone());
+            }
+        }
+    }
+    None
+}
+
+fn point_in_ring(p: (f64, f64), ring: &RingChain) -> bool {
+    let mut inside = false;
+    let ll = &ring.lonlats;
+    for i in 1..ll.len() {
+        let (a, b) = (&ll[i - 1], &ll[i]);
+        if ((a.lat as f64) > p.1) != ((b.lat as f64) > p.1) {
+            let x = (a.lon as f64) + (p.1 - (a.lat as f64)) * ((b.lon as f64) - (a.lon as f64)) / ((b.lat as f64) - (a.lat as f64));
+            if p.0 < x {
+                inside = !inside;
+            }
+        }
+    }
+    inside
+}
+
+//rings may share nodes, so test a node of `inner` which isn't part of
+//`outer`, or else the middle of a segment which isn't an edge of `outer`
+fn ring_in_ring(inner: &RingChain, outer: &RingChain) -> bool {
+    let outer_refs: BTreeSet<i64> = outer.refs.iter().cloned().collect();
+    for (r, ll) in inner.refs.iter().zip(inner.lonlats.iter()) {
+        if !outer_refs.contains(r) {
+            return point_in_ring((ll.lon as f64, ll.lat as f64), outer);
+        }
+    }
+
+    let edge = |a: i64, b: i64| (i64::min(a, b), i64::max(a, b));
+    let outer_edges: BTreeSet<(i64, i64)> = outer.refs.windows(2).map(|w| edge(w[0], w[1])).collect();
+    for i in 1..inner.refs.len() {
+        if !outer_edges.contains(&edge(inner.refs[i - 1], inner.refs[i])) {
+            let (a, b) = (&inner.lonlats[i - 1], &inner.lonlats[i]);
+            return point_in_ring(((a.lon as f64 + b.lon as f64) / 2.0, (a.lat as f64 + b.lat as f64) / 2.0), outer);
+        }
+    }
+    //the same ring twice
+    false
+}
+
+//returns two rings which cross, and the location, if any. rings may touch
+//at shared nodes, so segments sharing an end node are not compared
+fn find_ring_crossing(rings: &[RingChain]) -> Option<(usize, usize, LonLat)> {
+    //sweep over the segments of every ring in order of minimum lon
+    let mut segs: Vec<(i32, i32, usize, usize)> = Vec::new();
+    for (r, ring) in rings.iter().enumerate() {
+        for i in 1..ring.lonlats.len() {
+            let (p, q) = (&ring.lonlats[i - 1], &ring.lonlats[i]);
+            segs.push((p.lon.min(q.lon), p.lon.max(q.lon), r, i - 1));
+        }
+    }
+    segs.sort();
+
+    for (x, (_, maxlon, r, i)) in segs.iter().enumerate() {
+        for (minlon2, _, r2, j) in &segs[x + 1..] {
+            if minlon2 > maxlon {
+                break;
+            }
+            if r == r2 {
+                continue;
+            }
+            let (a, b) = (&rings[*r], &rings[*r2]);
+            if a.refs[*i] == b.refs[*j] || a.refs[*i] == b.refs[*j + 1] || a.refs[*i + 1] == b.refs[*j] || a.refs[*i + 1] == b.refs[*j + 1] {
+                continue;
+            }
+            if segments_intersect(&a.lonlats[*i], &a.lonlats[*i + 1], &b.lonlats[*j], &b.lonlats[*j + 1]) {
+                return Some((usize::min(*r, *r2), usize::max(*r, *r2), a.lonlats[*i].clone()));
+            }
+        }
+    }
+    None
+}
+
+fn join_rings(rel_id: i64, ways: &[WayPart]) -> PyResult<Vec<RingChain>> {
+    let mut rings = Vec::new();
+    let mut used = vec![false; ways.len()];
+
+    for start in 0..ways.len() {
+        if used[start] {
+            continue;
+        }
+        used[start] = true;
+        let mut parts = vec![(start, false)];
+        let first = ways[start].refs[0];
+        let mut last = *ways[start].refs.last().unwrap();
+
+        while last != first {
+            let next = (0..ways.len()).filter(|w| !used[*w]).find_map(|w| {
+                if ways[w].refs[0] == last {
+                    Some((w, false))
+                } else if *ways[w].refs.last().unwrap() == last {
+                    Some((w, true))
+                } else {
+                    None
+                }
+            });
+            match next {
+                None => {
+                    let ids: Vec<i64> = parts.iter().map(|(w, _)| ways[*w].id).collect();
+                    return Err(crate::errors::geometry_error(
+                        format!("relation {}: unclosed ring: ways {:?} run from node {} to node {}", rel_id, ids, first, last), rel_id));
+                },
+                Some((w, rev)) => {
+                    used[w] = true;
+                    last = if rev { ways[w].refs[0] } else { *ways[w].refs.last().unwrap() };
+                    parts.push((w, rev));
+                }
+            }
+        }
+
+        let ring = RingChain::new(ways, parts);
+        if ring.refs.len() < 4 {
+            return Err(crate::errors::geometry_error(
+                format!("relation {}: ring of ways {:?} has fewer than three nodes", rel_id, ring.way_ids(ways)), rel_id));
+        }
+        if let Some(ll) = find_self_intersection(&ring) {
+            return Err(crate::errors::geometry_error(
+                format!("relation {}: ring of ways {:?} is self-intersecting near ({}, {})", rel_id, ring.way_ids(ways), ll.lon, ll.lat), rel_id));
+        }
+        rings.push(ring);
+    }
+    if let Some((a, b, ll)) = find_ring_crossing(&rings) {
+        return Err(crate::errors::geometry_error(
+            format!("relation {}: rings of ways {:?} and {:?} cross near ({}, {})", rel_id, rings[a].way_ids(ways), rings[b].way_ids(ways), ll.lon, ll.lat), rel_id));
+    }
+    Ok(rings)
+}
+
+fn make_ring(ways: &[WayPart], chain: &RingChain) -> PyResult<osmquadtree_geometry::Ring> {
+    let mut ring = osmquadtree_geometry::Ring::new();
+    for (w, rev) in &chain.parts {
+        let wp = &ways[*w];
+        ring.parts.push(osmquadtree_geometry::RingPart::new(wp.id, *rev, wp.refs.clone(), wp.lonlats.clone()));
+    }
+    ring.calc_area_bbox()?;
+    Ok(ring)
+}
+
+/// Assembles the polygons of multipolygon (or boundary) `relation` from its
+/// member `ways` and their `nodes`, as process_geometry does but without a
+/// style: all the relation's tags apart from "type" are kept. Rings are
+/// nested by containment, whatever the member roles say. Raises a
+/// GeometryError if a member is missing, if a ring is unclosed or
+/// self-intersecting, or if two rings cross.
+#[pyfunction]
+pub fn assemble_multipolygon(relation: &Relation, ways: Vec<PyRef<Way>>, nodes: Vec<PyRef<Node>>) -> PyResult<ComplicatedPolygonGeometry> {
+    let rel = relation.get_ele();
+
+    let mut way_map = BTreeMap::new();
+    for w in &ways {
+        way_map.insert(w.get_ele().id, w.get_ele());
+    }
+    let mut node_map = BTreeMap::new();
+    for n in &nodes {
+        let n = n.get_ele();
+        node_map.insert(n.id, LonLat::new(n.lon, n.lat));
+    }
+
+    let mut parts = Vec::new();
+    let mut seen = BTreeSet::new();
+    for m in &rel.members {
+        if !matches!(m.mem_type, ElementType::Way) || !(m.role.is_empty() || m.role == "outer" || m.role == "inner") {
+            continue;
+        }
+        if !seen.insert(m.mem_ref) {
+            continue;
+        }
+        let w = match way_map.get(&m.mem_ref) {
+            Some(w) => w,
+            None => { return Err(crate::errors::geometry_error(format!("relation {}: missing member way {}", rel.id, m.mem_ref), rel.id)); }
+        };
+        if w.refs.len() < 2 {
+            return Err(crate::errors::geometry_error(format!("relation {}: way {} has fewer than two nodes", rel.id, w.id), rel.id));
+        }
+        let mut lonlats = Vec::with_capacity(w.refs.len());
+        for r in &w.refs {
+            match node_map.get(r) {
+                Some(ll) => { lonlats.push(ll.clone()); },
+                None => { return Err(crate::errors::geometry_error(format!("relation {}: missing node {} of way {}", rel.id, r, w.id), rel.id)); }
+            }
+        }
+        parts.push(WayPart{id: w.id, refs: w.refs.clone(), lonlats: lonlats});
+    }
+    if parts.is_empty() {
+        return Err(crate::errors::geometry_error(format!("relation {}: no outer or inner member ways", rel.id), rel.id));
+    }
+
+    let mut rings = join_rings(rel.id, &parts)?;
+
+    //largest first, so each ring's container has already been placed
+    rings.sort_by(|a, b| b.area.abs().partial_cmp(&a.area.abs()).unwrap());
+    let mut depth: Vec<usize> = Vec::with_capacity(rings.len());
+    let mut container: Vec<Option<usize>> = Vec::with_capacity(rings.len());
+    for i in 0..rings.len() {
+        let c = (0..i).rev().find(|j| ring_in_ring(&rings[i], &rings[*j]));
+        depth.push(c.map_or(0, |j| depth[j] + 1));
+        container.push(c);
+    }
+
+    let mut polys: Vec<osmquadtree_geometry::PolygonPart> = Vec::new();
+    let mut poly_idx: BTreeMap<usize, usize> = BTreeMap::new();
+    for i in 0..rings.len() {
+        //exteriors anti-clockwise, interiors clockwise
+        let is_exterior = depth[i] % 2 == 0;
+        if (rings[i].area > 0.0) != is_exterior {
+            rings[i].reverse();
+        }
+        let ring = make_ring(&parts, &rings[i])?;
+        if is_exterior {
+            poly_idx.insert(i, polys.len());
+            polys.push(osmquadtree_geometry::PolygonPart::new(ring));
+        } else {
+            let p = poly_idx[&container[i].unwrap()];
+            polys[p].add_interior(ring);
+        }
+    }
+
+    let tags: Vec<osmquadtree::elements::Tag> = rel.tags.iter().filter(|t| t.key != "type").cloned().collect();
+    let layer = tags.iter().find(|t| t.key == "layer").and_then(|t| t.val.parse::<i64>().ok());
+
+    ComplicatedPolygonGeometry::as_item(osmquadtree_geometry::ComplicatedPolygonGeometry::new(rel, tags, None, layer, polys))
+}
+
+
+pub(crate) fn wrap_multipolygon(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_wrapped(wrap_pyfunction!(assemble_multipolygon))?;
+    Ok(())
+}
@@ -0,0 +1,389 @@
+use pyo3::prelude::*;
+use pyo3::wrap_pyfunction;
+use std::fs::File;
+use std::io::{BufWriter,Write};
+
+use channelled_callbacks::{CallFinish,Timings,Result as ccResult};
+use osmquadtree::utils::Error;
+
+use crate::geometry::ResultSlot;
+use crate::geoparquet::{TableKind,TagColumns,TagType,TagValue,GeometryRow,block_rows};
+
+
+#[derive(Clone,Copy,PartialEq)]
+enum CopyFormat {
+    Binary,
+    Text,
+    Sql
+}
+
+impl CopyFormat {
+    fn from_str(s: &str) -> PyResult<CopyFormat> {
+        match s {
+            "binary" => Ok(CopyFormat::Binary),
+            "text" => Ok(CopyFormat::Text),
+            "sql" => Ok(CopyFormat::Sql),
+            _ => Err(crate::errors::invalid_input_error(format!("unknown format {}: expected binary, text or sql", s)))
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            CopyFormat::Binary => "copy",
+            CopyFormat::Text => "tsv",
+            CopyFormat::Sql => "sql"
+        }
+    }
+}
+
+#[derive(Clone,Copy)]
+enum ColumnType {
+    BigInt,
+    Double,
+    Text,
+    Jsonb,
+    Geometry
+}
+
+struct Column {
+    name: String,
+    ctype: ColumnType
+}
+
+fn quote_ident(s: &str) -> String {
+    format!("\"{}\"", s.replace('"', "\"\""))
+}
+
+fn srid(transform: bool) -> i64 {
+    if transform { 3857 } else { 4326 }
+}
+
+/// Columns of each table, as in the GeoParquet export.
+fn table_columns(kind: TableKind, tag_columns: &TagColumns) -> Vec<Column> {
+    let mut res = vec![
+        Column{name: String::from("osm_id"), ctype: ColumnType::BigInt},
+        Column{name: String::from("quadtree"), ctype: ColumnType::BigInt},
+        Column{name: String::from("minzoom"), ctype: ColumnType::BigInt},
+        Column{name: String::from("layer"), ctype: ColumnType::BigInt},
+    ];
+    if kind != TableKind::Point {
+        res.push(Column{name: String::from("z_order"), ctype: ColumnType::BigInt});
+    }
+    if let Some(m) = kind.measure() {
+        res.push(Column{name: String::from(m), ctype: ColumnType::Double});
+    }
+    for (k, t) in tag_columns.keys().iter().zip(tag_columns.types()) {
+        let ctype = match t {
+            TagType::Text => ColumnType::Text,
+            TagType::Int => ColumnType::BigInt
+        };
+        res.push(Column{name: k.clone(), ctype: ctype});
+    }
+    if tag_columns.has_other_tags() {
+        res.push(Column{name: String::from("other_tags"), ctype: ColumnType::Jsonb});
+    }
+    res.push(Column{name: String::from("geometry"), ctype: ColumnType::Geometry});
+    res
+}
+
+fn table_name(table_prefix: &str, kind: TableKind) -> String {
+    quote_ident(&format!("{}{}", table_prefix, kind.name()))
+}
+
+fn create_table_sql(table_prefix: &str, kind: TableKind, tag_columns: &TagColumns, transform: bool) -> String {
+    let geomtype = match kind {
+        TableKind::Point => "Point",
+        TableKind::Line => "LineString",
+        //complicated polygons may be multipolygons
+        TableKind::Polygon => "Geometry"
+    };
+    let cols: Vec<String> = table_columns(kind, tag_columns).iter().map(|c| {
+        let t = match c.ctype {
+            ColumnType::BigInt => String::from("bigint"),
+            ColumnType::Double => String::from("double precision"),
+            ColumnType::Text => String::from("text"),
+            ColumnType::Jsonb => String::from("jsonb"),
+            ColumnType::Geometry => format!("geometry({}, {})", geomtype, srid(transform))
+        };
+        format!("    {} {}", quote_ident(&c.name), t)
+    }).collect();
+    format!("CREATE TABLE {} (\n{}\n);\n", table_name(table_prefix, kind), cols.join(",\n"))
+}
+
+fn copy_sql(table_prefix: &str, kind: TableKind, tag_columns: &TagColumns, format: CopyFormat) -> String {
+    let cols: Vec<String> = table_columns(kind, tag_columns).iter().map(|c| quote_ident(&c.name)).collect();
+    match format {
+        CopyFormat::Binary => format!("COPY {} ({}) FROM STDIN WITH (FORMAT binary);\n", table_name(table_prefix, kind), cols.join(", ")),
+        _ => format!("COPY {} ({}) FROM stdin;\n", table_name(table_prefix, kind), cols.join(", "))
+    }
+}
+
+fn create_index_sql(table_prefix: &str, kind: TableKind) -> String {
+    let idx = quote_ident(&format!("{}{}_geometry_idx", table_prefix, kind.name()));
+    format!("CREATE INDEX {} ON {} USING gist (\"geometry\");\n", idx, table_name(table_prefix, kind))
+}
+
+enum CopyValue {
+    Int(Option<i64>),
+    Float(Option<f64>),
+    Text(Option<String>),
+    Json(Option<String>),
+    Geometry(Vec<u8>)
+}
+
+//text format escapes, see the postgresql COPY documentation
+fn escape_text(s: &str, out: &mut Vec<u8>) {
+    for c in s.bytes() {
+        match c {
+            b'\\' => out.extend_from_slice(b"\\\\"),
+            b'\t' => out.extend_from_slice(b"\\t"),
+            b'\n' => out.extend_from_slice(b"\\n"),
+            b'\r' => out.extend_from_slice(b"\\r"),
+            _ => out.push(c)
+        }
+    }
+}
+
+fn pack_text_row(vals: &[CopyValue], out: &mut Vec<u8>) {
+    for (i, v) in vals.iter().enumerate() {
+        if i > 0 {
+            out.push(b'\t');
+        }
+        match v {
+            CopyValue::Int(Some(x)) => out.extend_from_slice(x.to_string().as_bytes()),
+            CopyValue::Float(Some(x)) => out.extend_from_slice(x.to_string().as_bytes()),
+            CopyValue::Text(Some(x)) | CopyValue::Json(Some(x)) => escape_text(x, out),
+            //postgis reads hex encoded ewkb
+            CopyValue::Geometry(g) => {
+                for b in g {
+                    out.extend_from_slice(format!("{:02X}", b).as_bytes());
+                }
+            },
+            _ => out.extend_from_slice(b"\\N")
+        }
+    }
+    out.push(b'\n');
+}
+
+fn pack_binary_field(data: Option<&[u8]>, out: &mut Vec<u8>) {
+    match data {
+        None => out.extend_from_slice(&(-1i32).to_be_bytes()),
+        Some(d) => {
+            out.extend_from_slice(&(d.len() as i32).to_be_bytes());
+            out.extend_from_slice(d);
+        }
+    }
+}
+
+fn pack_binary_row(vals: &[CopyValue], out: &mut Vec<u8>) {
+    out.extend_from_slice(&(vals.len() as i16).to_be_bytes());
+    for v in vals {
+        match v {
+            CopyValue::Int(x) => pack_binary_field(x.map(|x| x.to_be_bytes()).as_ref().map(|b| &b[..]), out),
+            CopyValue::Float(x) => pack_binary_field(x.map(|x| x.to_be_bytes()).as_ref().map(|b| &b[..]), out),
+            CopyValue::Text(x) => pack_binary_field(x.as_ref().map(|s| s.as_bytes()), out),
+            CopyValue::Json(x) => {
+                //binary jsonb is a version byte followed by the json text
+                let j = x.as_ref().map(|s| { let mut j = vec![1u8]; j.extend_from_slice(s.as_bytes()); j });
+                pack_binary_field(j.as_ref().map(|j| &j[..]), out);
+            },
+            //geometry_recv reads ewkb
+            CopyValue::Geometry(g) => pack_binary_field(Some(g), out)
+        }
+    }
+}
+
+const BINARY_HEADER: &[u8] = b"PGCOPY\n\xff\r\n\0\0\0\0\0\0\0\0\0";
+const BINARY_TRAILER: &[u8] = b"\xff\xff";
+
+struct CopyTable {
+    kind: TableKind,
+    outfn: String,
+    out: Option<BufWriter<File>>,
+    format: CopyFormat,
+    table_prefix: String,
+    count: usize,
+    buf: Vec<u8>
+}
+
+impl CopyTable {
+    fn new(kind: TableKind, outprfx: &str, table_prefix: &str, format: CopyFormat, tag_columns: &TagColumns, transform: bool) -> std::result::Result<CopyTable, String> {
+        let outfn = format!("{}{}.{}", outprfx, kind.name(), format.extension());
+        let mut out = BufWriter::new(File::create(&outfn).map_err(|e| format!("{}: {}", outfn, e))?);
+        let header = match format {
+            CopyFormat::Binary => BINARY_HEADER.to_vec(),
+            CopyFormat::Text => Vec::new(),
+            CopyFormat::Sql => format!("{}\n{}", create_table_sql(table_prefix, kind, tag_columns, transform), copy_sql(table_prefix, kind, tag_columns, format)).into_bytes()
+        };
+        out.write_all(&header).map_err(|e| format!("{}: {}", outfn, e))?;
+        Ok(CopyTable{kind: kind, outfn: outfn, out: Some(out), format: format, table_prefix: String::from(table_prefix), count: 0, buf: Vec::new()})
+    }
+
+    fn add_row(&mut self, tag_columns: &TagColumns, row: GeometryRow) -> std::result::Result<(), String> {
+        let mut vals = vec![CopyValue::Int(Some(row.osm_id)), CopyValue::Int(Some(row.quadtree)), CopyValue::Int(row.minzoom), CopyValue::Int(row.layer)];
+        if self.kind != TableKind::Point {
+            vals.push(CopyValue::Int(row.z_order));
+        }
+        if self.kind.measure().is_some() {
+            vals.push(CopyValue::Float(row.measure));
+        }
+        let (tags, others) = tag_columns.split(row.tags);
+        for t in tags {
+            vals.push(match t {
+                TagValue::Text(v) => CopyValue::Text(v),
+                TagValue::Int(v) => CopyValue::Int(v)
+            });
+        }
+        if tag_columns.has_other_tags() {
+            vals.push(CopyValue::Json(others));
+        }
+        vals.push(CopyValue::Geometry(row.wkb));
+
+        self.buf.clear();
+        match self.format {
+            CopyFormat::Binary => pack_binary_row(&vals, &mut self.buf),
+            _ => pack_text_row(&vals, &mut self.buf)
+        }
+        match self.out.as_mut() {
+            Some(o) => o.write_all(&self.buf).map_err(|e| format!("{}: {}", self.outfn, e))?,
+            None => { return Err(format!("{} already closed", self.outfn)); }
+        }
+        self.count += 1;
+        Ok(())
+    }
+
+    fn close(&mut self) -> std::result::Result<(), String> {
+        let trailer = match self.format {
+            CopyFormat::Binary => BINARY_TRAILER.to_vec(),
+            CopyFormat::Text => Vec::new(),
+            CopyFormat::Sql => format!("\\.\n\n{}", create_index_sql(&self.table_prefix, self.kind)).into_bytes()
+        };
+        if let Some(mut o) = self.out.take() {
+            o.write_all(&trailer).map_err(|e| format!("{}: {}", self.outfn, e))?;
+            o.flush().map_err(|e| format!("{}: {}", self.outfn, e))?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes each GeometryBlock from the process_geometry pipeline to point,
+/// line and polygon COPY files as it arrives.
+struct WritePostgis {
+    tag_columns: TagColumns,
+    tables: Vec<CopyTable>,
+    transform: bool,
+    error: Option<String>,
+    time: f64,
+    result: ResultSlot<Vec<(String, usize)>>
+}
+
+impl WritePostgis {
+    fn new(outprfx: &str, table_prefix: &str, format: CopyFormat, style: &osmquadtree_geometry::GeometryStyle, transform: bool, result: ResultSlot<Vec<(String, usize)>>) -> std::result::Result<WritePostgis, String> {
+        let tag_columns = TagColumns::from_style(style);
+        let mut tables = Vec::new();
+        for kind in &TableKind::ALL {
+            tables.push(CopyTable::new(*kind, outprfx, table_prefix, format, &tag_columns, transform)?);
+        }
+        Ok(WritePostgis{tag_columns: tag_columns, tables: tables, transform: transform, error: None, time: 0.0, result: result})
+    }
+
+    fn add_block(&mut self, bl: &osmquadtree_geometry::GeometryBlock) -> std::result::Result<(), String> {
+        let tag_columns = &self.tag_columns;
+        let tables = &mut self.tables;
+        block_rows(bl, self.transform, true, |kind, row| match tables.iter_mut().find(|t| t.kind == kind) {
+            Some(t) => t.add_row(tag_columns, row),
+            None => Ok(())
+        })
+    }
+
+    fn outputs(&self) -> Vec<(String, usize)> {
+        self.tables.iter().map(|t| (t.outfn.clone(), t.count)).collect()
+    }
+}
+
+impl CallFinish for WritePostgis {
+    type CallType = osmquadtree_geometry::GeometryBlock;
+    type ReturnType = Timings<osmquadtree_geometry::OtherData>;
+    type ErrorType = Error;
+
+    fn call(&mut self, bl: osmquadtree_geometry::GeometryBlock) {
+        if self.error.is_some() {
+            return;
+        }
+        let st = std::time::Instant::now();
+        if let Err(e) = self.add_block(&bl) {
+            self.error = Some(e);
+        }
+        self.time += st.elapsed().as_secs_f64();
+    }
+
+    fn finish(&mut self) -> ccResult<Self::ReturnType, Error> {
+        if self.error.is_none() {
+            for t in self.tables.iter_mut() {
+                if let Err(e) = t.close() {
+                    self.error = Some(e);
+                    break;
+                }
+            }
+        }
+        let res = match self.error.take() {
+            Some(e) => Err(e),
+            None => Ok(self.outputs())
+        };
+        let mut tm = Timings::new();
+        tm.add("WritePostgis", self.time);
+        self.result.finish(res, tm)
+    }
+}
+
+/// Returns the CREATE TABLE statements for the point, line and polygon
+/// tables written by write_postgis, with tag columns from the style. The
+/// geometry srid is 3857 if `transform` is set, else 4326.
+#[pyfunction]
+#[pyo3(signature = (style_in=None, table_prefix="planet_osm_", transform=true))]
+pub fn postgis_create_tables(py: Python, style_in: Option<PyObject>, table_prefix: &str, transform: bool) -> PyResult<String> {
+    let style = crate::geometry::prep_style(py, style_in)?;
+    let tag_columns = TagColumns::from_style(&style);
+    Ok(TableKind::ALL.iter().map(|k| create_table_sql(table_prefix, *k, &tag_columns, transform)).collect::<Vec<String>>().join("\n"))
+}
+
+/// Runs process_geometry, writing the output for tables
+/// `{table_prefix}point`, `{table_prefix}line` and `{table_prefix}polygon`
+/// to files `{outprfx}point.{ext}` etc. `format` is one of "binary"
+/// (postgresql binary COPY, .copy), "text" (COPY text format, .tsv) or
+/// "sql" (a pg_dump style script with CREATE TABLE, COPY and CREATE INDEX
+/// statements, to be run with psql). The table layout is as given by
+/// postgis_create_tables. Returns the filename and number of rows of each
+/// file.
+#[pyfunction]
+#[pyo3(signature = (prfx, outprfx, format="binary", table_prefix="planet_osm_", filter=None, timestamp=None, minzoom_in=None, style_in=None, transform=true, numchan=crate::readpbf::default_numchan()))]
+pub fn write_postgis(py: Python,
+    prfx: &str,
+    outprfx: &str,
+    format: &str,
+    table_prefix: &str,
+    filter: Option<PyObject>,
+    timestamp: Option<&str>,
+    minzoom_in: Option<PyObject>,
+    style_in: Option<PyObject>,
+    transform: bool,
+    numchan: usize
+) -> PyResult<Vec<(String, usize)>> {
+
+    let format = CopyFormat::from_str(format)?;
+    let (pfilelocs, style, minzoom) = crate::geometry::prep_process_geometry(py, prfx, filter, timestamp, minzoom_in, style_in)?;
+
+    let result = ResultSlot::new();
+    let writer = WritePostgis::new(outprfx, table_prefix, format, &style, transform, result.clone())
+        .map_err(|e| crate::ErrorWrapped::from(std::io::Error::new(std::io::ErrorKind::Other, e)))?;
+    let outfns: Vec<String> = writer.outputs().into_iter().map(|(f,_)| f).collect();
+    let cb: crate::geometry::GeometryCallback = Box::new(writer);
+
+    crate::geometry::run_geometry_writer(py, pfilelocs, cb, &result, style, minzoom, numchan, &outfns)
+}
+
+pub(crate) fn wrap_postgis(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_wrapped(wrap_pyfunction!(postgis_create_tables))?;
+    m.add_wrapped(wrap_pyfunction!(write_postgis))?;
+    Ok(())
+}
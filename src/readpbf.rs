@@ -1,9 +1,12 @@
 use pyo3::prelude::*;
 //use pyo3::PyObjectProtocol;
-use pyo3::types::{PyList,PyTuple,PyBytes};
+use pyo3::types::{PyList,PyBytes};
 use pyo3::exceptions::*;
-use std::sync::Arc;
-use std::io::{Seek,SeekFrom,BufReader};
+use std::sync::{Arc,Mutex,Condvar};
+use std::sync::atomic::{AtomicBool,AtomicUsize,Ordering};
+use std::sync::mpsc::{sync_channel,SyncSender,Receiver,RecvTimeoutError};
+use std::collections::BTreeMap;
+use std::io::{Read,Seek,SeekFrom,BufReader};
 use std::fs::File;
 
 use channelled_callbacks::{CallFinish,CallbackMerge,CallbackSync,Callback,ReplaceNoneWithTimings,Timings,MergeTimings, Result as ccResult};
@@ -13,10 +16,12 @@ use crate::ErrorWrapped;
 #[pyclass]
 pub struct FileBlock {
     inner: osmquadtree::pbfformat::FileBlock,
+    //zstd or lz4: osmquadtree can't decode these, so inner is uncompressed
+    compression: Option<&'static str>,
 }
 impl FileBlock {
     pub fn new(inner: osmquadtree::pbfformat::FileBlock) -> FileBlock {
-        FileBlock{inner: inner}
+        FileBlock{inner: inner, compression: None}
     }
 }
 #[pymethods]
@@ -40,7 +45,12 @@ impl FileBlock {
         
     
     #[getter]
-    pub fn compression_type(&self) -> PyResult<String> { Ok(compression_type_string(self.inner.compression_type)) }
+    pub fn compression_type(&self) -> PyResult<String> {
+        match self.compression {
+            Some(c) => Ok(String::from(c)),
+            None => Ok(compression_type_string(self.inner.compression_type))
+        }
+    }
     
     #[getter]
     pub fn data(&self, py: Python) -> PyResult<PyObject> {
@@ -71,16 +81,214 @@ pub fn compression_type_from_string(input: (&str,u32)) -> PyResult<osmquadtree::
     }
 }
 
+fn py_io_error(e: PyErr) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Other, e.to_string())
+}
+
+/// A python object with read and seek methods. Each reader keeps its own
+/// position: readers of the same object (see BlockSource::reopen) share
+/// `lock`, which is held from the seek to the end of the read, as the
+/// object may release the GIL while reading.
+pub(crate) struct PythonSource {
+    obj: PyObject,
+    pos: u64,
+    moved: bool,
+    lock: Arc<Mutex<()>>
+}
+
+impl PythonSource {
+    //wait for the lock without holding the GIL, which the reader holding
+    //the lock may need
+    fn lock<'a>(py: Python, lock: &'a Mutex<()>) -> std::sync::MutexGuard<'a, ()> {
+        loop {
+            match lock.try_lock() {
+                Ok(g) => { return g; },
+                Err(std::sync::TryLockError::Poisoned(e)) => { return e.into_inner(); },
+                Err(std::sync::TryLockError::WouldBlock) => { py.allow_threads(std::thread::yield_now); }
+            }
+        }
+    }
+}
+
+/// Where ReadFileBlocks reads from: a file, a bytes-like object, read in
+/// place through the buffer protocol, or a python object with read and seek
+/// methods, which is read in chunks as the decoder needs them.
+pub(crate) enum BlockSource {
+    File(File),
+    Buffer(pyo3::buffer::PyBuffer<u8>, u64),
+    Python(PythonSource)
+}
+
+impl BlockSource {
+    /// Opens `src`: a path, a bytes, bytearray or memoryview, or a file-like
+    /// object. Returns the source, a name to use in error messages and the
+    /// path if there is one.
+    pub fn open(py: Python, src: &PyObject) -> PyResult<(BlockSource, String, Option<String>)> {
+        if let Ok(p) = src.extract::<std::path::PathBuf>(py) {
+            let fname = p.to_string_lossy().to_string();
+            let f = File::open(&p).map_err(|e| ErrorWrapped::from(e).with_filename(&fname))?;
+            return Ok((BlockSource::File(f), fname.clone(), Some(fname)));
+        }
+        
+        let src_b = src.bind(py);
+        if src_b.hasattr("read")? {
+            let name = match src_b.getattr("name") {
+                Ok(n) => n.str()?.to_string(),
+                Err(_) => String::from("<file object>")
+            };
+            //start from the current position, as with a tar file member
+            let pos = src_b.call_method0("tell").and_then(|p| p.extract::<u64>()).unwrap_or(0);
+            let ps = PythonSource{obj: src.clone_ref(py), pos: pos, moved: false, lock: Arc::new(Mutex::new(()))};
+            return Ok((BlockSource::Python(ps), name, None));
+        }
+        
+        match pyo3::buffer::PyBuffer::<u8>::get(src_b) {
+            Ok(buf) if buf.is_c_contiguous() => Ok((BlockSource::Buffer(buf, 0), String::from("<buffer>"), None)),
+            Ok(_) => Err(PyValueError::new_err("buffer must be contiguous")),
+            Err(_) => Err(PyTypeError::new_err("expected a path, a bytes-like object or a file-like object with read and seek"))
+        }
+    }
+
+    /// A second reader of `src`, which this was opened from, for use on
+    /// another thread. A buffer gets its own view; a python object is
+    /// shared, each reader seeking to its own position before reading.
+    pub fn reopen(&self, py: Python, src: &PyObject) -> PyResult<BlockSource> {
+        match self {
+            BlockSource::Python(ps) => Ok(BlockSource::Python(PythonSource{obj: ps.obj.clone_ref(py), pos: 0, moved: true, lock: ps.lock.clone()})),
+            _ => Ok(BlockSource::open(py, src)?.0)
+        }
+    }
+
+    //the exporting object can't be resized while the buffer is held, so
+    //this stays valid until the buffer is dropped
+    fn buffer_bytes(buf: &pyo3::buffer::PyBuffer<u8>) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(buf.buf_ptr() as *const u8, buf.len_bytes()) }
+    }
+}
+
+impl Read for BlockSource {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            BlockSource::File(f) => f.read(buf),
+            BlockSource::Buffer(b, pos) => {
+                let data = BlockSource::buffer_bytes(b);
+                let start = usize::min(*pos as usize, data.len());
+                let n = usize::min(buf.len(), data.len() - start);
+                buf[..n].copy_from_slice(&data[start..start + n]);
+                *pos += n as u64;
+                Ok(n)
+            },
+            BlockSource::Python(ps) => Python::with_gil(|py| {
+                let _guard = PythonSource::lock(py, &ps.lock);
+                //another reader may have moved the object. a stream which
+                //can't tell is only read forwards, so is where we left it
+                let at = ps.obj.call_method0(py, "tell").and_then(|p| p.extract::<u64>(py)).ok();
+                if ps.moved || at.map_or(false, |a| a != ps.pos) {
+                    ps.obj.call_method1(py, "seek", (ps.pos, 0)).map_err(py_io_error)?;
+                    ps.moved = false;
+                }
+                let data = ps.obj.call_method1(py, "read", (buf.len(),)).map_err(py_io_error)?;
+                //a non-blocking stream may return None: treat as no data
+                if data.is_none(py) {
+                    return Ok(0);
+                }
+                let data = data.bind(py);
+                let bytes = data.downcast::<PyBytes>()
+                    .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "read() didn't return bytes"))?
+                    .as_bytes();
+                if bytes.len() > buf.len() {
+                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "read() returned too many bytes"));
+                }
+                buf[..bytes.len()].copy_from_slice(bytes);
+                ps.pos += bytes.len() as u64;
+                Ok(bytes.len())
+            })
+        }
+    }
+}
+
+impl Seek for BlockSource {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        match self {
+            BlockSource::File(f) => f.seek(pos),
+            BlockSource::Buffer(b, p) => {
+                let (base, off) = match pos {
+                    SeekFrom::Start(x) => { *p = x; return Ok(x); },
+                    SeekFrom::Current(x) => (*p as i64, x),
+                    SeekFrom::End(x) => (b.len_bytes() as i64, x)
+                };
+                if base + off < 0 {
+                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "seek before the start of the buffer"));
+                }
+                *p = (base + off) as u64;
+                Ok(*p)
+            },
+            BlockSource::Python(ps) => {
+                //the object is only moved on the next read, so streams
+                //which can only be read forwards still work
+                let p = match pos {
+                    SeekFrom::Current(0) => { return Ok(ps.pos); },
+                    SeekFrom::Start(p) => p as i64,
+                    SeekFrom::Current(p) => ps.pos as i64 + p,
+                    SeekFrom::End(p) => Python::with_gil(|py| {
+                        let _guard = PythonSource::lock(py, &ps.lock);
+                        ps.obj.call_method1(py, "seek", (p, 2)).and_then(|p| p.extract::<i64>(py)).map_err(py_io_error)
+                    })?
+                };
+                if p < 0 {
+                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "seek before the start of the file"));
+                }
+                if p as u64 != ps.pos {
+                    ps.pos = p as u64;
+                    ps.moved = true;
+                }
+                Ok(ps.pos)
+            }
+        }
+    }
+}
+
+/// Decodes the OSMHeader block `fb` of `name`. The writers store the
+/// quadtree index locations as positions in the file (see
+/// writepbf::pack_index_entry), so the header is decoded at its own
+/// position and the locations used as they are.
+pub(crate) fn read_header_block(fb: &osmquadtree::pbfformat::FileBlock, name: &str) -> PyResult<osmquadtree::pbfformat::HeaderBlock> {
+    osmquadtree::pbfformat::HeaderBlock::read(fb.pos, &fb.data(), name)
+        .map_err(|e| PyErr::from(ErrorWrapped::from(e).with_filename(name).with_position(fb.pos)))
+}
+
 #[pyclass]
 struct ReadFileBlocks {
     fname: String,
-    fbuf: BufReader<File>,
+    path: Option<String>,
+    fbuf: BufReader<BlockSource>,
+    block_index: Option<crate::blockindex::BlockIndex>,
 }
 impl ReadFileBlocks {
+    fn get_path(&self) -> PyResult<&str> {
+        self.path.as_deref().ok_or_else(|| crate::errors::invalid_input_error(format!("{} is not a file: block index not available", self.fname)))
+    }
+    
+    fn get_block_index(&self) -> PyResult<&crate::blockindex::BlockIndex> {
+        match &self.block_index {
+            Some(bi) => Ok(bi),
+            None => {
+                let path = self.get_path()?;
+                let idxfn = crate::blockindex::BlockIndex::sidecar_path(path);
+                if std::path::Path::new(&idxfn).exists() {
+                    Err(crate::errors::invalid_input_error(format!("{} is out of date for {}: call build_block_index again", idxfn, path)))
+                } else {
+                    Err(crate::errors::missing_file_error(format!("no block index for {}: call build_block_index first", path), &idxfn))
+                }
+            }
+        }
+    }
+    
     fn read_all_call(&mut self, callback_func: PyObject, numchan: usize, ischange: bool, groupby: usize) -> PyResult<usize> {
             
         
-        let co = Box::new(CollectBlocksCall::new(callback_func, groupby));
+        let error: ErrorSlot = Arc::new(Mutex::new(None));
+        let co = Box::new(CollectBlocksCall::<osmquadtree::elements::PrimitiveBlock, Timings<usize>>::new("CollectBlocksCall", callback_func, groupby, error.clone()));
     
         let mut conv: Box<dyn CallFinish<CallType = (usize, osmquadtree::pbfformat::FileBlock), ReturnType = Timings<usize>, ErrorType=Error>> =
             if numchan == 0 {
@@ -103,15 +311,22 @@ impl ReadFileBlocks {
             };
         
         
-        self.fbuf.seek(SeekFrom::Start(0))?;
+        self.fbuf.seek(SeekFrom::Start(0)).map_err(ErrorWrapped::from)?;
         let mut i=0;
-        for bl in osmquadtree::pbfformat::ReadFileBlocks::new(&mut self.fbuf) {
+        let mut pos=0;
+        while let Some((next, bl, _)) = crate::compression::read_block(&mut self.fbuf, pos).map_err(ErrorWrapped::from)? {
+            if crate::cancel::is_cancelled() {
+                break;
+            }
             conv.call((i,bl));
             i+=1;
+            pos=next;
         }
         
         match conv.finish() {
             Ok(tm) => {
+                take_error(&error)?;
+                crate::cancel::check_cancelled()?;
                 let mut r = 0;
                 for (_,t) in tm.others {
                     r += t;
@@ -119,7 +334,7 @@ impl ReadFileBlocks {
                 
                 Ok(r)
             },
-            Err(e) => Err(PyErr::from(ErrorWrapped{e:e.into()}))
+            Err(e) => Err(PyErr::from(ErrorWrapped::new(e.into())))
         }
     }
     
@@ -127,37 +342,108 @@ impl ReadFileBlocks {
     
 #[pymethods]
 impl ReadFileBlocks {
+    /// Reads the blocks of a pbf file. `fname` may be a path, a bytes,
+    /// bytearray or memoryview holding the file contents, or a file-like
+    /// object with read and seek methods (such as io.BytesIO or a tar file
+    /// member).
     #[new]
-    fn new(fname: &str) -> PyResult<Self> {
-        let fobj = File::open(fname)?;
-        let fbuf = BufReader::new(fobj);        
-        Ok(ReadFileBlocks{fname: String::from(fname), fbuf: fbuf})
+    fn new(py: Python, fname: PyObject) -> PyResult<Self> {
+        let (src, name, path) = BlockSource::open(py, &fname)?;
+        let fbuf = BufReader::new(src);
+        
+        //use the sidecar index if there is one and it is up to date
+        let block_index = match &path {
+            Some(p) => {
+                let idxfn = crate::blockindex::BlockIndex::sidecar_path(p);
+                if std::path::Path::new(&idxfn).exists() {
+                    crate::blockindex::BlockIndex::read(&idxfn).ok().filter(|bi| bi.matches(p))
+                } else {
+                    None
+                }
+            },
+            None => None
+        };
+        Ok(ReadFileBlocks{fname: name, path: path, fbuf: fbuf, block_index: block_index})
         
     }
     
+    /// Loads the block index written by build_block_index, from the sidecar
+    /// file `<fname>.blockindex` or `indexfn`. Raises InvalidInputError if
+    /// the pbf file has changed since the index was built.
+    #[pyo3(signature = (indexfn=None))]
+    pub fn load_block_index(&mut self, indexfn: Option<&str>) -> PyResult<usize> {
+        let path = self.get_path()?;
+        let idxfn = indexfn.map_or_else(|| crate::blockindex::BlockIndex::sidecar_path(path), String::from);
+        let bi = crate::blockindex::BlockIndex::read(&idxfn)?;
+        if !bi.matches(path) {
+            return Err(crate::errors::invalid_input_error(format!("{} is out of date for {}", idxfn, path)));
+        }
+        let n = bi.entries.len();
+        self.block_index = Some(bi);
+        Ok(n)
+    }
+    
+    #[getter]
+    pub fn has_block_index(&self) -> PyResult<bool> {
+        Ok(self.block_index.is_some())
+    }
+    
+    /// Returns (index, pos, len, element_type, quadtree, id_ranges, bbox) for
+    /// each OSMData block, where element_type is "node", "way", "relation",
+    /// "mixed" or "empty", id_ranges is [(count, min id, max id)] for nodes,
+    /// ways and relations, and bbox the bounds of the block's nodes.
+    pub fn block_index(&self, py: Python) -> PyResult<PyObject> {
+        let bi = self.get_block_index()?;
+        let res = PyList::empty(py);
+        for (i, e) in bi.entries.iter().enumerate() {
+            let q = if e.quadtree >= 0 { Some(crate::elements::Quadtree::new(osmquadtree::elements::Quadtree::new(e.quadtree))) } else { None };
+            res.append((i, e.pos, e.len, e.element_type(), q, e.ids.to_vec(), e.bbox).into_py(py))?;
+        }
+        Ok(res.into())
+    }
+    
+    /// Returns (index, pos) of the block which holds element `id`, for
+    /// passing to read_block_at, or None if no block's id range includes it.
+    pub fn block_for_id(&self, element_type: &str, id: i64) -> PyResult<Option<(usize, u64)>> {
+        let t = crate::history::type_rank(element_type)? as usize;
+        let bi = self.get_block_index()?;
+        Ok(bi.block_for_id(t, id).map(|i| (i, bi.entries[i].pos)))
+    }
+    
+    /// Returns (index, pos) of each block which may hold elements in `bbox`
+    /// (minlon, minlat, maxlon, maxlat in units of 1e-7 degrees). Blocks of
+    /// ways or relations in files without quadtrees are always included.
+    pub fn blocks_in_bbox(&self, bbox: (i32, i32, i32, i32)) -> PyResult<Vec<(usize, u64)>> {
+        let bi = self.get_block_index()?;
+        let bx = osmquadtree::elements::Bbox::new(bbox.0, bbox.1, bbox.2, bbox.3);
+        Ok(bi.blocks_in_bbox(&bx).into_iter().map(|i| (i, bi.entries[i].pos)).collect())
+    }
+    
     
     pub fn position(&mut self) -> PyResult<u64> {
-        Ok(osmquadtree::pbfformat::file_position(&mut self.fbuf)?)
+        Ok(osmquadtree::pbfformat::file_position(&mut self.fbuf).map_err(ErrorWrapped::from)?)
     }
     
     pub fn next(&mut self) -> PyResult<FileBlock> {
-        let fb = osmquadtree::pbfformat::read_file_block(&mut self.fbuf)?;
-        Ok(FileBlock{inner: fb})
+        let pos = self.fbuf.stream_position().map_err(ErrorWrapped::from)?;
+        match crate::compression::read_block(&mut self.fbuf, pos).map_err(ErrorWrapped::from)? {
+            Some((_, fb, c)) => Ok(FileBlock{inner: fb, compression: c}),
+            None => Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, format!("no block at {} in {}", pos, self.fname)).into())
+        }
     }
     
     pub fn read_at(&mut self, pos: u64) -> PyResult<FileBlock> {
-        self.fbuf.seek(SeekFrom::Start(pos))?;
+        self.fbuf.seek(SeekFrom::Start(pos)).map_err(ErrorWrapped::from)?;
         self.next()
     }
     
     pub fn get_header(&mut self) -> PyResult<HeaderBlock> {
-        self.fbuf.seek(SeekFrom::Start(0))?;
-        let fb = osmquadtree::pbfformat::read_file_block(&mut self.fbuf)?;
+        self.fbuf.seek(SeekFrom::Start(0)).map_err(ErrorWrapped::from)?;
+        let fb = crate::compression::read_file_block(&mut self.fbuf).map_err(ErrorWrapped::from)?;
         if fb.block_type == "OSMHeader" {
-            let hb = osmquadtree::pbfformat::HeaderBlock::read(fb.pos, &fb.data(), &self.fname)?;
-            Ok(HeaderBlock{inner: hb})
+            Ok(HeaderBlock{inner: read_header_block(&fb, &self.fname)?})
         } else {
-            self.fbuf.seek(SeekFrom::Start(0))?;
+            self.fbuf.seek(SeekFrom::Start(0)).map_err(ErrorWrapped::from)?;
             Err(PyValueError::new_err("first block not an OSMHeader"))
         }
         
@@ -166,17 +452,14 @@ impl ReadFileBlocks {
     
     
     pub fn next_block(&mut self, py: Python, index: i64, ischange: bool, minimal: bool) -> PyResult<PyObject> {
-        let fb = osmquadtree::pbfformat::read_file_block(&mut self.fbuf)?;
+        let fb = crate::compression::read_file_block(&mut self.fbuf).map_err(ErrorWrapped::from)?;
         if fb.block_type == "OSMData" {
             match osmquadtree::elements::PrimitiveBlock::read(index, fb.pos, &fb.data(), ischange, minimal) {
                 Ok(bl) => Ok(crate::elements::PrimitiveBlock::new(bl).into_py(py)),
-                Err(e) => Err(PyErr::from(ErrorWrapped{e:e}))
+                Err(e) => Err(PyErr::from(ErrorWrapped::new(e).with_filename(&self.fname).with_position(fb.pos)))
             }
         } else if fb.block_type == "OSMHeader" {
-            match osmquadtree::pbfformat::HeaderBlock::read(fb.pos, &fb.data(), &self.fname) {
-                Ok(hb) => Ok(HeaderBlock{inner: hb}.into_py(py)),
-                Err(e) => Err(PyErr::from(ErrorWrapped{e:Error::Io(e)}))
-            }
+            Ok(HeaderBlock{inner: read_header_block(&fb, &self.fname)?}.into_py(py))
         } else {
             
             Err(PyValueError::new_err(format!("block at {} not a OSMData or OSMHeader", fb.pos)))
@@ -184,12 +467,12 @@ impl ReadFileBlocks {
     }
     
     pub fn read_block_at(&mut self, py: Python, index: i64, pos: u64, ischange: bool, minimal: bool) -> PyResult<PyObject> {
-        self.fbuf.seek(SeekFrom::Start(pos))?;
+        self.fbuf.seek(SeekFrom::Start(pos)).map_err(ErrorWrapped::from)?;
         self.next_block(py, index, ischange, minimal)
     }
     
     pub fn read_all(&mut self, py: Python, callback_func: PyObject, numchan: usize, ischange: bool, groupby: usize) -> PyResult<usize> {
-        py.allow_threads(|| self.read_all_call(callback_func, numchan, ischange, groupby))
+        crate::cancel::run_interruptible(py, &[], || self.read_all_call(callback_func, numchan, ischange, groupby))
         
     }
     
@@ -246,15 +529,87 @@ impl HeaderBlock {
 
     
 
-struct CollectBlocksCall {
+/// Holds the first exception raised by a python callback called from a
+/// worker thread, to be raised once the read has finished.
+pub(crate) type ErrorSlot = Arc<Mutex<Option<PyErr>>>;
+
+pub(crate) fn has_error(slot: &ErrorSlot) -> bool {
+    match slot.lock() {
+        Ok(e) => e.is_some(),
+        Err(_) => true
+    }
+}
+
+pub(crate) fn set_error(slot: &ErrorSlot, err: PyErr) {
+    if let Ok(mut e) = slot.lock() {
+        if e.is_none() {
+            *e = Some(err);
+        }
+    }
+}
+
+pub(crate) fn take_error(slot: &ErrorSlot) -> PyResult<()> {
+    match slot.lock() {
+        Ok(mut e) => match e.take() {
+            Some(err) => Err(err),
+            None => Ok(())
+        },
+        Err(_) => Err(PyRuntimeError::new_err("callback error lock poisoned"))
+    }
+}
+
+/// A block which CollectBlocksCall passes to python callbacks.
+pub(crate) trait PyBlock: Send + 'static {
+    fn into_py_block(self, py: Python) -> PyObject;
+}
+
+impl PyBlock for osmquadtree::elements::PrimitiveBlock {
+    fn into_py_block(self, py: Python) -> PyObject {
+        crate::elements::PrimitiveBlock::new(self).into_py(py)
+    }
+}
+
+impl PyBlock for osmquadtree::elements::MinimalBlock {
+    fn into_py_block(self, py: Python) -> PyObject {
+        crate::elements::MinimalBlock::new(self).into_py(py)
+    }
+}
+
+/// The timings CollectBlocksCall returns, given its name and the number of
+/// blocks passed to the callback.
+pub(crate) trait CollectTimings: Send + 'static {
+    fn collected(name: &'static str, count: usize) -> Self;
+}
+
+impl CollectTimings for Timings<usize> {
+    fn collected(name: &'static str, count: usize) -> Self {
+        let mut tm = Timings::new();
+        tm.add_other(name, count);
+        tm
+    }
+}
+
+/// Calls a python callback with lists of up to `groupby` blocks. The first
+/// exception raised by the callback is stored in `error`, and it isn't
+/// called again.
+pub(crate) struct CollectBlocksCall<B, T> {
+    name: &'static str,
     callback: PyObject,
-    pending: Vec<osmquadtree::elements::PrimitiveBlock>,
+    pending: Vec<B>,
     groupby: usize,
-    count: usize
+    count: Arc<AtomicUsize>,
+    error: ErrorSlot,
+    timings: std::marker::PhantomData<fn() -> T>
 }
-impl CollectBlocksCall {
-    pub fn new(callback: PyObject, groupby: usize) -> CollectBlocksCall {
-        CollectBlocksCall{callback: callback, pending: Vec::new(), groupby: groupby, count: 0}
+
+impl<B: PyBlock, T: CollectTimings> CollectBlocksCall<B, T> {
+    pub fn new(name: &'static str, callback: PyObject, groupby: usize, error: ErrorSlot) -> CollectBlocksCall<B, T> {
+        CollectBlocksCall{name: name, callback: callback, pending: Vec::new(), groupby: groupby, count: Arc::new(AtomicUsize::new(0)), error: error, timings: std::marker::PhantomData}
+    }
+    
+    /// The number of blocks passed to the callback so far.
+    pub fn counter(&self) -> Arc<AtomicUsize> {
+        self.count.clone()
     }
     
     fn clear_pending(&mut self) {
@@ -262,114 +617,304 @@ impl CollectBlocksCall {
         if self.pending.is_empty() {
             return;
         }
+        if has_error(&self.error) {
+            //callback has already failed: don't call it again
+            self.pending.clear();
+            return;
+        }
         
         Python::with_gil(|py| {
-        //let gil_guard = Python::acquire_gil();
-        //let py = gil_guard.python();
-        
             let list = PyList::empty(py);
             let mut num=0;
-            for bl in std::mem::replace(&mut self.pending, Vec::new()) {
-                let bll = crate::elements::PrimitiveBlock::new(bl);
-            
-                list.append(bll.into_py(py)).expect("!!");
+            for bl in std::mem::take(&mut self.pending) {
+                if let Err(e) = list.append(bl.into_py_block(py)) {
+                    set_error(&self.error, e);
+                    return;
+                }
                 num+=1;
             }
             
-            let args = PyTuple::new(py, &[list]).unwrap();
-            
-            self.callback.call1(py, args).expect("!!");
-            self.count+=num;
+            match self.callback.call1(py, (list,)) {
+                Ok(_) => { self.count.fetch_add(num, Ordering::Relaxed); },
+                Err(e) => { set_error(&self.error, e); }
+            }
         });
     }
     
 }
-impl CallFinish for CollectBlocksCall {
-    type CallType = osmquadtree::elements::PrimitiveBlock;
-    type ReturnType = Timings<usize>;
+impl<B: PyBlock, T: CollectTimings> CallFinish for CollectBlocksCall<B, T> {
+    type CallType = B;
+    type ReturnType = T;
     type ErrorType = Error;
     
-    fn call(&mut self, bl: osmquadtree::elements::PrimitiveBlock) {
+    fn call(&mut self, bl: B) {
         self.pending.push(bl);
         
         if self.pending.len() >= self.groupby {
             self.clear_pending();
         }
-        
-        
     }
     
     fn finish(&mut self) -> ccResult<Self::ReturnType, Error> {
         self.clear_pending();
-        
-        let mut tm = Timings::new();
-        tm.add_other("CollectBlocksCall", self.count);
-        Ok(tm)
+        Ok(T::collected(self.name, self.count.load(Ordering::Relaxed)))
     }
 }
     
-struct CollectBlocksMinimalCall {
-    callback: PyObject,
-    pending: Vec<osmquadtree::elements::MinimalBlock>,
-    groupby: usize,
+
+struct SendBlocksCall {
+    sender: SyncSender<osmquadtree::elements::PrimitiveBlock>,
+    cancel: Arc<AtomicBool>,
     count: usize
 }
-impl CollectBlocksMinimalCall {
-    pub fn new(callback: PyObject, groupby: usize) -> CollectBlocksMinimalCall {
-        CollectBlocksMinimalCall{callback: callback, pending: Vec::new(), groupby: groupby, count: 0}
-    }
-    
-    fn clear_pending(&mut self) {
-        
-        if self.pending.is_empty() {
-            return;
-        }
-        
-        Python::with_gil(|py| {
-        //let gil_guard = Python::acquire_gil();
-        //let py = gil_guard.python();
-        
-            let list = PyList::empty(py);
-            let mut num=0;
-            for bl in std::mem::replace(&mut self.pending, Vec::new()) {
-                let bll = crate::elements::MinimalBlock::new(bl);
-            
-                list.append(bll.into_py(py)).expect("!!");
-                num+=1;
-            }
-            
-            let args = PyTuple::new(py, &[list]).unwrap();
-            
-            self.callback.call1(py, args).expect("!!");
-            self.count+=num;
-        });
+
+impl SendBlocksCall {
+    pub fn new(sender: SyncSender<osmquadtree::elements::PrimitiveBlock>, cancel: Arc<AtomicBool>) -> SendBlocksCall {
+        SendBlocksCall{sender: sender, cancel: cancel, count: 0}
     }
-    
 }
-impl CallFinish for CollectBlocksMinimalCall {
-    type CallType = osmquadtree::elements::MinimalBlock;
+
+impl CallFinish for SendBlocksCall {
+    type CallType = osmquadtree::elements::PrimitiveBlock;
     type ReturnType = Timings<usize>;
     type ErrorType = Error;
     
-    fn call(&mut self, bl: osmquadtree::elements::MinimalBlock) {
-        self.pending.push(bl);
-        
-        if self.pending.len() >= self.groupby {
-            self.clear_pending();
+    fn call(&mut self, bl: osmquadtree::elements::PrimitiveBlock) {
+        if self.cancel.load(Ordering::Relaxed) {
+            return;
         }
         
-        
+        match self.sender.send(bl) {
+            Ok(()) => { self.count += 1; },
+            //receiver has been dropped: stop reading any more blocks
+            Err(_) => { self.cancel.store(true, Ordering::Relaxed); }
+        }
     }
     
     fn finish(&mut self) -> ccResult<Self::ReturnType, Error> {
-        self.clear_pending();
-        
         let mut tm = Timings::new();
-        tm.add_other("CollectBlocksMinimalCall", self.count);
+        tm.add_other("SendBlocksCall", self.count);
         Ok(tm)
     }
 }
+
+/// The number of cpus, which the python wrappers use as the default numchan.
+pub(crate) fn default_numchan() -> usize {
+    std::thread::available_parallelism().map_or(4, |n| n.get())
+}
+
+//limits how far the reading thread gets ahead of the blocks returned by
+//ReadFileBlocksParallelIter, so the blocks waiting to be put back in order
+//don't grow without bound when the consumer is slow. each index read gives
+//exactly one (possibly empty) block, so the block the iterator is waiting
+//for is always within the limit
+struct ReadAhead {
+    next: Mutex<i64>,
+    cond: Condvar,
+    limit: i64
+}
+
+impl ReadAhead {
+    fn new(limit: usize) -> ReadAhead {
+        ReadAhead{next: Mutex::new(0), cond: Condvar::new(), limit: limit as i64}
+    }
+    
+    fn wait_for(&self, idx: i64, cancel: &AtomicBool) {
+        let mut next = self.next.lock().unwrap_or_else(|e| e.into_inner());
+        while idx >= *next + self.limit && !cancel.load(Ordering::Relaxed) {
+            next = match self.cond.wait_timeout(next, std::time::Duration::from_millis(100)) {
+                Ok((n, _)) => n,
+                Err(e) => e.into_inner().0
+            };
+        }
+    }
+    
+    fn set_next(&self, idx: i64) {
+        *self.next.lock().unwrap_or_else(|e| e.into_inner()) = idx;
+        self.cond.notify_all();
+    }
+}
+
+/// The readers for a dataset, the quadtree and file locations of each
+/// block, and the total length, as get_file_locs returns for a path.
+pub(crate) type FileLocs<R> = (Vec<R>, Vec<(osmquadtree::elements::Quadtree, Vec<(usize, u64)>)>, u64);
+
+fn read_fileblocks<R: Read + Seek>(pfilelocs: &mut FileLocs<R>, idx: usize) -> PyResult<Vec<osmquadtree::pbfformat::FileBlock>> {
+    let mut fbs = Vec::new();
+    for (a,b) in &pfilelocs.1[idx].1 {
+        pfilelocs.0[*a].seek(SeekFrom::Start(*b)).map_err(ErrorWrapped::from)?;
+        let (_,fb) = crate::compression::read_file_block_with_pos(&mut pfilelocs.0[*a], *b).map_err(ErrorWrapped::from)?;
+        fbs.push(fb);
+    }
+    Ok(fbs)
+}
+
+fn read_all_blocks_cancellable<R: Read + Seek>(
+    pfilelocs: &mut FileLocs<R>,
+    mut conv: Box<dyn CallFinish<CallType = (usize, Vec<osmquadtree::pbfformat::FileBlock>), ReturnType = Timings<usize>, ErrorType=Error>>,
+    cancel: &AtomicBool,
+    ahead: &ReadAhead) -> PyResult<usize> {
+    
+    let mut result = Ok(());
+    for idx in 0..pfilelocs.1.len() {
+        ahead.wait_for(idx as i64, cancel);
+        if cancel.load(Ordering::Relaxed) {
+            break;
+        }
+        match read_fileblocks(pfilelocs, idx) {
+            Ok(fbs) => conv.call((idx, fbs)),
+            Err(e) => { result = Err(e); break; }
+        }
+    }
     
+    //always finish, so that the worker threads are joined
+    let tm = conv.finish();
+    result?;
+    match tm {
+        Ok(tm) => {
+            let mut r = 0;
+            for (_,t) in tm.others {
+                r += t;
+            }
+            Ok(r)
+        },
+        Err(e) => Err(PyErr::from(ErrorWrapped::new(e.into())))
+    }
+}
+
+/// The blocks of a single pbf file read from `src`, as get_file_locs
+/// returns for a path: from the quadtree index in the header, skipping
+/// blocks which don't overlap `bbox`, or every block if there's no index.
+fn source_file_locs(mut src: BlockSource, name: &str, bbox: &osmquadtree::elements::Bbox) -> PyResult<FileLocs<BlockSource>> {
+    let mut locs = Vec::new();
+    let mut index = None;
+    let mut pos = src.seek(SeekFrom::Start(0)).map_err(|e| ErrorWrapped::from(e).with_filename(name))?;
+    {
+        let mut fbuf = BufReader::new(&mut src);
+        while let Some((next, fb, _)) = crate::compression::read_block(&mut fbuf, pos).map_err(|e| ErrorWrapped::from(e).with_filename(name).with_position(pos))? {
+            if fb.block_type == "OSMHeader" {
+                let hb = read_header_block(&fb, name)?;
+                if !hb.index.is_empty() {
+                    index = Some(hb.index);
+                    break;
+                }
+            } else {
+                locs.push((osmquadtree::elements::Quadtree::new(-1), vec![(0, pos)]));
+            }
+            pos = next;
+        }
+    }
+    match index {
+        Some(index) => {
+            let total = index.iter().map(|ii| ii.length).sum();
+            let blocks = index.iter()
+                .filter(|ii| bbox.is_planet() || crate::dataset::overlaps(bbox, &ii.quadtree.as_bbox(crate::dataset::QUADTREE_BUFFER)))
+                .map(|ii| (ii.quadtree.clone(), vec![(0, ii.location)]))
+                .collect();
+            Ok((vec![src], blocks, total))
+        },
+        None => Ok((vec![src], locs, pos))
+    }
+}
+
+//the blocks of a prefix or file path, or of a single file read from a
+//python object
+enum DatasetLocs {
+    Path(osmquadtree::pbfformat::ParallelFileLocs),
+    Source(PyObject, FileLocs<BlockSource>)
+}
+
+impl DatasetLocs {
+    fn open(py: Python, prfx: &PyObject, bbox: &osmquadtree::elements::Bbox, ts: Option<i64>) -> PyResult<(DatasetLocs, String)> {
+        if let Ok(p) = prfx.extract::<std::path::PathBuf>(py) {
+            let p = p.to_string_lossy().to_string();
+            let pfilelocs = osmquadtree::pbfformat::get_file_locs(&p, Some(bbox.clone()), ts)
+                .map_err(|e| ErrorWrapped::from(e).with_filename(&p))?;
+            return Ok((DatasetLocs::Path(pfilelocs), p));
+        }
+        let (src, name, _) = BlockSource::open(py, prfx)?;
+        let locs = source_file_locs(src, &name, bbox)?;
+        Ok((DatasetLocs::Source(prfx.clone_ref(py), locs), name))
+    }
+
+    fn blocks(&self) -> &Vec<(osmquadtree::elements::Quadtree, Vec<(usize, u64)>)> {
+        match self {
+            DatasetLocs::Path(p) => &p.1,
+            DatasetLocs::Source(_, s) => &s.1
+        }
+    }
+
+    fn num_files(&self) -> usize {
+        match self {
+            DatasetLocs::Path(p) => p.0.len(),
+            DatasetLocs::Source(_, s) => s.0.len()
+        }
+    }
+
+    fn total_len(&self) -> u64 {
+        match self {
+            DatasetLocs::Path(p) => p.2,
+            DatasetLocs::Source(_, s) => s.2
+        }
+    }
+
+    fn read_fileblocks(&mut self, idx: usize) -> PyResult<Vec<osmquadtree::pbfformat::FileBlock>> {
+        match self {
+            DatasetLocs::Path(p) => read_fileblocks(p, idx),
+            DatasetLocs::Source(_, s) => read_fileblocks(s, idx)
+        }
+    }
+
+    fn read_primitive_blocks(&mut self, co: Box<dyn CallFinish<CallType = osmquadtree::elements::PrimitiveBlock, ReturnType = Timings<usize>, ErrorType=Error>>,
+            ids: Arc<dyn osmquadtree::elements::IdSet>, numchan: usize) -> PyResult<Timings<usize>> {
+        match self {
+            DatasetLocs::Path(p) => read_primitive_blocks(p, co, ids, numchan),
+            DatasetLocs::Source(_, s) => read_primitive_blocks(s, co, ids, numchan)
+        }
+    }
+
+    fn read_all_blocks(&mut self, conv: Box<dyn CallFinish<CallType = (usize, Vec<osmquadtree::pbfformat::FileBlock>), ReturnType = Timings<usize>, ErrorType=Error>>) -> PyResult<Timings<usize>> {
+        match self {
+            DatasetLocs::Path(p) => read_all_blocks(p, conv),
+            DatasetLocs::Source(_, s) => read_all_blocks(s, conv)
+        }
+    }
+
+    fn read_all_blocks_cancellable(&mut self, conv: Box<dyn CallFinish<CallType = (usize, Vec<osmquadtree::pbfformat::FileBlock>), ReturnType = Timings<usize>, ErrorType=Error>>,
+            cancel: &AtomicBool, ahead: &ReadAhead) -> PyResult<usize> {
+        match self {
+            DatasetLocs::Path(p) => read_all_blocks_cancellable(p, conv, cancel, ahead),
+            DatasetLocs::Source(_, s) => read_all_blocks_cancellable(s, conv, cancel, ahead)
+        }
+    }
+
+    fn prep_tag_filter(&mut self, filter: &crate::tagfilter::TagFilter, numchan: usize) -> PyResult<osmquadtree::elements::IdSetSet> {
+        match self {
+            DatasetLocs::Path(p) => crate::tagfilter::prep_tag_filter(p, filter, numchan),
+            DatasetLocs::Source(_, s) => crate::tagfilter::prep_tag_filter(s, filter, numchan)
+        }
+    }
+
+    //the merging functions in osmquadtree only read from files
+    fn path_locs(&mut self, what: &str) -> PyResult<&mut osmquadtree::pbfformat::ParallelFileLocs> {
+        match self {
+            DatasetLocs::Path(p) => Ok(p),
+            DatasetLocs::Source(..) => Err(PyValueError::new_err(format!("{} needs a path, not a buffer or file object", what)))
+        }
+    }
+
+    //a second set of readers, for reading on another thread
+    fn reopen(&self, py: Python, name: &str, bbox: &osmquadtree::elements::Bbox, ts: Option<i64>) -> PyResult<DatasetLocs> {
+        match self {
+            DatasetLocs::Path(_) => Ok(DatasetLocs::open(py, &name.into_py(py), bbox, ts)?.0),
+            DatasetLocs::Source(obj, (srcs, blocks, total)) => {
+                let srcs = srcs.iter().map(|s| s.reopen(py, obj)).collect::<PyResult<Vec<_>>>()?;
+                Ok(DatasetLocs::Source(obj.clone_ref(py), (srcs, blocks.clone(), *total)))
+            }
+        }
+    }
+}
+
 
 pub fn read_filter(py: Python, filter_in: Option<PyObject>) -> PyResult<(bool, osmquadtree::elements::Bbox, Option<osmquadtree::mergechanges::Poly>)> {
     
@@ -385,7 +930,7 @@ pub fn read_filter(py: Python, filter_in: Option<PyObject>) -> PyResult<(bool, o
             match v1 {
                 Ok(vv) => {
                     if vv.len()!=4 {
-                        return Err(PyValueError::new_err("must be length 4"));
+                        return Err(crate::errors::filter_error(format!("bbox filter must be length 4, not {}", vv.len())));
                     }
                     let bx = osmquadtree::elements::Bbox::new(vv[0], vv[1], vv[2], vv[3]);
                     return Ok((bx.is_planet(), bx, None));
@@ -404,7 +949,7 @@ pub fn read_filter(py: Python, filter_in: Option<PyObject>) -> PyResult<(bool, o
             }
             
            
-            return Err(PyValueError::new_err("can't handle filter"));
+            return Err(crate::errors::filter_error(String::from("can't handle filter: expected a bbox or Poly")));
         }
     }
     
@@ -413,6 +958,62 @@ pub fn read_filter(py: Python, filter_in: Option<PyObject>) -> PyResult<(bool, o
 
 
 
+/// Reads every block in `pfilelocs` in order and passes them to `conv`.
+/// The blocks are read here rather than by osmquadtree, which can't read
+/// zstd or lz4 blocks. Stops at the first block which can't be read, or if
+/// the call is cancelled.
+pub(crate) fn read_all_blocks<R: Read + Seek>(
+    pfilelocs: &mut FileLocs<R>,
+    mut conv: Box<dyn CallFinish<CallType = (usize, Vec<osmquadtree::pbfformat::FileBlock>), ReturnType = Timings<usize>, ErrorType=Error>>) -> PyResult<Timings<usize>> {
+    
+    let mut result = Ok(());
+    for idx in 0..pfilelocs.1.len() {
+        if crate::cancel::is_cancelled() {
+            break;
+        }
+        match read_fileblocks(pfilelocs, idx) {
+            Ok(fbs) => conv.call((idx, fbs)),
+            Err(e) => { result = Err(e); break; }
+        }
+    }
+    
+    //always finish, so that the worker threads are joined
+    let tm = conv.finish();
+    result?;
+    tm.map_err(|e| PyErr::from(ErrorWrapped::new(e.into())))
+}
+
+/// Reads and merges every block in `pfilelocs`, keeping only elements in
+/// `ids`, and passes the PrimitiveBlocks to `co` using `numchan` threads.
+pub(crate) fn read_primitive_blocks<R: Read + Seek>(
+    pfilelocs: &mut FileLocs<R>,
+    co: Box<dyn CallFinish<CallType = osmquadtree::elements::PrimitiveBlock, ReturnType = Timings<usize>, ErrorType=Error>>,
+    ids: Arc<dyn osmquadtree::elements::IdSet>,
+    numchan: usize) -> PyResult<Timings<usize>> {
+    
+    let conv: Box<dyn CallFinish<CallType = (usize, Vec<osmquadtree::pbfformat::FileBlock>), ReturnType = Timings<usize>, ErrorType=Error>> =
+        if numchan == 0 {
+            
+            osmquadtree::pbfformat::make_read_primitive_blocks_combine_call_all_idset(co, ids.clone(), true)
+        } else {
+            
+            let cosp = CallbackSync::new(co, numchan);
+            
+            let mut convs: Vec<
+                Box<dyn CallFinish<CallType = (usize, Vec<osmquadtree::pbfformat::FileBlock>), ReturnType = Timings<usize>, ErrorType=Error>>,
+            > = Vec::new();
+            for cos in cosp {
+                let cos2 = Box::new(ReplaceNoneWithTimings::new(cos));
+                convs.push(Box::new(Callback::new(
+                    osmquadtree::pbfformat::make_read_primitive_blocks_combine_call_all_idset(cos2, ids.clone(), true)
+                )));
+            }
+            Box::new(CallbackMerge::new(convs, Box::new(MergeTimings::new())))
+        };
+    
+    read_all_blocks(pfilelocs, conv)
+}
+
 #[pyclass]
 pub struct ReadFileBlocksParallel {
     
@@ -420,20 +1021,26 @@ pub struct ReadFileBlocksParallel {
     is_planet: bool, 
     bbox: osmquadtree::elements::Bbox,
     poly: Option<osmquadtree::mergechanges::Poly>,
-    
+    timestamp: Option<i64>,
     
     callback_num_blocks: usize,
-    pfilelocs: osmquadtree::pbfformat::ParallelFileLocs
+    pfilelocs: DatasetLocs
 }
 
 impl ReadFileBlocksParallel {
     
     
-    fn get_idset(&self, py: Python, ids: PyObject) -> PyResult<Arc<dyn osmquadtree::elements::IdSet>> {
+    fn get_idset(&self, py: Python, ids: PyObject, numchan: usize) -> PyResult<Arc<dyn osmquadtree::elements::IdSet>> {
         if ids.is_none(py) {
             return Ok(Arc::new(osmquadtree::elements::IdSetAll())); 
         }
         
+        //a TagFilter (or filter expression) needs a pass over the data first
+        if let Some(filter) = crate::tagfilter::extract_tag_filter(py, &ids)? {
+            let aa:Arc<dyn osmquadtree::elements::IdSet> = Arc::new(self.tag_filter_idset(py, &filter, numchan)?);
+            return Ok(aa);
+        }
+        
         let v1: PyResult<crate::elements::IdSet> = ids.extract(py);
         match v1 {
             Ok(vv) => { return Ok(vv.inner.clone()); },
@@ -452,15 +1059,39 @@ impl ReadFileBlocksParallel {
         Err(PyTypeError::new_err("didn't recogise ids"))
     }
     
+    fn tag_filter_idset(&self, py: Python, filter: &crate::tagfilter::TagFilter, numchan: usize) -> PyResult<osmquadtree::elements::IdSetSet> {
+        let mut pfilelocs = self.pfilelocs.reopen(py, &self.prfx, &self.bbox, self.timestamp)?;
+        
+        crate::cancel::run_interruptible(py, &[], || pfilelocs.prep_tag_filter(filter, numchan))
+    }
+    
     fn read_all_call(&mut self, callback_func: PyObject, ids: Arc<dyn osmquadtree::elements::IdSet>, numchan: usize/*, cb: Box<dyn Fn(f64)->std::io::Result<()>>*/) -> PyResult<usize> {
         
-        let co = Box::new(CollectBlocksCall::new(callback_func, self.callback_num_blocks));
+        let error: ErrorSlot = Arc::new(Mutex::new(None));
+        let co = Box::new(CollectBlocksCall::<osmquadtree::elements::PrimitiveBlock, Timings<usize>>::new("CollectBlocksCall", callback_func, self.callback_num_blocks, error.clone()));
+        
+        let tm = self.pfilelocs.read_primitive_blocks(co, ids, numchan);
+        take_error(&error)?;
+        let tm = tm?;
+        
+        let mut r = 0;
+        for (_,t) in tm.others {
+            r += t;
+        }
+        
+        Ok(r)
+    }
+    
+    fn read_all_minimal_call(&mut self, callback_func: PyObject, numchan: usize/*, cb: Box<dyn Fn(f64)->std::io::Result<()>>*/) -> PyResult<usize> {
+        
+        let error: ErrorSlot = Arc::new(Mutex::new(None));
+        let co = Box::new(CollectBlocksCall::<osmquadtree::elements::MinimalBlock, Timings<usize>>::new("CollectBlocksMinimalCall", callback_func, self.callback_num_blocks, error.clone()));
         
         
         let conv: Box<dyn CallFinish<CallType = (usize, Vec<osmquadtree::pbfformat::FileBlock>), ReturnType = Timings<usize>, ErrorType=Error>> =
             if numchan == 0 {
                 
-                osmquadtree::pbfformat::make_read_primitive_blocks_combine_call_all_idset(co, ids.clone(), true)
+                osmquadtree::pbfformat::make_read_minimal_blocks_combine_call_all(co)
             } else {
                 
                 let cosp = CallbackSync::new(co, numchan);
@@ -471,7 +1102,7 @@ impl ReadFileBlocksParallel {
                 for cos in cosp {
                     let cos2 = Box::new(ReplaceNoneWithTimings::new(cos));
                     convs.push(Box::new(Callback::new(
-                        osmquadtree::pbfformat::make_read_primitive_blocks_combine_call_all_idset(cos2, ids.clone(), true)
+                        osmquadtree::pbfformat::make_read_minimal_blocks_combine_call_all(cos2)
                     )));
                 }
                 Box::new(CallbackMerge::new(convs, Box::new(MergeTimings::new())))
@@ -482,14 +1113,9 @@ impl ReadFileBlocksParallel {
         
         
         //let tm = read_all_blocks_parallel_prog(&mut self.pfilelocs.0, &mut self.pfilelocs.1, conv, self.pfilelocs.2, cb);
-        let msg = format!("read {} blocks from {} [{} chan]", self.pfilelocs.1.len(), self.prfx, numchan);
-        let tm = osmquadtree::pbfformat::read_all_blocks_parallel_with_progbar(
-            &mut self.pfilelocs.0,
-            &self.pfilelocs.1,
-            conv,
-            &msg,
-            self.pfilelocs.2,
-        );
+        let tm = self.pfilelocs.read_all_blocks(conv);
+        take_error(&error)?;
+        let tm = tm?;
         
         let mut r = 0;
         for (_,t) in tm.others {
@@ -499,15 +1125,21 @@ impl ReadFileBlocksParallel {
         Ok(r)
     }
     
-    fn read_all_minimal_call(&mut self, callback_func: PyObject, numchan: usize/*, cb: Box<dyn Fn(f64)->std::io::Result<()>>*/) -> PyResult<usize> {
+    fn start_iter(&self, py: Python, ids: Arc<dyn osmquadtree::elements::IdSet>, numchan: usize) -> PyResult<ReadFileBlocksParallelIter> {
+        
+        //open a new set of file handles, so the worker thread owns its own readers
+        let mut pfilelocs = self.pfilelocs.reopen(py, &self.prfx, &self.bbox, self.timestamp)?;
         
-        let co = Box::new(CollectBlocksMinimalCall::new(callback_func, self.callback_num_blocks));
+        let (sender, receiver) = sync_channel(usize::max(numchan,1) * 2);
+        let cancel = Arc::new(AtomicBool::new(false));
+        let ahead = Arc::new(ReadAhead::new(usize::max(numchan,1) * 4));
         
+        let co = Box::new(SendBlocksCall::new(sender, cancel.clone()));
         
         let conv: Box<dyn CallFinish<CallType = (usize, Vec<osmquadtree::pbfformat::FileBlock>), ReturnType = Timings<usize>, ErrorType=Error>> =
             if numchan == 0 {
                 
-                osmquadtree::pbfformat::make_read_minimal_blocks_combine_call_all(co)
+                osmquadtree::pbfformat::make_read_primitive_blocks_combine_call_all_idset(co, ids.clone(), true)
             } else {
                 
                 let cosp = CallbackSync::new(co, numchan);
@@ -518,51 +1150,35 @@ impl ReadFileBlocksParallel {
                 for cos in cosp {
                     let cos2 = Box::new(ReplaceNoneWithTimings::new(cos));
                     convs.push(Box::new(Callback::new(
-                        osmquadtree::pbfformat::make_read_minimal_blocks_combine_call_all(cos2)
+                        osmquadtree::pbfformat::make_read_primitive_blocks_combine_call_all_idset(cos2, ids.clone(), true)
                     )));
                 }
                 Box::new(CallbackMerge::new(convs, Box::new(MergeTimings::new())))
             };
-
-
-        
         
-        
-        //let tm = read_all_blocks_parallel_prog(&mut self.pfilelocs.0, &mut self.pfilelocs.1, conv, self.pfilelocs.2, cb);
-        let msg = format!("read {} blocks from {} [{} chan]", self.pfilelocs.1.len(), self.prfx, numchan);
-        let tm = osmquadtree::pbfformat::read_all_blocks_parallel_with_progbar(
-            &mut self.pfilelocs.0,
-            &self.pfilelocs.1,
-            conv,
-            &msg,
-            self.pfilelocs.2,
-        );
-        
-        let mut r = 0;
-        for (_,t) in tm.others {
-            r += t;
-        }
-        
-        Ok(r)
+        let cancel_thread = cancel.clone();
+        let ahead_thread = ahead.clone();
+        let handle = std::thread::spawn(move || pfilelocs.read_all_blocks_cancellable(conv, &cancel_thread, &ahead_thread));
+        
+        Ok(ReadFileBlocksParallelIter{
+            receiver: Some(Mutex::new(receiver)),
+            pending: BTreeMap::new(),
+            next_index: 0,
+            ahead: ahead,
+            cancel: cancel,
+            handle: Some(handle)})
     }
     
     fn get_fileblocks_at(&mut self, mut idx: i64) -> PyResult<(osmquadtree::elements::Quadtree, Vec<osmquadtree::pbfformat::FileBlock>)> {
         if idx < 0 {
-            idx += self.pfilelocs.1.len() as i64;
+            idx += self.pfilelocs.blocks().len() as i64;
         }
-        if idx < 0 || idx > self.pfilelocs.1.len() as i64 {
+        if idx < 0 || idx >= self.pfilelocs.blocks().len() as i64 {
             return Err(PyIndexError::new_err(format!("{} out of range", idx)));
         }
         
-        let mut res = Vec::new();
-        for (a,b) in &self.pfilelocs.1[idx as usize].1 {
-            
-            self.pfilelocs.0[*a].seek(SeekFrom::Start(*b))?;
-            let (_,fb) = osmquadtree::pbfformat::read_file_block_with_pos(&mut self.pfilelocs.0[*a], *b)?;
-            res.push(fb);
-        
-        }
-        Ok((self.pfilelocs.1[idx as usize].0.clone(), res))
+        let res = self.pfilelocs.read_fileblocks(idx as usize)?;
+        Ok((self.pfilelocs.blocks()[idx as usize].0.clone(), res))
     }
     
     
@@ -576,23 +1192,27 @@ impl ReadFileBlocksParallel {
 #[pymethods]
 impl ReadFileBlocksParallel {
     
+    /// Reads the blocks of `prfx`: an osmquadtree prefix directory or pbf
+    /// file path, or a single pbf file as a bytes-like object or a file-like
+    /// object with read and seek methods. The write_merged methods and
+    /// prep_bbox_filter need a path.
     #[new]
     #[pyo3(signature = (prfx, filter=None,timestamp=None,callback_num_blocks=4))]
-    pub fn new(py: Python, prfx: &str, filter: Option<PyObject>/*, progress_call: PyObject*/, timestamp: Option<&str>, callback_num_blocks: usize) -> PyResult<ReadFileBlocksParallel> {
+    pub fn new(py: Python, prfx: PyObject, filter: Option<PyObject>/*, progress_call: PyObject*/, timestamp: Option<&str>, callback_num_blocks: usize) -> PyResult<ReadFileBlocksParallel> {
         
         let (is_planet, bbox, poly) = read_filter(py, filter)?;
         
         let ts = match timestamp {
-            Some(t) => Some(osmquadtree::utils::parse_timestamp(t).unwrap()),
+            Some(t) => Some(osmquadtree::utils::parse_timestamp(t).map_err(|e| crate::errors::timestamp_error(format!("{}: {}", t, e)))?),
             None => None
         };
         
-        let pfilelocs = osmquadtree::pbfformat::get_file_locs(prfx, Some(bbox.clone()), ts).unwrap();
+        let (pfilelocs, name) = DatasetLocs::open(py, &prfx, &bbox, ts)?;
         
         
         
         Ok(ReadFileBlocksParallel{
-            prfx: String::from(prfx), is_planet: is_planet, bbox: bbox, poly: poly,
+            prfx: name, is_planet: is_planet, bbox: bbox, poly: poly, timestamp: ts,
             //progress_call: progress_call, 
             callback_num_blocks: callback_num_blocks,
             pfilelocs: pfilelocs}
@@ -601,21 +1221,21 @@ impl ReadFileBlocksParallel {
         
     
     pub fn num_blocks(&self) -> PyResult<usize> {
-        Ok(self.pfilelocs.1.len())
+        Ok(self.pfilelocs.blocks().len())
     }
     
     pub fn index_at(&self, py: Python, mut idx: i64) -> PyResult<PyObject> {
         
         if idx < 0 {
-            idx += self.pfilelocs.1.len() as i64;
+            idx += self.pfilelocs.blocks().len() as i64;
         }
-        if idx < 0 || idx > self.pfilelocs.1.len() as i64 {
+        if idx < 0 || idx >= self.pfilelocs.blocks().len() as i64 {
             return Err(PyIndexError::new_err(format!("{} out of range", idx)));
         }
         
         
-        let s = self.pfilelocs.1[idx as usize].1.clone();
-        Ok((crate::elements::Quadtree::new(self.pfilelocs.1[idx as usize].0), s).into_py(py))
+        let s = self.pfilelocs.blocks()[idx as usize].1.clone();
+        Ok((crate::elements::Quadtree::new(self.pfilelocs.blocks()[idx as usize].0), s).into_py(py))
     }
     
         
@@ -631,17 +1251,34 @@ impl ReadFileBlocksParallel {
         Ok((crate::elements::Quadtree::new(q), res))
     }
     
+    /// Reads block `index`, keeping only the elements in `ids_obj`. A
+    /// TagFilter (or filter expression) is applied to this block alone,
+    /// rather than with a pass over the whole dataset: with add_referenced,
+    /// only the nodes and members found in this block are added, and those
+    /// in other blocks are missing. Pass the IdSet from prep_tag_filter for
+    /// complete data.
     pub fn primitive_block_at(&mut self, py: Python, index: i64, ids_obj: PyObject) -> PyResult<PyObject> {
         let (_,fbs) = self.get_fileblocks_at(index)?;
-        let ids = self.get_idset(py, ids_obj)?;
-        let merged = osmquadtree::pbfformat::read_primitive_blocks_combine(index, fbs, Some(ids.as_ref()))?;
+        let filter = crate::tagfilter::extract_tag_filter(py, &ids_obj)?;
+        let ids: Arc<dyn osmquadtree::elements::IdSet> = match filter {
+            Some(_) => Arc::new(osmquadtree::elements::IdSetAll()),
+            None => self.get_idset(py, ids_obj, default_numchan())?
+        };
+        let pos = fbs.first().map(|fb| fb.pos);
+        let mut merged = osmquadtree::pbfformat::read_primitive_blocks_combine(index, fbs, Some(ids.as_ref()))
+            .map_err(|e| { let e = ErrorWrapped::from(e).with_filename(&self.prfx); match pos { Some(p) => e.with_position(p), None => e } })?;
+        if let Some(f) = filter {
+            f.filter_block(&mut merged);
+        }
         
         Ok(crate::elements::PrimitiveBlock::new(merged).into_py(py))
     }
     pub fn minimal_block_at(&mut self, py: Python, index: i64) -> PyResult<PyObject> {
         let (_,fbs) = self.get_fileblocks_at(index)?;
         
-        let merged = osmquadtree::pbfformat::read_minimal_blocks_combine(index, fbs)?;
+        let pos = fbs.first().map(|fb| fb.pos);
+        let merged = osmquadtree::pbfformat::read_minimal_blocks_combine(index, fbs)
+            .map_err(|e| { let e = ErrorWrapped::from(e).with_filename(&self.prfx); match pos { Some(p) => e.with_position(p), None => e } })?;
         
         Ok(crate::elements::MinimalBlock::new(merged).into_py(py))
     }
@@ -649,58 +1286,97 @@ impl ReadFileBlocksParallel {
     pub fn read_all(&mut self, py: Python, callback_func: PyObject, ids_obj: PyObject, numchan: usize) -> PyResult<usize> {
         //let cb = self.get_prog_func(py);
         
-        let ids = self.get_idset(py, ids_obj)?;
-        py.allow_threads( || self.read_all_call(callback_func, ids, numchan))
+        let ids = self.get_idset(py, ids_obj, numchan)?;
+        crate::cancel::run_interruptible(py, &[], || self.read_all_call(callback_func, ids, numchan))
     }
     
     pub fn read_all_minimal(&mut self, py: Python, callback_func: PyObject, numchan: usize) -> PyResult<usize> {
         //let cb = self.get_prog_func(py);
         
-        py.allow_threads( || self.read_all_minimal_call(callback_func, numchan))
+        crate::cancel::run_interruptible(py, &[], || self.read_all_minimal_call(callback_func, numchan))
+    }
+    
+    
+    /// Iterates over the blocks in order. The reading thread stays at most
+    /// 4*numchan blocks ahead of the iterator.
+    #[pyo3(signature = (ids_obj=None, numchan=default_numchan()))]
+    pub fn iter_blocks(&self, py: Python, ids_obj: Option<PyObject>, numchan: usize) -> PyResult<ReadFileBlocksParallelIter> {
+        let ids = match ids_obj {
+            Some(ids_obj) => self.get_idset(py, ids_obj, numchan)?,
+            None => Arc::new(osmquadtree::elements::IdSetAll())
+        };
+        self.start_iter(py, ids, numchan)
     }
     
+    fn __iter__(&self, py: Python) -> PyResult<ReadFileBlocksParallelIter> {
+        self.start_iter(py, Arc::new(osmquadtree::elements::IdSetAll()), default_numchan())
+    }
     
     pub fn prep_bbox_filter(&mut self, py: Python, numchan: usize) -> PyResult<crate::elements::IdSet> {
-        let ii = py.allow_threads( || osmquadtree::mergechanges::prep_bbox_filter(
-            &mut self.pfilelocs,
-            &self.bbox,
-            &self.poly,
-            numchan))?;
+        let (bbox, poly) = (&self.bbox, &self.poly);
+        let pfilelocs = self.pfilelocs.path_locs("prep_bbox_filter")?;
+        crate::compression::check_upstream_readable_locs(pfilelocs, &self.prfx, "prep_bbox_filter")?;
+        let ii = crate::cancel::run_interruptible(py, &[], || Ok(osmquadtree::mergechanges::prep_bbox_filter(
+            pfilelocs,
+            bbox,
+            poly,
+            numchan)?))?;
         
         Ok(crate::elements::IdSet::new(ii))
         
                   
     }
     
+    /// Selects the elements matching `filter` (a TagFilter or a filter
+    /// expression string), for passing as `ids_obj` to `read_all` or
+    /// `write_merged`.
+    #[pyo3(signature = (filter, numchan=default_numchan()))]
+    pub fn prep_tag_filter(&self, py: Python, filter: PyObject, numchan: usize) -> PyResult<crate::elements::IdSetSet> {
+        match crate::tagfilter::extract_tag_filter(py, &filter)? {
+            Some(f) => Ok(crate::elements::IdSetSet{inner: self.tag_filter_idset(py, &f, numchan)?}),
+            None => Err(PyTypeError::new_err("expected a TagFilter or filter expression"))
+        }
+    }
+    
     pub fn write_merged(&mut self, py: Python, outfn: &str, ids_obj: PyObject, compression_type: (String, u32), numchan: usize) -> PyResult<()> {
-        let ids = self.get_idset(py, ids_obj)?;
+        let ids = self.get_idset(py, ids_obj, numchan)?;
+        let bbox = &self.bbox;
+        let pfilelocs = self.pfilelocs.path_locs("write_merged")?;
+        crate::compression::check_upstream_readable_locs(pfilelocs, &self.prfx, "write_merged")?;
         
         let tx = osmquadtree::utils::LogTimes::new();
-        let ct = compression_type_from_string((&compression_type.0, compression_type.1))?;
+        let compression = crate::compression::Compression::from_tuple((&compression_type.0, compression_type.1))?;
         
-        py.allow_threads( || { osmquadtree::mergechanges::call_mergechanges(&mut self.pfilelocs, outfn, ids, &self.bbox, ct, tx, numchan)?; Ok(()) })
+        crate::cancel::run_interruptible(py, &[String::from(outfn)], || crate::compression::write_with_compression(outfn, &compression, numchan, |fname, ct| {
+            osmquadtree::mergechanges::call_mergechanges(pfilelocs, fname, ids, bbox, ct, tx, numchan)?; Ok(())
+        }))
     }
     
     pub fn write_merged_sort(&mut self, py: Python, outfn: &str, ids_obj: PyObject, inmem: bool, compression_type: (String, u32), numchan: usize) -> PyResult<()> {
         
-        let ids = self.get_idset(py, ids_obj)?;
-        let ct = compression_type_from_string((&compression_type.0, compression_type.1))?;
+        let ids = self.get_idset(py, ids_obj, numchan)?;
+        let (bbox, is_planet) = (&self.bbox, self.is_planet);
+        let pfilelocs = self.pfilelocs.path_locs("write_merged_sort")?;
+        crate::compression::check_upstream_readable_locs(pfilelocs, &self.prfx, "write_merged_sort")?;
+        let compression = crate::compression::Compression::from_tuple((&compression_type.0, compression_type.1))?;
         let tx = osmquadtree::utils::LogTimes::new();
         if inmem {
             
-            py.allow_threads( || { osmquadtree::mergechanges::call_mergechanges_sort_inmem(&mut self.pfilelocs, outfn, ids, &self.bbox, ct, tx, numchan)?; Ok(())})
+            crate::cancel::run_interruptible(py, &[String::from(outfn)], || crate::compression::write_with_compression(outfn, &compression, numchan, |fname, ct| {
+                osmquadtree::mergechanges::call_mergechanges_sort_inmem(pfilelocs, fname, ids, bbox, ct, tx, numchan)?; Ok(())
+            }))
         } else {
-            py.allow_threads( || { 
-                let tempfn = format!("{}-temp", &outfn[..outfn.len()-4]);
+            let tempfn = format!("{}-temp", outfn.strip_suffix(".pbf").unwrap_or(outfn));
+            crate::cancel::run_interruptible(py, &[String::from(outfn), tempfn.clone()], || crate::compression::write_with_compression(outfn, &compression, numchan, |fname, ct| { 
                 let limit = 1500000;
-                let fsplit = if self.is_planet || self.pfilelocs.2 > 4 * 1024 * 1024 * 1024 {
+                let fsplit = if is_planet || pfilelocs.2 > 4 * 1024 * 1024 * 1024 {
                     128
                 } else {
                     0
                 };
-                osmquadtree::mergechanges::call_mergechanges_sort(&mut self.pfilelocs, outfn, &tempfn, limit, fsplit, ids, &self.bbox, false, ct, tx, numchan, 8)?;
+                osmquadtree::mergechanges::call_mergechanges_sort(pfilelocs, fname, &tempfn, limit, fsplit, ids, bbox, false, ct, tx, numchan, 8)?;
                 Ok(())
-            })
+            }))
         }
         
         
@@ -711,14 +1387,118 @@ impl ReadFileBlocksParallel {
         Ok(format!("{}", self.inner))
     }*/
     fn __repr__(&self) -> PyResult<String> {
-        Ok(format!("ReadFileBlocksParallel {} => {:?}, {:?}, {} files, {} locs, {} bytes", self.prfx, self.bbox, self.poly, self.pfilelocs.0.len(), self.pfilelocs.1.len(), self.pfilelocs.2))
+        Ok(format!("ReadFileBlocksParallel {} => {:?}, {:?}, {} files, {} locs, {} bytes", self.prfx, self.bbox, self.poly, self.pfilelocs.num_files(), self.pfilelocs.blocks().len(), self.pfilelocs.total_len()))
+    }
+}
+
+#[pyclass]
+pub struct ReadFileBlocksParallelIter {
+    receiver: Option<Mutex<Receiver<osmquadtree::elements::PrimitiveBlock>>>,
+    pending: BTreeMap<i64, osmquadtree::elements::PrimitiveBlock>,
+    next_index: i64,
+    ahead: Arc<ReadAhead>,
+    cancel: Arc<AtomicBool>,
+    handle: Option<std::thread::JoinHandle<PyResult<usize>>>
+}
+
+impl ReadFileBlocksParallelIter {
+    
+    fn recv_block(&mut self, py: Python) -> PyResult<Option<osmquadtree::elements::PrimitiveBlock>> {
+        loop {
+            let res = match &self.receiver {
+                None => { return Ok(None); },
+                Some(rx) => py.allow_threads(|| {
+                    match rx.lock() {
+                        Ok(r) => r.recv_timeout(std::time::Duration::from_millis(100)),
+                        Err(_) => Err(RecvTimeoutError::Disconnected)
+                    }
+                })
+            };
+            match res {
+                Ok(bl) => { return Ok(Some(bl)); },
+                Err(RecvTimeoutError::Disconnected) => { return Ok(None); },
+                Err(RecvTimeoutError::Timeout) => {
+                    if let Err(e) = py.check_signals() {
+                        self.close(py)?;
+                        return Err(e);
+                    }
+                }
+            }
+        }
+    }
+    
+    fn finish_thread(&mut self, py: Python) -> PyResult<()> {
+        self.receiver = None;
+        match self.handle.take() {
+            None => Ok(()),
+            Some(h) => match py.allow_threads(|| h.join()) {
+                Ok(r) => { r?; Ok(()) },
+                Err(_) => Err(PyRuntimeError::new_err("read blocks thread panicked"))
+            }
+        }
+    }
+}
+
+#[pymethods]
+impl ReadFileBlocksParallelIter {
+    
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+    
+    fn __next__(&mut self, py: Python) -> PyResult<Option<crate::elements::PrimitiveBlock>> {
+        loop {
+            //blocks arrive from the worker channels in any order: return them by index
+            if let Some(bl) = self.pending.remove(&self.next_index) {
+                self.next_index += 1;
+                self.ahead.set_next(self.next_index);
+                return Ok(Some(crate::elements::PrimitiveBlock::new(bl)));
+            }
+            
+            match self.recv_block(py)? {
+                Some(bl) => { self.pending.insert(bl.index, bl); },
+                None => {
+                    self.finish_thread(py)?;
+                    
+                    //reading stopped early: return what's left in order
+                    return match self.pending.keys().next().cloned() {
+                        Some(k) => {
+                            self.next_index = k + 1;
+                            Ok(self.pending.remove(&k).map(crate::elements::PrimitiveBlock::new))
+                        },
+                        None => Ok(None)
+                    };
+                }
+            }
+        }
+    }
+    
+    pub fn close(&mut self, py: Python) -> PyResult<()> {
+        self.cancel.store(true, Ordering::Relaxed);
+        self.pending.clear();
+        self.finish_thread(py)
+    }
+    
+    fn __repr__(&self) -> PyResult<String> {
+        Ok(format!("ReadFileBlocksParallelIter at block {} [{} pending]", self.next_index, self.pending.len()))
+    }
+}
+
+impl Drop for ReadFileBlocksParallelIter {
+    fn drop(&mut self) {
+        self.cancel.store(true, Ordering::Relaxed);
+        //dropping the receiver unblocks any worker waiting to send
+        self.receiver = None;
+        if let Some(h) = self.handle.take() {
+            Python::with_gil(|py| { py.allow_threads(|| { let _ = h.join(); }); });
+        }
     }
 }
 
 #[pyclass]
 #[derive(Clone)]
 pub struct Poly {
-    inner: osmquadtree::mergechanges::Poly
+    pub inner: osmquadtree::mergechanges::Poly
 }
 
 #[pymethods]
@@ -726,11 +1506,20 @@ impl Poly {
     
     #[staticmethod] 
     fn from_file(infn: &str) -> PyResult<Self> {
-        Ok(Poly{inner: osmquadtree::mergechanges::Poly::from_file(infn)?})
+        match osmquadtree::mergechanges::Poly::from_file(infn) {
+            Ok(p) => Ok(Poly{inner: p}),
+            Err(e) => Err(crate::errors::filter_error(format!("{}: {}", infn, e)))
+        }
     }
     
     #[new]
     fn new(vertsx: Vec<f64>, vertsy: Vec<f64>, name: String) -> PyResult<Self> {
+        if vertsx.len() != vertsy.len() {
+            return Err(crate::errors::filter_error(format!("vertsx and vertsy have different lengths ({} != {})", vertsx.len(), vertsy.len())));
+        }
+        if vertsx.len() < 3 {
+            return Err(crate::errors::filter_error(format!("poly {} needs at least 3 vertices", name)));
+        }
         Ok(Poly{inner: osmquadtree::mergechanges::Poly::new(vertsx, vertsy,name)})
     }
     
@@ -778,6 +1567,7 @@ pub(crate) fn wrap_readpbf(m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<FileBlock>()?;
     m.add_class::<HeaderBlock>()?;
     m.add_class::<ReadFileBlocksParallel>()?;
+    m.add_class::<ReadFileBlocksParallelIter>()?;
     m.add_class::<Poly>()?;
     
     Ok(())
@@ -2,10 +2,31 @@ use pyo3::prelude::*;
 use pyo3::wrap_pyfunction;
 use pyo3::exceptions::{PyIndexError,PyValueError};
 use crate::elements::Quadtree;
+use crate::checkpoint::Checkpoint;
+use crate::ErrorWrapped;
+use serde_json::{json,Value};
+use std::fs::File;
+use std::io::{BufReader,BufWriter,Read,Write};
 use std::sync::Arc;
 
+const GROUPS_MAGIC: &[u8] = b"OQTGRP\x01\n";
+
+fn msgs_json(msgs: &[(String, f64)]) -> Value {
+    Value::Array(msgs.iter().map(|(m, t)| json!([m, t])).collect())
+}
+
+fn msgs_from_json(v: &Value) -> Vec<(String, f64)> {
+    v.as_array().map_or_else(Vec::new, |mm| mm.iter().filter_map(|m| Some((String::from(m[0].as_str()?), m[1].as_f64()?))).collect())
+}
+
+/// Calculates the quadtree of every element in `fname`, writing them to
+/// `outfn` (by default `<fname>-qts.pbf`). With `resume`, the calculation
+/// is run in two stages recorded in the `<outfn>.checkpoint.json` manifest:
+/// collecting the way nodes into `<outfn>-waynodes`, then finding the
+/// quadtrees. A later run with the same input and parameters carries on
+/// after the last stage whose output is unchanged.
 #[pyfunction]
-#[pyo3(signature = (fname, outfn=None,qt_level=17,qt_buffer=0.05, mode=None,keep_temps=false, numchan=4, ram_gb=8))]
+#[pyo3(signature = (fname, outfn=None,qt_level=17,qt_buffer=0.05, mode=None,keep_temps=false, numchan=crate::readpbf::default_numchan(), ram_gb=8, resume=false))]
 pub fn run_calcqts(py: Python,
     fname: &str, 
     outfn: Option<&str>, 
@@ -14,12 +35,77 @@ pub fn run_calcqts(py: Python,
     mode: Option<&str>, 
     keep_temps: bool,
     numchan: usize,
-    ram_gb: usize) -> PyResult<PyObject> {
+    ram_gb: usize,
+    resume: bool) -> PyResult<PyObject> {
     
-    let (outfnf,lt,max_timestamp) = py.allow_threads( || osmquadtree::calcqts::run_calcqts(fname, outfn, qt_level, qt_buffer, mode, keep_temps, numchan,ram_gb))?;
+    if qt_level > 18 {
+        return Err(crate::errors::invalid_input_error(format!("qt_level must be at most 18, not {}", qt_level)));
+    }
+    crate::errors::check_exists(fname)?;
+    crate::compression::check_upstream_readable(fname, "run_calcqts")?;
+    let qtsfn = match outfn {
+        Some(o) => String::from(o),
+        None => format!("{}-qts.pbf", fname.strip_suffix(".pbf").unwrap_or(fname))
+    };
+
+    let mut checkpoint = Checkpoint::open(&qtsfn, &[fname], resume)?;
+    let params = json!({"qt_level": qt_level, "qt_buffer": qt_buffer, "mode": mode});
+    if let Some(r) = checkpoint.completed("calcqts", &params) {
+        if let (Some(outfnf), Some(max_timestamp)) = (r["outfn"].as_str(), r["max_timestamp"].as_i64()) {
+            return Ok((outfnf, msgs_from_json(&r["msgs"]).into_py(py), max_timestamp).into_py(py));
+        }
+    }
+
+    let (outfnf,lt,max_timestamp) = if resume {
+        let waynodesfn = format!("{}-waynodes", qtsfn);
+        if checkpoint.completed("calcqts_waynodes", &params).is_none() {
+            let lt = crate::cancel::run_interruptible(py, &[waynodesfn.clone()], || Ok(osmquadtree::calcqts::run_calcqts_prelim(fname, Some(&qtsfn), numchan)?))?;
+            checkpoint.finish_stage("calcqts_waynodes", params.clone(), &[&waynodesfn], msgs_json(&lt.msgs))?;
+        }
+        let res = crate::cancel::run_interruptible(py, &[qtsfn.clone()], || Ok(osmquadtree::calcqts::run_calcqts_load_existing(fname, Some(&qtsfn), qt_level, qt_buffer, true, numchan)?))?;
+        if !keep_temps {
+            std::fs::remove_file(&waynodesfn).map_err(|e| ErrorWrapped::from(e).with_filename(&waynodesfn))?;
+        }
+        res
+    } else {
+        crate::cancel::run_interruptible(py, &[qtsfn.clone()], || Ok(osmquadtree::calcqts::run_calcqts(fname, outfn, qt_level, qt_buffer, mode, keep_temps, numchan,ram_gb)?))?
+    };
+    checkpoint.finish_stage("calcqts", params, &[&outfnf],
+        json!({"outfn": outfnf, "msgs": msgs_json(&lt.msgs), "max_timestamp": max_timestamp}))?;
     Ok((outfnf,lt.msgs.into_py(py),max_timestamp).into_py(py))
 }
 
+type TempLocs = Vec<(i64, Vec<(u64, u64)>)>;
+
+fn temp_locs_json(fname: &str, locs: &TempLocs) -> Value {
+    json!({"fname": fname, "locs": locs})
+}
+
+fn temp_locs_from_json(v: &Value) -> Option<(String, TempLocs)> {
+    Some((String::from(v["fname"].as_str()?), serde_json::from_value(v["locs"].clone()).ok()?))
+}
+
+//the locations of the temporary blocks in each temporary file, and the
+//files themselves, or None if they were kept in memory
+fn temp_data_json(td: &osmquadtree::sortblocks::TempData) -> Option<(Value, Vec<String>)> {
+    match td {
+        osmquadtree::sortblocks::TempData::TempBlocks(_) => None,
+        osmquadtree::sortblocks::TempData::TempFile((f, locs)) => Some((json!([temp_locs_json(f, locs)]), vec![f.clone()])),
+        osmquadtree::sortblocks::TempData::TempFileSplit(parts) => Some((
+            Value::Array(parts.iter().map(|(f, locs)| temp_locs_json(f, locs)).collect()),
+            parts.iter().map(|(f, _)| f.clone()).collect()))
+    }
+}
+
+fn temp_data_from_json(v: &Value) -> Option<osmquadtree::sortblocks::TempData> {
+    let mut parts = v.as_array()?.iter().map(temp_locs_from_json).collect::<Option<Vec<_>>>()?;
+    if parts.len() == 1 {
+        parts.pop().map(osmquadtree::sortblocks::TempData::TempFile)
+    } else {
+        Some(osmquadtree::sortblocks::TempData::TempFileSplit(parts))
+    }
+}
+
 fn check_tree_idx(i: u32) -> Option<u32> {
     if i == 4294967295 {
         None
@@ -49,12 +135,74 @@ impl QuadtreeTree {
         }
     }
     
+    fn take_inner(&mut self) -> PyResult<Box<osmquadtree::sortblocks::QuadtreeTree>> {
+        match self.inner.take() {
+            Some(t) => Ok(t),
+            None => Err(PyValueError::new_err("null QuadtreeTree: already passed to find_tree_groups or sort_blocks"))
+        }
+    }
+    
+    fn check_idx(&self, i: u32) -> PyResult<()> {
+        let l = self.get_inner()?.len();
+        if (i as usize) >= l {
+            return Err(PyIndexError::new_err(format!("{} >= {}", i, l)));
+        }
+        Ok(())
+    }
+    
     fn get_inner_mut<'a>(&'a mut self) -> PyResult<&'a mut Box<osmquadtree::sortblocks::QuadtreeTree>> {
         match &mut self.inner {
             Some(t) => Ok(t),
             None => Err(PyValueError::new_err("null QuadtreeTree"))
         }
     }
+
+    fn write_file(&self, fname: &str) -> std::io::Result<()> {
+        let t = self.get_inner().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+        let mut w = BufWriter::new(File::create(fname)?);
+        w.write_all(GROUPS_MAGIC)?;
+        w.write_all(&(t.len() as u64).to_le_bytes())?;
+        for i in 0..t.len() {
+            let ii = t.at(i as u32);
+            w.write_all(&ii.qt.as_int().to_le_bytes())?;
+            w.write_all(&(ii.weight as u64).to_le_bytes())?;
+        }
+        w.flush()
+    }
+
+    //a hash of the quadtree and weight of each entry, as written by
+    //write_file, so a checkpoint can tell if the groups have changed
+    fn content_hash(&self) -> PyResult<String> {
+        let t = self.get_inner()?;
+        let mut h: u64 = 0xcbf29ce484222325;
+        for i in 0..t.len() {
+            let ii = t.at(i as u32);
+            for b in ii.qt.as_int().to_le_bytes().iter().chain((ii.weight as u64).to_le_bytes().iter()) {
+                h = (h ^ (*b as u64)).wrapping_mul(0x100000001b3);
+            }
+        }
+        Ok(format!("{:016x}", h))
+    }
+
+    fn read_file(fname: &str) -> PyResult<QuadtreeTree> {
+        crate::errors::check_exists(fname)?;
+        let mut data = Vec::new();
+        BufReader::new(File::open(fname).map_err(|e| ErrorWrapped::from(e).with_filename(fname))?).read_to_end(&mut data).map_err(ErrorWrapped::from)?;
+        if !data.starts_with(GROUPS_MAGIC) || data.len() < GROUPS_MAGIC.len() + 8 {
+            return Err(crate::errors::invalid_input_error(format!("{} is not a quadtree tree file", fname)));
+        }
+        let val = |p: usize| { let mut b = [0u8; 8]; b.copy_from_slice(&data[p..p + 8]); u64::from_le_bytes(b) };
+        let n = val(GROUPS_MAGIC.len()) as usize;
+        if data.len() != GROUPS_MAGIC.len() + 8 + n * 16 {
+            return Err(crate::errors::invalid_input_error(format!("{}: truncated quadtree tree file", fname)));
+        }
+        let mut t = osmquadtree::sortblocks::QuadtreeTree::new();
+        for i in 0..n {
+            let p = GROUPS_MAGIC.len() + 8 + i * 16;
+            t.add(&osmquadtree::elements::Quadtree::new(val(p) as i64), val(p + 8) as u32);
+        }
+        Ok(QuadtreeTree{inner: Some(Box::new(t))})
+    }
 }
 
 #[pymethods]
@@ -80,9 +228,11 @@ impl QuadtreeTree {
         Ok((p,quadtreetreeitem_tuple(py,ii)?))
     }
     pub fn next(&self, i: u32) -> PyResult<Option<u32>> {
+        self.check_idx(i)?;
         Ok(check_tree_idx(self.get_inner()?.next(i)))
     }
     pub fn next_sibling(&self, i: u32) -> PyResult<Option<u32>> {
+        self.check_idx(i)?;
         Ok(check_tree_idx(self.get_inner()?.next_sibling(i)))
     }
     
@@ -94,6 +244,17 @@ impl QuadtreeTree {
         let ii = self.get_inner_mut()?.add(&qt.inner, w);
         quadtreetreeitem_tuple(py, ii)
     }
+
+    /// Writes the quadtree and weight of each entry to `fname`.
+    pub fn write(&self, fname: &str) -> PyResult<()> {
+        self.write_file(fname).map_err(|e| PyErr::from(ErrorWrapped::from(e).with_filename(fname)))
+    }
+
+    /// Reads a tree written by QuadtreeTree.write.
+    #[staticmethod]
+    pub fn read(fname: &str) -> PyResult<QuadtreeTree> {
+        QuadtreeTree::read_file(fname)
+    }
     
 
 /*    fn __str__(&self) -> PyResult<String> {
@@ -136,7 +297,8 @@ impl PySequenceProtocol for QuadtreeTree {*/
 
 #[pyfunction]
 pub fn prepare_quadtree_tree(py: Python, qtsfn: &str, numchan: usize, maxdepth: usize) -> PyResult<QuadtreeTree> {
-    let tree = py.allow_threads( || osmquadtree::sortblocks::prepare_quadtree_tree(qtsfn, numchan, maxdepth))?;
+    crate::errors::check_exists(qtsfn)?;
+    let tree = crate::cancel::run_interruptible(py, &[], || Ok(osmquadtree::sortblocks::prepare_quadtree_tree(qtsfn, numchan, maxdepth)?))?;
     Ok(QuadtreeTree{inner: Some(tree)})
 }
 
@@ -144,7 +306,10 @@ pub fn prepare_quadtree_tree(py: Python, qtsfn: &str, numchan: usize, maxdepth:
 #[pyfunction]
 pub fn find_tree_groups(py: Python, tree_py: &mut QuadtreeTree, target: i64, min_target: i64) -> PyResult<QuadtreeTree> {
     
-    let tree = tree_py.inner.take().unwrap();
+    if target <= 0 || min_target < 0 || min_target > target {
+        return Err(crate::errors::invalid_input_error(format!("expected 0 <= min_target <= target, 0 < target: got target={}, min_target={}", target, min_target)));
+    }
+    let tree = tree_py.take_inner()?;
     
     let res = py.allow_threads(move || osmquadtree::sortblocks::find_tree_groups(tree, target, min_target))?;
     
@@ -152,17 +317,76 @@ pub fn find_tree_groups(py: Python, tree_py: &mut QuadtreeTree, target: i64, min
 }
 
 
+/// Prepares the quadtree tree from `qtsfn` and finds the groups of
+/// quadtrees for sort_blocks, as prepare_quadtree_tree followed by
+/// find_tree_groups, and writes the groups to `groupsfn`. With `resume`, if
+/// an earlier run with the same input and parameters finished, the groups
+/// are read from `groupsfn` instead.
+#[pyfunction]
+#[pyo3(signature = (qtsfn, groupsfn, target, min_target, maxdepth=17, numchan=crate::readpbf::default_numchan(), resume=false))]
+pub fn prepare_tree_groups(py: Python, qtsfn: &str, groupsfn: &str, target: i64, min_target: i64, maxdepth: usize, numchan: usize, resume: bool) -> PyResult<QuadtreeTree> {
+    let mut checkpoint = Checkpoint::open(groupsfn, &[qtsfn], resume)?;
+    let params = json!({"target": target, "min_target": min_target, "maxdepth": maxdepth});
+    if checkpoint.completed("tree_groups", &params).is_some() {
+        return QuadtreeTree::read_file(groupsfn);
+    }
+
+    let mut tree = prepare_quadtree_tree(py, qtsfn, numchan, maxdepth)?;
+    let groups = find_tree_groups(py, &mut tree, target, min_target)?;
+    groups.write(groupsfn)?;
+    checkpoint.finish_stage("tree_groups", params, &[groupsfn], json!(groups.num_entries()?))?;
+    Ok(groups)
+}
+
+/// Sorts the elements of `infn` into blocks by the groups of quadtrees in
+/// `groups_obj`, writing them to `outfn`. The elements are first split into
+/// temporary blocks for each group, which are then merged and written out.
+/// With `resume`, each of these stages is recorded in the
+/// `<outfn>.checkpoint.json` manifest, and a later run with the same
+/// inputs, groups and parameters carries on after the last stage whose
+/// output is unchanged. Temporary blocks kept in memory (`tempinmem`) can't
+/// be resumed from.
 #[pyfunction]
+#[pyo3(signature = (infn, qtsfn, outfn, groups_obj, numchan, splitat, tempinmem, limit, timestamp, keep_temps, compression_type, resume=false))]
 pub fn sort_blocks(
     py: Python, infn: &str, qtsfn: &str, outfn: &str, 
     groups_obj: &mut QuadtreeTree, numchan: usize, splitat: i64,
-    tempinmem: bool, limit: usize, timestamp: i64, keep_temps: bool, compression_type: (String,u32)) -> PyResult<PyObject> {
+    tempinmem: bool, limit: usize, timestamp: i64, keep_temps: bool, compression_type: (String,u32), resume: bool) -> PyResult<PyObject> {
         
+    crate::errors::check_exists(infn)?;
+    crate::errors::check_exists(qtsfn)?;
+    //bad arguments are reported before the input is read
+    let compression = crate::compression::Compression::from_tuple((&compression_type.0, compression_type.1))?;
+    let temp_params = json!({"groups": groups_obj.content_hash()?, "splitat": splitat, "limit": limit, "timestamp": timestamp});
+    let groups = Arc::from(groups_obj.take_inner()?);
+    crate::compression::check_upstream_readable(infn, "sort_blocks")?;
+
+    let mut checkpoint = Checkpoint::open(outfn, &[infn, qtsfn], resume)?;
+    let mut params = temp_params.clone();
+    params["compression_type"] = json!([compression_type.0, compression_type.1]);
+    if let Some(r) = checkpoint.completed("sort_blocks", &params) {
+        return Ok(msgs_from_json(&r).into_py(py));
+    }
+
     let mut lt = osmquadtree::utils::LogTimes::new();
-    let ct = crate::readpbf::compression_type_from_string((&compression_type.0, compression_type.1))?;
-    let groups = Arc::from(groups_obj.inner.take().unwrap());
-    
-    py.allow_threads(|| osmquadtree::sortblocks::sort_blocks(infn, qtsfn, outfn, groups, numchan, splitat, tempinmem, limit, timestamp, keep_temps, ct, &mut lt))?;
+
+    let tempfn = format!("{}-temp.pbf", outfn.strip_suffix(".pbf").unwrap_or(outfn));
+    let tempdata = match checkpoint.completed("temp_blocks", &temp_params).and_then(|r| temp_data_from_json(&r)) {
+        Some(td) => td,
+        None => {
+            let td = crate::cancel::run_interruptible(py, &[], || Ok(osmquadtree::sortblocks::write_temp_blocks(infn, qtsfn, &tempfn, groups.clone(), numchan, splitat, tempinmem, limit, timestamp, &mut lt)?))?;
+            if let Some((r, files)) = temp_data_json(&td) {
+                let files: Vec<&str> = files.iter().map(|f| f.as_str()).collect();
+                checkpoint.finish_stage("temp_blocks", temp_params, &files, r)?;
+            }
+            td
+        }
+    };
+
+    crate::cancel::run_interruptible(py, &[String::from(outfn)], || crate::compression::write_with_compression(outfn, &compression, numchan, |fname, ct| {
+        Ok(osmquadtree::sortblocks::write_blocks_from_temp(tempdata, fname, groups, numchan, timestamp, splitat, ct, &mut lt, keep_temps)?)
+    }))?;
+    checkpoint.finish_stage("sort_blocks", params, &[outfn], msgs_json(&lt.msgs))?;
     
     //Ok(format!("{}", lt))
     Ok(lt.msgs.into_py(py))
@@ -174,6 +398,7 @@ pub(crate) fn wrap_sortblocks(m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<QuadtreeTree>()?;
     m.add_wrapped(wrap_pyfunction!(prepare_quadtree_tree))?;
     m.add_wrapped(wrap_pyfunction!(find_tree_groups))?;
+    m.add_wrapped(wrap_pyfunction!(prepare_tree_groups))?;
     m.add_wrapped(wrap_pyfunction!(sort_blocks))?;
     Ok(())
 }
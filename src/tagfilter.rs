@@ -0,0 +1,439 @@
+use pyo3::prelude::*;
+use pyo3::exceptions::*;
+use std::collections::{BTreeMap,BTreeSet};
+use std::sync::{Arc,Mutex};
+
+use channelled_callbacks::{CallFinish,Timings,Result as ccResult};
+use osmquadtree::elements::ElementType;
+use osmquadtree::utils::Error;
+
+
+/// One filter expression, in the style of osmium tags-filter:
+/// `[types/]key[=value,...]` or `[types/]key!=value,...`, where types is
+/// any of "nwr" and a value of "*" matches any value. Only a '/' before the
+/// '=' separates the types, so values may contain '/'.
+#[derive(Clone,Debug)]
+struct FilterExpr {
+    nodes: bool,
+    ways: bool,
+    relations: bool,
+    key: String,
+    values: Option<Vec<String>>,
+    negate: bool
+}
+
+impl FilterExpr {
+    fn parse(expr: &str) -> std::result::Result<FilterExpr, String> {
+        let key_end = expr.find('=').unwrap_or(expr.len());
+        let (types, rest) = match expr[..key_end].find('/') {
+            Some(i) => (&expr[..i], &expr[i + 1..]),
+            None => ("nwr", expr)
+        };
+        if types.is_empty() || !types.chars().all(|c| c == 'n' || c == 'w' || c == 'r') {
+            return Err(format!("{}: element types must be some of \"nwr\"", expr));
+        }
+
+        let (key, values, negate) = match rest.find('=') {
+            None => (rest, None, false),
+            Some(i) => {
+                let (key, negate) = match rest[..i].strip_suffix('!') {
+                    Some(k) => (k, true),
+                    None => (&rest[..i], false)
+                };
+                let values: Vec<String> = rest[i + 1..].split(',').map(String::from).collect();
+                if values.iter().any(|v| v.is_empty()) {
+                    return Err(format!("{}: empty value", expr));
+                }
+                //key=* is the same as key
+                if values.iter().any(|v| v == "*") {
+                    if negate {
+                        return Err(format!("{}: can't negate \"*\"", expr));
+                    }
+                    (key, None, false)
+                } else {
+                    (key, Some(values), negate)
+                }
+            }
+        };
+        if key.is_empty() {
+            return Err(format!("{}: missing key", expr));
+        }
+
+        Ok(FilterExpr{
+            nodes: types.contains('n'), ways: types.contains('w'), relations: types.contains('r'),
+            key: String::from(key), values: values, negate: negate})
+    }
+
+    fn matches(&self, et: &ElementType, tags: &[osmquadtree::elements::Tag]) -> bool {
+        let type_ok = match et {
+            ElementType::Node => self.nodes,
+            ElementType::Way => self.ways,
+            ElementType::Relation => self.relations,
+            _ => false
+        };
+        if !type_ok {
+            return false;
+        }
+        match tags.iter().find(|t| t.key == self.key) {
+            None => false,
+            Some(t) => match &self.values {
+                None => true,
+                Some(vv) => vv.contains(&t.val) != self.negate
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for FilterExpr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let types: String = [(self.nodes, 'n'), (self.ways, 'w'), (self.relations, 'r')].iter().filter(|(a, _)| *a).map(|(_, c)| *c).collect();
+        write!(f, "{}/{}", types, self.key)?;
+        if let Some(vv) = &self.values {
+            write!(f, "{}={}", if self.negate { "!" } else { "" }, vv.join(","))?;
+        }
+        Ok(())
+    }
+}
+
+/// A set of tag filter expressions: an element is selected if it matches
+/// any of them. For example `TagFilter(["w/highway", "nwr/amenity=pub,bar",
+/// "r/type=multipolygon"])`. If `add_referenced` is set, the nodes of
+/// selected ways, and the members of selected relations (with the nodes of
+/// member ways), are also selected, so that the output is complete.
+#[pyclass]
+#[derive(Clone)]
+pub struct TagFilter {
+    exprs: Vec<FilterExpr>,
+    add_referenced: bool
+}
+
+impl TagFilter {
+    pub fn from_strs(exprs: &[String], add_referenced: bool) -> PyResult<TagFilter> {
+        if exprs.is_empty() {
+            return Err(crate::errors::filter_error(String::from("no tag filter expressions given")));
+        }
+        let mut res = Vec::new();
+        for e in exprs {
+            res.push(FilterExpr::parse(e.trim()).map_err(crate::errors::filter_error)?);
+        }
+        Ok(TagFilter{exprs: res, add_referenced: add_referenced})
+    }
+
+    pub fn matches_element(&self, et: &ElementType, tags: &[osmquadtree::elements::Tag]) -> bool {
+        self.exprs.iter().any(|e| e.matches(et, tags))
+    }
+
+    /// Keeps the elements of `bl` matching the filter, with the elements of
+    /// `bl` they refer to if add_referenced is set. Referenced elements in
+    /// other blocks are not added: use prep_tag_filter for complete data.
+    pub fn filter_block(&self, bl: &mut osmquadtree::elements::PrimitiveBlock) {
+        let mut ids = osmquadtree::elements::IdSetSet::new();
+        ids.nodes.extend(bl.nodes.iter().filter(|n| self.matches_element(&ElementType::Node, &n.tags)).map(|n| n.id));
+        ids.ways.extend(bl.ways.iter().filter(|w| self.matches_element(&ElementType::Way, &w.tags)).map(|w| w.id));
+        ids.relations.extend(bl.relations.iter().filter(|r| self.matches_element(&ElementType::Relation, &r.tags)).map(|r| r.id));
+
+        if self.add_referenced {
+            //relation members may themselves be relations, so repeat until
+            //no more are added
+            loop {
+                let before = ids.relations.len();
+                for r in &bl.relations {
+                    if ids.relations.contains(&r.id) {
+                        ids.relations.extend(r.members.iter().filter(|m| matches!(m.mem_type, ElementType::Relation)).map(|m| m.mem_ref));
+                    }
+                }
+                if ids.relations.len() == before {
+                    break;
+                }
+            }
+            for r in &bl.relations {
+                if ids.relations.contains(&r.id) {
+                    for m in &r.members {
+                        match m.mem_type {
+                            ElementType::Node => { ids.nodes.insert(m.mem_ref); },
+                            ElementType::Way => { ids.ways.insert(m.mem_ref); },
+                            _ => {}
+                        }
+                    }
+                }
+            }
+            for w in &bl.ways {
+                if ids.ways.contains(&w.id) {
+                    ids.nodes.extend(w.refs.iter().cloned());
+                }
+            }
+        }
+        bl.nodes.retain(|n| ids.nodes.contains(&n.id));
+        bl.ways.retain(|w| ids.ways.contains(&w.id));
+        bl.relations.retain(|r| ids.relations.contains(&r.id));
+    }
+}
+
+#[pymethods]
+impl TagFilter {
+    #[new]
+    #[pyo3(signature = (exprs, add_referenced=true))]
+    pub fn new(py: Python, exprs: PyObject, add_referenced: bool) -> PyResult<TagFilter> {
+        if let Ok(s) = exprs.extract::<String>(py) {
+            return TagFilter::from_strs(&[s], add_referenced);
+        }
+        match exprs.extract::<Vec<String>>(py) {
+            Ok(ss) => TagFilter::from_strs(&ss, add_referenced),
+            Err(_) => Err(PyTypeError::new_err("expected a str or a list of str"))
+        }
+    }
+
+    #[getter]
+    pub fn add_referenced(&self) -> PyResult<bool> { Ok(self.add_referenced) }
+
+    /// Tests element type ("node", "way" or "relation", or "n", "w", "r")
+    /// and tags against the filter.
+    pub fn matches(&self, element_type: &str, tags: Vec<(String, String)>) -> PyResult<bool> {
+        let et = match element_type.to_lowercase().as_str() {
+            "n" | "node" => ElementType::Node,
+            "w" | "way" => ElementType::Way,
+            "r" | "relation" => ElementType::Relation,
+            _ => { return Err(PyValueError::new_err(format!("unexpected type {}", element_type))); }
+        };
+        let tags: Vec<osmquadtree::elements::Tag> = tags.into_iter().map(|(k, v)| osmquadtree::elements::Tag::new(k, v)).collect();
+        Ok(self.matches_element(&et, &tags))
+    }
+
+    fn __repr__(&self) -> PyResult<String> {
+        Ok(format!("TagFilter({})", self.exprs.iter().map(|e| e.to_string()).collect::<Vec<String>>().join(" ")))
+    }
+}
+
+/// Returns the TagFilter if `obj` is a TagFilter or a filter expression string.
+pub fn extract_tag_filter(py: Python, obj: &PyObject) -> PyResult<Option<TagFilter>> {
+    if let Ok(f) = obj.extract::<TagFilter>(py) {
+        return Ok(Some(f));
+    }
+    if let Ok(s) = obj.extract::<String>(py) {
+        return Ok(Some(TagFilter::from_strs(&[s], true)?));
+    }
+    Ok(None)
+}
+
+/// As extract_tag_filter, but raises an error for anything other than None,
+/// a TagFilter or a filter expression string.
+pub fn prep_tag_filter_arg(py: Python, obj: Option<PyObject>) -> PyResult<Option<TagFilter>> {
+    match obj {
+        None => Ok(None),
+        Some(o) if o.is_none(py) => Ok(None),
+        Some(o) => match extract_tag_filter(py, &o)? {
+            Some(f) => Ok(Some(f)),
+            None => Err(PyTypeError::new_err("expected a TagFilter or filter expression"))
+        }
+    }
+}
+
+//points are kept if their node was selected, linestrings and simple
+//polygons if their way was and complicated polygons if their relation was.
+//the geometries have already been built from complete data
+struct FilterGeometryCall {
+    ids: osmquadtree::elements::IdSetSet,
+    out: crate::geometry::GeometryCallback
+}
+
+impl CallFinish for FilterGeometryCall {
+    type CallType = osmquadtree_geometry::GeometryBlock;
+    type ReturnType = Timings<osmquadtree_geometry::OtherData>;
+    type ErrorType = Error;
+
+    fn call(&mut self, mut bl: osmquadtree_geometry::GeometryBlock) {
+        let ids = &self.ids;
+        bl.points.retain(|p| ids.nodes.contains(&p.id));
+        bl.linestrings.retain(|l| ids.ways.contains(&l.id));
+        bl.simple_polygons.retain(|p| ids.ways.contains(&p.id));
+        bl.complicated_polygons.retain(|p| ids.relations.contains(&p.id));
+        self.out.call(bl);
+    }
+
+    fn finish(&mut self) -> ccResult<Self::ReturnType, Error> {
+        self.out.finish()
+    }
+}
+
+/// Wraps `cb` so that it only sees the geometry objects built from elements
+/// matching `filter`. The elements are selected with prep_tag_filter, on
+/// all their tags rather than just those the style keeps: this needs a pass
+/// over `pfilelocs` first. Referenced elements aren't added, as the nodes of
+/// a selected way aren't points in their own right.
+pub(crate) fn filter_geometry_callback<R: std::io::Read + std::io::Seek>(pfilelocs: &mut crate::readpbf::FileLocs<R>,
+        cb: crate::geometry::GeometryCallback, filter: Option<TagFilter>, numchan: usize) -> PyResult<crate::geometry::GeometryCallback> {
+    match filter {
+        None => Ok(cb),
+        Some(f) => {
+            let f = TagFilter{add_referenced: false, ..f};
+            let ids = prep_tag_filter(pfilelocs, &f, numchan)?;
+            Ok(Box::new(FilterGeometryCall{ids: ids, out: cb}))
+        }
+    }
+}
+
+
+type IdSetSlot = Arc<Mutex<osmquadtree::elements::IdSetSet>>;
+
+struct TagMatches {
+    ids: osmquadtree::elements::IdSetSet,
+    member_ways: BTreeSet<i64>,
+    member_relations: BTreeSet<i64>,
+    //the relation members of every relation, to find nested members
+    child_relations: BTreeMap<i64, Vec<i64>>
+}
+
+impl TagMatches {
+    fn new() -> TagMatches {
+        TagMatches{ids: osmquadtree::elements::IdSetSet::new(), member_ways: BTreeSet::new(), member_relations: BTreeSet::new(), child_relations: BTreeMap::new()}
+    }
+}
+
+//first pass: elements matching the filter, with the nodes of matched ways
+//and the members of matched relations
+struct CollectTagMatches {
+    filter: TagFilter,
+    matches: TagMatches,
+    result: Arc<Mutex<TagMatches>>
+}
+
+impl CallFinish for CollectTagMatches {
+    type CallType = osmquadtree::elements::PrimitiveBlock;
+    type ReturnType = Timings<usize>;
+    type ErrorType = Error;
+
+    fn call(&mut self, bl: osmquadtree::elements::PrimitiveBlock) {
+        let res = &mut self.matches;
+        for n in &bl.nodes {
+            if self.filter.matches_element(&ElementType::Node, &n.tags) {
+                res.ids.nodes.insert(n.id);
+            }
+        }
+        for w in &bl.ways {
+            if self.filter.matches_element(&ElementType::Way, &w.tags) {
+                res.ids.ways.insert(w.id);
+                if self.filter.add_referenced {
+                    res.ids.nodes.extend(w.refs.iter().cloned());
+                }
+            }
+        }
+        for r in &bl.relations {
+            if !self.filter.add_referenced {
+                if self.filter.matches_element(&ElementType::Relation, &r.tags) {
+                    res.ids.relations.insert(r.id);
+                }
+                continue;
+            }
+            let children: Vec<i64> = r.members.iter().filter(|m| matches!(m.mem_type, ElementType::Relation)).map(|m| m.mem_ref).collect();
+            if self.filter.matches_element(&ElementType::Relation, &r.tags) {
+                res.ids.relations.insert(r.id);
+                for m in &r.members {
+                    match m.mem_type {
+                        ElementType::Node => { res.ids.nodes.insert(m.mem_ref); },
+                        ElementType::Way => { res.member_ways.insert(m.mem_ref); },
+                        ElementType::Relation => { res.member_relations.insert(m.mem_ref); },
+                        _ => {}
+                    }
+                }
+            }
+            if !children.is_empty() {
+                res.child_relations.insert(r.id, children);
+            }
+        }
+    }
+
+    fn finish(&mut self) -> ccResult<Self::ReturnType, Error> {
+        let mut res = self.result.lock().unwrap();
+        *res = std::mem::replace(&mut self.matches, TagMatches::new());
+        let mut tm = Timings::new();
+        tm.add_other("CollectTagMatches", res.ids.nodes.len() + res.ids.ways.len() + res.ids.relations.len());
+        Ok(tm)
+    }
+}
+
+//later passes: the members of nested relations and the nodes of ways which
+//are only relation members
+struct CollectMembers {
+    ways: BTreeSet<i64>,
+    relations: BTreeSet<i64>,
+    result: IdSetSlot,
+    found_ways: Arc<Mutex<BTreeSet<i64>>>
+}
+
+impl CallFinish for CollectMembers {
+    type CallType = osmquadtree::elements::PrimitiveBlock;
+    type ReturnType = Timings<usize>;
+    type ErrorType = Error;
+
+    fn call(&mut self, bl: osmquadtree::elements::PrimitiveBlock) {
+        let mut res = self.result.lock().unwrap();
+        for w in &bl.ways {
+            if self.ways.contains(&w.id) {
+                res.ways.insert(w.id);
+                res.nodes.extend(w.refs.iter().cloned());
+            }
+        }
+        for r in &bl.relations {
+            if self.relations.contains(&r.id) {
+                res.relations.insert(r.id);
+                for m in &r.members {
+                    match m.mem_type {
+                        ElementType::Node => { res.nodes.insert(m.mem_ref); },
+                        ElementType::Way => { self.found_ways.lock().unwrap().insert(m.mem_ref); },
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+
+    fn finish(&mut self) -> ccResult<Self::ReturnType, Error> {
+        let mut tm = Timings::new();
+        tm.add_other("CollectMembers", self.ways.len() + self.relations.len());
+        Ok(tm)
+    }
+}
+
+/// Reads the data in `pfilelocs` and returns the selected elements. Further
+/// passes are needed if selected relations have member ways which don't
+/// match the filter themselves, or member relations: at most three in all.
+pub fn prep_tag_filter<R: std::io::Read + std::io::Seek>(pfilelocs: &mut crate::readpbf::FileLocs<R>, filter: &TagFilter, numchan: usize) -> PyResult<osmquadtree::elements::IdSetSet> {
+    let result = Arc::new(Mutex::new(TagMatches::new()));
+    let co = Box::new(CollectTagMatches{filter: filter.clone(), matches: TagMatches::new(), result: result.clone()});
+    crate::readpbf::read_primitive_blocks(pfilelocs, co, Arc::new(osmquadtree::elements::IdSetAll()), numchan)?;
+
+    let matches = std::mem::replace(&mut *result.lock().unwrap(), TagMatches::new());
+    let ids = matches.ids;
+
+    //member relations at any depth, skipping those already matched
+    let mut relations = BTreeSet::new();
+    let mut stack: Vec<i64> = matches.member_relations.into_iter().collect();
+    while let Some(r) = stack.pop() {
+        if ids.relations.contains(&r) || !relations.insert(r) {
+            continue;
+        }
+        if let Some(cc) = matches.child_relations.get(&r) {
+            stack.extend(cc.iter().cloned());
+        }
+    }
+    let mut ways: BTreeSet<i64> = matches.member_ways.into_iter().filter(|w| !ids.ways.contains(w)).collect();
+
+    let ids: IdSetSlot = Arc::new(Mutex::new(ids));
+    while !ways.is_empty() || !relations.is_empty() {
+        let found_ways = Arc::new(Mutex::new(BTreeSet::new()));
+        let co = Box::new(CollectMembers{ways: ways, relations: std::mem::take(&mut relations), result: ids.clone(), found_ways: found_ways.clone()});
+        crate::readpbf::read_primitive_blocks(pfilelocs, co, Arc::new(osmquadtree::elements::IdSetAll()), numchan)?;
+
+        let done = ids.lock().unwrap();
+        ways = found_ways.lock().unwrap().iter().filter(|w| !done.ways.contains(w)).cloned().collect();
+    }
+
+    let res = std::mem::replace(&mut *ids.lock().unwrap(), osmquadtree::elements::IdSetSet::new());
+    Ok(res)
+}
+
+
+pub(crate) fn wrap_tagfilter(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<TagFilter>()?;
+    Ok(())
+}
@@ -0,0 +1,1096 @@
+use pyo3::prelude::*;
+use pyo3::wrap_pyfunction;
+use pyo3::types::PyBytes;
+use std::collections::{BTreeMap,BTreeSet};
+use std::io::{Read,Write};
+use std::sync::Arc;
+
+use simple_protocolbuffers::{IterTags,PbfTag,PackedInt,pack_data,pack_int_ref,pack_value,zig_zag};
+use channelled_callbacks::{CallFinish,Timings,Result as ccResult};
+use osmquadtree::utils::Error;
+use osmquadtree::elements::ElementType;
+use rusqlite::OptionalExtension;
+
+use crate::geometry::{GeometryBlock,ResultSlot};
+
+type Point = (f64, f64);
+
+fn world_xy(ll: &osmquadtree_geometry::LonLat) -> Point {
+    world_xy_e7(ll.lon, ll.lat)
+}
+
+//web mercator coordinates scaled to [0,1], with y increasing southwards
+fn world_xy_e7(lon: i32, lat: i32) -> Point {
+    let lon = lon as f64 * 1e-7;
+    let lat = (lat as f64 * 1e-7).max(-85.0511).min(85.0511).to_radians();
+    let x = (lon + 180.0) / 360.0;
+    let y = (1.0 - (lat.tan() + 1.0 / lat.cos()).ln() / std::f64::consts::PI) / 2.0;
+    (x, y)
+}
+
+fn world_lonlat(p: Point) -> (f64, f64) {
+    let lon = p.0 * 360.0 - 180.0;
+    let lat = (std::f64::consts::PI * (1.0 - 2.0 * p.1)).sinh().atan().to_degrees();
+    (lon, lat)
+}
+
+#[derive(Clone,Copy)]
+struct Bounds {
+    minx: f64,
+    miny: f64,
+    maxx: f64,
+    maxy: f64
+}
+
+impl Bounds {
+    fn empty() -> Bounds {
+        Bounds{minx: f64::MAX, miny: f64::MAX, maxx: f64::MIN, maxy: f64::MIN}
+    }
+
+    fn expand(&mut self, p: Point) {
+        self.minx = self.minx.min(p.0);
+        self.miny = self.miny.min(p.1);
+        self.maxx = self.maxx.max(p.0);
+        self.maxy = self.maxy.max(p.1);
+    }
+
+    fn expand_bounds(&mut self, b: &Bounds) {
+        self.expand((b.minx, b.miny));
+        self.expand((b.maxx, b.maxy));
+    }
+
+    fn contains(&self, p: Point) -> bool {
+        p.0 >= self.minx && p.0 <= self.maxx && p.1 >= self.miny && p.1 <= self.maxy
+    }
+}
+
+fn seg_dist2(p: Point, a: Point, b: Point) -> f64 {
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+    let l = dx * dx + dy * dy;
+    let t = if l == 0.0 { 0.0 } else { (((p.0 - a.0) * dx + (p.1 - a.1) * dy) / l).max(0.0).min(1.0) };
+    let (x, y) = (a.0 + t * dx - p.0, a.1 + t * dy - p.1);
+    x * x + y * y
+}
+
+//douglas-peucker
+fn simplify(pts: &[Point], tol: f64) -> Vec<Point> {
+    if pts.len() <= 2 || tol <= 0.0 {
+        return pts.to_vec();
+    }
+    let mut keep = vec![false; pts.len()];
+    keep[0] = true;
+    keep[pts.len() - 1] = true;
+    let mut stack = vec![(0, pts.len() - 1)];
+    while let Some((a, b)) = stack.pop() {
+        let mut maxd = 0.0;
+        let mut idx = a;
+        for i in a + 1..b {
+            let d = seg_dist2(pts[i], pts[a], pts[b]);
+            if d > maxd {
+                maxd = d;
+                idx = i;
+            }
+        }
+        if maxd > tol * tol {
+            keep[idx] = true;
+            stack.push((a, idx));
+            stack.push((idx, b));
+        }
+    }
+    pts.iter().zip(keep).filter(|(_, k)| *k).map(|(p, _)| *p).collect()
+}
+
+//liang-barsky
+fn clip_segment(a: Point, b: Point, bx: &Bounds) -> Option<(Point, Point)> {
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+    let mut t0 = 0.0;
+    let mut t1 = 1.0;
+    for (p, q) in [(-dx, a.0 - bx.minx), (dx, bx.maxx - a.0), (-dy, a.1 - bx.miny), (dy, bx.maxy - a.1)] {
+        if p == 0.0 {
+            if q < 0.0 {
+                return None;
+            }
+        } else {
+            let r = q / p;
+            if p < 0.0 {
+                if r > t1 { return None; }
+                if r > t0 { t0 = r; }
+            } else {
+                if r < t0 { return None; }
+                if r < t1 { t1 = r; }
+            }
+        }
+    }
+    let pa = if t0 > 0.0 { (a.0 + t0 * dx, a.1 + t0 * dy) } else { a };
+    let pb = if t1 < 1.0 { (a.0 + t1 * dx, a.1 + t1 * dy) } else { b };
+    Some((pa, pb))
+}
+
+fn clip_line(pts: &[Point], bx: &Bounds) -> Vec<Vec<Point>> {
+    let mut res = Vec::new();
+    let mut curr: Vec<Point> = Vec::new();
+    for w in pts.windows(2) {
+        match clip_segment(w[0], w[1], bx) {
+            Some((p, q)) => {
+                if curr.last() != Some(&p) {
+                    if curr.len() >= 2 {
+                        res.push(std::mem::take(&mut curr));
+                    }
+                    curr.clear();
+                    curr.push(p);
+                }
+                curr.push(q);
+            },
+            None => {
+                if curr.len() >= 2 {
+                    res.push(std::mem::take(&mut curr));
+                }
+                curr.clear();
+            }
+        }
+    }
+    if curr.len() >= 2 {
+        res.push(curr);
+    }
+    res
+}
+
+fn inside_edge(p: Point, edge: usize, bx: &Bounds) -> bool {
+    match edge {
+        0 => p.0 >= bx.minx,
+        1 => p.0 <= bx.maxx,
+        2 => p.1 >= bx.miny,
+        _ => p.1 <= bx.maxy
+    }
+}
+
+fn intersect_edge(a: Point, b: Point, edge: usize, bx: &Bounds) -> Point {
+    match edge {
+        0 | 1 => {
+            let x = if edge == 0 { bx.minx } else { bx.maxx };
+            (x, a.1 + (x - a.0) / (b.0 - a.0) * (b.1 - a.1))
+        },
+        _ => {
+            let y = if edge == 2 { bx.miny } else { bx.maxy };
+            (a.0 + (y - a.1) / (b.1 - a.1) * (b.0 - a.0), y)
+        }
+    }
+}
+
+//sutherland-hodgman: the ring is given without its closing point
+fn clip_ring(ring: &[Point], bx: &Bounds) -> Vec<Point> {
+    let mut res = ring.to_vec();
+    for edge in 0..4 {
+        if res.is_empty() {
+            break;
+        }
+        let inp = std::mem::take(&mut res);
+        let mut prev = inp[inp.len() - 1];
+        for &curr in &inp {
+            let (ci, pi) = (inside_edge(curr, edge, bx), inside_edge(prev, edge, bx));
+            if ci {
+                if !pi {
+                    res.push(intersect_edge(prev, curr, edge, bx));
+                }
+                res.push(curr);
+            } else if pi {
+                res.push(intersect_edge(prev, curr, edge, bx));
+            }
+            prev = curr;
+        }
+    }
+    res
+}
+
+fn closed_ring<'a, T: Iterator<Item=&'a osmquadtree_geometry::LonLat>>(ll: T) -> Vec<Point> {
+    let mut res: Vec<Point> = ll.map(world_xy).collect();
+    if !res.is_empty() && res.first() != res.last() {
+        res.push(res[0]);
+    }
+    res
+}
+
+enum WorldGeometry {
+    Point(Point),
+    Line(Vec<Point>),
+    //parts, each an exterior ring followed by any interior rings, all closed
+    Polygon(Vec<Vec<Vec<Point>>>)
+}
+
+impl WorldGeometry {
+    fn bounds(&self) -> Bounds {
+        let mut b = Bounds::empty();
+        match self {
+            WorldGeometry::Point(p) => b.expand(*p),
+            WorldGeometry::Line(pts) => pts.iter().for_each(|p| b.expand(*p)),
+            WorldGeometry::Polygon(parts) => parts.iter().for_each(|pt| pt.iter().for_each(|r| r.iter().for_each(|p| b.expand(*p))))
+        }
+        b
+    }
+
+    fn simplified(&self, tol: f64) -> Option<WorldGeometry> {
+        match self {
+            WorldGeometry::Point(p) => Some(WorldGeometry::Point(*p)),
+            WorldGeometry::Line(pts) => Some(WorldGeometry::Line(simplify(pts, tol))),
+            WorldGeometry::Polygon(parts) => {
+                let mut res = Vec::new();
+                for pt in parts {
+                    let mut rings = Vec::new();
+                    for (i, r) in pt.iter().enumerate() {
+                        let s = simplify(r, tol);
+                        //rings are closed, so a triangle has four points
+                        if s.len() >= 4 {
+                            rings.push(s);
+                        } else if i == 0 {
+                            break;
+                        }
+                    }
+                    if !rings.is_empty() {
+                        res.push(rings);
+                    }
+                }
+                if res.is_empty() { None } else { Some(WorldGeometry::Polygon(res)) }
+            }
+        }
+    }
+}
+
+fn command(id: u64, count: usize) -> u64 {
+    (id & 7) | ((count as u64) << 3)
+}
+
+struct GeometryEncoder {
+    cmds: Vec<u64>,
+    cx: i64,
+    cy: i64
+}
+
+impl GeometryEncoder {
+    fn new() -> GeometryEncoder {
+        GeometryEncoder{cmds: Vec::new(), cx: 0, cy: 0}
+    }
+
+    fn add_point(&mut self, p: (i64, i64)) {
+        self.cmds.push(zig_zag(p.0 - self.cx));
+        self.cmds.push(zig_zag(p.1 - self.cy));
+        self.cx = p.0;
+        self.cy = p.1;
+    }
+
+    fn add_path(&mut self, pts: &[(i64, i64)], close: bool) {
+        self.cmds.push(command(1, 1));
+        self.add_point(pts[0]);
+        self.cmds.push(command(2, pts.len() - 1));
+        for p in &pts[1..] {
+            self.add_point(*p);
+        }
+        if close {
+            self.cmds.push(command(7, 1));
+        }
+    }
+}
+
+//twice the signed area, positive for clockwise rings in tile coordinates
+fn ring_area2(pts: &[(i64, i64)]) -> i64 {
+    let mut a = 0;
+    for i in 0..pts.len() {
+        let (p, q) = (pts[i], pts[(i + 1) % pts.len()]);
+        a += p.0 * q.1 - q.0 * p.1;
+    }
+    a
+}
+
+/// Tile geometry type and encoded commands.
+struct TileGeometry {
+    geom_type: u64,
+    commands: Vec<u64>
+}
+
+struct TileTransform {
+    scale: f64,
+    x: f64,
+    y: f64,
+    extent: f64
+}
+
+impl TileTransform {
+    fn apply(&self, p: Point) -> (i64, i64) {
+        (((p.0 * self.scale - self.x) * self.extent).round() as i64, ((p.1 * self.scale - self.y) * self.extent).round() as i64)
+    }
+
+    fn apply_all(&self, pts: &[Point]) -> Vec<(i64, i64)> {
+        let mut res: Vec<(i64, i64)> = Vec::with_capacity(pts.len());
+        for p in pts {
+            let q = self.apply(*p);
+            if res.last() != Some(&q) {
+                res.push(q);
+            }
+        }
+        res
+    }
+}
+
+fn make_tile_geometry(geom: &WorldGeometry, bx: &Bounds, tf: &TileTransform) -> Option<TileGeometry> {
+    let mut enc = GeometryEncoder::new();
+    let geom_type = match geom {
+        WorldGeometry::Point(p) => {
+            if !bx.contains(*p) {
+                return None;
+            }
+            enc.cmds.push(command(1, 1));
+            enc.add_point(tf.apply(*p));
+            1
+        },
+        WorldGeometry::Line(pts) => {
+            for part in clip_line(pts, bx) {
+                let q = tf.apply_all(&part);
+                if q.len() >= 2 {
+                    enc.add_path(&q, false);
+                }
+            }
+            2
+        },
+        WorldGeometry::Polygon(parts) => {
+            for pt in parts {
+                for (i, r) in pt.iter().enumerate() {
+                    let mut q = tf.apply_all(&clip_ring(&r[..r.len() - 1], bx));
+                    if q.len() > 1 && q.first() == q.last() {
+                        q.pop();
+                    }
+                    let a = ring_area2(&q);
+                    if q.len() < 3 || a == 0 {
+                        if i == 0 {
+                            break;
+                        }
+                        continue;
+                    }
+                    //exterior rings are clockwise, interior rings anticlockwise
+                    if (i == 0) != (a > 0) {
+                        q.reverse();
+                    }
+                    enc.add_path(&q, true);
+                }
+            }
+            3
+        }
+    };
+    if enc.cmds.is_empty() {
+        None
+    } else {
+        Some(TileGeometry{geom_type: geom_type, commands: enc.cmds})
+    }
+}
+
+struct TileFeature {
+    id: u64,
+    z_order: i64,
+    tags: Arc<Vec<(String, String)>>,
+    geometry: TileGeometry
+}
+
+//the osm id times ten plus 1 for nodes, 2 for ways and 3 for relations, so
+//that objects of different types don't share an id
+fn feature_id(id: i64, et: ElementType) -> u64 {
+    let t = match et {
+        ElementType::Node => 1,
+        ElementType::Way => 2,
+        _ => 3
+    };
+    (id.max(0) as u64) * 10 + t
+}
+
+type TileLayers = BTreeMap<String, Vec<TileFeature>>;
+
+fn pack_layer(name: &str, features: &[TileFeature], extent: u32) -> Vec<u8> {
+    let mut keys: Vec<&str> = Vec::new();
+    let mut key_idx: BTreeMap<&str, u64> = BTreeMap::new();
+    let mut values: Vec<&str> = Vec::new();
+    let mut value_idx: BTreeMap<&str, u64> = BTreeMap::new();
+
+    let mut res = Vec::new();
+    pack_value(&mut res, 15, 2);
+    pack_data(&mut res, 1, name.as_bytes());
+
+    //features are drawn in order
+    let mut sorted: Vec<&TileFeature> = features.iter().collect();
+    sorted.sort_by_key(|f| f.z_order);
+    for f in sorted {
+        let mut tags = Vec::with_capacity(f.tags.len() * 2);
+        for (k, v) in f.tags.iter() {
+            let ki = *key_idx.entry(k.as_str()).or_insert_with(|| { keys.push(k.as_str()); (keys.len() - 1) as u64 });
+            let vi = *value_idx.entry(v.as_str()).or_insert_with(|| { values.push(v.as_str()); (values.len() - 1) as u64 });
+            tags.push(ki);
+            tags.push(vi);
+        }
+        let mut fd = Vec::new();
+        pack_value(&mut fd, 1, f.id);
+        if !tags.is_empty() {
+            pack_data(&mut fd, 2, &pack_int_ref(tags.iter()));
+        }
+        pack_value(&mut fd, 3, f.geometry.geom_type);
+        pack_data(&mut fd, 4, &pack_int_ref(f.geometry.commands.iter()));
+        pack_data(&mut res, 2, &fd);
+    }
+    for k in keys {
+        pack_data(&mut res, 3, k.as_bytes());
+    }
+    for v in values {
+        let mut vd = Vec::new();
+        pack_data(&mut vd, 1, v.as_bytes());
+        pack_data(&mut res, 4, &vd);
+    }
+    pack_value(&mut res, 5, extent as u64);
+    res
+}
+
+fn pack_tile(layers: &TileLayers, extent: u32) -> Vec<u8> {
+    let mut res = Vec::new();
+    for (name, features) in layers {
+        pack_data(&mut res, 3, &pack_layer(name, features, extent));
+    }
+    res
+}
+
+//reads back a layer written by pack_layer: only string values are handled
+fn unpack_layer(data: &[u8]) -> (String, Vec<TileFeature>) {
+    let mut name = String::new();
+    let mut features = Vec::new();
+    let mut keys = Vec::new();
+    let mut values = Vec::new();
+    for t in IterTags::new(data) {
+        match t {
+            PbfTag::Data(1, d) => { name = String::from_utf8_lossy(d).to_string(); },
+            PbfTag::Data(2, d) => { features.push(d); },
+            PbfTag::Data(3, d) => { keys.push(String::from_utf8_lossy(d).to_string()); },
+            PbfTag::Data(4, d) => {
+                let mut v = String::new();
+                for vt in IterTags::new(d) {
+                    if let PbfTag::Data(1, x) = vt {
+                        v = String::from_utf8_lossy(x).to_string();
+                    }
+                }
+                values.push(v);
+            },
+            _ => {}
+        }
+    }
+    let features = features.into_iter().map(|d| {
+        let mut f = TileFeature{id: 0, z_order: 0, tags: Arc::new(Vec::new()), geometry: TileGeometry{geom_type: 0, commands: Vec::new()}};
+        let mut tags = Vec::new();
+        for t in IterTags::new(d) {
+            match t {
+                PbfTag::Value(1, v) => { f.id = v; },
+                PbfTag::Data(2, x) => {
+                    let idx: Vec<u64> = PackedInt::new(x).collect();
+                    for kv in idx.chunks(2) {
+                        if let (Some(k), Some(v)) = (keys.get(kv[0] as usize), kv.get(1).and_then(|v| values.get(*v as usize))) {
+                            tags.push((k.clone(), v.clone()));
+                        }
+                    }
+                },
+                PbfTag::Value(3, v) => { f.geometry.geom_type = v; },
+                PbfTag::Data(4, x) => { f.geometry.commands = PackedInt::new(x).collect(); },
+                _ => {}
+            }
+        }
+        f.tags = Arc::new(tags);
+        f
+    }).collect();
+    (name, features)
+}
+
+fn unpack_tile(data: &[u8]) -> TileLayers {
+    let mut res = TileLayers::new();
+    for t in IterTags::new(data) {
+        if let PbfTag::Data(3, d) = t {
+            let (name, features) = unpack_layer(d);
+            res.entry(name).or_insert_with(Vec::new).extend(features);
+        }
+    }
+    res
+}
+
+//the z_order of each feature of each layer, in the order pack_layer writes
+//them, as runs of (z_order, count). the z_order isn't part of the packed
+//tile, so this is kept for tiles which may need late features merged in
+type ZOrderRuns = BTreeMap<String, Vec<(i64, usize)>>;
+
+fn z_order_runs(layers: &TileLayers) -> ZOrderRuns {
+    layers.iter().map(|(name, features)| {
+        let mut zz: Vec<i64> = features.iter().map(|f| f.z_order).collect();
+        zz.sort();
+        let mut runs: Vec<(i64, usize)> = Vec::new();
+        for z in zz {
+            match runs.last_mut() {
+                Some((lz, n)) if *lz == z => { *n += 1; },
+                _ => { runs.push((z, 1)); }
+            }
+        }
+        (name.clone(), runs)
+    }).collect()
+}
+
+//unpacks a tile written by pack_tile, restoring the z_order of its features
+//from `runs`
+fn unpack_tile_with_z_order(data: &[u8], runs: &ZOrderRuns) -> TileLayers {
+    let mut res = unpack_tile(data);
+    for (name, features) in res.iter_mut() {
+        if let Some(rr) = runs.get(name) {
+            let zz = rr.iter().flat_map(|(z, n)| std::iter::repeat(*z).take(*n));
+            for (f, z) in features.iter_mut().zip(zz) {
+                f.z_order = z;
+            }
+        }
+    }
+    res
+}
+
+fn gzip(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut enc = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    enc.write_all(data)?;
+    enc.finish()
+}
+
+struct LayerInfo {
+    fields: BTreeSet<String>,
+    minzoom: u32,
+    maxzoom: u32
+}
+
+type TileKey = (u32, u32, u32);
+
+/// Where tiles are written: an MBTiles file if the path ends with .mbtiles,
+/// else a directory tree `{outpath}/{z}/{x}/{y}.pbf`.
+enum TileOutput {
+    Directory(String),
+    MbTiles(String, rusqlite::Connection)
+}
+
+impl TileOutput {
+    fn open(outpath: &str) -> std::result::Result<TileOutput, String> {
+        if !outpath.ends_with(".mbtiles") {
+            return Ok(TileOutput::Directory(String::from(outpath)));
+        }
+        let map_err = |e: rusqlite::Error| format!("{}: {}", outpath, e);
+        if std::path::Path::new(outpath).exists() {
+            std::fs::remove_file(outpath).map_err(|e| format!("{}: {}", outpath, e))?;
+        }
+        let conn = rusqlite::Connection::open(outpath).map_err(map_err)?;
+        conn.execute_batch("CREATE TABLE metadata (name text, value text);
+            CREATE TABLE tiles (zoom_level integer, tile_column integer, tile_row integer, tile_data blob);
+            CREATE UNIQUE INDEX tile_index ON tiles (zoom_level, tile_column, tile_row);").map_err(map_err)?;
+        Ok(TileOutput::MbTiles(String::from(outpath), conn))
+    }
+
+    fn name(&self) -> String {
+        match self {
+            TileOutput::Directory(outdir) => std::path::Path::new(outdir).file_name(),
+            TileOutput::MbTiles(outfn, _) => std::path::Path::new(outfn).file_stem()
+        }.map(|n| n.to_string_lossy().to_string()).unwrap_or_default()
+    }
+
+    //directory tiles are uncompressed: mbtiles stores gzipped tiles with
+    //tms row numbers
+    fn write_tiles(&mut self, tiles: &[(TileKey, Vec<u8>)]) -> std::result::Result<(), String> {
+        match self {
+            TileOutput::Directory(outdir) => {
+                let map_err = |e: std::io::Error| format!("{}: {}", outdir, e);
+                for ((z, x, y), data) in tiles {
+                    let dir = std::path::Path::new(outdir.as_str()).join(z.to_string()).join(x.to_string());
+                    std::fs::create_dir_all(&dir).map_err(map_err)?;
+                    std::fs::write(dir.join(format!("{}.pbf", y)), data).map_err(map_err)?;
+                }
+                Ok(())
+            },
+            TileOutput::MbTiles(outfn, conn) => {
+                let map_err = |e: rusqlite::Error| format!("{}: {}", outfn, e);
+                let tx = conn.transaction().map_err(map_err)?;
+                {
+                    let mut st = tx.prepare("INSERT OR REPLACE INTO tiles (zoom_level, tile_column, tile_row, tile_data) VALUES (?1, ?2, ?3, ?4)").map_err(map_err)?;
+                    for ((z, x, y), data) in tiles {
+                        let data = gzip(data).map_err(|e| format!("{}: {}", outfn, e))?;
+                        let row = (1i64 << z) - 1 - *y as i64;
+                        st.execute(rusqlite::params![z, x, row, data]).map_err(map_err)?;
+                    }
+                }
+                tx.commit().map_err(map_err)
+            }
+        }
+    }
+
+    fn read_tile(&self, (z, x, y): TileKey) -> std::result::Result<Option<Vec<u8>>, String> {
+        match self {
+            TileOutput::Directory(outdir) => {
+                let path = std::path::Path::new(outdir.as_str()).join(z.to_string()).join(x.to_string()).join(format!("{}.pbf", y));
+                if !path.exists() {
+                    return Ok(None);
+                }
+                std::fs::read(path).map(Some).map_err(|e| format!("{}: {}", outdir, e))
+            },
+            TileOutput::MbTiles(outfn, conn) => {
+                let row = (1i64 << z) - 1 - y as i64;
+                let data: Option<Vec<u8>> = conn.query_row("SELECT tile_data FROM tiles WHERE zoom_level = ?1 AND tile_column = ?2 AND tile_row = ?3",
+                    rusqlite::params![z, x, row], |r| r.get(0)).optional().map_err(|e| format!("{}: {}", outfn, e))?;
+                match data {
+                    None => Ok(None),
+                    Some(d) => {
+                        let mut res = Vec::new();
+                        flate2::read::GzDecoder::new(&d[..]).read_to_end(&mut res).map_err(|e| format!("{}: {}", outfn, e))?;
+                        Ok(Some(res))
+                    }
+                }
+            }
+        }
+    }
+
+    fn write_metadata(&mut self, metadata: Vec<(&'static str, String)>) -> std::result::Result<(), String> {
+        match self {
+            TileOutput::Directory(outdir) => {
+                let meta: serde_json::Map<String, serde_json::Value> = metadata.into_iter().map(|(k, v)| (String::from(k), serde_json::Value::from(v))).collect();
+                std::fs::create_dir_all(outdir.as_str()).map_err(|e| format!("{}: {}", outdir, e))?;
+                std::fs::write(std::path::Path::new(outdir.as_str()).join("metadata.json"), serde_json::Value::Object(meta).to_string())
+                    .map_err(|e| format!("{}: {}", outdir, e))
+            },
+            TileOutput::MbTiles(outfn, conn) => {
+                let map_err = |e: rusqlite::Error| format!("{}: {}", outfn, e);
+                let tx = conn.transaction().map_err(map_err)?;
+                {
+                    let mut st = tx.prepare("INSERT INTO metadata (name, value) VALUES (?1, ?2)").map_err(map_err)?;
+                    for (k, v) in metadata {
+                        st.execute(rusqlite::params![k, v]).map_err(map_err)?;
+                    }
+                }
+                tx.commit().map_err(map_err)
+            }
+        }
+    }
+}
+
+/// The blocks to be added to a TileSet, so that each tile can be written
+/// once every block whose quadtree, with its buffer, overlaps the tile has
+/// been added: the objects of a block are always inside those bounds.
+struct BlockProgress {
+    zoom: u32,
+    //the last block overlapping each cell of a grid at `zoom`
+    last: Vec<i64>,
+    //every block before next has been added
+    next: i64,
+    added: BTreeSet<i64>,
+    waiting: BTreeMap<i64, Vec<TileKey>>
+}
+
+impl BlockProgress {
+    fn new(quadtrees: &[osmquadtree::elements::Quadtree], maxzoom: u32) -> BlockProgress {
+        let zoom = maxzoom.min(10);
+        let mut res = BlockProgress{zoom: zoom, last: vec![-1; 1 << (2 * zoom)], next: 0, added: BTreeSet::new(), waiting: BTreeMap::new()};
+        for (i, q) in quadtrees.iter().enumerate() {
+            let bx = q.as_bbox(crate::dataset::QUADTREE_BUFFER);
+            let (minx, miny) = world_xy_e7(bx.minlon, bx.maxlat);
+            let (maxx, maxy) = world_xy_e7(bx.maxlon, bx.minlat);
+            for c in res.cells(&Bounds{minx: minx, miny: miny, maxx: maxx, maxy: maxy}) {
+                res.last[c] = i as i64;
+            }
+        }
+        res
+    }
+
+    fn cells(&self, b: &Bounds) -> Vec<usize> {
+        let n = 1i64 << self.zoom;
+        let cell = |v: f64| ((v * n as f64).floor() as i64).max(0).min(n - 1);
+        let mut res = Vec::new();
+        for y in cell(b.miny)..=cell(b.maxy) {
+            for x in cell(b.minx)..=cell(b.maxx) {
+                res.push((y * n + x) as usize);
+            }
+        }
+        res
+    }
+
+    //the last block which may have objects inside `b`
+    fn last_block(&self, b: &Bounds) -> i64 {
+        self.cells(b).into_iter().map(|c| self.last[c]).max().unwrap_or(-1)
+    }
+
+    /// Marks block `idx` as added, returning the tiles which are now
+    /// complete.
+    fn add(&mut self, idx: i64) -> Vec<TileKey> {
+        self.added.insert(idx);
+        while self.added.remove(&self.next) {
+            self.next += 1;
+        }
+        let rest = self.waiting.split_off(&self.next);
+        std::mem::replace(&mut self.waiting, rest).into_values().flatten().collect()
+    }
+}
+
+/// Clipped and simplified features for each tile. Without a BlockProgress
+/// every tile is kept in memory until written: with one, tiles are taken
+/// as they are completed, and features for tiles which have already been
+/// taken are kept apart, to be merged into the written tile at the end.
+struct TileSet {
+    minzoom: u32,
+    maxzoom: u32,
+    extent: u32,
+    buffer: f64,
+    simplify: f64,
+    feature_keys: Vec<String>,
+    layer_names: BTreeMap<String, String>,
+    tiles: BTreeMap<TileKey, TileLayers>,
+    progress: Option<BlockProgress>,
+    late: BTreeMap<TileKey, TileLayers>,
+    written: BTreeMap<TileKey, ZOrderRuns>,
+    layers: BTreeMap<String, LayerInfo>,
+    bounds: Bounds
+}
+
+impl TileSet {
+    fn new(minzoom: u32, maxzoom: u32, style: &osmquadtree_geometry::GeometryStyle, layer_names: Option<BTreeMap<String, String>>, extent: u32, buffer: u32, simplify: f64) -> PyResult<TileSet> {
+        if minzoom > maxzoom || maxzoom > 24 {
+            return Err(crate::errors::invalid_input_error(format!("invalid zoom range {} to {}", minzoom, maxzoom)));
+        }
+        if extent == 0 {
+            return Err(crate::errors::invalid_input_error(String::from("extent must be greater than zero")));
+        }
+        Ok(TileSet{
+            minzoom: minzoom, maxzoom: maxzoom, extent: extent, buffer: buffer as f64 / extent as f64, simplify: simplify,
+            feature_keys: style.feature_keys.iter().cloned().collect(),
+            layer_names: layer_names.unwrap_or_else(BTreeMap::new),
+            tiles: BTreeMap::new(), progress: None, late: BTreeMap::new(), written: BTreeMap::new(), layers: BTreeMap::new(), bounds: Bounds::empty()})
+    }
+
+    //the first of the style's feature keys found in the tags, or else the
+    //geometry type
+    fn layer_for(&self, tags: &[osmquadtree::elements::Tag], default: &str) -> Option<String> {
+        let key = self.feature_keys.iter().find(|k| tags.iter().any(|t| &t.key == *k)).map(|k| k.as_str()).unwrap_or(default);
+        match self.layer_names.get(key) {
+            Some(n) if n.is_empty() => None,
+            Some(n) => Some(n.clone()),
+            None => Some(String::from(key))
+        }
+    }
+
+    fn tile_layers(&mut self, key: TileKey, bx: &Bounds) -> &mut TileLayers {
+        if !self.tiles.contains_key(&key) {
+            if let Some(pg) = self.progress.as_mut() {
+                let last = pg.last_block(bx);
+                if last < pg.next {
+                    return self.late.entry(key).or_insert_with(BTreeMap::new);
+                }
+                pg.waiting.entry(last).or_insert_with(Vec::new).push(key);
+            }
+        }
+        self.tiles.entry(key).or_insert_with(BTreeMap::new)
+    }
+
+    fn add_object(&mut self, default_layer: &str, id: u64, tags: &[osmquadtree::elements::Tag], minzoom: Option<i64>, z_order: Option<i64>, geom: WorldGeometry) {
+        let layer = match self.layer_for(tags, default_layer) {
+            Some(l) => l,
+            None => { return; }
+        };
+        let minzoom = u32::max(self.minzoom, minzoom.unwrap_or(0).max(0) as u32);
+        if minzoom > self.maxzoom {
+            return;
+        }
+        let tags: Arc<Vec<(String, String)>> = Arc::new(tags.iter().map(|t| (t.key.clone(), t.val.clone())).collect());
+        let bounds = geom.bounds();
+
+        let mut added = false;
+        for z in minzoom..=self.maxzoom {
+            let scale = (1u64 << z) as f64;
+            let g = match geom.simplified(self.simplify / (self.extent as f64 * scale)) {
+                Some(g) => g,
+                None => { continue; }
+            };
+            let maxt = (1u64 << z) as i64 - 1;
+            let tile_range = |a: f64, b: f64| ((a * scale - self.buffer).floor().max(0.0) as i64, i64::min(maxt, (b * scale + self.buffer).floor() as i64));
+            let (x0, x1) = tile_range(bounds.minx, bounds.maxx);
+            let (y0, y1) = tile_range(bounds.miny, bounds.maxy);
+            let mut added_z = false;
+            for x in x0..=x1 {
+                for y in y0..=y1 {
+                    let bx = Bounds{
+                        minx: (x as f64 - self.buffer) / scale, miny: (y as f64 - self.buffer) / scale,
+                        maxx: (x as f64 + 1.0 + self.buffer) / scale, maxy: (y as f64 + 1.0 + self.buffer) / scale};
+                    let tf = TileTransform{scale: scale, x: x as f64, y: y as f64, extent: self.extent as f64};
+                    if let Some(tg) = make_tile_geometry(&g, &bx, &tf) {
+                        self.tile_layers((z, x as u32, y as u32), &bx)
+                            .entry(layer.clone()).or_insert_with(Vec::new)
+                            .push(TileFeature{id: id, z_order: z_order.unwrap_or(0), tags: tags.clone(), geometry: tg});
+                        added_z = true;
+                    }
+                }
+            }
+            if added_z {
+                let li = self.layers.entry(layer.clone()).or_insert_with(|| LayerInfo{fields: BTreeSet::new(), minzoom: z, maxzoom: z});
+                li.minzoom = li.minzoom.min(z);
+                li.maxzoom = li.maxzoom.max(z);
+                added = true;
+            }
+        }
+        if added {
+            if let Some(li) = self.layers.get_mut(&layer) {
+                li.fields.extend(tags.iter().map(|(k, _)| k.clone()));
+            }
+            self.bounds.expand_bounds(&bounds);
+        }
+    }
+
+    fn add_block(&mut self, bl: &osmquadtree_geometry::GeometryBlock) {
+        for p in &bl.points {
+            self.add_object("point", feature_id(p.id, ElementType::Node), &p.tags, p.minzoom, None, WorldGeometry::Point(world_xy(&p.lonlat)));
+        }
+        for l in &bl.linestrings {
+            let pts = l.lonlats.iter().map(world_xy).collect();
+            self.add_object("line", feature_id(l.id, ElementType::Way), &l.tags, l.minzoom, l.z_order, WorldGeometry::Line(pts));
+        }
+        for p in &bl.simple_polygons {
+            let ring = closed_ring(p.lonlats.iter());
+            self.add_object("polygon", feature_id(p.id, ElementType::Way), &p.tags, p.minzoom, p.z_order, WorldGeometry::Polygon(vec![vec![ring]]));
+        }
+        for p in &bl.complicated_polygons {
+            let mut parts = Vec::new();
+            for pt in &p.parts {
+                let mut rings = vec![closed_ring(pt.exterior.lonlats_iter())];
+                for ii in &pt.interiors {
+                    rings.push(closed_ring(ii.lonlats_iter()));
+                }
+                parts.push(rings);
+            }
+            self.add_object("polygon", feature_id(p.id, ElementType::Relation), &p.tags, p.minzoom, p.z_order, WorldGeometry::Polygon(parts));
+        }
+    }
+
+    fn tile_data(&self, z: u32, x: u32, y: u32) -> Option<Vec<u8>> {
+        self.tiles.get(&(z, x, y)).map(|l| pack_tile(l, self.extent))
+    }
+
+    /// Removes the tiles completed by adding block `idx`, returning the
+    /// encoded data.
+    fn take_complete(&mut self, idx: i64) -> Vec<(TileKey, Vec<u8>)> {
+        let keys = match self.progress.as_mut() {
+            Some(pg) => pg.add(idx),
+            None => { return Vec::new(); }
+        };
+        let mut res = Vec::new();
+        for k in keys {
+            if let Some(l) = self.tiles.remove(&k) {
+                self.written.insert(k, z_order_runs(&l));
+                res.push((k, pack_tile(&l, self.extent)));
+            }
+        }
+        res
+    }
+
+    fn take_all(&mut self) -> Vec<(TileKey, Vec<u8>)> {
+        let extent = self.extent;
+        std::mem::take(&mut self.tiles).into_iter().map(|(k, l)| (k, pack_tile(&l, extent))).collect()
+    }
+
+    fn metadata(&self, name: &str) -> Vec<(&'static str, String)> {
+        let (minlon, maxlat) = world_lonlat((self.bounds.minx, self.bounds.miny));
+        let (maxlon, minlat) = world_lonlat((self.bounds.maxx, self.bounds.maxy));
+        let vector_layers: Vec<serde_json::Value> = self.layers.iter().map(|(k, li)| serde_json::json!({
+            "id": k,
+            "fields": li.fields.iter().map(|f| (f.clone(), serde_json::Value::from("String"))).collect::<serde_json::Map<String, serde_json::Value>>(),
+            "minzoom": li.minzoom,
+            "maxzoom": li.maxzoom
+        })).collect();
+        vec![
+            ("name", String::from(name)),
+            ("format", String::from("pbf")),
+            ("minzoom", self.minzoom.to_string()),
+            ("maxzoom", self.maxzoom.to_string()),
+            ("bounds", format!("{:.7},{:.7},{:.7},{:.7}", minlon, minlat, maxlon, maxlat)),
+            ("center", format!("{:.7},{:.7},{}", (minlon + maxlon) / 2.0, (minlat + maxlat) / 2.0, self.minzoom)),
+            ("json", serde_json::json!({"vector_layers": vector_layers}).to_string())
+        ]
+    }
+
+    /// Writes the tiles still held, merges in any late features and adds
+    /// the metadata. Returns the number of tiles added to `output`.
+    fn finish_output(&mut self, output: &mut TileOutput) -> std::result::Result<usize, String> {
+        let tiles = self.take_all();
+        output.write_tiles(&tiles)?;
+        let mut count = tiles.len();
+
+        let mut merged = Vec::new();
+        for (k, late) in std::mem::take(&mut self.late) {
+            //the written features keep their z_order, so the late ones are
+            //drawn in the right place among them
+            let mut layers = match output.read_tile(k)? {
+                Some(d) => match self.written.get(&k) {
+                    Some(runs) => unpack_tile_with_z_order(&d, runs),
+                    None => unpack_tile(&d)
+                },
+                None => { count += 1; TileLayers::new() }
+            };
+            for (name, features) in late {
+                layers.entry(name).or_insert_with(Vec::new).extend(features);
+            }
+            merged.push((k, pack_tile(&layers, self.extent)));
+        }
+        self.written.clear();
+        output.write_tiles(&merged)?;
+
+        let name = output.name();
+        output.write_metadata(self.metadata(&name))?;
+        Ok(count)
+    }
+
+    /// Writes to an MBTiles file if `outpath` ends with .mbtiles, else to
+    /// a directory tree.
+    fn write(&self, outpath: &str) -> std::result::Result<usize, String> {
+        let mut output = TileOutput::open(outpath)?;
+        let tiles: Vec<(TileKey, Vec<u8>)> = self.tiles.iter().map(|(k, l)| (*k, pack_tile(l, self.extent))).collect();
+        output.write_tiles(&tiles)?;
+        let name = output.name();
+        output.write_metadata(self.metadata(&name))?;
+        Ok(tiles.len())
+    }
+}
+
+fn write_error(msg: String) -> PyErr {
+    crate::ErrorWrapped::from(std::io::Error::new(std::io::ErrorKind::Other, msg)).into()
+}
+
+/// Builds Mapbox Vector Tiles for zooms `minzoom` to `maxzoom` from
+/// GeometryBlocks. Each object is added to the tiles from its own minzoom,
+/// simplified to `simplify` pixels at each zoom and clipped to the tile
+/// plus `buffer` pixels, in tiles of `extent` pixels. Objects go in the
+/// layer named by the first of the style's feature keys they have, or
+/// `layer_names[key]` if given: objects whose key maps to "" are left out.
+#[pyclass]
+pub struct TileBuilder {
+    inner: TileSet
+}
+
+#[pymethods]
+impl TileBuilder {
+    #[new]
+    #[pyo3(signature = (minzoom=0, maxzoom=14, style_in=None, layer_names=None, extent=4096, buffer=64, simplify=1.0))]
+    pub fn new(py: Python, minzoom: u32, maxzoom: u32, style_in: Option<PyObject>, layer_names: Option<BTreeMap<String, String>>, extent: u32, buffer: u32, simplify: f64) -> PyResult<TileBuilder> {
+        let style = crate::geometry::prep_style(py, style_in)?;
+        Ok(TileBuilder{inner: TileSet::new(minzoom, maxzoom, &style, layer_names, extent, buffer, simplify)?})
+    }
+
+    pub fn add_block(&mut self, py: Python, block: &GeometryBlock) -> PyResult<()> {
+        let inner = &mut self.inner;
+        py.allow_threads(|| inner.add_block(block.get_inner()));
+        Ok(())
+    }
+
+    pub fn add_blocks(&mut self, blocks: Vec<PyRef<GeometryBlock>>) -> PyResult<()> {
+        for bl in blocks {
+            self.inner.add_block(bl.get_inner());
+        }
+        Ok(())
+    }
+
+    #[getter]
+    pub fn num_tiles(&self) -> PyResult<usize> { Ok(self.inner.tiles.len()) }
+
+    /// The (z, x, y) of each tile with any features.
+    pub fn tile_keys(&self) -> PyResult<Vec<(u32, u32, u32)>> {
+        Ok(self.inner.tiles.keys().cloned().collect())
+    }
+
+    /// The encoded (uncompressed) tile, or None if the tile is empty.
+    pub fn tile(&self, py: Python, z: u32, x: u32, y: u32) -> PyResult<Option<PyObject>> {
+        Ok(self.inner.tile_data(z, x, y).map(|d| PyBytes::new(py, &d).into_py(py)))
+    }
+
+    /// Writes the tiles to an MBTiles file if `outpath` ends with .mbtiles,
+    /// else to a directory tree `{outpath}/{z}/{x}/{y}.pbf`. Returns the
+    /// number of tiles written.
+    pub fn write(&self, py: Python, outpath: &str) -> PyResult<usize> {
+        let inner = &self.inner;
+        py.allow_threads(|| inner.write(outpath)).map_err(write_error)
+    }
+
+    fn __repr__(&self) -> PyResult<String> {
+        Ok(format!("TileBuilder zooms {} to {} [{} tiles]", self.inner.minzoom, self.inner.maxzoom, self.inner.tiles.len()))
+    }
+}
+
+struct WriteTiles {
+    tiles: TileSet,
+    output: TileOutput,
+    count: usize,
+    error: Option<String>,
+    time: f64,
+    result: ResultSlot<usize>
+}
+
+impl CallFinish for WriteTiles {
+    type CallType = osmquadtree_geometry::GeometryBlock;
+    type ReturnType = Timings<osmquadtree_geometry::OtherData>;
+    type ErrorType = Error;
+
+    fn call(&mut self, bl: osmquadtree_geometry::GeometryBlock) {
+        if self.error.is_some() {
+            return;
+        }
+        let st = std::time::Instant::now();
+        self.tiles.add_block(&bl);
+        let done = self.tiles.take_complete(bl.index);
+        match self.output.write_tiles(&done) {
+            Ok(()) => { self.count += done.len(); },
+            Err(e) => { self.error = Some(e); }
+        }
+        self.time += st.elapsed().as_secs_f64();
+    }
+
+    fn finish(&mut self) -> ccResult<Self::ReturnType, Error> {
+        let st = std::time::Instant::now();
+        let res = match self.error.take() {
+            Some(e) => Err(e),
+            None => self.tiles.finish_output(&mut self.output).map(|n| self.count + n)
+        };
+        let mut tm = Timings::new();
+        tm.add("WriteTiles", self.time);
+        tm.add("WriteTiles finish", st.elapsed().as_secs_f64());
+        self.result.finish(res, tm)
+    }
+}
+
+/// Runs process_geometry and writes vector tiles, as TileBuilder, to an
+/// MBTiles file if `outpath` ends with .mbtiles, else to a directory tree.
+/// Each tile is written once all the blocks which could have features in
+/// it have been processed, so only the tiles in progress are kept in
+/// memory. Returns the number of tiles written.
+#[pyfunction]
+#[pyo3(signature = (prfx, outpath, minzoom=0, maxzoom=14, filter=None, timestamp=None, minzoom_in=None, style_in=None, layer_names=None, extent=4096, buffer=64, simplify=1.0, numchan=crate::readpbf::default_numchan()))]
+pub fn write_tiles(py: Python,
+    prfx: &str,
+    outpath: &str,
+    minzoom: u32,
+    maxzoom: u32,
+    filter: Option<PyObject>,
+    timestamp: Option<&str>,
+    minzoom_in: Option<PyObject>,
+    style_in: Option<PyObject>,
+    layer_names: Option<BTreeMap<String, String>>,
+    extent: u32,
+    buffer: u32,
+    simplify: f64,
+    numchan: usize
+) -> PyResult<usize> {
+
+    let (pfilelocs, style, minzoom_spec) = crate::geometry::prep_process_geometry(py, prfx, filter, timestamp, minzoom_in, style_in)?;
+    let mut tiles = TileSet::new(minzoom, maxzoom, &style, layer_names, extent, buffer, simplify)?;
+    let quadtrees: Vec<osmquadtree::elements::Quadtree> = pfilelocs.1.iter().map(|(q, _)| *q).collect();
+    tiles.progress = Some(BlockProgress::new(&quadtrees, maxzoom));
+    let output = TileOutput::open(outpath).map_err(write_error)?;
+
+    let result = ResultSlot::new();
+    let cb: crate::geometry::GeometryCallback = Box::new(WriteTiles{tiles: tiles, output: output, count: 0, error: None, time: 0.0, result: result.clone()});
+
+    let outputs = if outpath.ends_with(".mbtiles") { vec![String::from(outpath)] } else { vec![] };
+    crate::geometry::run_geometry_writer(py, pfilelocs, cb, &result, style, minzoom_spec, numchan, &outputs)
+}
+
+pub(crate) fn wrap_tiles(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<TileBuilder>()?;
+    m.add_wrapped(wrap_pyfunction!(write_tiles))?;
+    Ok(())
+}
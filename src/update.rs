@@ -0,0 +1,166 @@
+use pyo3::prelude::*;
+use pyo3::wrap_pyfunction;
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{BufWriter,Write};
+
+
+#[pyclass]
+#[derive(Clone)]
+pub struct FilelistEntry {
+    inner: osmquadtree::update::FilelistEntry
+}
+
+#[pymethods]
+impl FilelistEntry {
+    #[getter]
+    pub fn filename(&self) -> PyResult<String> { Ok(self.inner.filename.clone()) }
+
+    #[getter]
+    pub fn state(&self) -> PyResult<i64> { Ok(self.inner.state) }
+
+    #[getter]
+    pub fn end_date(&self) -> PyResult<i64> { Ok(self.inner.end_date) }
+
+    pub fn is_change(&self) -> PyResult<bool> { Ok(self.inner.filename.ends_with(".pbfc")) }
+
+    fn __repr__(&self) -> PyResult<String> {
+        Ok(format!("FilelistEntry({}, {}, {})", self.inner.filename, self.inner.state, osmquadtree::utils::timestamp_string(self.inner.end_date)))
+    }
+}
+
+fn read_filelist_entries(prfx: &str) -> PyResult<Vec<FilelistEntry>> {
+    crate::errors::check_exists(&format!("{}filelist.json", prfx))?;
+    Ok(osmquadtree::update::read_filelist(prfx).into_iter().map(|f| FilelistEntry{inner: f}).collect())
+}
+
+fn change_files(prfx: &str) -> BTreeSet<String> {
+    let dir = if prfx.is_empty() { "." } else { prfx };
+    let mut res = BTreeSet::new();
+    if let Ok(entries) = std::fs::read_dir(dir) {
+        for e in entries.flatten() {
+            let fname = e.file_name().to_string_lossy().to_string();
+            if fname.ends_with(".pbfc") {
+                res.insert(fname);
+            }
+        }
+    }
+    res
+}
+
+//the filelist is only written once a change file is complete, so a .pbfc
+//file which the failed update created but didn't add to the filelist is
+//incomplete. files which were there before the update are left alone.
+fn remove_partial_changes(prfx: &str, existing: &BTreeSet<String>) {
+    let listed: BTreeSet<String> = match read_filelist_entries(prfx) {
+        Ok(fl) => fl.into_iter().map(|f| f.inner.filename).collect(),
+        Err(_) => { return; }
+    };
+    for fname in change_files(prfx).difference(existing) {
+        if !listed.contains(fname) {
+            let _ = std::fs::remove_file(format!("{}{}", prfx, fname));
+        }
+    }
+}
+
+/// Reads the filelist.json from the prefix directory `prfx`: the initial
+/// sorted .pbf file followed by each .pbfc change file.
+#[pyfunction]
+pub fn read_filelist(prfx: &str) -> PyResult<Vec<FilelistEntry>> {
+    read_filelist_entries(prfx)
+}
+
+/// Creates a new prefix directory from a quadtree sorted .pbf file, with
+/// timestamp and replication state `initial_state`. Changes will be read
+/// from `diffs_location`.
+#[pyfunction]
+#[pyo3(signature = (prfx, infn, timestamp, initial_state, diffs_location, numchan=crate::readpbf::default_numchan()))]
+pub fn run_update_initial(py: Python, prfx: &str, infn: &str, timestamp: &str, initial_state: i64, diffs_location: &str, numchan: usize) -> PyResult<Vec<FilelistEntry>> {
+    crate::errors::check_exists(infn)?;
+    crate::errors::check_exists(diffs_location)?;
+    crate::elements::parse_timestamp(timestamp)?;
+
+    crate::cancel::run_interruptible(py, &[format!("{}filelist.json", prfx)], || {
+        osmquadtree::update::run_update_initial(prfx, infn, timestamp, initial_state, diffs_location, numchan)?;
+        Ok(())
+    })?;
+    read_filelist_entries(prfx)
+}
+
+/// Applies up to `limit` new .osc.gz diffs from the dataset's diffs_location:
+/// for each, quadtrees are assigned to the changed elements and a new .pbfc
+/// change file is written to `prfx` and added to the filelist. Returns the
+/// entries added. If the update fails and `remove_partial` is set, the .pbfc
+/// files it created but didn't add to the filelist are removed.
+#[pyfunction]
+#[pyo3(signature = (prfx, limit=1, as_demo=false, numchan=crate::readpbf::default_numchan(), remove_partial=false))]
+pub fn run_update(py: Python, prfx: &str, limit: usize, as_demo: bool, numchan: usize, remove_partial: bool) -> PyResult<Vec<FilelistEntry>> {
+    let before = read_filelist_entries(prfx)?;
+    let existing = change_files(prfx);
+
+    let res = crate::cancel::run_interruptible(py, &[], || {
+        osmquadtree::update::run_update(prfx, limit, as_demo, numchan)?;
+        Ok(())
+    });
+    if res.is_err() && remove_partial {
+        remove_partial_changes(prfx, &existing);
+    }
+    res?;
+
+    let after = read_filelist_entries(prfx)?;
+    Ok(after.into_iter().skip(before.len()).collect())
+}
+
+//the name run_update reads the diff taking the dataset to `state` from
+fn diff_filename(diffs_location: &str, state: i64) -> String {
+    format!("{}{}.osc.gz", diffs_location, state)
+}
+
+fn copy_change_file(oscfn: &str, target: &str) -> std::io::Result<()> {
+    if oscfn.ends_with(".gz") {
+        std::fs::copy(oscfn, target)?;
+        return Ok(());
+    }
+    let mut enc = flate2::write::GzEncoder::new(BufWriter::new(File::create(target)?), flate2::Compression::default());
+    std::io::copy(&mut File::open(oscfn)?, &mut enc)?;
+    enc.finish()?.flush()
+}
+
+/// Applies the change file `oscfn` (.osc or .osc.gz) as the dataset's next
+/// state, rather than waiting for it to appear in `diffs_location`. The file
+/// is copied into `diffs_location` under the name run_update reads, and
+/// removed again afterwards. Returns the entry added.
+#[pyfunction]
+#[pyo3(signature = (prfx, oscfn, diffs_location, as_demo=false, numchan=crate::readpbf::default_numchan(), remove_partial=false))]
+pub fn run_update_file(py: Python, prfx: &str, oscfn: &str, diffs_location: &str, as_demo: bool, numchan: usize, remove_partial: bool) -> PyResult<FilelistEntry> {
+    crate::errors::check_exists(oscfn)?;
+    crate::errors::check_exists(diffs_location)?;
+    let before = read_filelist_entries(prfx)?;
+    let state = match before.last() {
+        Some(f) => f.inner.state + 1,
+        None => { return Err(crate::errors::invalid_input_error(format!("{}filelist.json is empty", prfx))); }
+    };
+
+    let target = diff_filename(diffs_location, state);
+    if std::path::Path::new(&target).exists() {
+        return Err(crate::errors::invalid_input_error(format!("{} already exists: use run_update to apply it", target)));
+    }
+    copy_change_file(oscfn, &target).map_err(|e| crate::ErrorWrapped::from(e).with_filename(&target))?;
+
+    let res = run_update(py, prfx, 1, as_demo, numchan, remove_partial);
+    let _ = std::fs::remove_file(&target);
+    let mut added = res?;
+    if added.len() != 1 {
+        return Err(crate::errors::invalid_input_error(format!("{} was not applied to {}", oscfn, prfx)));
+    }
+    Ok(added.remove(0))
+}
+
+pub(crate) fn wrap_update(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<FilelistEntry>()?;
+    m.add_wrapped(wrap_pyfunction!(read_filelist))?;
+    m.add_wrapped(wrap_pyfunction!(run_update_initial))?;
+    m.add_wrapped(wrap_pyfunction!(run_update))?;
+    m.add_wrapped(wrap_pyfunction!(run_update_file))?;
+    Ok(())
+}
@@ -0,0 +1,495 @@
+use pyo3::prelude::*;
+use pyo3::types::{PyDict,PyList};
+use pyo3::wrap_pyfunction;
+use serde_json::{json,Value};
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Cursor,Read,Seek,SeekFrom,Write,BufWriter};
+use std::panic::{catch_unwind,AssertUnwindSafe};
+use std::sync::{Arc,Mutex,Once};
+
+use channelled_callbacks::{CallFinish,CallbackMerge,Callback,Timings,MergeTimings, Result as ccResult};
+use osmquadtree::utils::Error;
+use simple_protocolbuffers::{IterTags,PbfTag,pack_data};
+
+use crate::ErrorWrapped;
+use crate::compression::{MAX_HEADER_LEN,MAX_BLOB_LEN};
+
+
+struct Problem {
+    kind: &'static str,
+    block: Option<usize>,
+    position: u64,
+    length: Option<u64>,
+    message: String
+}
+
+impl Problem {
+    fn to_json(&self) -> Value {
+        json!({"kind": self.kind, "block": self.block, "position": self.position, "length": self.length, "message": self.message})
+    }
+}
+
+thread_local! {
+    static QUIET_PANICS: Cell<bool> = Cell::new(false);
+}
+static INSTALL_QUIET_HOOK: Once = Once::new();
+
+//corrupt data may panic in the decoders rather than return an error. catch
+//these without the panic hook printing a message for every bad block:
+//panics anywhere else are still reported as before
+fn catch_quietly<T, F: FnOnce() -> T>(f: F) -> std::thread::Result<T> {
+    INSTALL_QUIET_HOOK.call_once(|| {
+        let prev = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            if !QUIET_PANICS.with(|q| q.get()) {
+                prev(info);
+            }
+        }));
+    });
+    let was_quiet = QUIET_PANICS.with(|q| q.replace(true));
+    let res = catch_unwind(AssertUnwindSafe(f));
+    QUIET_PANICS.with(|q| q.set(was_quiet));
+    res
+}
+
+//the block type and blob length from a BlobHeader, or None if it can't be
+//parsed
+fn parse_blob_header(data: &[u8]) -> Option<(String, usize)> {
+    catch_quietly(|| {
+        let mut block_type = None;
+        let mut datasize = None;
+        for t in IterTags::new(data) {
+            match t {
+                PbfTag::Data(1, d) => { block_type = std::str::from_utf8(d).ok().map(String::from); },
+                PbfTag::Value(3, v) => { datasize = Some(v as usize); },
+                _ => {}
+            }
+        }
+        Some((block_type.filter(|b| !b.is_empty())?, datasize?))
+    }).ok().flatten()
+}
+
+//the bytes of the block at pos, or a description of why there isn't a
+//complete block there
+fn read_frame(f: &mut File, pos: u64, flen: u64) -> std::io::Result<Result<Vec<u8>, (&'static str, String)>> {
+    f.seek(SeekFrom::Start(pos))?;
+    let mut lenb = [0u8; 4];
+    if crate::compression::read_fully(f, &mut lenb)? < 4 {
+        return Ok(Err(("truncated", format!("truncated block length at {}", pos))));
+    }
+    let hlen = u32::from_be_bytes(lenb) as usize;
+    if hlen == 0 || hlen > MAX_HEADER_LEN {
+        return Ok(Err(("corrupt", format!("invalid block header length {} at {}", hlen, pos))));
+    }
+    if pos + 4 + hlen as u64 > flen {
+        return Ok(Err(("truncated", format!("truncated block header at {}", pos))));
+    }
+    let mut bytes = lenb.to_vec();
+    bytes.resize(4 + hlen, 0);
+    f.read_exact(&mut bytes[4..])?;
+
+    let (_, datasize) = match parse_blob_header(&bytes[4..]) {
+        Some(h) => h,
+        None => { return Ok(Err(("corrupt", format!("invalid block header at {}", pos)))); }
+    };
+    if datasize > MAX_BLOB_LEN {
+        return Ok(Err(("corrupt", format!("invalid blob length {} at {}", datasize, pos))));
+    }
+    if pos + (4 + hlen + datasize) as u64 > flen {
+        return Ok(Err(("truncated", format!("block at {} needs {} bytes, only {} left", pos, 4 + hlen + datasize, flen - pos))));
+    }
+    bytes.resize(4 + hlen + datasize, 0);
+    f.read_exact(&mut bytes[4 + hlen..])?;
+    Ok(Ok(bytes))
+}
+
+//the start of the next complete OSMData block after a corrupt region
+fn find_next_block(f: &mut File, from: u64, flen: u64) -> std::io::Result<Option<u64>> {
+    const PATTERN: &[u8] = b"\x0a\x07OSMData";
+    let mut buf = vec![0u8; 1 << 20];
+    let mut start = from + 4;
+    while start < flen {
+        f.seek(SeekFrom::Start(start))?;
+        let n = crate::compression::read_fully(f, &mut buf)?;
+        if n < PATTERN.len() {
+            break;
+        }
+        for i in 0..=(n - PATTERN.len()) {
+            if &buf[i..i + PATTERN.len()] == PATTERN {
+                let cand = start + i as u64 - 4;
+                if read_frame(f, cand, flen)?.is_ok() {
+                    return Ok(Some(cand));
+                }
+            }
+        }
+        start += (n - PATTERN.len() + 1) as u64;
+    }
+    Ok(None)
+}
+
+#[derive(Default)]
+struct BlockCheck {
+    idx: usize,
+    pos: u64,
+    len: u64,
+    block_type: String,
+    quadtree: Option<i64>,
+    first: Option<(u8, i64)>,
+    last: Option<(u8, i64)>,
+    error: Option<String>,
+    problems: Vec<Problem>
+}
+
+fn check_block(idx: usize, pos: u64, bytes: &[u8], ischange: bool, check_order: bool) -> BlockCheck {
+    let mut c = BlockCheck{idx: idx, pos: pos, len: bytes.len() as u64, ..BlockCheck::default()};
+    let fb = match catch_quietly(|| crate::compression::read_file_block_with_pos(&mut Cursor::new(bytes), pos)) {
+        Ok(Ok((_, fb))) => fb,
+        Ok(Err(e)) => { c.error = Some(format!("can't read block: {}", e)); return c; },
+        Err(_) => { c.error = Some(String::from("can't read block")); return c; }
+    };
+    c.block_type = fb.block_type.clone();
+    if fb.block_type != "OSMData" {
+        c.error = Some(format!("unexpected {} block", fb.block_type));
+        return c;
+    }
+
+    let data = match catch_quietly(|| fb.data()) {
+        Ok(d) => d,
+        Err(_) => { c.error = Some(String::from("failed to decompress")); return c; }
+    };
+    let bl = match catch_quietly(|| osmquadtree::elements::PrimitiveBlock::read(idx as i64, pos, &data, ischange, false)) {
+        Ok(Ok(bl)) => bl,
+        Ok(Err(e)) => { c.error = Some(format!("failed to parse: {}", e)); return c; },
+        Err(_) => { c.error = Some(String::from("failed to parse")); return c; }
+    };
+
+    c.quadtree = Some(bl.quadtree.as_int());
+    let keys: Vec<(u8, i64)> = bl.nodes.iter().map(|n| (0, n.id))
+        .chain(bl.ways.iter().map(|w| (1, w.id)))
+        .chain(bl.relations.iter().map(|r| (2, r.id)))
+        .collect();
+    c.first = keys.first().cloned();
+    c.last = keys.last().cloned();
+    if check_order {
+        if let Some(w) = keys.windows(2).find(|w| w[0] >= w[1]) {
+            c.problems.push(Problem{kind: "order", block: Some(idx), position: pos, length: None,
+                message: format!("{} {} follows {} {}", type_name(w[1].0), w[1].1, type_name(w[0].0), w[0].1)});
+        }
+    }
+    c
+}
+
+fn type_name(t: u8) -> &'static str {
+    match t {
+        0 => "node",
+        1 => "way",
+        _ => "relation"
+    }
+}
+
+struct CheckBlocks {
+    ischange: bool,
+    check_order: bool,
+    checks: Vec<BlockCheck>,
+    result: Arc<Mutex<Vec<BlockCheck>>>
+}
+
+impl CallFinish for CheckBlocks {
+    type CallType = (usize, u64, Vec<u8>);
+    type ReturnType = Timings<usize>;
+    type ErrorType = Error;
+
+    fn call(&mut self, (idx, pos, bytes): (usize, u64, Vec<u8>)) {
+        self.checks.push(check_block(idx, pos, &bytes, self.ischange, self.check_order));
+    }
+
+    fn finish(&mut self) -> ccResult<Self::ReturnType, Error> {
+        let checks = std::mem::take(&mut self.checks);
+        let mut tm = Timings::new();
+        tm.add_other("CheckBlocks", checks.len());
+        self.result.lock().map_err(|_| Error::Io(std::io::Error::new(std::io::ErrorKind::Other, "lock poisoned")))?.extend(checks);
+        Ok(tm)
+    }
+}
+
+struct Header {
+    len: u64,
+    data: Vec<u8>,
+    index: Vec<(i64, u64, u64)>,
+    sorted: bool,
+    ischange: bool
+}
+
+fn read_header(fname: &str, bytes: &[u8]) -> Result<Header, String> {
+    let (_, fb) = match catch_quietly(|| crate::compression::read_file_block_with_pos(&mut Cursor::new(bytes), 0)) {
+        Ok(r) => r.map_err(|e| e.to_string())?,
+        Err(_) => { return Err(String::from("can't read header")); }
+    };
+    let data = catch_quietly(|| fb.data()).map_err(|_| String::from("failed to decompress header"))?;
+    let hb = match catch_quietly(|| osmquadtree::pbfformat::HeaderBlock::read(0, &data, fname)) {
+        Ok(Ok(hb)) => hb,
+        Ok(Err(e)) => { return Err(format!("failed to parse header: {}", e)); },
+        Err(_) => { return Err(String::from("failed to parse header")); }
+    };
+    let has = |f: &str| hb.optional_features.iter().chain(hb.required_features.iter()).any(|x| x == f);
+    Ok(Header{
+        len: bytes.len() as u64,
+        index: hb.index.iter().map(|ii| (ii.quadtree.as_int(), ii.location, ii.length)).collect(),
+        sorted: has("Sort.Type_then_ID"),
+        ischange: has("HasChangetype"),
+        data: data
+    })
+}
+
+struct Verified {
+    file_len: u64,
+    header: Option<Header>,
+    checks: Vec<BlockCheck>,
+    problems: Vec<Problem>
+}
+
+impl Verified {
+    fn report(&self, fname: &str, max_problems: usize) -> Value {
+        let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+        for p in &self.problems {
+            *counts.entry(p.kind).or_insert(0) += 1;
+        }
+        json!({
+            "filename": fname,
+            "file_length": self.file_len,
+            "num_blocks": self.checks.len(),
+            "num_bad_blocks": self.checks.iter().filter(|c| c.error.is_some()).count(),
+            "ok": self.problems.is_empty(),
+            "counts": counts,
+            "problems": self.problems.iter().take(max_problems).map(Problem::to_json).collect::<Vec<_>>()
+        })
+    }
+}
+
+fn verify_file(fname: &str, numchan: usize) -> PyResult<Verified> {
+    let file_len = std::fs::metadata(fname).map_err(|e| ErrorWrapped::from(e).with_filename(fname))?.len();
+    let mut f = File::open(fname).map_err(|e| ErrorWrapped::from(e).with_filename(fname))?;
+    let mut problems = Vec::new();
+    let mut pos = 0;
+
+    //the header is needed first, to know how to decode the data blocks
+    let mut header = None;
+    if let Ok(bytes) = read_frame(&mut f, 0, file_len).map_err(ErrorWrapped::from)? {
+        if parse_blob_header(&bytes[4..]).map_or(false, |(t, _)| t == "OSMHeader") {
+            match read_header(fname, &bytes) {
+                Ok(h) => { header = Some(h); },
+                Err(e) => { problems.push(Problem{kind: "header", block: None, position: 0, length: Some(bytes.len() as u64), message: e}); }
+            }
+            pos = bytes.len() as u64;
+        }
+    }
+    if pos == 0 {
+        problems.push(Problem{kind: "header", block: None, position: 0, length: None, message: String::from("no OSMHeader block")});
+    }
+    let ischange = header.as_ref().map_or(false, |h| h.ischange);
+    let check_order = header.as_ref().map_or(false, |h| h.sorted || !h.index.is_empty());
+
+    //blocks are read here, and decoded and checked on numchan threads
+    let result = Arc::new(Mutex::new(Vec::new()));
+    let make = || Box::new(CheckBlocks{ischange: ischange, check_order: check_order, checks: Vec::new(), result: result.clone()});
+    let mut conv: Box<dyn CallFinish<CallType = (usize, u64, Vec<u8>), ReturnType = Timings<usize>, ErrorType = Error>> =
+        if numchan == 0 {
+            make()
+        } else {
+            let mut convs: Vec<Box<dyn CallFinish<CallType = (usize, u64, Vec<u8>), ReturnType = Timings<usize>, ErrorType = Error>>> = Vec::new();
+            for _ in 0..numchan {
+                convs.push(Box::new(Callback::new(make())));
+            }
+            Box::new(CallbackMerge::new(convs, Box::new(MergeTimings::new())))
+        };
+
+    let mut idx = 0;
+    while pos < file_len && !crate::cancel::is_cancelled() {
+        match read_frame(&mut f, pos, file_len).map_err(ErrorWrapped::from)? {
+            Ok(bytes) => {
+                let len = bytes.len() as u64;
+                conv.call((idx, pos, bytes));
+                idx += 1;
+                pos += len;
+            },
+            Err((kind, msg)) => {
+                //skip to the next block which can be read
+                let next = find_next_block(&mut f, pos, file_len).map_err(ErrorWrapped::from)?;
+                let end = next.unwrap_or(file_len);
+                problems.push(Problem{kind: kind, block: None, position: pos, length: Some(end - pos), message: msg});
+                pos = end;
+            }
+        }
+    }
+    conv.finish().map_err(|e| ErrorWrapped::new(e.into()))?;
+    crate::cancel::check_cancelled()?;
+
+    let mut checks = std::mem::take(&mut *result.lock().map_err(|_| pyo3::exceptions::PyRuntimeError::new_err("lock poisoned"))?);
+    checks.sort_by_key(|c| c.idx);
+
+    for c in checks.iter_mut() {
+        if let Some(e) = &c.error {
+            problems.push(Problem{kind: "decode", block: Some(c.idx), position: c.pos, length: Some(c.len), message: e.clone()});
+        }
+        problems.append(&mut c.problems);
+    }
+
+    if let Some(h) = &header {
+        let good: Vec<&BlockCheck> = checks.iter().filter(|c| c.error.is_none()).collect();
+        for w in good.windows(2) {
+            if !h.index.is_empty() && w[1].quadtree < w[0].quadtree {
+                problems.push(Problem{kind: "order", block: Some(w[1].idx), position: w[1].pos, length: None,
+                    message: format!("block quadtree {:?} follows {:?}", w[1].quadtree, w[0].quadtree)});
+            } else if h.sorted && w[1].first.is_some() && w[0].last.is_some() && w[1].first <= w[0].last {
+                let (a, b) = (w[0].last.unwrap(), w[1].first.unwrap());
+                problems.push(Problem{kind: "order", block: Some(w[1].idx), position: w[1].pos, length: None,
+                    message: format!("{} {} follows {} {} in previous block", type_name(b.0), b.1, type_name(a.0), a.1)});
+            }
+        }
+
+        if !h.index.is_empty() {
+            let by_pos: BTreeMap<u64, &BlockCheck> = checks.iter().map(|c| (c.pos, c)).collect();
+            for (q, loc, length) in &h.index {
+                let msg = match by_pos.get(loc) {
+                    None => Some(format!("header index has a block at {} which isn't in the file", loc)),
+                    Some(c) if c.len != *length => Some(format!("header index has length {} for block at {}, not {}", length, loc, c.len)),
+                    Some(c) if c.quadtree.map_or(false, |bq| bq != *q) => Some(format!("header index has quadtree {} for block at {}, not {:?}", q, loc, c.quadtree)),
+                    _ => None
+                };
+                if let Some(m) = msg {
+                    problems.push(Problem{kind: "index", block: by_pos.get(loc).map(|c| c.idx), position: *loc, length: Some(*length), message: m});
+                }
+            }
+            let indexed: std::collections::BTreeSet<u64> = h.index.iter().map(|(_, l, _)| *l).collect();
+            for c in &checks {
+                if c.block_type == "OSMData" && !indexed.contains(&c.pos) {
+                    problems.push(Problem{kind: "index", block: Some(c.idx), position: c.pos, length: Some(c.len), message: format!("block at {} isn't in the header index", c.pos)});
+                }
+            }
+        }
+    }
+    problems.sort_by_key(|p| p.position);
+    Ok(Verified{file_len: file_len, header: header, checks: checks, problems: problems})
+}
+
+fn json_to_py(py: Python, v: &Value) -> PyResult<PyObject> {
+    Ok(match v {
+        Value::Null => py.None(),
+        Value::Bool(b) => (*b).into_py(py),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => i.into_py(py),
+            None => n.as_f64().into_py(py)
+        },
+        Value::String(s) => s.as_str().into_py(py),
+        Value::Array(a) => {
+            let list = PyList::empty(py);
+            for x in a {
+                list.append(json_to_py(py, x)?)?;
+            }
+            list.into_py(py)
+        },
+        Value::Object(o) => {
+            let dict = PyDict::new(py);
+            for (k, x) in o {
+                dict.set_item(k, json_to_py(py, x)?)?;
+            }
+            dict.into_py(py)
+        }
+    })
+}
+
+/// Reads every block of `fname`, decoding them using `numchan` threads,
+/// and returns a report dict. The report lists problems of each kind:
+/// "truncated" or "corrupt" regions where no block can be read, blocks which
+/// fail to "decode", a missing or unreadable "header", "index" entries in
+/// the header which don't match the blocks, and elements or block quadtrees
+/// out of "order" in sorted files. Only the first `max_problems` are
+/// listed, but "counts" has the number of each kind.
+#[pyfunction]
+#[pyo3(signature = (fname, numchan=crate::readpbf::default_numchan(), max_problems=1000))]
+pub fn verify_pbf(py: Python, fname: &str, numchan: usize, max_problems: usize) -> PyResult<PyObject> {
+    crate::errors::check_exists(fname)?;
+    let verified = crate::cancel::run_interruptible(py, &[], || verify_file(fname, numchan))?;
+    json_to_py(py, &verified.report(fname, max_problems))
+}
+
+//used when the input has no readable header
+fn minimal_header() -> Vec<u8> {
+    let mut res = Vec::new();
+    pack_data(&mut res, 4, b"OsmSchema-V0.6");
+    pack_data(&mut res, 4, b"DenseNodes");
+    pack_data(&mut res, 16, b"osmquadtree_rust_bindings");
+    res
+}
+
+fn salvage_file(infile: &str, outfile: &str, reportfn: &str, numchan: usize) -> PyResult<Value> {
+    let verified = verify_file(infile, numchan)?;
+    let kept: Vec<&BlockCheck> = verified.checks.iter().filter(|c| c.error.is_none()).collect();
+
+    let mut locs = BTreeMap::new();
+    let mut p = 0;
+    for c in &kept {
+        locs.insert(c.pos, (p, c.len));
+        p += c.len;
+    }
+    let header_data = verified.header.as_ref().map_or_else(minimal_header, |h| h.data.clone());
+    let compression = crate::compression::Compression::Upstream(osmquadtree::pbfformat::CompressionType::ZlibLevel(6));
+    let header = crate::compression::rewrite_header(&header_data, &locs, &compression)?;
+
+    let mut inf = File::open(infile).map_err(|e| ErrorWrapped::from(e).with_filename(infile))?;
+    let mut outf = BufWriter::new(File::create(outfile).map_err(|e| ErrorWrapped::from(e).with_filename(outfile))?);
+    outf.write_all(&header).map_err(|e| ErrorWrapped::from(e).with_filename(outfile))?;
+    let mut buf = Vec::new();
+    for c in &kept {
+        crate::cancel::check_cancelled()?;
+        buf.resize(c.len as usize, 0);
+        inf.seek(SeekFrom::Start(c.pos)).map_err(ErrorWrapped::from)?;
+        inf.read_exact(&mut buf).map_err(|e| ErrorWrapped::from(e).with_filename(infile).with_position(c.pos))?;
+        outf.write_all(&buf).map_err(|e| ErrorWrapped::from(e).with_filename(outfile))?;
+    }
+    outf.flush().map_err(|e| ErrorWrapped::from(e).with_filename(outfile))?;
+
+    //what was lost: blocks which couldn't be decoded, and regions where no
+    //block could be read
+    let mut lost = Vec::new();
+    for pr in &verified.problems {
+        if pr.kind == "decode" || pr.kind == "truncated" || pr.kind == "corrupt" {
+            lost.push(json!({"block": pr.block, "position": pr.position, "length": pr.length, "reason": pr.message}));
+        }
+    }
+
+    let mut report = verified.report(infile, usize::MAX);
+    report["outfile"] = json!(outfile);
+    report["kept_blocks"] = json!(kept.len());
+    report["lost_bytes"] = json!(verified.file_len - verified.header.as_ref().map_or(0, |h| h.len) - p);
+    report["lost"] = json!(lost);
+    std::fs::write(reportfn, serde_json::to_string_pretty(&report).unwrap_or_default())
+        .map_err(|e| ErrorWrapped::from(e).with_filename(reportfn))?;
+    Ok(report)
+}
+
+/// Rewrites `infile` to `outfile` with only the blocks which can be read
+/// and decoded, updating the header index to the blocks kept (or writing a
+/// minimal header if the original can't be read). Writes a JSON report, as
+/// for verify_pbf with the blocks and byte ranges lost, to `report` or
+/// `<outfile>.report.json`, and returns it as a dict.
+#[pyfunction]
+#[pyo3(signature = (infile, outfile, report=None, numchan=crate::readpbf::default_numchan()))]
+pub fn salvage_pbf(py: Python, infile: &str, outfile: &str, report: Option<&str>, numchan: usize) -> PyResult<PyObject> {
+    crate::errors::check_exists(infile)?;
+    if std::path::Path::new(infile) == std::path::Path::new(outfile) {
+        return Err(crate::errors::invalid_input_error(format!("can't salvage {} in place", infile)));
+    }
+    let reportfn = report.map_or_else(|| format!("{}.report.json", outfile), String::from);
+    let report = crate::cancel::run_interruptible(py, &[String::from(outfile)], || salvage_file(infile, outfile, &reportfn, numchan))?;
+    json_to_py(py, &report)
+}
+
+
+pub(crate) fn wrap_verify(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_wrapped(wrap_pyfunction!(verify_pbf))?;
+    m.add_wrapped(wrap_pyfunction!(salvage_pbf))?;
+    Ok(())
+}
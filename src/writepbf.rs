@@ -0,0 +1,244 @@
+use pyo3::prelude::*;
+use pyo3::exceptions::*;
+use std::fs::File;
+use std::io::{BufWriter,Write,Read,BufReader};
+
+use simple_protocolbuffers::{pack_data,pack_value,zig_zag};
+use crate::ErrorWrapped;
+use crate::compression::{Compression,pack_file_block,pack_header_block};
+
+
+struct IndexEntry {
+    quadtree: osmquadtree::elements::Quadtree,
+    is_change: bool,
+    location: u64, //relative to the end of the header block
+    length: u64
+}
+
+fn pack_bbox(bbox: &(i64,i64,i64,i64)) -> Vec<u8> {
+    //HeaderBBox is stored in nanodegrees, bbox is given in 1e-7 degrees
+    let mut res = Vec::new();
+    pack_value(&mut res, 1, zig_zag(bbox.0 * 100));
+    pack_value(&mut res, 2, zig_zag(bbox.2 * 100));
+    pack_value(&mut res, 3, zig_zag(bbox.3 * 100));
+    pack_value(&mut res, 4, zig_zag(bbox.1 * 100));
+    res
+}
+
+fn pack_index_entry(ii: &IndexEntry, offset: u64) -> Vec<u8> {
+    let mut res = Vec::new();
+    pack_value(&mut res, 1, zig_zag(ii.quadtree.as_int()));
+    if ii.is_change {
+        pack_value(&mut res, 2, 1);
+    }
+    pack_value(&mut res, 3, ii.location + offset);
+    pack_value(&mut res, 4, ii.length);
+    res
+}
+
+#[pyclass]
+pub struct PbfWriter {
+    outfn: String,
+    tempfn: String,
+    tempf: Option<BufWriter<File>>,
+
+    bbox: (i64,i64,i64,i64),
+    writingprogram: String,
+    source: Option<String>,
+    osmosis_replication_timestamp: Option<i64>,
+    osmosis_replication_sequence_number: Option<i64>,
+    osmosis_replication_base_url: Option<String>,
+
+    compression_type: Compression,
+    ischange: bool,
+    sorted: bool,
+
+    index: Vec<IndexEntry>,
+    pos: u64
+}
+
+impl PbfWriter {
+    fn pack_header(&self, offset: u64) -> Vec<u8> {
+        let mut res = Vec::new();
+        pack_data(&mut res, 1, &pack_bbox(&self.bbox));
+        pack_data(&mut res, 4, b"OsmSchema-V0.6");
+        pack_data(&mut res, 4, b"DenseNodes");
+        if self.ischange {
+            pack_data(&mut res, 5, b"HasChangetype");
+        }
+        if self.sorted {
+            pack_data(&mut res, 5, b"Sort.Type_then_ID");
+        }
+        pack_data(&mut res, 16, self.writingprogram.as_bytes());
+        if let Some(s) = &self.source {
+            pack_data(&mut res, 17, s.as_bytes());
+        }
+        for ii in &self.index {
+            pack_data(&mut res, 22, &pack_index_entry(ii, offset));
+        }
+        if let Some(t) = self.osmosis_replication_timestamp {
+            pack_value(&mut res, 32, t as u64);
+        }
+        if let Some(s) = self.osmosis_replication_sequence_number {
+            pack_value(&mut res, 33, s as u64);
+        }
+        if let Some(u) = &self.osmosis_replication_base_url {
+            pack_data(&mut res, 34, u.as_bytes());
+        }
+        res
+    }
+
+    fn make_header_block(&self) -> PyResult<Vec<u8>> {
+        pack_header_block(|offset| self.pack_header(offset), &self.compression_type)
+    }
+
+    fn get_tempf(&mut self) -> PyResult<&mut BufWriter<File>> {
+        match self.tempf.as_mut() {
+            Some(f) => Ok(f),
+            None => Err(PyValueError::new_err(format!("PbfWriter for {} already finished", self.outfn)))
+        }
+    }
+
+    fn write_packed(&mut self, quadtree: osmquadtree::elements::Quadtree, data: &[u8]) -> PyResult<()> {
+        let ischange = self.ischange;
+        let packed = pack_file_block("OSMData", data, &self.compression_type)?;
+
+        self.get_tempf()?.write_all(&packed)
+            .map_err(|e| ErrorWrapped::from(e).with_filename(&self.tempfn))?;
+
+        self.index.push(IndexEntry{quadtree: quadtree, is_change: ischange, location: self.pos, length: packed.len() as u64});
+        self.pos += packed.len() as u64;
+        Ok(())
+    }
+
+    pub(crate) fn write_primitive_block(&mut self, bl: &osmquadtree::elements::PrimitiveBlock) -> PyResult<()> {
+        let data = bl.pack(true, self.ischange).map_err(|e| ErrorWrapped::from(e).with_position(bl.location))?;
+        self.write_packed(bl.quadtree.clone(), &data)
+    }
+
+    /// Marks the file as sorted by element type and id in the header.
+    pub(crate) fn set_sorted(&mut self) {
+        self.sorted = true;
+    }
+
+    pub(crate) fn finish_file(&mut self) -> PyResult<u64> {
+        let mut tempf = match self.tempf.take() {
+            Some(f) => f,
+            None => { return Err(PyValueError::new_err(format!("PbfWriter for {} already finished", self.outfn))); }
+        };
+        tempf.flush().map_err(|e| ErrorWrapped::from(e).with_filename(&self.tempfn))?;
+        drop(tempf);
+
+        let header = self.make_header_block()?;
+
+        let mut outf = BufWriter::new(File::create(&self.outfn).map_err(|e| ErrorWrapped::from(e).with_filename(&self.outfn))?);
+        outf.write_all(&header).map_err(|e| ErrorWrapped::from(e).with_filename(&self.outfn))?;
+
+        let mut inf = BufReader::new(File::open(&self.tempfn).map_err(|e| ErrorWrapped::from(e).with_filename(&self.tempfn))?);
+        let mut buf = vec![0u8; 1<<20];
+        loop {
+            let n = inf.read(&mut buf).map_err(|e| ErrorWrapped::from(e).with_filename(&self.tempfn))?;
+            if n == 0 { break; }
+            outf.write_all(&buf[..n]).map_err(|e| ErrorWrapped::from(e).with_filename(&self.outfn))?;
+        }
+        outf.flush().map_err(|e| ErrorWrapped::from(e).with_filename(&self.outfn))?;
+
+        let _ = std::fs::remove_file(&self.tempfn);
+        Ok(header.len() as u64 + self.pos)
+    }
+}
+
+impl Drop for PbfWriter {
+    fn drop(&mut self) {
+        //not finished: remove the partial output
+        if self.tempf.take().is_some() {
+            let _ = std::fs::remove_file(&self.tempfn);
+        }
+    }
+}
+
+#[pymethods]
+impl PbfWriter {
+    #[new]
+    #[pyo3(signature=(outfn, bbox=None, writingprogram=None, source=None, osmosis_replication_timestamp=None, osmosis_replication_sequence_number=None, osmosis_replication_base_url=None, compression_type=(String::from("ZlibLevel"),6), ischange=false))]
+    pub fn new(
+            outfn: &str, bbox: Option<(i64,i64,i64,i64)>, writingprogram: Option<String>, source: Option<String>,
+            osmosis_replication_timestamp: Option<i64>, osmosis_replication_sequence_number: Option<i64>, osmosis_replication_base_url: Option<String>,
+            compression_type: (String, u32), ischange: bool) -> PyResult<PbfWriter> {
+
+        let bbox = bbox.unwrap_or((-1800000000, -900000000, 1800000000, 900000000));
+        if bbox.0 > bbox.2 || bbox.1 > bbox.3 {
+            return Err(crate::errors::filter_error(format!("invalid bbox {:?}", bbox)));
+        }
+
+        let ct = Compression::from_tuple((&compression_type.0, compression_type.1))?;
+
+        let tempfn = format!("{}-partial", outfn);
+        let tempf = BufWriter::new(File::create(&tempfn).map_err(|e| ErrorWrapped::from(e).with_filename(&tempfn))?);
+
+        Ok(PbfWriter{
+            outfn: String::from(outfn), tempfn: tempfn, tempf: Some(tempf),
+            bbox: bbox,
+            writingprogram: writingprogram.unwrap_or_else(|| String::from("osmquadtree_rust_bindings")),
+            source: source,
+            osmosis_replication_timestamp: osmosis_replication_timestamp,
+            osmosis_replication_sequence_number: osmosis_replication_sequence_number,
+            osmosis_replication_base_url: osmosis_replication_base_url,
+            compression_type: ct, ischange: ischange, sorted: false,
+            index: Vec::new(), pos: 0
+        })
+    }
+
+    #[getter]
+    pub fn outfn(&self) -> PyResult<String> { Ok(self.outfn.clone()) }
+
+    #[getter]
+    pub fn num_blocks(&self) -> PyResult<usize> { Ok(self.index.len()) }
+
+    #[getter]
+    pub fn finished(&self) -> PyResult<bool> { Ok(self.tempf.is_none()) }
+
+    pub fn write_block(&mut self, block: &crate::elements::PrimitiveBlock) -> PyResult<()> {
+        self.write_primitive_block(block.get_inner())
+    }
+
+    pub fn write_blocks(&mut self, blocks: Vec<PyRef<crate::elements::PrimitiveBlock>>) -> PyResult<()> {
+        for bl in blocks {
+            self.write_block(&bl)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the header block, with the quadtree index of all blocks
+    /// written, followed by the blocks. Returns the file length.
+    pub fn finish(&mut self) -> PyResult<u64> {
+        self.finish_file()
+    }
+
+    fn __enter__(slf: Py<Self>) -> Py<Self> {
+        slf
+    }
+
+    #[pyo3(signature=(exc_type=None, _exc_value=None, _traceback=None))]
+    fn __exit__(&mut self, exc_type: Option<PyObject>, _exc_value: Option<PyObject>, _traceback: Option<PyObject>) -> PyResult<bool> {
+        if self.tempf.is_some() {
+            if exc_type.is_none() {
+                self.finish_file()?;
+            } else {
+                self.tempf = None;
+                let _ = std::fs::remove_file(&self.tempfn);
+            }
+        }
+        Ok(false)
+    }
+
+    fn __repr__(&self) -> String {
+        format!("PbfWriter({}, {} blocks{})", self.outfn, self.index.len(), if self.tempf.is_none() { ", finished" } else { "" })
+    }
+}
+
+
+pub(crate) fn wrap_writepbf(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PbfWriter>()?;
+    Ok(())
+}
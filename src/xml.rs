@@ -0,0 +1,566 @@
+use pyo3::prelude::*;
+use pyo3::exceptions::*;
+use std::fs::File;
+use std::io::{BufRead,BufReader,BufWriter,Write};
+use std::sync::Mutex;
+
+use quick_xml::events::{Event,BytesStart};
+use quick_xml::escape::escape;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+
+use osmquadtree::elements::{Changetype,ElementType,Info,Member,Tag};
+use crate::ErrorWrapped;
+
+
+fn xml_error(fname: &str, position: u64, msg: String) -> PyErr {
+    crate::errors::make_error(crate::errors::ErrorKind::PbfDecode, format!("{}: {}", fname, msg), Some(String::from(fname)), Some(position), None)
+}
+
+fn open_input(fname: &str) -> PyResult<Box<dyn BufRead + Send + Sync>> {
+    let f = File::open(fname).map_err(|e| ErrorWrapped::from(e).with_filename(fname))?;
+    if fname.ends_with(".gz") {
+        Ok(Box::new(BufReader::new(GzDecoder::new(BufReader::new(f)))))
+    } else {
+        Ok(Box::new(BufReader::new(f)))
+    }
+}
+
+enum XmlElement {
+    Node(osmquadtree::elements::Node),
+    Way(osmquadtree::elements::Way),
+    Relation(osmquadtree::elements::Relation)
+}
+
+impl XmlElement {
+    fn tags(&mut self) -> &mut Vec<Tag> {
+        match self {
+            XmlElement::Node(n) => &mut n.tags,
+            XmlElement::Way(w) => &mut w.tags,
+            XmlElement::Relation(r) => &mut r.tags
+        }
+    }
+}
+
+fn parse_coord(v: &str) -> Option<i32> {
+    v.parse::<f64>().ok().map(|f| (f * 10000000.0).round() as i32)
+}
+
+struct XmlParser {
+    fname: String,
+    reader: quick_xml::Reader<Box<dyn BufRead + Send + Sync>>,
+    buf: Vec<u8>,
+    is_change: bool,
+    changetype: Changetype,
+    current: Option<XmlElement>,
+    finished: bool
+}
+
+impl XmlParser {
+    fn new(fname: &str) -> PyResult<XmlParser> {
+        let reader = quick_xml::Reader::from_reader(open_input(fname)?);
+        Ok(XmlParser{fname: String::from(fname), reader: reader, buf: Vec::new(), is_change: false, changetype: Changetype::Normal, current: None, finished: false})
+    }
+
+    fn error(&self, msg: String) -> PyErr {
+        xml_error(&self.fname, self.reader.buffer_position() as u64, msg)
+    }
+
+    fn start_element(&self, e: &BytesStart) -> PyResult<Option<XmlElement>> {
+        let name = e.name();
+        let which = match name.as_ref() {
+            b"node" => ElementType::Node,
+            b"way" => ElementType::Way,
+            b"relation" => ElementType::Relation,
+            _ => { return Ok(None); }
+        };
+
+        let mut id: Option<i64> = None;
+        let mut info = Info::new();
+        let mut has_info = false;
+        let mut lon = 0;
+        let mut lat = 0;
+        for a in e.attributes() {
+            let a = a.map_err(|e| self.error(format!("{}", e)))?;
+            let v = a.unescape_value().map_err(|e| self.error(format!("{}", e)))?;
+            match a.key.as_ref() {
+                b"id" => { id = Some(v.parse().map_err(|_| self.error(format!("invalid id {}", v)))?); },
+                b"version" => { info.version = v.parse().map_err(|_| self.error(format!("invalid version {}", v)))?; has_info = true; },
+                b"changeset" => { info.changeset = v.parse().map_err(|_| self.error(format!("invalid changeset {}", v)))?; has_info = true; },
+                b"uid" => { info.user_id = v.parse().map_err(|_| self.error(format!("invalid uid {}", v)))?; has_info = true; },
+                b"user" => { info.user = v.to_string(); has_info = true; },
+                b"timestamp" => { info.timestamp = osmquadtree::utils::parse_timestamp(&v).map_err(|_| self.error(format!("invalid timestamp {}", v)))?; has_info = true; },
+                b"lon" => { lon = parse_coord(&v).ok_or_else(|| self.error(format!("invalid lon {}", v)))?; },
+                b"lat" => { lat = parse_coord(&v).ok_or_else(|| self.error(format!("invalid lat {}", v)))?; },
+                _ => {}
+            }
+        }
+        let id = id.ok_or_else(|| self.error(String::from("element without id")))?;
+        let info = if has_info { Some(info) } else { None };
+
+        Ok(Some(match which {
+            ElementType::Node => {
+                let mut n = osmquadtree::elements::Node::new(id, self.changetype.clone());
+                n.info = info;
+                n.lon = lon;
+                n.lat = lat;
+                XmlElement::Node(n)
+            },
+            ElementType::Way => {
+                let mut w = osmquadtree::elements::Way::new(id, self.changetype.clone());
+                w.info = info;
+                XmlElement::Way(w)
+            },
+            _ => {
+                let mut r = osmquadtree::elements::Relation::new(id, self.changetype.clone());
+                r.info = info;
+                XmlElement::Relation(r)
+            }
+        }))
+    }
+
+    fn add_child(&mut self, e: &BytesStart) -> PyResult<()> {
+        let mut k: Option<String> = None;
+        let mut v: Option<String> = None;
+        let mut mem_type: Option<String> = None;
+        let mut rf: Option<i64> = None;
+        let mut role = String::new();
+        for a in e.attributes() {
+            let a = a.map_err(|e| self.error(format!("{}", e)))?;
+            let val = a.unescape_value().map_err(|e| self.error(format!("{}", e)))?.to_string();
+            match a.key.as_ref() {
+                b"k" => { k = Some(val); },
+                b"v" => { v = Some(val); },
+                b"type" => { mem_type = Some(val); },
+                b"ref" => { rf = Some(val.parse().map_err(|_| self.error(format!("invalid ref {}", val)))?); },
+                b"role" => { role = val; },
+                _ => {}
+            }
+        }
+
+        let name = e.name();
+        let err = match (name.as_ref(), self.current.as_mut()) {
+            (b"tag", Some(curr)) => match (k, v) {
+                (Some(k), Some(v)) => { curr.tags().push(Tag::new(k, v)); None },
+                _ => Some("tag without k or v")
+            },
+            (b"nd", Some(XmlElement::Way(w))) => match rf {
+                Some(r) => { w.refs.push(r); None },
+                None => Some("nd without ref")
+            },
+            (b"member", Some(XmlElement::Relation(r))) => {
+                let mt = match mem_type.as_deref() {
+                    Some("node") => Some(ElementType::Node),
+                    Some("way") => Some(ElementType::Way),
+                    Some("relation") => Some(ElementType::Relation),
+                    _ => None
+                };
+                match (mt, rf) {
+                    (Some(mt), Some(rf)) => { r.members.push(Member{mem_type: mt, mem_ref: rf, role: role}); None },
+                    _ => Some("member without valid type or ref")
+                }
+            },
+            _ => None
+        };
+        match err {
+            Some(msg) => Err(self.error(String::from(msg))),
+            None => Ok(())
+        }
+    }
+
+    fn next_element(&mut self) -> PyResult<Option<XmlElement>> {
+        if self.finished {
+            return Ok(None);
+        }
+        loop {
+            self.buf.clear();
+            let ev = match self.reader.read_event_into(&mut self.buf) {
+                Ok(ev) => ev.into_owned(),
+                Err(e) => {
+                    let msg = format!("{}", e);
+                    return Err(self.error(msg));
+                }
+            };
+            match ev {
+                Event::Start(e) => {
+                    match e.name().as_ref() {
+                        b"osmChange" => { self.is_change = true; },
+                        b"create" => { self.changetype = Changetype::Create; },
+                        b"modify" => { self.changetype = Changetype::Modify; },
+                        b"delete" => { self.changetype = Changetype::Delete; },
+                        _ => {
+                            if self.current.is_none() {
+                                self.current = self.start_element(&e)?;
+                            } else {
+                                self.add_child(&e)?;
+                            }
+                        }
+                    }
+                },
+                Event::Empty(e) => {
+                    if self.current.is_some() {
+                        self.add_child(&e)?;
+                    } else if let Some(ele) = self.start_element(&e)? {
+                        return Ok(Some(ele));
+                    }
+                },
+                Event::End(e) => {
+                    match e.name().as_ref() {
+                        b"node" | b"way" | b"relation" => {
+                            if let Some(ele) = self.current.take() {
+                                return Ok(Some(ele));
+                            }
+                        },
+                        b"create" | b"modify" | b"delete" => { self.changetype = Changetype::Normal; },
+                        _ => {}
+                    }
+                },
+                Event::Eof => {
+                    self.finished = true;
+                    if self.current.is_some() {
+                        return Err(self.error(String::from("unexpected end of file")));
+                    }
+                    return Ok(None);
+                },
+                _ => {}
+            }
+        }
+    }
+
+    fn next_block(&mut self, index: i64, block_size: usize) -> PyResult<Option<osmquadtree::elements::PrimitiveBlock>> {
+        let mut pb = osmquadtree::elements::PrimitiveBlock::new(index, 0);
+        let mut count = 0;
+        while count < block_size {
+            match self.next_element()? {
+                None => { break; },
+                Some(XmlElement::Node(n)) => { pb.nodes.push(n); },
+                Some(XmlElement::Way(w)) => { pb.ways.push(w); },
+                Some(XmlElement::Relation(r)) => { pb.relations.push(r); }
+            }
+            count += 1;
+        }
+        if count == 0 {
+            Ok(None)
+        } else {
+            Ok(Some(pb))
+        }
+    }
+}
+
+/// Reads an OSM XML file (.osm or .osc, optionally gzipped), returning
+/// PrimitiveBlocks of up to `block_size` elements. Elements in a .osc file
+/// take their changetype from the enclosing create, modify or delete.
+#[pyclass]
+pub struct XmlReader {
+    parser: Mutex<XmlParser>,
+    block_size: usize,
+    index: i64
+}
+
+#[pymethods]
+impl XmlReader {
+    #[new]
+    #[pyo3(signature=(fname, block_size=8000))]
+    pub fn new(fname: &str, block_size: usize) -> PyResult<XmlReader> {
+        if block_size == 0 {
+            return Err(crate::errors::invalid_input_error(String::from("block_size must be greater than zero")));
+        }
+        Ok(XmlReader{parser: Mutex::new(XmlParser::new(fname)?), block_size: block_size, index: 0})
+    }
+
+    #[getter]
+    pub fn is_change(&self) -> PyResult<bool> {
+        Ok(self.parser.lock().map_err(|_| PyRuntimeError::new_err("lock poisoned"))?.is_change)
+    }
+
+    pub fn next_block(&mut self) -> PyResult<Option<crate::elements::PrimitiveBlock>> {
+        let bl = self.parser.get_mut().map_err(|_| PyRuntimeError::new_err("lock poisoned"))?.next_block(self.index, self.block_size)?;
+        Ok(bl.map(|b| {
+            self.index += 1;
+            crate::elements::PrimitiveBlock::new(b)
+        }))
+    }
+
+    pub fn read_all(&mut self) -> PyResult<Vec<crate::elements::PrimitiveBlock>> {
+        let mut res = Vec::new();
+        while let Some(bl) = self.next_block()? {
+            res.push(bl);
+        }
+        Ok(res)
+    }
+
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&mut self) -> PyResult<Option<crate::elements::PrimitiveBlock>> {
+        self.next_block()
+    }
+}
+
+
+pub(crate) enum XmlOutput {
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>)
+}
+
+impl Write for XmlOutput {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            XmlOutput::Plain(f) => f.write(buf),
+            XmlOutput::Gzip(f) => f.write(buf)
+        }
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            XmlOutput::Plain(f) => f.flush(),
+            XmlOutput::Gzip(f) => f.flush()
+        }
+    }
+}
+
+impl XmlOutput {
+    pub(crate) fn finish(self) -> std::io::Result<()> {
+        match self {
+            XmlOutput::Plain(mut f) => f.flush(),
+            XmlOutput::Gzip(f) => f.finish()?.flush()
+        }
+    }
+}
+
+/// Creates `outfn`, gzipped if `gzip` is set or by default if it ends with
+/// ".gz".
+pub(crate) fn open_output(outfn: &str, gzip: Option<bool>) -> PyResult<XmlOutput> {
+    let f = BufWriter::new(File::create(outfn).map_err(|e| ErrorWrapped::from(e).with_filename(outfn))?);
+    Ok(if gzip.unwrap_or(outfn.ends_with(".gz")) {
+        XmlOutput::Gzip(GzEncoder::new(f, flate2::Compression::default()))
+    } else {
+        XmlOutput::Plain(f)
+    })
+}
+
+//normal and unchanged elements aren't changes, so have no section in a
+//.osc file
+fn changetype_section(ct: &Changetype) -> PyResult<&'static str> {
+    match ct {
+        Changetype::Create => Ok("create"),
+        Changetype::Modify => Ok("modify"),
+        Changetype::Delete | Changetype::Remove => Ok("delete"),
+        Changetype::Normal | Changetype::Unchanged => Err(crate::errors::invalid_input_error(
+            format!("can't write an element with changetype {} to a change file", crate::elements::changetype_str(ct))))
+    }
+}
+
+pub(crate) fn timestamp_xml(ts: i64) -> String {
+    let mut s = osmquadtree::utils::timestamp_string(ts);
+    if !s.ends_with('Z') {
+        s.push('Z');
+    }
+    s
+}
+
+pub(crate) fn coord_xml(v: i32) -> String {
+    format!("{:.7}", (v as f64) / 10000000.0)
+}
+
+pub(crate) fn info_attrs(info: &Option<Info>) -> String {
+    match info {
+        None => String::new(),
+        Some(i) => format!(" version=\"{}\" timestamp=\"{}\" changeset=\"{}\" uid=\"{}\" user=\"{}\"",
+            i.version, timestamp_xml(i.timestamp), i.changeset, i.user_id, escape(i.user.as_str()))
+    }
+}
+
+pub(crate) fn tags_xml(out: &mut String, tags: &[Tag]) {
+    for t in tags {
+        out.push_str(&format!("    <tag k=\"{}\" v=\"{}\"/>\n", escape(t.key.as_str()), escape(t.val.as_str())));
+    }
+}
+
+pub(crate) fn node_xml(n: &osmquadtree::elements::Node) -> String {
+    let mut res = format!("  <node id=\"{}\"{}", n.id, info_attrs(&n.info));
+    match n.changetype {
+        Changetype::Delete | Changetype::Remove => {},
+        _ => { res.push_str(&format!(" lat=\"{}\" lon=\"{}\"", coord_xml(n.lat), coord_xml(n.lon))); }
+    }
+    if n.tags.is_empty() {
+        res.push_str("/>\n");
+    } else {
+        res.push_str(">\n");
+        tags_xml(&mut res, &n.tags);
+        res.push_str("  </node>\n");
+    }
+    res
+}
+
+fn way_xml(w: &osmquadtree::elements::Way) -> String {
+    let mut res = format!("  <way id=\"{}\"{}", w.id, info_attrs(&w.info));
+    if w.tags.is_empty() && w.refs.is_empty() {
+        res.push_str("/>\n");
+    } else {
+        res.push_str(">\n");
+        for r in &w.refs {
+            res.push_str(&format!("    <nd ref=\"{}\"/>\n", r));
+        }
+        tags_xml(&mut res, &w.tags);
+        res.push_str("  </way>\n");
+    }
+    res
+}
+
+fn relation_xml(r: &osmquadtree::elements::Relation) -> String {
+    let mut res = format!("  <relation id=\"{}\"{}", r.id, info_attrs(&r.info));
+    if r.tags.is_empty() && r.members.is_empty() {
+        res.push_str("/>\n");
+    } else {
+        res.push_str(">\n");
+        for m in &r.members {
+            let mt = match m.mem_type {
+                ElementType::Node => "node",
+                ElementType::Way => "way",
+                _ => "relation"
+            };
+            res.push_str(&format!("    <member type=\"{}\" ref=\"{}\" role=\"{}\"/>\n", mt, m.mem_ref, escape(m.role.as_str())));
+        }
+        tags_xml(&mut res, &r.tags);
+        res.push_str("  </relation>\n");
+    }
+    res
+}
+
+/// Writes elements to an OSM XML file. With `ischange` the output is a .osc
+/// file, with each element in a create, modify or delete section according
+/// to its changetype (writing a normal or unchanged element raises an
+/// InvalidInputError): otherwise deleted elements are skipped. The output is
+/// gzipped if `gzip` is set, or by default if `outfn` ends with ".gz".
+#[pyclass]
+pub struct XmlWriter {
+    outfn: String,
+    out: Option<XmlOutput>,
+    ischange: bool,
+    section: Option<&'static str>,
+    count: usize
+}
+
+impl XmlWriter {
+    fn write_str(&mut self, s: &str) -> PyResult<()> {
+        match self.out.as_mut() {
+            Some(o) => Ok(o.write_all(s.as_bytes()).map_err(|e| ErrorWrapped::from(e).with_filename(&self.outfn))?),
+            None => Err(PyValueError::new_err(format!("XmlWriter for {} already finished", self.outfn)))
+        }
+    }
+
+    fn write_xml(&mut self, ct: &Changetype, xml: String) -> PyResult<()> {
+        if self.ischange {
+            let section = changetype_section(ct)?;
+            if self.section != Some(section) {
+                if let Some(prev) = self.section {
+                    self.write_str(&format!("</{}>\n", prev))?;
+                }
+                self.write_str(&format!("<{}>\n", section))?;
+                self.section = Some(section);
+            }
+        } else {
+            match ct {
+                Changetype::Delete | Changetype::Remove => { return Ok(()); },
+                _ => {}
+            }
+        }
+        self.write_str(&xml)?;
+        self.count += 1;
+        Ok(())
+    }
+
+    fn write_node(&mut self, n: &osmquadtree::elements::Node) -> PyResult<()> {
+        self.write_xml(&n.changetype, node_xml(n))
+    }
+
+    fn write_way(&mut self, w: &osmquadtree::elements::Way) -> PyResult<()> {
+        self.write_xml(&w.changetype, way_xml(w))
+    }
+
+    fn write_relation(&mut self, r: &osmquadtree::elements::Relation) -> PyResult<()> {
+        self.write_xml(&r.changetype, relation_xml(r))
+    }
+
+    fn finish_file(&mut self) -> PyResult<()> {
+        if let Some(s) = self.section.take() {
+            self.write_str(&format!("</{}>\n", s))?;
+        }
+        self.write_str(if self.ischange { "</osmChange>\n" } else { "</osm>\n" })?;
+        if let Some(o) = self.out.take() {
+            o.finish().map_err(|e| ErrorWrapped::from(e).with_filename(&self.outfn))?;
+        }
+        Ok(())
+    }
+}
+
+#[pymethods]
+impl XmlWriter {
+    #[new]
+    #[pyo3(signature=(outfn, ischange=false, gzip=None, bbox=None))]
+    pub fn new(outfn: &str, ischange: bool, gzip: Option<bool>, bbox: Option<(i32,i32,i32,i32)>) -> PyResult<XmlWriter> {
+        let out = open_output(outfn, gzip)?;
+        let mut res = XmlWriter{outfn: String::from(outfn), out: Some(out), ischange: ischange, section: None, count: 0};
+
+        res.write_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n")?;
+        res.write_str(&format!("<{} version=\"0.6\" generator=\"osmquadtree_rust_bindings\">\n", if ischange { "osmChange" } else { "osm" }))?;
+        if let (Some(bx), false) = (bbox, ischange) {
+            res.write_str(&format!("  <bounds minlat=\"{}\" minlon=\"{}\" maxlat=\"{}\" maxlon=\"{}\"/>\n",
+                coord_xml(bx.1), coord_xml(bx.0), coord_xml(bx.3), coord_xml(bx.2)))?;
+        }
+        Ok(res)
+    }
+
+    #[getter]
+    pub fn count(&self) -> PyResult<usize> { Ok(self.count) }
+
+    pub fn write_block(&mut self, block: &crate::elements::PrimitiveBlock) -> PyResult<()> {
+        let bl = block.get_inner();
+        for n in &bl.nodes {
+            self.write_node(n)?;
+        }
+        for w in &bl.ways {
+            self.write_way(w)?;
+        }
+        for r in &bl.relations {
+            self.write_relation(r)?;
+        }
+        Ok(())
+    }
+
+    pub fn write_element(&mut self, py: Python, ele: PyObject) -> PyResult<()> {
+        if let Ok(n) = ele.extract::<PyRef<crate::elements::Node>>(py) {
+            self.write_node(n.get_ele())
+        } else if let Ok(w) = ele.extract::<PyRef<crate::elements::Way>>(py) {
+            self.write_way(w.get_ele())
+        } else if let Ok(r) = ele.extract::<PyRef<crate::elements::Relation>>(py) {
+            self.write_relation(r.get_ele())
+        } else {
+            Err(PyTypeError::new_err("expected Node, Way or Relation"))
+        }
+    }
+
+    pub fn finish(&mut self) -> PyResult<()> {
+        self.finish_file()
+    }
+
+    fn __enter__(slf: Py<Self>) -> Py<Self> {
+        slf
+    }
+
+    #[pyo3(signature=(_exc_type=None, _exc_value=None, _traceback=None))]
+    fn __exit__(&mut self, _exc_type: Option<PyObject>, _exc_value: Option<PyObject>, _traceback: Option<PyObject>) -> PyResult<bool> {
+        if self.out.is_some() {
+            self.finish_file()?;
+        }
+        Ok(false)
+    }
+}
+
+
+pub(crate) fn wrap_xml(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<XmlReader>()?;
+    m.add_class::<XmlWriter>()?;
+    Ok(())
+}
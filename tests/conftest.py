@@ -0,0 +1,149 @@
+import collections
+import os
+import pytest
+
+import osmquadtree_rust_bindings as oqt
+from osmquadtree_rust_bindings import rust
+
+SMALL_OSM = """<?xml version="1.0" encoding="UTF-8"?>
+<osm version="0.6">
+  <node id="1" version="1" timestamp="2021-01-01T00:00:00Z" lat="51.5" lon="-0.1"/>
+  <node id="2" version="1" timestamp="2021-01-01T00:00:00Z" lat="51.6" lon="-0.2"/>
+  <node id="3" version="1" timestamp="2021-01-01T00:00:00Z" lat="51.7" lon="-0.3"/>
+  <node id="4" version="1" timestamp="2021-01-01T00:00:00Z" lat="51.8" lon="-0.4"/>
+  <way id="10" version="1" timestamp="2021-01-01T00:00:00Z">
+    <nd ref="1"/>
+    <nd ref="2"/>
+    <tag k="highway" v="road"/>
+  </way>
+  <relation id="20" version="1" timestamp="2021-01-01T00:00:00Z">
+    <member type="way" ref="10" role="outer"/>
+  </relation>
+</osm>
+"""
+
+TIMESTAMP = 'version="1" timestamp="2021-01-01T00:00:00Z" changeset="1" uid="1" user="test"'
+
+
+def grid_node_id(i, j):
+    return 1 + j * 41 + i
+
+
+def grid_osm():
+    #a 41 x 21 grid of nodes around london, 0.05 degrees apart. short ways
+    #join five nodes along each row, long ways run the length of every fifth
+    #column and across the whole of the middle row, so their nodes end up in
+    #smaller quadtrees than the ways themselves
+    lines = ['<?xml version="1.0" encoding="UTF-8"?>', '<osm version="0.6">']
+    for j in range(21):
+        for i in range(41):
+            tags = '<tag k="amenity" v="pub"/>' if (i * 7 + j * 3) % 17 == 0 else ''
+            lines.append('  <node id="%d" %s lat="%.2f" lon="%.2f">%s</node>' % (
+                grid_node_id(i, j), TIMESTAMP, 51.0 + j * 0.05, -1.0 + i * 0.05, tags))
+
+    def way(wid, refs, tags):
+        lines.append('  <way id="%d" %s>' % (wid, TIMESTAMP))
+        lines.extend('    <nd ref="%d"/>' % r for r in refs)
+        lines.extend('    <tag k="%s" v="%s"/>' % kv for kv in tags)
+        lines.append('  </way>')
+
+    wid = 1000
+    for j in range(21):
+        for s in range(0, 40, 5):
+            way(wid, [grid_node_id(i, j) for i in range(s, s + 6)], [("highway", "residential")])
+            wid += 1
+    for i in range(0, 41, 5):
+        way(2000 + i, [grid_node_id(i, j) for j in range(21)], [("highway", "primary")])
+    way(3000, [grid_node_id(i, 10) for i in range(41)], [("highway", "trunk")])
+
+    #a square with a hole, for a multipolygon
+    way(4000, [grid_node_id(i, j) for i, j in [(20, 14), (24, 14), (24, 18), (20, 18), (20, 14)]], [])
+    way(4001, [grid_node_id(i, j) for i, j in [(21, 15), (23, 15), (23, 17), (21, 17), (21, 15)]], [])
+
+    def relation(rid, members, tags):
+        lines.append('  <relation id="%d" %s>' % (rid, TIMESTAMP))
+        lines.extend('    <member type="%s" ref="%d" role="%s"/>' % m for m in members)
+        lines.extend('    <tag k="%s" v="%s"/>' % kv for kv in tags)
+        lines.append('  </relation>')
+
+    relation(5000, [("way", 4000, "outer"), ("way", 4001, "inner")], [("type", "multipolygon"), ("landuse", "grass")])
+    relation(5001, [("way", 2000, ""), ("way", 3000, ""), ("node", grid_node_id(0, 0), "stop")], [("type", "route"), ("route", "bus")])
+    relation(5002, [("relation", 5001, "")], [("type", "route_master")])
+    lines.append('</osm>')
+    return "\n".join(lines) + "\n"
+
+
+def write_pbf(osmfn, outfn, block_size, writingprogram="test", compression_type=("ZlibLevel", 6)):
+    with rust.PbfWriter(outfn, None, writingprogram, None, None, None, None, compression_type) as w:
+        w.write_blocks(rust.XmlReader(osmfn, block_size).read_all())
+    return outfn
+
+
+@pytest.fixture
+def make_pbf(tmp_path):
+    """Writes osm (xml text) to <name>.osm and converts it to <name>.pbf."""
+
+    def make(osm=SMALL_OSM, name="in", block_size=2, **kwargs):
+        osmfn = str(tmp_path / (name + ".osm"))
+        open(osmfn, "w").write(osm)
+        return write_pbf(osmfn, str(tmp_path / (name + ".pbf")), block_size, **kwargs)
+    return make
+
+
+@pytest.fixture
+def small_blocks(tmp_path):
+    fn = str(tmp_path / "in.osm")
+    open(fn, "w").write(SMALL_OSM)
+    return rust.XmlReader(fn, 2).read_all()
+
+
+@pytest.fixture
+def pbf(make_pbf):
+    return make_pbf()
+
+
+SortedDataset = collections.namedtuple("SortedDataset", ["prfx", "qts", "source", "osm"])
+
+
+@pytest.fixture(scope="session")
+def sorted_dataset(tmp_path_factory):
+    d = tmp_path_factory.mktemp("grid")
+    osmfn = str(d / "grid.osm")
+    open(osmfn, "w").write(grid_osm())
+    source = write_pbf(osmfn, str(d / "grid.pbf"), 500)
+
+    qtsfn = rust.run_calcqts(source, numchan=2)[0]
+    prfx = str(d / "grid-sorted.pbf")
+    oqt.run_sortblocks(source, prfx, target=100, numchan=2)
+    return SortedDataset(prfx, qtsfn, source, osmfn)
+
+
+#OSMQUADTREE_TEST_PREFIX, OSMQUADTREE_TEST_QTS and OSMQUADTREE_TEST_PBF
+#run the tests against real data instead of the generated grid
+
+@pytest.fixture
+def prfx(sorted_dataset):
+    return os.environ.get("OSMQUADTREE_TEST_PREFIX", sorted_dataset.prfx)
+
+
+@pytest.fixture
+def qts(sorted_dataset):
+    if "OSMQUADTREE_TEST_PREFIX" in os.environ:
+        if "OSMQUADTREE_TEST_QTS" not in os.environ:
+            pytest.skip("OSMQUADTREE_TEST_QTS not set")
+        return os.environ["OSMQUADTREE_TEST_QTS"]
+    return sorted_dataset.qts
+
+
+@pytest.fixture
+def source_pbf(sorted_dataset):
+    return os.environ.get("OSMQUADTREE_TEST_PBF", sorted_dataset.source)
+
+
+@pytest.fixture
+def real_prfx():
+    """For tests which need data the grid doesn't have, such as change files."""
+    prfx = os.environ.get("OSMQUADTREE_TEST_PREFIX")
+    if prfx is None:
+        pytest.skip("OSMQUADTREE_TEST_PREFIX not set")
+    return prfx
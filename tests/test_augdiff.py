@@ -0,0 +1,52 @@
+import xml.etree.ElementTree as ET
+
+import pytest
+
+import osmquadtree_rust_bindings as oqt
+from osmquadtree_rust_bindings import rust
+
+
+def test_bad_order():
+    with pytest.raises(oqt.InvalidInputError):
+        rust.AugmentedDiff("/nonexistent/osmquadtree/missing", "2021-01-02T00:00:00", "2021-01-01T00:00:00")
+
+
+@pytest.fixture
+def prfx(real_prfx):
+    return real_prfx
+
+
+@pytest.fixture
+def timestamps(prfx):
+    fl = rust.read_filelist(prfx)
+    if len(fl) < 2:
+        pytest.skip("no change files")
+    return rust.timestamp_string(fl[-2].end_date), rust.timestamp_string(fl[-1].end_date)
+
+
+def test_same_timestamp(prfx, timestamps):
+    assert len(rust.AugmentedDiff(prfx, timestamps[1], timestamps[1])) == 0
+
+
+def test_augmented_diff(prfx, timestamps, tmp_path):
+    diff = rust.AugmentedDiff(prfx, *timestamps)
+    assert len(diff) > 0
+
+    actions = list(diff)
+    assert len(actions) == len(diff)
+    for a in actions:
+        if a.action == "create":
+            assert a.old is None and a.old_geometry is None
+        elif a.action == "delete":
+            assert a.new is None and a.new_geometry is None
+        else:
+            assert a.action == "modify"
+            assert a.old.id == a.new.id == a.id
+        if a.element_type == "way" and a.new is not None:
+            assert len(a.new_geometry) == len(a.new.refs)
+
+    outfn = str(tmp_path / "diff.xml")
+    assert diff.write_xml(outfn) == len(diff)
+    root = ET.parse(outfn).getroot()
+    assert root.tag == "osm"
+    assert [a.get("type") for a in root.findall("action")] == [a.action for a in actions]
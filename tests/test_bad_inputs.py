@@ -0,0 +1,155 @@
+import os
+import pytest
+
+import osmquadtree_rust_bindings as oqt
+from osmquadtree_rust_bindings import rust
+
+MISSING = "/nonexistent/osmquadtree/missing.pbf"
+
+
+def test_error_hierarchy():
+    for e in (oqt.OsmIoError, oqt.PbfDecodeError, oqt.TimestampParseError,
+              oqt.StyleError, oqt.FilterError, oqt.InvalidInputError, oqt.GeometryError):
+        assert issubclass(e, oqt.OsmQuadtreeError)
+    assert issubclass(oqt.MissingFileError, oqt.OsmIoError)
+    #I/O and decode errors are OSErrors, bad arguments and input ValueErrors
+    for e in (oqt.OsmIoError, oqt.MissingFileError, oqt.PbfDecodeError):
+        assert issubclass(e, OSError) and not issubclass(e, ValueError)
+    for e in (oqt.TimestampParseError, oqt.StyleError, oqt.FilterError, oqt.InvalidInputError, oqt.GeometryError):
+        assert issubclass(e, ValueError) and not issubclass(e, OSError)
+
+
+def test_parse_timestamp():
+    with pytest.raises(oqt.TimestampParseError):
+        rust.parse_timestamp("not a timestamp")
+
+
+def test_missing_files(tmp_path):
+    with pytest.raises(oqt.MissingFileError) as e:
+        rust.call_count(MISSING)
+    assert e.value.filename == MISSING
+
+    with pytest.raises(oqt.MissingFileError):
+        rust.run_calcqts(MISSING)
+    with pytest.raises(oqt.MissingFileError):
+        rust.prepare_quadtree_tree(MISSING, 4, 17)
+    with pytest.raises(oqt.MissingFileError):
+        rust.compare_pbf_files(MISSING, MISSING, 4, 10)
+    with pytest.raises(oqt.MissingFileError):
+        rust.compare_pbf_files_json(MISSING, MISSING, 4, str(tmp_path / "out.json"))
+    with pytest.raises(oqt.MissingFileError):
+        rust.ReadFileBlocks(MISSING)
+    with pytest.raises(oqt.MissingFileError):
+        rust.QuadtreeTree.read(MISSING)
+
+    #removed after being opened: the io error is still a MissingFileError
+    fn = str(tmp_path / "history.osh.pbf")
+    open(fn, "wb").close()
+    h = rust.HistoryFile(fn)
+    os.remove(fn)
+    with pytest.raises(oqt.MissingFileError):
+        iter(h)
+    with pytest.raises(oqt.OsmQuadtreeError):
+        rust.ReadFileBlocksParallel(MISSING)
+    with pytest.raises(oqt.OsmQuadtreeError):
+        rust.process_geometry(MISSING)
+    with pytest.raises(oqt.MissingFileError):
+        rust.read_filelist("/nonexistent/osmquadtree/")
+    with pytest.raises(oqt.MissingFileError):
+        rust.run_update("/nonexistent/osmquadtree/")
+    with pytest.raises(oqt.MissingFileError):
+        rust.run_update_initial(str(tmp_path / "prfx") + "/", MISSING, "2021-01-01T00:00:00", 1, str(tmp_path))
+
+
+def test_bad_timestamps(tmp_path):
+    with pytest.raises(oqt.TimestampParseError):
+        rust.ReadFileBlocksParallel(str(tmp_path), None, "yesterday")
+    with pytest.raises(oqt.TimestampParseError):
+        rust.process_geometry(str(tmp_path), None, "yesterday")
+    open(tmp_path / "a.pbf", "wb").close()
+    with pytest.raises(oqt.TimestampParseError):
+        rust.call_count(str(tmp_path / "a.pbf"), False, 4, None, "yesterday")
+
+
+def test_bad_filters(tmp_path):
+    with pytest.raises(oqt.FilterError):
+        rust.ReadFileBlocksParallel(str(tmp_path), [1, 2, 3])
+    with pytest.raises(oqt.FilterError):
+        rust.ReadFileBlocksParallel(str(tmp_path), "not a filter")
+    with pytest.raises(oqt.FilterError):
+        rust.Poly([0.0, 1.0, 1.0], [0.0, 1.0], "bad")
+    with pytest.raises(oqt.FilterError):
+        rust.Poly([0.0, 1.0], [0.0, 1.0], "bad")
+    with pytest.raises(oqt.FilterError):
+        rust.Poly.from_file(MISSING)
+
+
+def test_bad_style(tmp_path):
+    with pytest.raises(oqt.StyleError):
+        rust.process_geometry(str(tmp_path), None, None, None, "{not json")
+    with pytest.raises(oqt.StyleError):
+        rust.process_geometry(str(tmp_path), None, None, ("not a minzoom spec", None))
+
+
+def test_bad_blocks():
+    with pytest.raises(oqt.PbfDecodeError) as e:
+        rust.read_primitive_block(0, 1234, b"\xff\xff\xff\xff", False, False)
+    assert e.value.position == 1234
+
+
+def test_bad_elements():
+    with pytest.raises(ValueError):
+        rust.IdSetSet().insert("x", 1)
+    with pytest.raises(TypeError):
+        rust.Node(1, "normal", 1, 0, 0, 0, "", [], 0, 0, None)
+
+
+def test_quadtree_tree(tmp_path):
+    tree = rust.QuadtreeTree()
+    with pytest.raises(IndexError):
+        tree.next(1000)
+    with pytest.raises(IndexError):
+        tree.next_sibling(1000)
+    with pytest.raises(IndexError):
+        tree[1000]
+
+    with pytest.raises(oqt.InvalidInputError):
+        rust.find_tree_groups(tree, 0, 0)
+
+    groups = rust.find_tree_groups(tree, 40000, 20000)
+    with pytest.raises(ValueError):
+        rust.find_tree_groups(tree, 40000, 20000)
+    with pytest.raises(ValueError):
+        len(tree)
+
+    with pytest.raises(ValueError):
+        rust.sort_blocks(__file__, __file__, str(tmp_path / "out.pbf"),
+            tree, 4, 10, True, 100, 0, False, ("ZlibLevel", 6))
+    assert os.listdir(tmp_path) == []
+
+    with pytest.raises(oqt.InvalidInputError):
+        rust.run_calcqts(str(tmp_path), None, 25)
+
+
+def test_bad_compression(tmp_path):
+    with pytest.raises(ValueError):
+        rust.sort_blocks(__file__, __file__, str(tmp_path / "out.pbf"), rust.QuadtreeTree(),
+            4, 10, True, 100, 0, False, ("Unknown", 0))
+
+
+def test_callback_errors_are_raised(source_pbf):
+    def callback(bls):
+        raise RuntimeError("callback failed")
+
+    with pytest.raises(RuntimeError):
+        rust.ReadFileBlocks(source_pbf).read_all(callback, 4, False, 1)
+
+
+def test_messenger_errors_are_raised(source_pbf, tmp_path, monkeypatch):
+    def message(message):
+        raise RuntimeError("messenger failed")
+
+    #oqt.messenger is the object registered with the rust module
+    monkeypatch.setattr(oqt.messenger, "message", message)
+    with pytest.raises(RuntimeError, match="messenger failed"):
+        rust.run_calcqts(source_pbf, str(tmp_path / "out-qts.pbf"), numchan=2)
@@ -0,0 +1,70 @@
+import os
+import pytest
+
+import osmquadtree_rust_bindings as oqt
+from osmquadtree_rust_bindings import rust
+
+
+def test_no_index(pbf):
+    rf = rust.ReadFileBlocks(pbf)
+    assert not rf.has_block_index
+    with pytest.raises(oqt.MissingFileError):
+        rf.block_for_id("node", 1)
+    with pytest.raises(oqt.MissingFileError):
+        rf.load_block_index()
+
+
+def test_block_index(pbf):
+    assert rust.build_block_index(pbf) == 3
+    assert os.path.exists(pbf + ".blockindex")
+
+    rf = rust.ReadFileBlocks(pbf)
+    assert rf.has_block_index
+    idx = rf.block_index()
+    assert [e[3] for e in idx] == ["node", "node", "mixed"]
+    assert idx[0][5][0] == (2, 1, 2)
+    assert idx[2][5][1:] == [(1, 10, 10), (1, 20, 20)]
+    assert idx[0][6] == (-2000000, 515000000, -1000000, 516000000)
+
+    i, pos = rf.block_for_id("way", 10)
+    assert i == 2
+    bl = rf.read_block_at(i, pos, False, False)
+    assert bl.way_at(0).id == 10
+
+    i, pos = rf.block_for_id("n", 3)
+    assert rf.read_block_at(i, pos, False, False).node_at(0).id == 3
+    assert rf.block_for_id("node", 5) is None
+    with pytest.raises(ValueError):
+        rf.block_for_id("changeset", 1)
+
+    assert (0, idx[0][1]) in rf.blocks_in_bbox((-1500000, 515500000, -500000, 520000000))
+
+
+def test_stale_index(pbf, tmp_path):
+    rust.build_block_index(pbf)
+    with open(pbf, "ab") as f:
+        f.write(b"\0")
+    rf = rust.ReadFileBlocks(pbf)
+    assert not rf.has_block_index
+    with pytest.raises(oqt.InvalidInputError):
+        rf.load_block_index()
+
+    bad = str(tmp_path / "bad.blockindex")
+    open(bad, "wb").write(b"not an index")
+    with pytest.raises(oqt.InvalidInputError):
+        rf.load_block_index(bad)
+    with pytest.raises(oqt.InvalidInputError):
+        rf.block_for_id("node", 1)
+
+
+def test_block_for_every_id(make_pbf):
+    fn = make_pbf(block_size=1)
+    n = rust.build_block_index(fn)
+    rf = rust.ReadFileBlocks(fn)
+    for i, pos, _, _, _, ranges, _ in rf.block_index():
+        for t, (c, a, b) in zip(["node", "way", "relation"], ranges):
+            if c > 0:
+                assert rf.block_for_id(t, a) == (i, pos)
+    assert n == len(rf.block_index()) > 3
+    assert rf.block_for_id("node", 0) is None
+    assert rf.block_for_id("way", 11) is None
@@ -0,0 +1,66 @@
+import threading
+import time
+
+import pytest
+
+from osmquadtree_rust_bindings import rust
+
+
+def test_request_cancel(source_pbf):
+    def callback(bls):
+        rust.request_cancel()
+        assert rust.cancel_requested()
+
+    with pytest.raises(KeyboardInterrupt):
+        rust.ReadFileBlocks(source_pbf).read_all(callback, 2, False, 1)
+    assert not rust.cancel_requested()
+
+    #each operation has its own token, so the next one isn't cancelled
+    got = []
+    rust.ReadFileBlocks(source_pbf).read_all(got.extend, 2, False, 1)
+    assert len(got) > 0
+
+
+def test_nothing_running(pbf):
+    #with no operation running there is nothing to cancel
+    rust.request_cancel()
+    assert not rust.cancel_requested()
+    assert rust.build_block_index(pbf) == 3
+
+
+def test_cancel_sort_blocks(sorted_dataset, tmp_path):
+    groups = rust.prepare_tree_groups(sorted_dataset.qts, str(tmp_path / "groups.bin"), 100, 50, 17, 2)
+    outfn = tmp_path / "out.pbf"
+
+    #sort_blocks runs as several stages: whichever one is running when the
+    #request comes in stops, and nothing after it is run
+    done = threading.Event()
+    def cancel():
+        while not done.is_set():
+            rust.request_cancel()
+            time.sleep(0.001)
+
+    t = threading.Thread(target=cancel)
+    t.start()
+    try:
+        with pytest.raises(KeyboardInterrupt):
+            rust.sort_blocks(sorted_dataset.source, sorted_dataset.qts, str(outfn), groups, 2, 15, True, 1000000, 0, False, ("ZlibLevel", 6))
+    finally:
+        done.set()
+        t.join()
+    assert not outfn.exists()
+
+
+def test_cancel_process_geometry(prfx):
+    total = rust.process_geometry_callback(prfx, lambda bls: None, callback_num_blocks=1, numchan=2)
+
+    calls = []
+    def callback(bls):
+        calls.append(len(bls))
+        rust.request_cancel()
+
+    with pytest.raises(KeyboardInterrupt):
+        rust.process_geometry_callback(prfx, callback, callback_num_blocks=1, numchan=2)
+    #the blocks after the request are dropped rather than passed on
+    assert 0 < len(calls) < total
+    assert not rust.cancel_requested()
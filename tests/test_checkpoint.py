@@ -0,0 +1,102 @@
+import json
+import os
+import pytest
+
+import osmquadtree_rust_bindings as oqt
+from osmquadtree_rust_bindings import rust
+
+
+def test_no_manifest(tmp_path):
+    assert rust.checkpoint_stages(str(tmp_path / "out.pbf")) == []
+
+
+def test_bad_tree_file(tmp_path):
+    fn = str(tmp_path / "groups.bin")
+    open(fn, "wb").write(b"not a tree")
+    with pytest.raises(oqt.InvalidInputError):
+        rust.QuadtreeTree.read(fn)
+
+
+def test_tree_roundtrip(tmp_path):
+    fn = str(tmp_path / "groups.bin")
+    tree = rust.QuadtreeTree()
+    tree.write(fn)
+    assert len(rust.QuadtreeTree.read(fn)) == len(tree)
+
+
+def test_calcqts_resume(source_pbf, tmp_path):
+    infn = source_pbf
+    qtsfn = str(tmp_path / "test-qts.pbf")
+
+    first = rust.run_calcqts(infn, qtsfn, numchan=2, resume=True)
+    assert rust.checkpoint_stages(qtsfn) == ["calcqts_waynodes", "calcqts"]
+    assert not os.path.exists(qtsfn + "-waynodes")
+    mtime = os.stat(qtsfn).st_mtime_ns
+
+    #the recorded result, timings included, is returned without running again
+    second = rust.run_calcqts(infn, qtsfn, numchan=2, resume=True)
+    assert second == first
+    assert os.stat(qtsfn).st_mtime_ns == mtime
+
+    #different parameters start again
+    third = rust.run_calcqts(infn, qtsfn, qt_level=16, numchan=2, resume=True)
+    assert third[1] != first[1]
+    assert rust.checkpoint_stages(qtsfn) == ["calcqts_waynodes", "calcqts"]
+
+
+def test_calcqts_resume_stage(source_pbf, tmp_path):
+    infn = source_pbf
+    qtsfn = str(tmp_path / "test-qts.pbf")
+    waynodesfn = qtsfn + "-waynodes"
+
+    first = rust.run_calcqts(infn, qtsfn, keep_temps=True, numchan=2, resume=True)
+    waynodes_mtime = os.stat(waynodesfn).st_mtime_ns
+
+    #as if interrupted after collecting the way nodes
+    manifest = qtsfn + ".checkpoint.json"
+    m = json.load(open(manifest))
+    m["stages"] = m["stages"][:1]
+    json.dump(m, open(manifest, "w"))
+    os.remove(qtsfn)
+
+    second = rust.run_calcqts(infn, qtsfn, keep_temps=True, numchan=2, resume=True)
+    assert os.stat(waynodesfn).st_mtime_ns == waynodes_mtime
+    assert second[0] == first[0] and second[2] == first[2]
+    assert rust.checkpoint_stages(qtsfn) == ["calcqts_waynodes", "calcqts"]
+
+
+def test_tree_groups_resume(qts, tmp_path):
+    qtsfn = qts
+    groupsfn = str(tmp_path / "groups.bin")
+
+    groups = rust.prepare_tree_groups(qtsfn, groupsfn, 40000, 20000, resume=True)
+    assert rust.checkpoint_stages(groupsfn) == ["tree_groups"]
+    again = rust.prepare_tree_groups(qtsfn, groupsfn, 40000, 20000, resume=True)
+    assert again.num_entries() == groups.num_entries()
+
+    open(groupsfn, "ab").write(b"x")
+    with pytest.raises(oqt.InvalidInputError):
+        rust.QuadtreeTree.read(groupsfn)
+    #the changed artefact means the stage is run again
+    assert rust.prepare_tree_groups(qtsfn, groupsfn, 40000, 20000, resume=True).num_entries() == groups.num_entries()
+
+
+def test_sort_blocks_resume(source_pbf, qts, tmp_path):
+    groupsfn = str(tmp_path / "groups.bin")
+    outfn = str(tmp_path / "sorted.pbf")
+
+    def run(groups):
+        return rust.sort_blocks(source_pbf, qts, outfn, groups, 2, 15, False, 1000000, 0, False, ("ZlibLevel", 6), True)
+
+    groups = rust.prepare_tree_groups(qts, groupsfn, 100, 50)
+    first = run(rust.QuadtreeTree.read(groupsfn))
+    assert rust.checkpoint_stages(outfn) == ["temp_blocks", "sort_blocks"]
+    mtime = os.stat(outfn).st_mtime_ns
+    assert run(rust.QuadtreeTree.read(groupsfn)) == first
+    assert os.stat(outfn).st_mtime_ns == mtime
+
+    #the same number of groups, with different weights, sorts again
+    changed = rust.QuadtreeTree.read(groupsfn)
+    changed.add(changed[0][0], 1)
+    assert changed.num_entries() == groups.num_entries()
+    assert run(changed) != first
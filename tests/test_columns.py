@@ -0,0 +1,65 @@
+import pytest
+
+from osmquadtree_rust_bindings import rust
+
+OSM = """<?xml version="1.0" encoding="UTF-8"?>
+<osm version="0.6">
+  <node id="1" version="1" timestamp="2021-01-01T00:00:00Z" changeset="5" uid="3" user="a" lat="51.5" lon="-0.1"/>
+  <node id="2" version="2" timestamp="2021-01-01T00:00:00Z" changeset="6" uid="3" user="a" lat="51.6" lon="-0.2"/>
+  <way id="3" version="1" timestamp="2021-01-01T00:00:00Z" changeset="5" uid="3" user="a">
+    <nd ref="1"/>
+    <nd ref="2"/>
+  </way>
+  <way id="4" version="1" timestamp="2021-01-01T00:00:00Z" changeset="5" uid="3" user="a">
+    <nd ref="2"/>
+  </way>
+  <relation id="5" version="1" timestamp="2021-01-01T00:00:00Z" changeset="5" uid="3" user="a">
+    <member type="node" ref="1" role=""/>
+    <member type="way" ref="3" role="outer"/>
+  </relation>
+</osm>
+"""
+
+
+@pytest.fixture
+def block(tmp_path):
+    fn = str(tmp_path / "in.osm")
+    open(fn, "w").write(OSM)
+    return rust.XmlReader(fn).read_all()[0]
+
+
+def test_node_columns(block):
+    cols = block.node_columns()
+    assert cols["id"].dtype == "int64"
+    assert cols["lon"].dtype == "int32"
+    assert memoryview(cols["id"]).tolist() == [1, 2]
+    assert memoryview(cols["lon"]).tolist() == [-1000000, -2000000]
+    assert memoryview(cols["lat"]).tolist() == [515000000, 516000000]
+    assert memoryview(cols["changeset"]).tolist() == [5, 6]
+    assert memoryview(cols["changetype"]).tolist() == [0, 0]
+    assert memoryview(cols["id"]).readonly
+    assert len(cols["id"]) == block.num_nodes()
+
+
+def test_way_columns(block):
+    cols = block.way_columns()
+    assert cols["id"].to_list() == [3, 4]
+    assert cols["ref_offsets"].to_list() == [0, 2, 3]
+    assert cols["refs"].to_list() == [1, 2, 2]
+
+
+def test_relation_columns(block):
+    cols = block.relation_columns()
+    assert cols["member_offsets"].to_list() == [0, 2]
+    assert cols["member_types"].to_list() == [0, 1]
+    assert cols["member_refs"].to_list() == [1, 3]
+
+
+def test_numpy(block):
+    numpy = pytest.importorskip("numpy")
+    cols = block.node_columns()
+    lon = numpy.asarray(cols["lon"])
+    assert lon.dtype == numpy.int32
+    assert list(lon) == [-1000000, -2000000]
+    del cols
+    assert list(lon) == [-1000000, -2000000]
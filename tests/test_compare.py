@@ -0,0 +1,66 @@
+import pytest
+
+import conftest
+import osmquadtree_rust_bindings as oqt
+from osmquadtree_rust_bindings import rust
+
+
+def test_missing():
+    with pytest.raises(oqt.MissingFileError):
+        rust.compare_quadtree_datasets("/nonexistent/osmquadtree/left", "/nonexistent/osmquadtree/right")
+
+
+def test_same(prfx):
+    diffs, counts = rust.compare_quadtree_datasets(prfx, prfx, numchan=2)
+    assert diffs == [] and counts == {}
+
+
+def test_timestamps(real_prfx):
+    prfx = real_prfx
+    fl = rust.read_filelist(prfx)
+    if len(fl) < 2:
+        pytest.skip("no change files")
+    diffs, counts = rust.compare_quadtree_datasets(prfx, prfx,
+        rust.timestamp_string(fl[0].end_date), rust.timestamp_string(fl[-1].end_date), max_result_len=10)
+    assert sum(counts.values()) > 0
+    assert len(diffs) == min(10, sum(counts.values()))
+    for d in diffs:
+        assert d[0] in counts
+        if d[0] == "MovedBlock":
+            assert d[1] != d[2] and d[3][2] == d[4][2]
+
+
+def test_differences(sorted_dataset, tmp_path):
+    #move a node to the far corner of the grid, retag a way and drop a relation
+    osm = open(sorted_dataset.osm).read()
+    nid = conftest.grid_node_id(3, 3)
+    old_node = 'id="%d" %s lat="51.15" lon="-0.85"' % (nid, conftest.TIMESTAMP)
+    assert old_node in osm
+    osm = osm.replace(old_node, 'id="%d" %s lat="51.95" lon="0.95"' % (nid, conftest.TIMESTAMP))
+    osm = osm.replace('<tag k="highway" v="trunk"/>', '<tag k="highway" v="motorway"/>')
+    start = osm.index('  <relation id="5002"')
+    osm = osm[:start] + osm[osm.index('</relation>', start) + len('</relation>\n'):]
+
+    osmfn = str(tmp_path / "changed.osm")
+    open(osmfn, "w").write(osm)
+    source = conftest.write_pbf(osmfn, str(tmp_path / "changed.pbf"), 500)
+    right = str(tmp_path / "changed-sorted.pbf")
+    oqt.run_sortblocks(source, right, target=100, numchan=2)
+
+    diffs, counts = rust.compare_quadtree_datasets(sorted_dataset.prfx, right, numchan=2, max_result_len=100000)
+    assert len(diffs) == sum(counts.values())
+
+    found = {}
+    for d in diffs:
+        if d[0] in ("OnlyLeft", "OnlyRight", "DifferentData", "DifferentTags", "MovedBlock"):
+            ele = d[3] if d[0] == "MovedBlock" else d[1]
+            found.setdefault((ele[0], ele[2]), set()).add(d[0])
+    #the moved node is in another block, and its location change isn't hidden
+    assert found[("node", nid)] == {"MovedBlock", "DifferentData"}
+    assert "DifferentTags" in found[("way", 3000)]
+    assert found[("relation", 5002)] == {"OnlyLeft"}
+
+    #truncating keeps the first differences in block order, whichever pass found them
+    few, few_counts = rust.compare_quadtree_datasets(sorted_dataset.prfx, right, numchan=2, max_result_len=3)
+    assert few_counts == counts
+    assert few == diffs[:3]
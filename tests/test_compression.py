@@ -0,0 +1,124 @@
+import os
+import pytest
+
+import osmquadtree_rust_bindings as oqt
+from osmquadtree_rust_bindings import rust
+
+
+def write(fn, blocks, compression):
+    with rust.PbfWriter(fn, None, "test", None, None, None, None, compression) as w:
+        w.write_blocks(blocks)
+
+
+def check_file(fn, blocks, compression_name):
+    hb = rust.ReadFileBlocks(fn).get_header()
+    assert hb.writingprogram == "test"
+    assert len(hb.index) == len(blocks)
+
+    rf = rust.ReadFileBlocks(fn)
+    for (_, _, loc, _), bl in zip(hb.index, blocks):
+        fb = rf.read_at(loc)
+        assert fb.block_type == "OSMData"
+        if compression_name is not None:
+            assert fb.compression_type == compression_name
+        bl2 = rf.read_block_at(0, loc, False, False)
+        assert (bl2.num_nodes(), bl2.num_ways()) == (bl.num_nodes(), bl.num_ways())
+
+    got = []
+    rust.ReadFileBlocks(fn).read_all(got.extend, 2, False, 1)
+    assert sum(bl.num_nodes() for bl in got) == 4
+
+
+@pytest.mark.parametrize("compression,name", [(("ZstdLevel", 3), "Zstd"), (("Lz4", 0), "Lz4")])
+def test_write_read(tmp_path, small_blocks, compression, name):
+    fn = str(tmp_path / "out.pbf")
+    write(fn, small_blocks, compression)
+    check_file(fn, small_blocks, name)
+
+
+def test_recompress(tmp_path, small_blocks):
+    zlib = str(tmp_path / "zlib.pbf")
+    write(zlib, small_blocks, ("ZlibLevel", 6))
+
+    zstd = str(tmp_path / "zstd.pbf")
+    assert rust.recompress(zlib, zstd, ("ZstdLevel", 19)) == len(small_blocks)
+    check_file(zstd, small_blocks, "Zstd")
+
+    lz4 = str(tmp_path / "lz4.pbf")
+    assert rust.recompress(zstd, lz4, ("Lz4", 0), numchan=0) == len(small_blocks)
+    check_file(lz4, small_blocks, "Lz4")
+
+    back = str(tmp_path / "back.pbf")
+    rust.recompress(lz4, back, ("ZlibLevel", 6))
+    check_file(back, small_blocks, None)
+    assert rust.ReadFileBlocks(back).get_header().index == rust.ReadFileBlocks(zlib).get_header().index
+    assert sorted(os.listdir(tmp_path)) == ["back.pbf", "in.osm", "lz4.pbf", "zlib.pbf", "zstd.pbf"]
+
+
+def test_bad_args(tmp_path, small_blocks):
+    fn = str(tmp_path / "out.pbf")
+    with pytest.raises(ValueError):
+        rust.PbfWriter(fn, None, None, None, None, None, None, ("Zstd", 3))
+
+    write(fn, small_blocks, ("ZlibLevel", 6))
+    with pytest.raises(oqt.InvalidInputError):
+        rust.recompress(fn, fn, ("ZstdLevel", 3))
+    with pytest.raises(oqt.MissingFileError):
+        rust.recompress(fn + "-missing", str(tmp_path / "x.pbf"), ("ZstdLevel", 3))
+    #not a pbf file: the block lengths are refused before anything is read
+    with pytest.raises(oqt.OsmIoError, match="length"):
+        rust.recompress(__file__, str(tmp_path / "x.pbf"), ("ZstdLevel", 3))
+
+
+def test_write_merged(prfx, tmp_path):
+    outfn = str(tmp_path / "merged.pbf")
+    rf = rust.ReadFileBlocksParallel(prfx)
+    rf.write_merged(outfn, None, ("ZstdLevel", 3), 4)
+    assert not os.path.exists(outfn + "-intermediate")
+
+    hb = rust.ReadFileBlocks(outfn).get_header()
+    rf2 = rust.ReadFileBlocks(outfn)
+    for _, _, loc, _ in hb.index[:10]:
+        assert rf2.read_at(loc).compression_type == "Zstd"
+
+
+def block_summary(bls):
+    return sorted((bl.quadtree.integer, bl.num_nodes(), bl.num_ways(), bl.num_relations()) for bl in bls)
+
+
+def geometry_summary(gbs):
+    return sorted((g.num_points(), g.num_linestrings(), g.num_simple_polygons(), g.num_complicated_polygons()) for g in gbs)
+
+
+@pytest.mark.parametrize("compression", [("ZstdLevel", 3), ("Lz4", 0)])
+def test_parallel_round_trip(tmp_path, sorted_dataset, compression):
+    fn = str(tmp_path / "grid.pbf")
+    rust.recompress(sorted_dataset.prfx, fn, compression)
+
+    expected = rust.ReadFileBlocksParallel(sorted_dataset.prfx)
+    rd = rust.ReadFileBlocksParallel(fn)
+    assert [rd.index_at(i)[0] for i in range(rd.num_blocks())] == [expected.index_at(i)[0] for i in range(expected.num_blocks())]
+    assert block_summary(rd.iter_blocks(None, 2)) == block_summary(expected.iter_blocks(None, 2))
+
+    got = []
+    assert rd.read_all(got.extend, None, 2) == expected.num_blocks()
+    assert block_summary(got) == block_summary(expected.iter_blocks(None, 2))
+
+
+def test_upstream_readers(tmp_path, sorted_dataset):
+    #process_geometry, write_merged, run_calcqts and sort_blocks read through
+    #osmquadtree, which can't decode zstd: these are refused, and work again
+    #once the file is recompressed with zlib
+    zstd = str(tmp_path / "grid-zstd.pbf")
+    rust.recompress(sorted_dataset.prfx, zstd, ("ZstdLevel", 3))
+    with pytest.raises(oqt.InvalidInputError, match="recompress"):
+        rust.process_geometry(zstd)
+    with pytest.raises(oqt.InvalidInputError, match="recompress"):
+        rust.ReadFileBlocksParallel(zstd).write_merged(str(tmp_path / "merged.pbf"), None, ("ZlibLevel", 6), 2)
+    with pytest.raises(oqt.InvalidInputError, match="recompress"):
+        rust.run_calcqts(zstd, numchan=2)
+    assert not os.path.exists(str(tmp_path / "merged.pbf"))
+
+    zlib = str(tmp_path / "grid-zlib.pbf")
+    rust.recompress(zstd, zlib, ("ZlibLevel", 6))
+    assert geometry_summary(rust.process_geometry(zlib)) == geometry_summary(rust.process_geometry(sorted_dataset.prfx))
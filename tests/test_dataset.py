@@ -0,0 +1,92 @@
+import pytest
+
+import osmquadtree_rust_bindings as oqt
+from osmquadtree_rust_bindings import rust
+
+
+def test_missing():
+    with pytest.raises(oqt.MissingFileError):
+        rust.Dataset("/nonexistent/osmquadtree/missing")
+
+
+def centre_box(prfx, size=50000):
+    #a small box around the first node in the file
+    rd = rust.ReadFileBlocksParallel(prfx)
+    for i in range(rd.num_blocks()):
+        bl = rd.primitive_block_at(i, None)
+        if bl.num_nodes() > 0:
+            n = bl.node_at(0)
+            return (n.lon - size, n.lat - size, n.lon + size, n.lat + size)
+    pytest.skip("no nodes")
+
+
+def test_query_bbox(prfx):
+    ds = rust.Dataset(prfx, cache_size=16)
+    bbox = centre_box(prfx)
+    with pytest.raises(oqt.FilterError):
+        ds.query_bbox((bbox[2], bbox[1], bbox[0], bbox[3]))
+
+    bls = ds.query_bbox(bbox)
+    assert len(bls) > 0
+    assert {bl.index for bl in bls} <= set(ds.block_indices(list(bbox)))
+
+    node_ids = {bl.node_at(i).id for bl in bls for i in range(bl.num_nodes())}
+    for bl in bls:
+        for i in range(bl.num_ways()):
+            assert set(bl.way_at(i).refs) <= node_ids
+
+    hits, misses, cached, size = ds.cache_stats
+    assert hits == 0 and misses == len(ds.block_indices(list(bbox)))
+    assert cached == min(misses, 16) and size == 16
+
+    bls2 = ds.query_bbox(bbox)
+    assert [bl.index for bl in bls2] == [bl.index for bl in bls]
+    if misses <= 16:
+        assert ds.cache_stats[:2] == (misses, misses)
+
+    ds.clear_cache()
+    assert ds.cache_stats[2] == 0
+
+
+def test_query_edge_way(sorted_dataset):
+    #a box around just the middle node of the grid: the long ways through it
+    #have nodes in blocks the box doesn't reach
+    ds = rust.Dataset(sorted_dataset.prfx)
+    lon, lat = 0, 515000000
+    bbox = (lon - 100000, lat - 100000, lon + 100000, lat + 100000)
+    bls = ds.query_bbox(bbox)
+
+    node_ids = {bl.node_at(i).id for bl in bls for i in range(bl.num_nodes())}
+    ways = {bl.way_at(i).id: bl.way_at(i).refs for bl in bls for i in range(bl.num_ways())}
+    assert 3000 in ways and 2020 in ways
+    assert len(ways[3000]) == 41
+    assert set(ways[3000]) <= node_ids
+    assert set(ways[2020]) <= node_ids
+    assert not set(ds.block_indices(list(bbox))) >= {bl.index for bl in bls}
+
+
+def test_query_poly(prfx):
+    ds = rust.Dataset(prfx)
+    a, b, c, d = centre_box(prfx)
+    xs, ys = [v * 1e-7 for v in (a, c, c, a)], [v * 1e-7 for v in (b, b, d, d)]
+    poly = rust.Poly(xs, ys, "box")
+    by_poly = ds.query_poly(poly)
+    by_box = ds.query_bbox((a, b, c, d))
+    count = lambda bls: sum(bl.num_nodes() for bl in bls)
+    assert 0 < count(by_poly) <= count(by_box)
+
+
+def test_query_geometry(prfx):
+    ds = rust.Dataset(prfx)
+    bbox = centre_box(prfx)
+    bls = ds.query_bbox(bbox, geometry=True)
+    for bl in bls:
+        for i in range(bl.num_points()):
+            minx, miny, maxx, maxy = bl.point_at(i).bounds(False)
+            assert bbox[0] <= maxx and minx <= bbox[2]
+    assert ds.num_blocks() == rust.ReadFileBlocksParallel(prfx).num_blocks()
+
+    #repeated queries use the cached geometry
+    bls2 = ds.query_bbox(bbox, geometry=True)
+    assert [bl.index for bl in bls2] == [bl.index for bl in bls]
+    assert [bl.num_points() for bl in bls2] == [bl.num_points() for bl in bls]
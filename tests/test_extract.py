@@ -0,0 +1,137 @@
+import os
+import pytest
+
+import osmquadtree_rust_bindings as oqt
+from osmquadtree_rust_bindings import rust
+
+
+def some_node(prfx):
+    rf = rust.ReadFileBlocksParallel(prfx)
+    for i in range(rf.num_blocks() // 2, rf.num_blocks()):
+        bl = rf.primitive_block_at(i, None)
+        if bl.num_nodes() > 0:
+            return bl.node_at(0)
+    pytest.skip("no nodes found")
+
+
+def read_ids(fn):
+    ids = {"node": [], "way": [], "relation": []}
+    rf = rust.ReadFileBlocks(fn)
+    got = []
+    rf.read_all(got.extend, 0, False, 1)
+    for bl in got:
+        ids["node"] += [bl.node_at(i).id for i in range(bl.num_nodes())]
+        ids["way"] += [bl.way_at(i).id for i in range(bl.num_ways())]
+        ids["relation"] += [bl.relation_at(i).id for i in range(bl.num_relations())]
+    return ids
+
+
+def boxes(prfx):
+    n = some_node(prfx)
+    small = [n.lon - 20000, n.lat - 20000, n.lon + 20000, n.lat + 20000]
+    large = [n.lon - 100000, n.lat - 100000, n.lon + 100000, n.lat + 100000]
+    return small, large
+
+
+@pytest.mark.parametrize("strategy", ["simple", "complete_ways", "smart"])
+def test_extract_many(prfx, tmp_path, strategy):
+    small, large = boxes(prfx)
+    outs = [str(tmp_path / "small.pbf"), str(tmp_path / "large.pbf")]
+    counts = rust.extract_many(prfx, list(zip([small, large], outs)), strategy, numchan=2)
+    assert [c[0] for c in counts] == outs
+
+    got = [read_ids(o) for o in outs]
+    for (_, nn, nw, nr), ids in zip(counts, got):
+        assert (len(ids["node"]), len(ids["way"]), len(ids["relation"])) == (nn, nw, nr)
+        assert len(set(ids["node"])) == nn
+
+    #the larger box includes everything in the smaller
+    for t in ("node", "way", "relation"):
+        assert set(got[0][t]) <= set(got[1][t])
+    assert rust.verify_pbf(outs[1])["ok"]
+
+
+def test_extract_matches_single(prfx, tmp_path):
+    small, large = boxes(prfx)
+    out = str(tmp_path / "one.pbf")
+    both = [str(tmp_path / "a.pbf"), str(tmp_path / "b.pbf")]
+    rust.extract_many(prfx, [(small, out)], "complete_ways")
+    rust.extract_many(prfx, [(large, both[0]), (small, both[1])], "complete_ways")
+    assert read_ids(out) == read_ids(both[1])
+
+
+def reference(prfx, bbox, strategy):
+    #the expected extract, from a read of the whole file
+    nodes, ways, relations = {}, {}, {}
+    for bl in rust.ReadFileBlocksParallel(prfx).iter_blocks(None, 2):
+        for i in range(bl.num_nodes()):
+            n = bl.node_at(i)
+            nodes[n.id] = (n.lon, n.lat)
+        for i in range(bl.num_ways()):
+            w = bl.way_at(i)
+            ways[w.id] = w.refs
+        for i in range(bl.num_relations()):
+            r = bl.relation_at(i)
+            relations[r.id] = (r.members, dict(r.tags))
+
+    inside = {n for n, (lon, lat) in nodes.items() if bbox[0] <= lon <= bbox[2] and bbox[1] <= lat <= bbox[3]}
+    sel_ways = {w for w, refs in ways.items() if inside & set(refs)}
+    sel_relations = set()
+    while True:
+        more = {r for r, (mems, _) in relations.items() if r not in sel_relations and any(
+            (t == "node" and m in inside) or (t == "way" and m in sel_ways) or (t == "relation" and m in sel_relations)
+            for t, m, _ in mems)}
+        if not more:
+            break
+        sel_relations |= more
+    if strategy == "smart":
+        sel_ways |= {m for r in sel_relations for t, m, _ in relations[r][0]
+            if t == "way" and relations[r][1].get("type") in ("multipolygon", "boundary")}
+    sel_nodes = set(inside)
+    if strategy != "simple":
+        sel_nodes |= {n for w in sel_ways for n in ways[w]}
+    return {"node": sel_nodes, "way": sel_ways, "relation": sel_relations}
+
+
+@pytest.mark.parametrize("strategy", ["simple", "complete_ways", "smart"])
+def test_extract_reference(sorted_dataset, tmp_path, strategy):
+    prfx = sorted_dataset.prfx
+    #the middle of the trunk way (3000), which crosses the whole grid: its
+    #nodes are in smaller quadtrees, so in later blocks than the way itself,
+    #and most are outside the box. the second box holds one corner of the
+    #multipolygon 5000
+    middle = [-1025000, 513975000, 1025000, 516025000]
+    corner = [-25000, 516975000, 25000, 517025000]
+    outs = [str(tmp_path / "middle.pbf"), str(tmp_path / "corner.pbf")]
+    rust.extract_many(prfx, list(zip([middle, corner], outs)), strategy, numchan=2)
+
+    for bbox, out in zip([middle, corner], outs):
+        expected = reference(prfx, bbox, strategy)
+        got = {t: set(v) for t, v in read_ids(out).items()}
+        assert got == expected
+    assert 3000 in read_ids(outs[0])["way"]
+    if strategy == "smart":
+        assert {4000, 4001} <= set(read_ids(outs[1])["way"])
+
+
+def test_extract_sorted(prfx, tmp_path):
+    small, _ = boxes(prfx)
+    out = str(tmp_path / "sorted.pbf")
+    poly = rust.Poly([small[0] * 1e-7, small[2] * 1e-7, small[0] * 1e-7], [small[1] * 1e-7, small[1] * 1e-7, small[3] * 1e-7], "tri")
+    (_, nn, nw, nr), = oqt.extract_many(prfx, [(poly, out)], sort=True, compression_type=("ZstdLevel", 3), numchan=2)
+    ids = read_ids(out)
+    assert ids["node"] == sorted(ids["node"]) and len(ids["node"]) == nn
+    assert ids["way"] == sorted(ids["way"]) and len(ids["way"]) == nw
+    assert not os.path.exists(str(tmp_path / "sorted-unsorted.pbf"))
+
+
+def test_bad_args(tmp_path):
+    out = str(tmp_path / "x.pbf")
+    with pytest.raises(oqt.InvalidInputError):
+        rust.extract_many("missing", [([0, 0, 10, 10], out)], "bogus")
+    with pytest.raises(oqt.InvalidInputError):
+        rust.extract_many("missing", [])
+    with pytest.raises(oqt.InvalidInputError):
+        rust.extract_many("missing", [([0, 0, 10, 10], out), ([0, 0, 20, 20], out)])
+    with pytest.raises(oqt.FilterError):
+        rust.extract_many("missing", [([0, 0, 10], out)])
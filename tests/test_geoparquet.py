@@ -0,0 +1,34 @@
+import json
+import os
+import pytest
+
+import osmquadtree_rust_bindings as oqt
+from osmquadtree_rust_bindings import rust
+
+
+def test_bad_args(tmp_path):
+    with pytest.raises(oqt.InvalidInputError):
+        rust.write_geoparquet(str(tmp_path), str(tmp_path / "out-"), row_group_size=0)
+    with pytest.raises(oqt.StyleError):
+        rust.write_geoparquet(str(tmp_path), str(tmp_path / "out-"), style_in="{not json")
+
+
+def test_write_geoparquet(prfx, tmp_path):
+    pq = pytest.importorskip("pyarrow.parquet")
+
+    outprfx = str(tmp_path / "out-")
+    res = rust.write_geoparquet(prfx, outprfx, row_group_size=1000)
+    assert [os.path.basename(f) for f, _ in res] == ["out-point.parquet", "out-line.parquet", "out-polygon.parquet"]
+
+    for fn, count in res:
+        meta = json.loads(pq.read_metadata(fn).metadata[b"geo"])
+        assert meta["primary_column"] == "geometry"
+        assert meta["columns"]["geometry"]["encoding"] == "WKB"
+
+        tab = pq.read_table(fn)
+        assert tab.num_rows == count
+        assert tab.schema.field("osm_id").type == "int64"
+        assert tab.schema.field("geometry").type == "binary"
+        #tag columns take their type from the style
+        assert tab.schema.field("highway").type == "string"
+        assert tab.schema.field("min_admin_level").type == "int64"
@@ -0,0 +1,167 @@
+import struct
+import zlib
+
+import pytest
+
+import osmquadtree_rust_bindings as oqt
+from osmquadtree_rust_bindings import rust
+
+
+def varint(v):
+    res = b""
+    while v >= 0x80:
+        res += bytes([(v & 0x7f) | 0x80])
+        v >>= 7
+    return res + bytes([v])
+
+
+def zigzag(v):
+    return (v << 1) ^ (v >> 63)
+
+
+def field(i, v):
+    if isinstance(v, bytes):
+        return varint((i << 3) | 2) + varint(len(v)) + v
+    return varint(i << 3) + varint(v)
+
+
+def packed(vals, delta=False, signed=False):
+    res, prev = b"", 0
+    for v in vals:
+        x = v - prev if delta else v
+        prev = v
+        res += varint(zigzag(x) if signed else x)
+    return res
+
+
+STRINGS = [b"", b"highway", b"residential", b"amenity", b"cafe", b"user"]
+
+#(id, version, timestamp, visible, lon, lat, tags)
+NODES = [
+    (1, 1, 1000, True, 1, 1, []),
+    (1, 2, 2000, True, 2, 2, [(3, 4)]),
+    (1, 3, 3000, False, 0, 0, []),
+    (2, 1, 1000, True, 50, 50, []),
+    (3, 1, 1500, True, 3, 3, []),
+]
+
+#(id, version, timestamp, visible, refs, tags)
+WAYS = [
+    (10, 1, 1000, True, [1, 2], [(1, 2)]),
+    (10, 2, 2500, True, [1, 2, 3], [(1, 2)]),
+]
+
+
+def dense_nodes():
+    ids = [n[0] for n in NODES]
+    info = field(1, packed([n[1] for n in NODES]))
+    info += field(2, packed([n[2] for n in NODES], True, True))
+    info += field(3, packed([1] * len(NODES), True, True))
+    info += field(4, packed([7] * len(NODES), True, True))
+    info += field(5, packed([5] * len(NODES), True, True))
+    info += field(6, packed([int(n[3]) for n in NODES]))
+    kv = []
+    for n in NODES:
+        for k, v in n[6]:
+            kv += [k, v]
+        kv.append(0)
+    res = field(1, packed(ids, True, True)) + field(5, info)
+    res += field(8, packed([n[5] * 10000000 for n in NODES], True, True))
+    res += field(9, packed([n[4] * 10000000 for n in NODES], True, True))
+    return res + field(10, packed(kv))
+
+
+def way(w):
+    i, version, ts, visible, refs, tags = w
+    info = field(1, version) + field(2, ts) + field(3, 1) + field(4, 7) + field(5, 5) + field(6, int(visible))
+    res = field(1, i) + field(2, packed([k for k, _ in tags])) + field(3, packed([v for _, v in tags]))
+    return res + field(4, info) + field(8, packed(refs, True, True))
+
+
+def block(group):
+    #default granularity of 100 nanodegrees, the same units as osmquadtree
+    strs = b"".join(field(1, s) for s in STRINGS)
+    return field(1, strs) + field(2, group)
+
+
+def file_block(block_type, data):
+    blob = field(2, len(data)) + field(3, zlib.compress(data))
+    header = field(1, block_type) + field(3, len(blob))
+    return struct.pack(">I", len(header)) + header + blob
+
+
+def write_history(fn, ways=WAYS, split_ways=False):
+    data = file_block(b"OSMHeader", field(4, b"HistoricalInformation"))
+    data += file_block(b"OSMData", block(field(2, dense_nodes())))
+    groups = [[w] for w in ways] if split_ways else [ways]
+    for g in groups:
+        data += file_block(b"OSMData", block(b"".join(field(3, way(w)) for w in g)))
+    open(fn, "wb").write(data)
+
+
+@pytest.fixture
+def history(tmp_path):
+    fn = str(tmp_path / "history.osh.pbf")
+    write_history(fn)
+    return rust.HistoryFile(fn)
+
+
+def test_read_blocks(history):
+    bls = list(history)
+    assert [(bl.num_nodes(), bl.num_ways()) for bl in bls] == [(5, 0), (0, 2)]
+    nodes = [bls[0].node_at(i) for i in range(5)]
+    assert [(n.id, n.version, n.changetype) for n in nodes[:3]] == [(1, 1, "normal"), (1, 2, "normal"), (1, 3, "delete")]
+    assert nodes[1].tags == [("amenity", "cafe")]
+    assert (nodes[3].lon, nodes[3].lat) == (500000000, 500000000)
+    assert nodes[0].user == "user"
+
+
+def test_versions(history):
+    assert [n.version for n in history.versions("node", 1)] == [1, 2, 3]
+    assert [w.refs for w in history.versions("way", 10)] == [[1, 2], [1, 2, 3]]
+    assert history.versions("relation", 1) == []
+
+
+def test_versions_across_blocks(tmp_path):
+    #each version of way 10 in a block of its own
+    fn = str(tmp_path / "split.osh.pbf")
+    write_history(fn, split_ways=True)
+    hf = rust.HistoryFile(fn)
+    assert [w.version for w in hf.versions("way", 10)] == [1, 2]
+    assert hf.element_at("way", 10, 2000).version == 1
+    assert [n.version for n in hf.versions("node", 3)] == [1]
+    assert hf.versions("way", 11) == []
+
+
+def test_element_at(history):
+    assert history.element_at("node", 1, 500) is None
+    assert history.element_at("node", 1, 1500).version == 1
+    assert history.element_at("node", 1, 2999).version == 2
+    assert history.element_at("node", 1, 3000) is None
+    assert history.element_at("way", 10, "1970-01-01T00:45:00").refs == [1, 2, 3]
+
+
+def test_snapshot(history):
+    snap = history.snapshot(2000)
+    bls = list(snap)
+    assert list(snap) == []
+    nodes = [bl.node_at(i) for bl in bls for i in range(bl.num_nodes())]
+    ways = [bl.way_at(i) for bl in bls for i in range(bl.num_ways())]
+    assert [(n.id, n.version) for n in nodes] == [(1, 2), (2, 1), (3, 1)]
+    assert [(w.id, w.version) for w in ways] == [(10, 1)]
+    #one block for each element type
+    assert [(bl.num_nodes(), bl.num_ways()) for bl in bls] == [(3, 0), (0, 1)]
+
+    bls = list(history.snapshot(3000, (0, 0, 400000000, 400000000)))
+    nodes = [bl.node_at(i) for bl in bls for i in range(bl.num_nodes())]
+    ways = [bl.way_at(i) for bl in bls for i in range(bl.num_ways())]
+    #node 1 is deleted; node 2 is outside the box but used by way 10
+    assert [n.id for n in nodes] == [2, 3]
+    assert [(w.id, w.version) for w in ways] == [(10, 2)]
+
+
+def test_unsorted(tmp_path):
+    fn = str(tmp_path / "unsorted.osh.pbf")
+    write_history(fn, WAYS[::-1])
+    with pytest.raises(oqt.InvalidInputError, match="not sorted"):
+        rust.HistoryFile(fn).versions("way", 10)
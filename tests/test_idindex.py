@@ -0,0 +1,74 @@
+import pytest
+
+import osmquadtree_rust_bindings as oqt
+from osmquadtree_rust_bindings import rust
+
+
+def test_bad_args(tmp_path):
+    with pytest.raises(oqt.MissingFileError):
+        rust.build_id_index("/nonexistent/osmquadtree/missing-qts.pbf", outfn=str(tmp_path / "idx"))
+
+    with pytest.raises(oqt.InvalidInputError, match="one of prfx or outfn"):
+        rust.build_id_index(__file__)
+
+
+def test_bad_index(prfx, tmp_path):
+    fn = str(tmp_path / "idx")
+    open(fn, "wb").write(b"not an index")
+    ds = rust.Dataset(prfx)
+    with pytest.raises(oqt.InvalidInputError, match="is not an id index"):
+        ds.load_id_index(fn)
+
+    #a valid header, with more entries than the file holds
+    open(fn, "wb").write(b"OQTIDX\x01\n" + (1000).to_bytes(8, "little") + bytes(16))
+    with pytest.raises(oqt.InvalidInputError, match="truncated"):
+        ds.load_id_index(fn)
+
+
+@pytest.fixture
+def dataset(prfx, qts, tmp_path):
+
+    ds = rust.Dataset(prfx)
+    with pytest.raises(oqt.MissingFileError):
+        ds.load_id_index(str(tmp_path / "missing"))
+
+    idxfn = str(tmp_path / "idindex.bin")
+    assert rust.build_id_index(qts, outfn=idxfn) > 0
+    ds.load_id_index(idxfn)
+    assert ds.has_id_index
+    return prfx, ds
+
+
+def first_block_with(prfx, num):
+    rd = rust.ReadFileBlocksParallel(prfx)
+    for i in range(rd.num_blocks()):
+        bl = rd.primitive_block_at(i, None)
+        if num(bl) > 0:
+            return bl
+    pytest.skip("no elements")
+
+
+def test_get_way(dataset):
+    prfx, ds = dataset
+    w = first_block_with(prfx, lambda bl: bl.num_ways()).way_at(0)
+
+    assert ds.get_way(w.id, full=False).refs == w.refs
+    bl = ds.get_way(w.id)
+    assert bl.num_ways() == 1
+    assert {bl.node_at(i).id for i in range(bl.num_nodes())} <= set(w.refs)
+    assert ds.get_way(-1) is None
+
+
+def test_get_relation(dataset):
+    prfx, ds = dataset
+    r = first_block_with(prfx, lambda bl: bl.num_relations()).relation_at(0)
+
+    bl = ds.get_relation(r.id)
+    rels = [bl.relation_at(i) for i in range(bl.num_relations())]
+    assert r.id in {r2.id for r2 in rels}
+    assert ds.get_relation(r.id, full=False).members == r.members
+
+    refs = {ref for r2 in rels for t, ref, _ in r2.members if t == "node"}
+    for i in range(bl.num_ways()):
+        refs.update(bl.way_at(i).refs)
+    assert {bl.node_at(i).id for i in range(bl.num_nodes())} <= refs
@@ -0,0 +1,77 @@
+import pytest
+
+import osmquadtree_rust_bindings as oqt
+from osmquadtree_rust_bindings import rust
+
+NODES = {
+    1: (0.0, 0.0), 2: (1.0, 0.0), 3: (1.0, 1.0), 4: (0.0, 1.0),
+    5: (0.2, 0.2), 6: (0.2, 0.8), 7: (0.8, 0.8), 8: (0.8, 0.2),
+    9: (0.4, 0.4), 10: (0.6, 0.4), 11: (0.6, 0.6), 12: (1.5, 0.5),
+}
+
+
+def read_elements(tmp_path, ways, members):
+    xml = ['<?xml version="1.0" encoding="UTF-8"?>', '<osm version="0.6">']
+    for i, (lon, lat) in NODES.items():
+        xml.append('<node id="%d" version="1" lat="%f" lon="%f"/>' % (i, lat, lon))
+    for i, refs in ways.items():
+        xml.append('<way id="%d" version="1">%s</way>' % (i, "".join('<nd ref="%d"/>' % r for r in refs)))
+    xml.append('<relation id="100" version="1">')
+    xml.extend('<member type="way" ref="%d" role="%s"/>' % (r, role) for r, role in members)
+    xml.append('<tag k="type" v="multipolygon"/><tag k="landuse" v="grass"/></relation>')
+    xml.append("</osm>")
+
+    fn = str(tmp_path / "in.osm")
+    open(fn, "w").write("\n".join(xml))
+    bls = rust.XmlReader(fn).read_all()
+    nodes = [bl.node_at(i) for bl in bls for i in range(bl.num_nodes())]
+    ways = [bl.way_at(i) for bl in bls for i in range(bl.num_ways())]
+    rel = [bl.relation_at(i) for bl in bls for i in range(bl.num_relations())][0]
+    return rel, ways, nodes
+
+
+def test_assemble(tmp_path):
+    #outer ring split over two ways, a hole, and an island in the hole
+    ways = {20: [1, 2, 3], 21: [1, 4, 3], 22: [5, 6, 7, 8, 5], 23: [9, 10, 11, 9]}
+    members = [(20, "outer"), (21, "outer"), (22, "inner"), (23, "outer")]
+    rel, ways, nodes = read_elements(tmp_path, ways, members)
+
+    poly = rust.assemble_multipolygon(rel, ways, nodes)
+    assert poly.id == 100
+    assert poly.tags == [("landuse", "grass")]
+    assert poly.num_parts() == 2
+
+    big, island = sorted((poly.part_at(i) for i in range(2)), key=lambda p: -p.area)
+    assert big.num_interiors() == 1 and island.num_interiors() == 0
+    assert sorted(big.exterior.refs) == sorted([1, 2, 3, 4, 1])
+    assert [p[0] for p in big.exterior.parts] == [20, 21]
+    assert sorted(big.interior_at(0).refs) == sorted([5, 6, 7, 8, 5])
+
+
+@pytest.mark.parametrize("ways,message", [
+    ({20: [1, 2, 3], 21: [3, 4]}, "unclosed ring"),
+    ({20: [1, 3, 2, 4, 1], 21: [5, 6, 7, 8, 5]}, "self-intersecting"),
+    ({20: [1, 2, 3, 4, 1], 21: [5, 6, 7, 99, 5]}, "missing node 99"),
+    ({20: [1, 2, 3, 4, 1], 21: [9, 10, 12, 9]}, r"rings of ways \[20\] and \[21\] cross"),
+])
+def test_assemble_errors(tmp_path, ways, message):
+    rel, ww, nodes = read_elements(tmp_path, ways, [(w, "outer") for w in ways])
+    with pytest.raises(oqt.GeometryError, match=message) as e:
+        rust.assemble_multipolygon(rel, ww, nodes)
+    assert e.value.element_id == 100
+
+
+def test_missing_way(tmp_path):
+    rel, ways, nodes = read_elements(tmp_path, {20: [1, 2, 3, 4, 1]}, [(20, "outer"), (21, "inner")])
+    with pytest.raises(oqt.GeometryError, match="missing member way 21"):
+        rust.assemble_multipolygon(rel, ways, nodes)
+
+
+def test_inner_sharing_all_nodes(tmp_path):
+    #every node of the inner ring is on the outer ring
+    ways = {20: [1, 2, 3, 4, 1], 21: [1, 2, 3, 1]}
+    rel, ways, nodes = read_elements(tmp_path, ways, [(20, "outer"), (21, "inner")])
+
+    poly = rust.assemble_multipolygon(rel, ways, nodes)
+    assert poly.num_parts() == 1
+    assert poly.part_at(0).num_interiors() == 1
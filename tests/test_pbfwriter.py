@@ -0,0 +1,62 @@
+import os
+import pytest
+
+import osmquadtree_rust_bindings as oqt
+from osmquadtree_rust_bindings import rust
+
+
+def test_bad_args(tmp_path):
+    with pytest.raises(oqt.FilterError):
+        rust.PbfWriter(str(tmp_path / "out.pbf"), (10, 10, 0, 0))
+    with pytest.raises(ValueError):
+        rust.PbfWriter(str(tmp_path / "out.pbf"), None, None, None, None, None, None, ("Unknown", 0))
+
+
+def test_finish_twice(tmp_path):
+    w = rust.PbfWriter(str(tmp_path / "out.pbf"))
+    w.finish()
+    assert w.finished
+    with pytest.raises(ValueError):
+        w.finish()
+
+    hb = rust.ReadFileBlocks(str(tmp_path / "out.pbf")).get_header()
+    assert hb.index == []
+
+
+def test_exception_removes_output(tmp_path):
+    with pytest.raises(RuntimeError):
+        with rust.PbfWriter(str(tmp_path / "out.pbf")):
+            raise RuntimeError()
+    assert os.listdir(tmp_path) == []
+
+
+def test_round_trip(source_pbf, tmp_path):
+    rf = rust.ReadFileBlocks(source_pbf)
+    rf.get_header()
+    blocks = []
+    while len(blocks) < 10:
+        bl = rf.next_block(len(blocks), False, False)
+        if not isinstance(bl, rust.PrimitiveBlock):
+            break
+        blocks.append(bl)
+
+    outfn = str(tmp_path / "out.pbf")
+    with rust.PbfWriter(outfn, None, "test", None, 1600000000, 12, "https://example.com/replication",
+            ("ZlibLevel", 6)) as w:
+        w.write_blocks(blocks)
+
+    hb = rust.ReadFileBlocks(outfn).get_header()
+    assert hb.writingprogram == "test"
+    assert hb.osmosis_replication_timestamp == 1600000000
+    assert hb.osmosis_replication_sequence_number == 12
+    assert len(hb.index) == len(blocks)
+
+    rf2 = rust.ReadFileBlocks(outfn)
+    for (q, _, loc, _), bl in zip(hb.index, blocks):
+        bl2 = rf2.read_block_at(0, loc, False, False)
+        assert q == bl.quadtree
+        assert bl2.num_nodes() == bl.num_nodes()
+        assert bl2.num_ways() == bl.num_ways()
+        assert bl2.num_relations() == bl.num_relations()
+
+    assert rust.ReadFileBlocksParallel(outfn).num_blocks() == len(blocks)
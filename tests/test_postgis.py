@@ -0,0 +1,59 @@
+import os
+import pytest
+
+import osmquadtree_rust_bindings as oqt
+from osmquadtree_rust_bindings import rust
+
+
+def test_bad_args(tmp_path):
+    with pytest.raises(oqt.InvalidInputError):
+        rust.write_postgis(str(tmp_path), str(tmp_path / "out-"), format="csv")
+    with pytest.raises(oqt.StyleError):
+        rust.postgis_create_tables(style_in="{not json")
+
+
+def test_create_tables():
+    sql = rust.postgis_create_tables(table_prefix="test_", transform=False)
+    for table in ["point", "line", "polygon"]:
+        assert 'CREATE TABLE "test_%s"' % table in sql
+    assert "geometry(Point, 4326)" in sql
+    assert '"length" double precision' in sql
+    assert '"min_admin_level" bigint' in sql
+
+
+def test_binary_header(prfx, tmp_path):
+    res = rust.write_postgis(prfx, str(tmp_path / "out-"), format="binary")
+    assert [os.path.basename(f) for f, _ in res] == ["out-point.copy", "out-line.copy", "out-polygon.copy"]
+    for fn, _ in res:
+        data = open(fn, "rb").read()
+        assert data.startswith(b"PGCOPY\n\xff\r\n\x00")
+        assert data.endswith(b"\xff\xff")
+
+
+def test_text_rows(prfx, tmp_path):
+    res = rust.write_postgis(prfx, str(tmp_path / "out-"), format="text")
+    for fn, count in res:
+        assert sum(1 for _ in open(fn)) == count
+
+
+def test_load_postgis(prfx, tmp_path):
+    #needs a database with the postgis extension, eg OSMQUADTREE_TEST_PG="dbname=test"
+    psycopg = pytest.importorskip("psycopg")
+    dsn = os.environ.get("OSMQUADTREE_TEST_PG")
+    if dsn is None:
+        pytest.skip("OSMQUADTREE_TEST_PG not set")
+
+    table_prefix = "oqt_test_"
+    res = rust.write_postgis(prfx, str(tmp_path / "out-"), format="binary", table_prefix=table_prefix)
+    with psycopg.connect(dsn) as conn:
+        cur = conn.cursor()
+        for table in ["point", "line", "polygon"]:
+            cur.execute('DROP TABLE IF EXISTS "%s%s"' % (table_prefix, table))
+        cur.execute(rust.postgis_create_tables(table_prefix=table_prefix))
+
+        for (fn, count), table in zip(res, ["point", "line", "polygon"]):
+            with cur.copy('COPY "%s%s" FROM STDIN WITH (FORMAT binary)' % (table_prefix, table)) as copy:
+                copy.write(open(fn, "rb").read())
+            cur.execute('SELECT count(*), count(*) FILTER (WHERE ST_IsValid("geometry") IS NOT NULL) FROM "%s%s"' % (table_prefix, table))
+            assert cur.fetchone() == (count, count)
+        conn.rollback()
@@ -0,0 +1,53 @@
+import pytest
+
+import osmquadtree_rust_bindings as oqt
+from osmquadtree_rust_bindings import rust
+
+
+def test_bad_args(tmp_path):
+    with pytest.raises(oqt.InvalidInputError):
+        rust.process_geometry_callback(str(tmp_path), lambda bls: None, callback_num_blocks=0)
+    with pytest.raises(oqt.StyleError):
+        rust.iter_geometry(str(tmp_path), style_in="{not json")
+
+
+def test_callback_matches_process_geometry(prfx):
+    expected = sorted(bl.index for bl in rust.process_geometry(prfx))
+
+    groups = []
+    count = rust.process_geometry_callback(prfx, lambda bls: groups.append([bl.index for bl in bls]), callback_num_blocks=3)
+    assert count == len(expected)
+    assert all(1 <= len(g) <= 3 for g in groups)
+    assert sorted(i for g in groups for i in g) == expected
+
+    assert sorted(bl.index for bl in rust.iter_geometry(prfx)) == expected
+
+
+def test_callback_error(prfx):
+    def callback(bls):
+        raise ValueError("stop")
+
+    with pytest.raises(ValueError):
+        rust.process_geometry_callback(prfx, callback)
+
+
+def test_iter_close(prfx):
+    it = rust.iter_geometry(prfx, numchan=1)
+    next(it)
+    it.close()
+    with pytest.raises(StopIteration):
+        next(it)
+
+
+def test_iter_close_joins(prfx):
+    #closing (or dropping) the iterator waits for the background thread,
+    #including while it is still selecting the elements for a tag filter
+    expected = len(list(rust.iter_geometry(prfx, tag_filter="nwr/amenity")))
+    for tag_filter in [None, "nwr/amenity"]:
+        it = rust.iter_geometry(prfx, numchan=1, tag_filter=tag_filter)
+        it.close()
+        with pytest.raises(StopIteration):
+            next(it)
+        it = rust.iter_geometry(prfx, numchan=1, tag_filter=tag_filter)
+        del it
+    assert len(list(rust.iter_geometry(prfx, tag_filter="nwr/amenity"))) == expected
@@ -0,0 +1,160 @@
+import gzip
+import io
+import tarfile
+import time
+
+import pytest
+
+import osmquadtree_rust_bindings as oqt
+from osmquadtree_rust_bindings import rust
+
+
+@pytest.fixture
+def pbf_data(pbf):
+    return open(pbf, "rb").read()
+
+
+def check_blocks(rf):
+    assert rf.get_header().writingprogram == "test"
+    bls = [rf.next_block(i, False, False) for i in range(3)]
+    assert [bls[0].node_at(i).id for i in range(bls[0].num_nodes())] == [1, 2]
+    assert bls[2].way_at(0).refs == [1, 2]
+
+    bl = rf.read_block_at(2, bls[2].location, False, False)
+    assert bl.way_at(0).id == 10
+
+
+@pytest.mark.parametrize("wrap", [bytes, bytearray, memoryview, io.BytesIO])
+def test_in_memory(pbf_data, wrap):
+    check_blocks(rust.ReadFileBlocks(wrap(pbf_data)))
+
+
+def test_gzip_stream(pbf_data):
+    check_blocks(rust.ReadFileBlocks(gzip.GzipFile(fileobj=io.BytesIO(gzip.compress(pbf_data)))))
+
+
+def test_tar_member(pbf_data, tmp_path):
+    tfn = str(tmp_path / "in.tar")
+    with tarfile.open(tfn, "w") as tf:
+        ti = tarfile.TarInfo("data/out.pbf")
+        ti.size = len(pbf_data)
+        tf.addfile(ti, io.BytesIO(pbf_data))
+
+    with tarfile.open(tfn) as tf:
+        check_blocks(rust.ReadFileBlocks(tf.extractfile("data/out.pbf")))
+
+
+def test_read_all(pbf_data):
+    got = []
+    rf = rust.ReadFileBlocks(io.BytesIO(pbf_data))
+    rf.read_all(got.extend, 2, False, 1)
+    assert sorted(bl.num_nodes() + bl.num_ways() + bl.num_relations() for bl in got) == [2, 2, 2]
+
+
+def test_bad_source(pbf_data):
+    with pytest.raises(TypeError):
+        rust.ReadFileBlocks(12)
+
+    rf = rust.ReadFileBlocks(pbf_data)
+    with pytest.raises(oqt.InvalidInputError):
+        rf.load_block_index()
+    with pytest.raises(oqt.InvalidInputError):
+        rf.block_for_id("node", 1)
+
+    class Failing(io.BytesIO):
+        def read(self, n=-1):
+            raise RuntimeError("broken")
+
+    with pytest.raises(Exception, match="broken"):
+        rust.ReadFileBlocks(Failing(pbf_data)).get_header()
+
+
+@pytest.mark.parametrize("numchan", [0, 1, 4])
+def test_iter_blocks_in_order(prfx, numchan):
+    rd = rust.ReadFileBlocksParallel(prfx)
+    bls = list(rd.iter_blocks(None, numchan))
+    assert [bl.index for bl in bls] == list(range(rd.num_blocks()))
+    assert [bl.quadtree.integer for bl in bls] == [rd.index_at(i)[0].integer for i in range(rd.num_blocks())]
+
+
+def test_iter_blocks_bounded(prfx):
+    rd = rust.ReadFileBlocksParallel(prfx)
+    it = rd.iter_blocks(None, 1)
+    next(it)
+    #give the reading thread time to get as far ahead as it can
+    time.sleep(0.5)
+    next(it)
+    assert int(repr(it).split("[")[1].split()[0]) <= 4
+    it.close()
+
+
+def test_iter_blocks_stop_early(prfx):
+    rd = rust.ReadFileBlocksParallel(prfx)
+    it = rd.iter_blocks(None, 2)
+    next(it)
+    it.close()
+    with pytest.raises(StopIteration):
+        next(it)
+
+    for bl in rd:
+        break
+    it = iter(rd)
+    next(it)
+    del it
+    assert len(list(rd)) == rd.num_blocks()
+
+
+@pytest.mark.parametrize("wrap", [bytes, memoryview, io.BytesIO])
+def test_parallel_in_memory(sorted_dataset, wrap):
+    data = open(sorted_dataset.prfx, "rb").read()
+    expected = rust.ReadFileBlocksParallel(sorted_dataset.prfx)
+    rd = rust.ReadFileBlocksParallel(wrap(data))
+    assert rd.num_blocks() == expected.num_blocks()
+    assert [rd.index_at(i) for i in range(rd.num_blocks())] == [expected.index_at(i) for i in range(rd.num_blocks())]
+
+    def summary(bls):
+        return [(bl.quadtree.integer, bl.num_nodes(), bl.num_ways(), bl.num_relations()) for bl in bls]
+    assert summary(rd.iter_blocks(None, 2)) == summary(expected.iter_blocks(None, 2))
+
+    got = []
+    assert rd.read_all(got.extend, None, 2) == expected.num_blocks()
+    assert sorted(summary(got)) == sorted(summary(expected))
+
+    #a bbox filter selects the same blocks from the header index
+    bbox = [-1000000, 510000000, -500000, 512000000]
+    assert rust.ReadFileBlocksParallel(wrap(data), bbox).num_blocks() == rust.ReadFileBlocksParallel(sorted_dataset.prfx, bbox).num_blocks()
+
+    with pytest.raises(ValueError, match="needs a path"):
+        rd.write_merged("/nonexistent/osmquadtree/out.pbf", None, ("ZlibLevel", 6), 1)
+
+
+def test_parallel_indexed_sources(sorted_dataset, tmp_path):
+    #PbfWriter stores the header index: the same blocks should be found
+    #from the path, from bytes and from a file object
+    outfn = str(tmp_path / "indexed.pbf")
+    with rust.PbfWriter(outfn) as w:
+        w.write_blocks(list(rust.ReadFileBlocksParallel(sorted_dataset.prfx).iter_blocks(None, 2)))
+
+    expected = rust.ReadFileBlocksParallel(outfn)
+    num_blocks = expected.num_blocks()
+    assert num_blocks > 1
+
+    def summary(bls):
+        return [(bl.quadtree.integer, [bl.node_at(i).id for i in range(bl.num_nodes())], bl.num_ways(), bl.num_relations()) for bl in bls]
+    expected_blocks = summary(expected.iter_blocks(None, 2))
+
+    with open(outfn, "rb") as fobj:
+        for src in [open(outfn, "rb").read(), fobj]:
+            rd = rust.ReadFileBlocksParallel(src)
+            assert [rd.index_at(i) for i in range(num_blocks)] == [expected.index_at(i) for i in range(num_blocks)]
+            assert summary(rd.iter_blocks(None, 2)) == expected_blocks
+
+
+def test_parallel_shared_file_object(sorted_dataset):
+    #iter_blocks reads the file object on another thread while
+    #primitive_block_at reads it here
+    with open(sorted_dataset.prfx, "rb") as fobj:
+        rd = rust.ReadFileBlocksParallel(fobj)
+        for i, bl in enumerate(rd.iter_blocks(None, 2)):
+            other = rd.primitive_block_at(i, None)
+            assert (other.quadtree.integer, other.num_nodes(), other.num_ways()) == (bl.quadtree.integer, bl.num_nodes(), bl.num_ways())
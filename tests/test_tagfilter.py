@@ -0,0 +1,123 @@
+import pytest
+
+import conftest
+import osmquadtree_rust_bindings as oqt
+from osmquadtree_rust_bindings import rust
+
+
+@pytest.mark.parametrize("expr", ["x/highway", "w/", "w/highway=", "w/highway!=*", []])
+def test_bad_expressions(expr):
+    with pytest.raises(oqt.FilterError):
+        rust.TagFilter(expr)
+
+
+def test_matches():
+    f = rust.TagFilter(["w/highway", "nwr/amenity=pub,bar", "r/type=multipolygon"])
+    assert repr(f) == "TagFilter(w/highway nwr/amenity=pub,bar r/type=multipolygon)"
+    assert f.matches("way", [("highway", "primary")])
+    assert not f.matches("node", [("highway", "bus_stop")])
+    assert f.matches("n", [("amenity", "bar"), ("name", "x")])
+    assert not f.matches("node", [("amenity", "cafe")])
+    assert f.matches("relation", [("type", "multipolygon")])
+    assert not f.matches("way", [])
+
+    f = rust.TagFilter("w/highway!=motorway,trunk", add_referenced=False)
+    assert not f.add_referenced
+    assert f.matches("way", [("highway", "residential")])
+    assert not f.matches("way", [("highway", "trunk")])
+    assert not f.matches("way", [("name", "x")])
+
+    with pytest.raises(ValueError):
+        f.matches("changeset", [])
+
+
+def test_value_with_slash():
+    #only a '/' before the '=' gives the element types
+    f = rust.TagFilter("name=A/B")
+    assert f.matches("way", [("name", "A/B")])
+    assert not f.matches("way", [("name", "A")])
+    f = rust.TagFilter("w/opening_hours=24/7")
+    assert repr(f) == "TagFilter(w/opening_hours=24/7)"
+    assert f.matches("way", [("opening_hours", "24/7")])
+    assert not f.matches("node", [("opening_hours", "24/7")])
+
+
+def test_read_complete(prfx):
+    rd = rust.ReadFileBlocksParallel(prfx)
+    node_ids, way_refs, highways = set(), set(), 0
+    for bl in rd.iter_blocks("w/highway"):
+        for i in range(bl.num_nodes()):
+            node_ids.add(bl.node_at(i).id)
+        for i in range(bl.num_ways()):
+            w = bl.way_at(i)
+            assert any(k == "highway" for k, _ in w.tags)
+            way_refs.update(w.refs)
+            highways += 1
+        assert bl.num_relations() == 0
+    assert highways > 0
+    assert way_refs <= node_ids
+
+
+def test_prep_tag_filter(prfx, tmp_path):
+    rd = rust.ReadFileBlocksParallel(prfx)
+    ids = rd.prep_tag_filter(rust.TagFilter("r/type=multipolygon"))
+    outfn = str(tmp_path / "out.pbf")
+    rd.write_merged(outfn, ids, ("zlib", 6), 4)
+
+    rels = [bl.relation_at(i) for bl in rust.ReadFileBlocksParallel(outfn) for i in range(bl.num_relations())]
+    assert any(("type", "multipolygon") in r.tags for r in rels)
+
+
+def test_process_geometry(prfx):
+    for bl in rust.iter_geometry(prfx, tag_filter="nwr/amenity"):
+        for i in range(bl.num_points()):
+            assert any(k == "amenity" for k, _ in bl.point_at(i).tags)
+
+
+def test_process_geometry_selected_elements(sorted_dataset):
+    #the points returned are exactly those of the tagged nodes, and none of
+    #the ways' geometries
+    tagged = {conftest.grid_node_id(i, j) for i in range(41) for j in range(21) if (i * 7 + j * 3) % 17 == 0}
+    everything = rust.process_geometry(sorted_dataset.prfx)
+    expected = sorted(bl.point_at(i).id for bl in everything for i in range(bl.num_points()) if bl.point_at(i).id in tagged)
+    assert expected
+
+    got = rust.process_geometry(sorted_dataset.prfx, tag_filter="n/amenity")
+    assert sorted(bl.point_at(i).id for bl in got for i in range(bl.num_points())) == expected
+    assert sum(bl.num_linestrings() + bl.num_simple_polygons() for bl in got) == 0
+
+
+def test_primitive_block_at(prfx):
+    rd = rust.ReadFileBlocksParallel(prfx)
+    highways = 0
+    for i in range(rd.num_blocks()):
+        bl = rd.primitive_block_at(i, "w/highway")
+        refs = set()
+        for j in range(bl.num_ways()):
+            w = bl.way_at(j)
+            assert any(k == "highway" for k, _ in w.tags)
+            refs.update(w.refs)
+            highways += 1
+        # only nodes of the selected ways in this block are kept
+        assert {bl.node_at(j).id for j in range(bl.num_nodes())} <= refs
+        assert bl.num_relations() == 0
+
+    expected = sum(bl.num_ways() for bl in rd.iter_blocks("w/highway"))
+    assert highways == expected > 0
+
+
+def test_prep_tag_filter_nested(prfx, tmp_path):
+    rd = rust.ReadFileBlocksParallel(prfx)
+    ids = rd.prep_tag_filter("r/type=route_master")
+    outfn = str(tmp_path / "out.pbf")
+    rd.write_merged(outfn, ids, ("zlib", 6), 4)
+
+    blocks = list(rust.ReadFileBlocksParallel(outfn))
+    rels = {bl.relation_at(i).id for bl in blocks for i in range(bl.num_relations())}
+    ways = {bl.way_at(i).id for bl in blocks for i in range(bl.num_ways())}
+    nodes = {bl.node_at(i).id for bl in blocks for i in range(bl.num_nodes())}
+    # the route_master's member route, with the route's ways and their nodes
+    assert rels == {5001, 5002}
+    assert {2000, 3000} <= ways
+    refs = {n for bl in blocks for i in range(bl.num_ways()) for n in bl.way_at(i).refs}
+    assert refs <= nodes
@@ -0,0 +1,99 @@
+import gzip
+import json
+import sqlite3
+import pytest
+
+import osmquadtree_rust_bindings as oqt
+from osmquadtree_rust_bindings import rust
+
+
+def test_bad_args(tmp_path):
+    with pytest.raises(oqt.InvalidInputError):
+        rust.TileBuilder(minzoom=10, maxzoom=5)
+    with pytest.raises(oqt.InvalidInputError):
+        rust.TileBuilder(maxzoom=30)
+    with pytest.raises(oqt.InvalidInputError):
+        rust.TileBuilder(extent=0)
+    with pytest.raises(oqt.StyleError):
+        rust.TileBuilder(style_in="{not json")
+
+
+def test_empty():
+    tb = rust.TileBuilder(0, 4)
+    assert tb.num_tiles == 0
+    assert tb.tile_keys() == []
+    assert tb.tile(0, 0, 0) is None
+
+
+def test_tile_builder(prfx, tmp_path):
+    tb = rust.TileBuilder(0, 10)
+    for bl in rust.iter_geometry(prfx):
+        tb.add_block(bl)
+    assert tb.num_tiles > 0
+
+    keys = tb.tile_keys()
+    assert {z for z, _, _ in keys} <= set(range(11))
+    z, x, y = keys[-1]
+    assert len(tb.tile(z, x, y)) > 0
+
+    outdir = tmp_path / "tiles"
+    assert tb.write(str(outdir)) == len(keys)
+    assert (outdir / str(z) / str(x) / ("%d.pbf" % y)).read_bytes() == tb.tile(z, x, y)
+    meta = json.loads((outdir / "metadata.json").read_text())
+    assert meta["format"] == "pbf"
+
+
+def test_write_mbtiles(prfx, tmp_path):
+    outfn = str(tmp_path / "out.mbtiles")
+    count = rust.write_tiles(prfx, outfn, minzoom=4, maxzoom=8)
+
+    db = sqlite3.connect(outfn)
+    assert db.execute("SELECT count(*) FROM tiles").fetchone()[0] == count
+    meta = dict(db.execute("SELECT name, value FROM metadata"))
+    assert (meta["minzoom"], meta["maxzoom"]) == ("4", "8")
+    layers = json.loads(meta["json"])["vector_layers"]
+    assert len(layers) > 0
+
+    z, x, row, data = db.execute("SELECT zoom_level, tile_column, tile_row, tile_data FROM tiles LIMIT 1").fetchone()
+    assert 4 <= z <= 8
+    mvt = pytest.importorskip("mapbox_vector_tile")
+    tile = mvt.decode(gzip.decompress(data))
+    assert set(tile) <= {l["id"] for l in layers}
+
+
+def test_write_directory(prfx, tmp_path):
+    tb = rust.TileBuilder(0, 10)
+    for bl in rust.iter_geometry(prfx):
+        tb.add_block(bl)
+
+    outdir = tmp_path / "tiles"
+    count = rust.write_tiles(prfx, str(outdir), minzoom=0, maxzoom=10)
+    written = sorted(tuple(int(p) for p in f.relative_to(outdir).with_suffix("").parts) for f in outdir.glob("*/*/*.pbf"))
+    assert count == len(written)
+    assert written == sorted(tb.tile_keys())
+
+    mvt = pytest.importorskip("mapbox_vector_tile")
+    for z, x, y in written:
+        tile = mvt.decode((outdir / str(z) / str(x) / ("%d.pbf" % y)).read_bytes())
+        for layer in tile.values():
+            for feat in layer["features"]:
+                # the element type is the last digit of the feature id
+                assert feat["id"] % 10 in (1, 2, 3)
+
+
+def test_late_features_z_order(sorted_dataset, tmp_path):
+    #the long ways are in larger quadtrees than the short ones, so are added
+    #to tiles which have already been written: they must still be drawn in
+    #z_order among the features already there
+    mvt = pytest.importorskip("mapbox_vector_tile")
+    rank = {"residential": 0, "primary": 1, "trunk": 2}
+
+    outdir = tmp_path / "tiles"
+    rust.write_tiles(sorted_dataset.prfx, str(outdir), minzoom=6, maxzoom=10)
+    mixed = 0
+    for f in outdir.glob("*/*/*.pbf"):
+        for layer in mvt.decode(f.read_bytes()).values():
+            order = [rank[feat["properties"]["highway"]] for feat in layer["features"] if feat["properties"].get("highway") in rank]
+            assert order == sorted(order)
+            mixed += len(set(order)) > 1
+    assert mixed > 0
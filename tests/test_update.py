@@ -0,0 +1,99 @@
+import gzip
+import os
+import shutil
+import pytest
+
+import conftest
+import osmquadtree_rust_bindings as oqt
+from osmquadtree_rust_bindings import rust
+
+
+@pytest.fixture
+def update_prfx(sorted_dataset, tmp_path, monkeypatch):
+    #the filelist stores the initial file relative to the prefix
+    prfx = tmp_path / "prfx"
+    diffs = tmp_path / "diffs"
+    prfx.mkdir()
+    diffs.mkdir()
+    shutil.copy(sorted_dataset.prfx, prfx / "initial.pbf")
+    monkeypatch.chdir(prfx)
+    rust.run_update_initial(str(prfx) + "/", "initial.pbf", "2021-01-01T00:00:00", 100, str(diffs) + "/", numchan=2)
+    return str(prfx) + "/"
+
+
+def test_update_initial(update_prfx):
+    fl = rust.read_filelist(update_prfx)
+    assert len(fl) == 1
+    assert fl[0].filename == "initial.pbf"
+    assert fl[0].state == 100
+    assert not fl[0].is_change()
+
+
+def test_failed_update_keeps_other_files(update_prfx):
+    #not created by the update, so it is never removed
+    user_file = os.path.join(update_prfx, "mine.pbfc")
+    open(user_file, "wb").write(b"not a change file")
+
+    #the diffs location has no diffs to apply
+    for remove_partial in (False, True):
+        with pytest.raises(oqt.OsmQuadtreeError):
+            rust.run_update(update_prfx, 1, False, 2, remove_partial)
+        assert os.path.exists(user_file)
+        assert len(rust.read_filelist(update_prfx)) == 1
+
+
+OSC = """<?xml version="1.0" encoding="UTF-8"?>
+<osmChange version="0.6">
+<modify>
+  <node id="%d" version="2" timestamp="2021-01-01T12:00:00Z" changeset="2" uid="1" user="test" lat="51.0" lon="-1.0">
+    <tag k="name" v="moved"/>
+  </node>
+</modify>
+<create>
+  <node id="900000" version="1" timestamp="2021-01-01T12:00:00Z" changeset="2" uid="1" user="test" lat="51.5" lon="-0.5"/>
+</create>
+</osmChange>
+""" % conftest.grid_node_id(0, 0)
+
+
+@pytest.mark.parametrize("oscname", ["change.osc", "change.osc.gz"])
+def test_update_file(update_prfx, tmp_path, oscname):
+    oscfn = str(tmp_path / oscname)
+    opener = gzip.open if oscname.endswith(".gz") else open
+    with opener(oscfn, "wt") as f:
+        f.write(OSC)
+
+    diffs = str(tmp_path / "diffs") + "/"
+    entry = rust.run_update_file(update_prfx, oscfn, diffs, numchan=2)
+    assert entry.is_change()
+    assert entry.state == 101
+    #the copy in the diffs location is removed again
+    assert os.listdir(diffs) == []
+
+    fl = rust.read_filelist(update_prfx)
+    assert [f.filename for f in fl] == ["initial.pbf", entry.filename]
+
+    got = []
+    with open(os.path.join(update_prfx, entry.filename), "rb") as f:
+        rust.ReadFileBlocks(f).read_all(got.extend, 1, True, 1)
+    nodes = {}
+    for bl in got:
+        for i in range(bl.num_nodes()):
+            n = bl.node_at(i)
+            nodes[n.id] = n
+    assert nodes[conftest.grid_node_id(0, 0)].tags == [("name", "moved")]
+    assert 900000 in nodes
+
+
+def test_update_file_bad_args(update_prfx, tmp_path):
+    diffs = str(tmp_path / "diffs") + "/"
+    with pytest.raises(oqt.MissingFileError):
+        rust.run_update_file(update_prfx, str(tmp_path / "missing.osc"), diffs)
+
+    oscfn = str(tmp_path / "change.osc")
+    open(oscfn, "w").write(OSC)
+    #a diff for the next state is already waiting
+    open(diffs + "101.osc.gz", "wb").write(b"")
+    with pytest.raises(oqt.InvalidInputError, match="already exists"):
+        rust.run_update_file(update_prfx, oscfn, diffs)
+    assert len(rust.read_filelist(update_prfx)) == 1
@@ -0,0 +1,73 @@
+import json
+import os
+import pytest
+
+import osmquadtree_rust_bindings as oqt
+from osmquadtree_rust_bindings import rust
+
+
+def block_locations(fn):
+    return [loc for _, _, loc, _ in rust.ReadFileBlocks(fn).get_header().index]
+
+
+def test_verify_ok(pbf):
+    report = rust.verify_pbf(pbf)
+    assert report["ok"]
+    assert report["num_blocks"] == 3
+    assert report["num_bad_blocks"] == 0
+    assert report["problems"] == []
+
+
+def test_corrupt_block(pbf, tmp_path, capfd):
+    locs = block_locations(pbf)
+    data = bytearray(open(pbf, "rb").read())
+    #overwrite the compressed data at the end of the second block
+    end = locs[2]
+    data[end - 8:end] = b"\xff" * 8
+    bad = str(tmp_path / "bad.pbf")
+    open(bad, "wb").write(data)
+
+    report = rust.verify_pbf(bad, numchan=0)
+    assert not report["ok"]
+    assert report["num_bad_blocks"] == 1
+    assert [(p["kind"], p["block"], p["position"]) for p in report["problems"]] == [("decode", 1, locs[1])]
+
+    out = str(tmp_path / "out.pbf")
+    report = rust.salvage_pbf(bad, out)
+    assert report["kept_blocks"] == 2
+    assert [l["position"] for l in report["lost"]] == [locs[1]]
+    assert json.load(open(out + ".report.json")) == report
+    #decoder panics on the corrupt block are caught without printing
+    assert "panicked" not in capfd.readouterr().err
+
+    assert rust.verify_pbf(out)["ok"]
+    got = []
+    rust.ReadFileBlocks(out).read_all(got.extend, 2, False, 1)
+    assert sorted(bl.num_nodes() + bl.num_ways() + bl.num_relations() for bl in got) == [2, 2]
+
+
+def test_truncated(pbf, tmp_path):
+    locs = block_locations(pbf)
+    data = open(pbf, "rb").read()
+    bad = str(tmp_path / "bad.pbf")
+    open(bad, "wb").write(data[:-5])
+
+    report = rust.verify_pbf(bad)
+    assert report["counts"] == {"truncated": 1, "index": 1}
+    assert [p["position"] for p in report["problems"]] == [locs[2], locs[2]]
+
+    out = str(tmp_path / "out.pbf")
+    rep = str(tmp_path / "report.json")
+    report = rust.salvage_pbf(bad, out, rep)
+    assert report["kept_blocks"] == 2
+    assert report["lost"][0]["position"] == locs[2]
+    assert report["lost_bytes"] == len(data) - 5 - locs[2]
+    assert os.path.exists(rep)
+    assert len(block_locations(out)) == 2
+
+
+def test_bad_args(pbf, tmp_path):
+    with pytest.raises(oqt.MissingFileError):
+        rust.verify_pbf(pbf + "-missing")
+    with pytest.raises(oqt.InvalidInputError):
+        rust.salvage_pbf(pbf, pbf)
@@ -0,0 +1,111 @@
+import gzip
+import pytest
+
+import osmquadtree_rust_bindings as oqt
+from osmquadtree_rust_bindings import rust
+
+OSC = """<?xml version="1.0" encoding="UTF-8"?>
+<osmChange version="0.6">
+<create>
+  <node id="1" version="1" timestamp="2021-01-01T00:00:00Z" changeset="5" uid="3" user="a&amp;b" lat="51.5" lon="-0.1">
+    <tag k="name" v="x &quot;y&quot;"/>
+  </node>
+  <way id="2" version="2" timestamp="2021-01-01T00:00:00Z" changeset="5" uid="3" user="a">
+    <nd ref="1"/>
+    <nd ref="3"/>
+    <tag k="highway" v="road"/>
+  </way>
+</create>
+<delete>
+  <relation id="4" version="3" timestamp="2021-01-01T00:00:00Z" changeset="5" uid="3" user="a">
+    <member type="way" ref="2" role="outer"/>
+  </relation>
+</delete>
+</osmChange>
+"""
+
+
+def read_all(fn, block_size=8000):
+    return rust.XmlReader(fn, block_size).read_all()
+
+
+def test_read_osc(tmp_path):
+    fn = str(tmp_path / "in.osc")
+    open(fn, "w").write(OSC)
+
+    r = rust.XmlReader(fn, 2)
+    bls = list(r)
+    assert r.is_change
+    assert len(bls) == 2
+    assert bls[0].num_nodes() == 1 and bls[0].num_ways() == 1
+    assert bls[1].num_relations() == 1
+
+    n = bls[0].node_at(0)
+    assert n.changetype == "create"
+    assert n.user == "a&b"
+    assert n.tags == [("name", 'x "y"')]
+    assert (n.lon, n.lat) == (-1000000, 515000000)
+    assert bls[0].way_at(0).refs == [1, 3]
+    assert bls[1].relation_at(0).changetype == "delete"
+    assert bls[1].relation_at(0).members == [("way", 2, "outer")]
+
+
+@pytest.mark.parametrize("outname", ["out.osc", "out.osc.gz"])
+def test_round_trip(tmp_path, outname):
+    fn = str(tmp_path / "in.osc")
+    open(fn, "w").write(OSC)
+    bls = read_all(fn)
+
+    outfn = str(tmp_path / outname)
+    with rust.XmlWriter(outfn, True) as w:
+        for bl in bls:
+            w.write_block(bl)
+    assert w.count == 3
+    if outname.endswith(".gz"):
+        assert gzip.open(outfn).read().startswith(b"<?xml")
+
+    bls2 = read_all(outfn)
+    assert [b.node_at(0).as_tuple() for b in bls2] == [b.node_at(0).as_tuple() for b in bls]
+    assert bls2[0].relation_at(0).as_tuple() == bls[0].relation_at(0).as_tuple()
+
+
+def test_write_osm_skips_deleted(tmp_path):
+    fn = str(tmp_path / "in.osc")
+    open(fn, "w").write(OSC)
+    bls = read_all(fn)
+
+    outfn = str(tmp_path / "out.osm")
+    with rust.XmlWriter(outfn, False, None, (-10000000, 500000000, 10000000, 520000000)) as w:
+        w.write_block(bls[0])
+        w.write_element(bls[0].way_at(0))
+    assert w.count == 3
+
+    r = rust.XmlReader(outfn)
+    bls2 = r.read_all()
+    assert not r.is_change
+    assert bls2[0].num_nodes() == 1 and bls2[0].num_ways() == 2 and bls2[0].num_relations() == 0
+    assert bls2[0].node_at(0).changetype == "normal"
+
+
+def test_bad_xml(tmp_path):
+    fn = str(tmp_path / "bad.osm")
+    open(fn, "w").write('<osm><node lat="1" lon="2"/></osm>')
+    with pytest.raises(oqt.PbfDecodeError):
+        read_all(fn)
+
+    open(fn, "w").write('<osm><node id="1" lat="x" lon="2"/></osm>')
+    with pytest.raises(oqt.PbfDecodeError):
+        read_all(fn)
+
+    with pytest.raises(oqt.MissingFileError):
+        rust.XmlReader(str(tmp_path / "missing.osm"))
+    with pytest.raises(TypeError):
+        rust.XmlWriter(str(tmp_path / "out.osm")).write_element(1)
+
+
+def test_write_osc_needs_changes(tmp_path, small_blocks):
+    #elements read from a .osm file aren't changes
+    w = rust.XmlWriter(str(tmp_path / "out.osc"), True)
+    with pytest.raises(oqt.InvalidInputError):
+        w.write_block(small_blocks[0])
+    assert w.count == 0